@@ -0,0 +1,214 @@
+//! Speech to text behind one trait, so applications can swap providers as
+//! they can for chat. OpenAI and Groq both serve Whisper models over the
+//! same multipart API.
+use futures::future::BoxFuture;
+use reqwest::Client;
+use reqwest::multipart::{Form, Part};
+use serde_derive::Deserialize;
+use crate::common::{Attachment, LlmError};
+use crate::gpt::{gpt_transcribe, gpt_translate};
+use crate::groq::{groq_transcribe, groq_translate};
+
+/// Output format of a transcription or translation
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TranscriptionFormat {
+    #[default]
+    Json,
+    Text,
+    /// Adds language, duration and timed segments
+    VerboseJson,
+}
+
+impl TranscriptionFormat {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TranscriptionFormat::Json => "json",
+            TranscriptionFormat::Text => "text",
+            TranscriptionFormat::VerboseJson => "verbose_json",
+        }
+    }
+}
+
+/// Options for speech to text, the model defaults to the provider's:
+/// GROQ_AUDIO_MODEL or whisper-large-v3, GPT_AUDIO_MODEL or whisper-1
+#[derive(Debug, Clone, Default)]
+pub struct TranscriptionOptions {
+    pub model: Option<String>,
+    /// ISO-639-1 language of the audio, for transcription only
+    pub language: Option<String>,
+    /// Text to guide the style or spelling of the output
+    pub prompt: Option<String>,
+    pub temperature: Option<f32>,
+    pub response_format: TranscriptionFormat,
+}
+
+impl TranscriptionOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn model(mut self, model: &str) -> Self {
+        self.model = Some(model.into());
+        self
+    }
+
+    pub fn language(mut self, language: &str) -> Self {
+        self.language = Some(language.into());
+        self
+    }
+
+    pub fn prompt(mut self, prompt: &str) -> Self {
+        self.prompt = Some(prompt.into());
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    pub fn response_format(mut self, response_format: TranscriptionFormat) -> Self {
+        self.response_format = response_format;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Transcription {
+    pub text: String,
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Seconds of audio
+    #[serde(default)]
+    pub duration: Option<f64>,
+    #[serde(default)]
+    pub segments: Option<Vec<TranscriptionSegment>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TranscriptionSegment {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
+/// Post audio to an OpenAI style transcription or translation endpoint
+pub(crate) async fn whisper(client: &Client, url: &str, model: &str, audio: &Attachment, options: &TranscriptionOptions, is_transcription: bool) -> Result<Transcription, Box<dyn std::error::Error + Send>> {
+    let part = Part::bytes(audio.data.clone())
+        .file_name(audio.name.clone())
+        .mime_str(&audio.mime_type)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+    let mut form = Form::new()
+        .text("model", model.to_string())
+        .text("response_format", options.response_format.as_str())
+        .part("file", part);
+
+    if let (Some(ref language), true) = (&options.language, is_transcription) {
+        form = form.text("language", language.clone());
+    }
+    if let Some(ref prompt) = options.prompt {
+        form = form.text("prompt", prompt.clone());
+    }
+    if let Some(temperature) = options.temperature {
+        form = form.text("temperature", temperature.to_string());
+    }
+
+    let res = client
+        .post(url)
+        .multipart(form)
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    transcription(&res, options.response_format)
+}
+
+fn transcription(res: &str, format: TranscriptionFormat) -> Result<Transcription, Box<dyn std::error::Error + Send>> {
+    if res.contains("\"error\"") && res.trim_start().starts_with('{') {
+        let err = serde_json::from_str::<LlmError>(res)
+            .map(|e| e.error.to_string())
+            .unwrap_or(res.to_string());
+
+        return Err(Box::new(std::io::Error::other(err)));
+    }
+
+    match format {
+        TranscriptionFormat::Text => Ok(Transcription { text: res.trim_end().to_string(), language: None, duration: None, segments: None }),
+        _ => serde_json::from_str(res)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) }),
+    }
+}
+
+/// Speech to text provider
+pub trait AudioTranscriber: Send + Sync {
+    /// Text of the audio in its own language
+    fn transcribe<'a>(&'a self, audio: &'a Attachment, options: &'a TranscriptionOptions) -> BoxFuture<'a, Result<Transcription, Box<dyn std::error::Error + Send>>>;
+
+    /// Text of the audio in English
+    fn translate<'a>(&'a self, audio: &'a Attachment, options: &'a TranscriptionOptions) -> BoxFuture<'a, Result<Transcription, Box<dyn std::error::Error + Send>>>;
+}
+
+/// OpenAI Whisper, at GPT_TRANSCRIPTION_URL and GPT_TRANSLATION_URL
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GptTranscriber;
+
+impl AudioTranscriber for GptTranscriber {
+    fn transcribe<'a>(&'a self, audio: &'a Attachment, options: &'a TranscriptionOptions) -> BoxFuture<'a, Result<Transcription, Box<dyn std::error::Error + Send>>> {
+        Box::pin(gpt_transcribe(audio, options))
+    }
+
+    fn translate<'a>(&'a self, audio: &'a Attachment, options: &'a TranscriptionOptions) -> BoxFuture<'a, Result<Transcription, Box<dyn std::error::Error + Send>>> {
+        Box::pin(gpt_translate(audio, options))
+    }
+}
+
+/// Whisper on Groq, at GROQ_TRANSCRIPTION_URL and GROQ_TRANSLATION_URL
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GroqTranscriber;
+
+impl AudioTranscriber for GroqTranscriber {
+    fn transcribe<'a>(&'a self, audio: &'a Attachment, options: &'a TranscriptionOptions) -> BoxFuture<'a, Result<Transcription, Box<dyn std::error::Error + Send>>> {
+        Box::pin(groq_transcribe(audio, options))
+    }
+
+    fn translate<'a>(&'a self, audio: &'a Attachment, options: &'a TranscriptionOptions) -> BoxFuture<'a, Result<Transcription, Box<dyn std::error::Error + Send>>> {
+        Box::pin(groq_translate(audio, options))
+    }
+}
+
+/// Transcriber for a named provider, None if it has no speech to text
+pub fn transcriber(llm: &str) -> Option<Box<dyn AudioTranscriber>> {
+    match llm {
+        "openai" | "gpt" => Some(Box::new(GptTranscriber)),
+        "groq" => Some(Box::new(GroqTranscriber)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[tokio::test]
+    #[serial]
+    async fn test_gpt_transcriber() {
+        let server = crate::mock::mock_post("/v1/audio/transcriptions", 200, "groq/transcription.json").await;
+
+        std::env::set_var("GPT_TRANSCRIPTION_URL", format!("{}/v1/audio/transcriptions", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        let audio = Attachment::new("capital.wav", "audio/wav", vec![0; 16]);
+        let res = transcriber("gpt").unwrap().transcribe(&audio, &TranscriptionOptions::new()).await.unwrap();
+
+        assert_eq!(res.text.trim(), "The capital of Australia is Canberra.");
+        assert!(transcriber("claude").is_none());
+
+        let body = String::from_utf8_lossy(&server.received_requests().await.unwrap()[0].body).to_string();
+
+        assert!(body.contains("whisper-1"));
+    }
+}
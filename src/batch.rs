@@ -0,0 +1,353 @@
+use serde_derive::{Deserialize, Serialize};
+use crate::common::*;
+use crate::config::required_env;
+use crate::error::LlmClientError;
+use crate::files::{FilePurpose, get_file_text, upload_file};
+use crate::gpt::{GptCompletion, get_gpt_client, gpt_response_to_return};
+use crate::pending::PendingResult;
+
+pub use crate::files::FileObject;
+
+// OpenAI Batch API: many chat completions processed offline within 24 hours
+// at a reduced price. Build JSONL, upload it, create batch, poll and collect.
+
+// Input structures
+
+/// One line of the batch input file
+#[derive(Debug, Serialize, Clone)]
+pub struct BatchLine<'a> {
+    pub custom_id: String,
+    pub method: String,
+    pub url: String,
+    pub body: &'a GptCompletion,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct BatchCreate {
+    pub input_file_id: String,
+    pub endpoint: String,
+    pub completion_window: String,
+}
+
+// Output structures
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Batch {
+    pub id: String,
+    pub status: String,
+    pub input_file_id: String,
+    pub output_file_id: Option<String>,
+    pub error_file_id: Option<String>,
+    pub created_at: u64,
+    pub request_counts: Option<RequestCounts>,
+}
+
+impl Batch {
+    /// Batch will make no further progress
+    pub fn is_finished(&self) -> bool {
+        matches!(self.status.as_str(), "completed" | "failed" | "expired" | "cancelled")
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RequestCounts {
+    pub total: usize,
+    pub completed: usize,
+    pub failed: usize,
+}
+
+impl std::fmt::Display for RequestCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} of {} completed, {} failed", self.completed, self.total, self.failed)
+    }
+}
+
+/// One line of the batch output (or error) file
+#[derive(Debug, Deserialize)]
+pub struct BatchResultLine {
+    pub custom_id: String,
+    pub response: Option<BatchResponse>,
+    pub error: Option<LlmErrorMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchResponse {
+    pub status_code: u16,
+    pub body: serde_json::Value,
+}
+
+/// Build JSONL batch input from many completions. Lines are tagged with
+/// their position so results can be returned in the same order.
+pub fn batch_jsonl(completions: &[GptCompletion]) -> Result<String, Box<dyn std::error::Error + Send>> {
+    completions.iter()
+        .enumerate()
+        .map(|(i, c)| {
+            let line = BatchLine {
+                custom_id: format!("request-{i}"),
+                method: "POST".into(),
+                url: "/v1/chat/completions".into(),
+                body: c,
+            };
+
+            serde_json::to_string(&line)
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+        })
+        .collect::<Result<Vec<String>, _>>()
+        .map(|lines| lines.join("\n"))
+}
+
+/// Upload JSONL batch input, returning the file id
+pub async fn upload_batch_file(jsonl: &str) -> Result<String, Box<dyn std::error::Error + Send>> {
+    let file = upload_file("batch.jsonl", jsonl.as_bytes(), FilePurpose::Batch).await?;
+
+    Ok(file.id)
+}
+
+/// Create a batch from a previously uploaded input file
+pub async fn create_batch(input_file_id: &str) -> Result<Batch, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_BATCH_URL")?;
+    let client = get_gpt_client().await?;
+
+    let create = BatchCreate {
+        input_file_id: input_file_id.into(),
+        endpoint: "/v1/chat/completions".into(),
+        completion_window: "24h".into(),
+    };
+
+    // Retried with the same key, so a blip can't create the batch twice
+    let res = send_idempotent(client
+        .post(url)
+        .json(&create))
+        .await?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    batch_json(&res)
+}
+
+/// Retrieve current state of a batch
+pub async fn get_batch(batch_id: &str) -> Result<Batch, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_BATCH_URL")?;
+    let client = get_gpt_client().await?;
+
+    let res = client
+        .get(format!("{url}/{batch_id}"))
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    batch_json(&res)
+}
+
+/// Cancel a batch that is in progress
+pub async fn cancel_batch(batch_id: &str) -> Result<Batch, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_BATCH_URL")?;
+    let client = get_gpt_client().await?;
+
+    let res = client
+        .post(format!("{url}/{batch_id}/cancel"))
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    batch_json(&res)
+}
+
+/// Poll a batch until it is finished, failing with a TimedOut error if
+/// max_wait passes first. The batch carries on, so can be waited for again
+/// or cancelled.
+pub async fn wait_for_batch(batch_id: &str, poll_interval: std::time::Duration, max_wait: std::time::Duration) -> Result<Batch, Box<dyn std::error::Error + Send>> {
+    let start = std::time::Instant::now();
+
+    loop {
+        let batch = get_batch(batch_id).await?;
+
+        if batch.is_finished() {
+            return Ok(batch);
+        }
+        if start.elapsed() + poll_interval > max_wait {
+            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::TimedOut, format!("Batch {batch_id} not finished in {max_wait:?}"))));
+        }
+
+        tokio::time::sleep(poll_interval).await;
+    }
+}
+
+/// Download the output (and any error) file of a finished batch and
+/// unpack into LlmReturns in the original request order. A batch that
+/// failed, expired or was cancelled before producing any output is an error.
+pub async fn get_batch_results(batch: &Batch) -> Result<Vec<LlmReturn>, Box<dyn std::error::Error + Send>> {
+    if batch.status != "completed" && batch.output_file_id.is_none() {
+        return Err(Box::new(LlmClientError::Failed(format!("Batch {} {} with no output", batch.id, batch.status))));
+    }
+
+    let mut lines = String::new();
+
+    for file_id in [&batch.output_file_id, &batch.error_file_id].into_iter().flatten() {
+        lines.push_str(&get_file_text(file_id).await?);
+        lines.push('\n');
+    }
+
+    batch_results(&lines, batch.request_counts.as_ref().map(|c| c.total).unwrap_or(0))
+}
+
+/// Build, upload, run and collect a batch. Blocks until the batch finishes,
+/// which may take up to 24 hours.
+pub async fn call_gpt_batch(completions: &[GptCompletion], poll_interval: std::time::Duration) -> Result<Vec<LlmReturn>, Box<dyn std::error::Error + Send>> {
+    submit_gpt_batch(completions, poll_interval).await?
+        .await_result()
+        .await
+}
+
+/// Build, upload and create a batch without waiting for it. The handle
+/// polls, waits or calls back with the results.
+pub async fn submit_gpt_batch(completions: &[GptCompletion], poll_interval: std::time::Duration) -> Result<PendingResult<Vec<LlmReturn>>, Box<dyn std::error::Error + Send>> {
+    let jsonl = batch_jsonl(completions)?;
+    let file_id = upload_batch_file(&jsonl).await?;
+    let batch = create_batch(&file_id).await?;
+
+    Ok(pending_batch(&batch.id, poll_interval))
+}
+
+/// Handle to a batch created earlier, e.g. by another process, by its id
+pub fn pending_batch(batch_id: &str, poll_interval: std::time::Duration) -> PendingResult<Vec<LlmReturn>> {
+    PendingResult::new(batch_id, poll_interval, |batch_id| Box::pin(async move {
+        let batch = get_batch(&batch_id).await?;
+
+        if !batch.is_finished() {
+            return Ok(None);
+        }
+
+        get_batch_results(&batch).await.map(Some)
+    }))
+}
+
+/// Unpack batch output JSONL into LlmReturns, one per submitted request in
+/// request order. Any request with no line, e.g. when a batch expired part
+/// way, gets a GPT_ERROR "No result" in its place.
+pub fn batch_results(jsonl: &str, total: usize) -> Result<Vec<LlmReturn>, Box<dyn std::error::Error + Send>> {
+    let mut results: Vec<Option<LlmReturn>> = vec![None; total];
+
+    for line in jsonl.lines().filter(|l| !l.trim().is_empty()) {
+        let res: BatchResultLine = batch_json(line)?;
+        let pos = res.custom_id.strip_prefix("request-")
+            .and_then(|pos| pos.parse::<usize>().ok())
+            .ok_or_else(|| -> Box<dyn std::error::Error + Send> { Box::new(LlmClientError::Failed(format!("Unexpected batch custom_id: {}", res.custom_id))) })?;
+
+        let ret = match (res.response, res.error) {
+            (Some(response), None) if response.status_code == 200 =>
+                gpt_response_to_return(&response.body.to_string(), 0.0)?,
+            (Some(response), None) => {
+                let body = response.body.to_string();
+
+                LlmReturn::new(LlmType::GPT_ERROR, body.clone(), body.into(), (0, 0, 0), 0.0, None, None)
+            },
+            (_, Some(error)) =>
+                LlmReturn::new(LlmType::GPT_ERROR, error.to_string(), error.to_string().into(), (0, 0, 0), 0.0, None, None),
+            (None, None) =>
+                LlmReturn::new(LlmType::GPT_ERROR, "No response".into(), "No response".into(), (0, 0, 0), 0.0, None, None),
+        };
+
+        if pos >= results.len() {
+            results.resize(pos + 1, None);
+        }
+        results[pos] = Some(ret);
+    }
+
+    Ok(results.into_iter()
+        .map(|r| r.unwrap_or_else(|| LlmReturn::new(LlmType::GPT_ERROR, "No result".into(), "No result".into(), (0, 0, 0), 0.0, None, None)))
+        .collect())
+}
+
+fn batch_json<T: serde::de::DeserializeOwned>(res: &str) -> Result<T, Box<dyn std::error::Error + Send>> {
+    if res.contains("\"error\":") && !res.contains("\"custom_id\"") {
+        if let Ok(err) = serde_json::from_str::<LlmError>(res) {
+            return Err(Box::new(std::io::Error::other(err.error.to_string())));
+        }
+    }
+
+    serde_json::from_str(res)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt::{GptMessage, ResponseFormat};
+    use crate::mock::mock_get;
+    use serial_test::serial;
+
+    #[test]
+    fn test_batch_jsonl() {
+        let completion = GptCompletion {
+            model: "gpt-4o-mini".into(),
+            tools: None,
+            messages: vec![GptMessage::text("user", "Hello")],
+            response_format: ResponseFormat::new(false),
+            temperature: 0.2,
+            top_p: None,
+            max_tokens: None,
+        };
+        let jsonl = batch_jsonl(&[completion.clone(), completion]).unwrap();
+        let lines: Vec<&str> = jsonl.lines().collect();
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].contains("\"custom_id\":\"request-1\""));
+        assert!(lines[0].contains("\"url\":\"/v1/chat/completions\""));
+    }
+
+    #[test]
+    #[serial]
+    fn test_batch_results_ordered() {
+        let jsonl = r#"{"id":"b1","custom_id":"request-1","response":{"status_code":200,"request_id":"r1","body":{"id":"c1","object":"chat.completion","created":1,"model":"gpt-4o-mini","choices":[{"index":0,"message":{"role":"assistant","content":"Second"},"logprobs":null,"finish_reason":"stop"}],"usage":{"prompt_tokens":5,"completion_tokens":1,"total_tokens":6}}},"error":null}
+{"id":"b0","custom_id":"request-0","response":null,"error":{"code":"bad","message":"Failed"}}"#;
+        let results = batch_results(jsonl, 2).unwrap();
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].llm_type, LlmType::GPT_ERROR);
+        assert_eq!(results[1].text, "Second\n");
+        assert_eq!(results[1].usage, (5, 1, 6));
+    }
+
+    #[test]
+    #[serial]
+    fn test_batch_results_missing_line() {
+        let jsonl = r#"{"id":"b1","custom_id":"request-1","response":{"status_code":200,"request_id":"r1","body":{"id":"c1","object":"chat.completion","created":1,"model":"gpt-4o-mini","choices":[{"index":0,"message":{"role":"assistant","content":"Second"},"logprobs":null,"finish_reason":"stop"}],"usage":{"prompt_tokens":5,"completion_tokens":1,"total_tokens":6}}},"error":null}"#;
+        let results = batch_results(jsonl, 3).unwrap();
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].llm_type, LlmType::GPT_ERROR);
+        assert_eq!(results[0].text, "No result");
+        assert_eq!(results[1].text, "Second\n");
+        assert_eq!(results[2].text, "No result");
+        assert!(batch_results(r#"{"id":"b2","custom_id":"other","response":null,"error":null}"#, 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failed_batch_results() {
+        let batch: Batch = serde_json::from_str(r#"{"id":"batch_abc123","status":"failed","input_file_id":"file-abc123","output_file_id":null,"error_file_id":null,"created_at":1720958400,"request_counts":{"total":0,"completed":0,"failed":0}}"#).unwrap();
+        let err = get_batch_results(&batch).await.unwrap_err();
+
+        assert_eq!(err.to_string(), "Batch batch_abc123 failed with no output");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_wait_for_batch_max_wait() {
+        let server = mock_get("/v1/batches/batch_abc123", 200, "gpt/batch_in_progress.json").await;
+
+        std::env::set_var("GPT_BATCH_URL", format!("{}/v1/batches", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        let err = wait_for_batch("batch_abc123", std::time::Duration::from_millis(10), std::time::Duration::from_millis(50)).await.unwrap_err();
+
+        assert!(err.to_string().contains("batch_abc123 not finished in 50ms"));
+    }
+}
@@ -0,0 +1,130 @@
+use crate::common::{call_llm_model, LlmReturn};
+use crate::pricing::cost;
+
+/// Fixed prompt set, short enough to keep a benchmark cheap but varied
+/// enough in answer length to give a fair tokens/sec figure
+pub const BENCH_PROMPTS: [&str; 5] = [
+    "Reply with the single word: pong",
+    "What is the capital of Australia? Answer in one sentence.",
+    "List the first ten prime numbers, comma separated.",
+    "Explain in one paragraph why the sky is blue.",
+    "Write a haiku about the sea.",
+];
+
+/// Latency, throughput and cost of one provider and model over a benchmark run
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchReport {
+    pub llm: String,
+    pub model: String,
+    pub calls: usize,
+    pub errors: usize,
+    /// Latency percentiles in seconds, over successful calls
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    /// Output tokens per second of elapsed call time
+    pub tokens_per_sec: f64,
+    pub tokens: usize,
+    /// None if the model price is unknown
+    pub cost: Option<f64>,
+}
+
+impl BenchReport {
+    /// Summarise the results of a run
+    pub fn new(llm: &str, model: &str, results: &[Result<LlmReturn, String>]) -> Self {
+        let ok: Vec<&LlmReturn> = results.iter().filter_map(|r| r.as_ref().ok()).collect();
+        let mut timings: Vec<f64> = ok.iter().map(|r| r.timing.total).collect();
+        timings.sort_by(f64::total_cmp);
+
+        let elapsed: f64 = timings.iter().sum();
+        let output: usize = ok.iter().map(|r| r.usage.1).sum();
+        let tokens: usize = ok.iter().map(|r| r.usage.2).sum();
+        let cost: Option<f64> = ok.iter().map(|r| cost(model, r.usage)).sum();
+
+        BenchReport {
+            llm: llm.into(),
+            model: model.into(),
+            calls: results.len(),
+            errors: results.len() - ok.len(),
+            p50: percentile(&timings, 50.0),
+            p90: percentile(&timings, 90.0),
+            p99: percentile(&timings, 99.0),
+            tokens_per_sec: if elapsed > 0.0 { output as f64 / elapsed } else { 0.0 },
+            tokens,
+            cost,
+        }
+    }
+
+    /// Column headings matching Display
+    pub fn header() -> String {
+        format!("{:<8} {:<32} {:>5} {:>6} {:>7} {:>7} {:>7} {:>9} {:>8} {:>9}",
+                "Provider", "Model", "Calls", "Errors", "p50 s", "p90 s", "p99 s", "Tokens/s", "Tokens", "Cost $")
+    }
+}
+
+impl std::fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let cost = match self.cost {
+            Some(c) => format!("{c:.4}"),
+            None => "unknown".into(),
+        };
+
+        write!(f, "{:<8} {:<32} {:>5} {:>6} {:>7.2} {:>7.2} {:>7.2} {:>9.1} {:>8} {:>9}",
+               self.llm, self.model, self.calls, self.errors, self.p50, self.p90, self.p99, self.tokens_per_sec, self.tokens, cost)
+    }
+}
+
+/// Nearest-rank percentile of sorted values, 0 if there are none
+pub fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Send each prompt `runs` times to a model, one call at a time so that
+/// latency is not distorted by our own concurrency
+pub async fn bench(llm: &str, model: &str, prompts: &[&str], runs: usize) -> BenchReport {
+    let mut results = Vec::new();
+
+    for _ in 0..runs {
+        for prompt in prompts {
+            let res = call_llm_model(llm, model, "", &[prompt.to_string()], 0.2, false, false).await
+                .map_err(|e| e.to_string())
+                .and_then(|r| if r.llm_type.is_error() { Err(r.text) } else { Ok(r) });
+
+            results.push(res);
+        }
+    }
+
+    BenchReport::new(llm, model, &results)
+}
+
+/// Benchmark several provider and model pairs side by side, concurrently
+pub async fn bench_all(configs: &[(&str, &str)], prompts: &[&str], runs: usize) -> Vec<BenchReport> {
+    futures::future::join_all(configs.iter().map(|(llm, model)| bench(llm, model, prompts, runs))).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{FinishReason, LlmType};
+
+    #[test]
+    fn test_bench_report() {
+        let ret = |timing, out| Ok(LlmReturn::new(LlmType::GPT, "".into(), FinishReason::Stop, (10, out, 10 + out), timing, None, None));
+        let results = vec![ret(1.0, 10), ret(3.0, 30), Err("timeout".into()), ret(2.0, 20)];
+        let report = BenchReport::new("gpt", "gpt-4o", &results);
+
+        assert_eq!(report.calls, 4);
+        assert_eq!(report.errors, 1);
+        assert_eq!((report.p50, report.p90, report.p99), (2.0, 3.0, 3.0));
+        assert_eq!(report.tokens_per_sec, 10.0);
+        assert_eq!(report.tokens, 90);
+        assert!(report.cost.unwrap() > 0.0);
+        assert_eq!(percentile(&[], 50.0), 0.0);
+    }
+}
@@ -0,0 +1,61 @@
+use clap::Parser;
+use llmclient::bench::{bench_all, BenchReport, BENCH_PROMPTS};
+use llmclient::compare::configuration;
+
+/// Send a fixed prompt set to several providers and compare latency,
+/// throughput and cost side by side
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Provider to benchmark, optionally with a model, e.g. gpt or claude:claude-3-haiku-20240307.
+    /// Repeat for each provider
+    #[arg(short, long, required = true)]
+    provider: Vec<String>,
+
+    /// Times to send each prompt
+    #[arg(short, long, default_value_t = 1)]
+    runs: usize,
+
+    /// File of prompts, one per line, instead of the built in set
+    #[arg(long)]
+    prompts_file: Option<String>,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+
+    let mut configs: Vec<(&str, String)> = Vec::new();
+
+    for provider in &args.provider {
+        match configuration(provider) {
+            Some(config) => configs.push(config),
+            None => {
+                eprintln!("Unknown provider '{provider}' or no model set for it: use gemini, gpt, claude, mistral or groq");
+
+                return;
+            }
+        }
+    }
+
+    let prompts: Vec<String> = match args.prompts_file {
+        Some(ref path) => match std::fs::read_to_string(path) {
+            Ok(text) => text.lines().map(|l| l.trim().to_string()).filter(|l| !l.is_empty()).collect(),
+            Err(e) => {
+                eprintln!("Cannot read prompts from '{path}': {e}");
+
+                return;
+            }
+        },
+        None => BENCH_PROMPTS.iter().map(|p| p.to_string()).collect(),
+    };
+    let prompts: Vec<&str> = prompts.iter().map(|p| p.as_str()).collect();
+    let configs: Vec<(&str, &str)> = configs.iter().map(|(llm, model)| (*llm, model.as_str())).collect();
+
+    println!("Benchmarking {} prompts x {} runs", prompts.len(), args.runs);
+
+    let reports = bench_all(&configs, &prompts, args.runs).await;
+
+    println!("{}", BenchReport::header());
+    reports.iter().for_each(|r| println!("{r}"));
+}
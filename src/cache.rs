@@ -0,0 +1,417 @@
+//! Response caching, so identical calls are answered without a round trip.
+//! Once a cache is installed with `set_response_cache`, `call_llm_model`
+//! looks up each call by a hash of the LLM, model, prompts and settings,
+//! including any response schema and the provider defaults, and stores
+//! successful replies. Under `Guardrails` only replies that pass are stored. The cache is best effort: if it cannot be
+//! read or written the call goes to the LLM as usual.
+//!
+//! A cached reply is returned as it was, usage and timing included, so
+//! sampling at a non zero temperature is not repeated.
+//!
+//! Calls inside `tenants::with_tenant` bypass the cache.
+use std::sync::{Arc, RwLock};
+use futures::future::BoxFuture;
+use sha2::{Digest, Sha256};
+use crate::common::{LlmReturn, current_response_schema};
+use crate::config::provider_defaults;
+
+/// Where replies are kept between calls
+pub trait ResponseCache: Send + Sync {
+    /// Reply stored under key, None if absent or expired
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<LlmReturn>, Box<dyn std::error::Error + Send>>>;
+
+    /// Store reply under key
+    fn put<'a>(&'a self, key: &'a str, ret: &'a LlmReturn) -> BoxFuture<'a, Result<(), Box<dyn std::error::Error + Send>>>;
+}
+
+/// Hex SHA-256 of everything that determines a reply, including the
+/// current response schema and the LLM's provider defaults
+pub fn cache_key(llm: &str, model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool) -> String {
+    let mut hasher = Sha256::new();
+
+    // Lengths first, so different splits of the same text differ
+    for part in [llm, model, system].into_iter().chain(user.iter().map(|u| u.as_str())) {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part.as_bytes());
+    }
+    hasher.update(temperature.to_le_bytes());
+    hasher.update([is_json as u8, is_chat as u8]);
+    // max_tokens, top_p, stop_sequences and the like, as they are sent
+    hasher.update(format!("{:?}", provider_defaults(llm)).as_bytes());
+    if let Some(schema) = current_response_schema() {
+        hasher.update(schema.to_string().as_bytes());
+    }
+
+    hasher.finalize().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+static RESPONSE_CACHE: RwLock<Option<Arc<dyn ResponseCache>>> = RwLock::new(None);
+
+/// Cache replies from now on
+pub fn set_response_cache(cache: impl ResponseCache + 'static) {
+    *RESPONSE_CACHE.write().unwrap() = Some(Arc::new(cache));
+}
+
+/// Stop caching replies
+pub fn clear_response_cache() {
+    *RESPONSE_CACHE.write().unwrap() = None;
+}
+
+/// Cache in use, if any
+pub fn response_cache() -> Option<Arc<dyn ResponseCache>> {
+    RESPONSE_CACHE.read().unwrap().clone()
+}
+
+/// Replies kept as JSON files named by key in a directory, e.g. for
+/// notebooks and test suites. Entries older than the TTL are ignored and
+/// removed when read; once the files exceed the size cap the oldest are
+/// removed until under it.
+#[derive(Debug, Clone)]
+pub struct DiskCache {
+    dir: std::path::PathBuf,
+    ttl: Option<std::time::Duration>,
+    max_bytes: Option<u64>,
+}
+
+impl DiskCache {
+    /// Cache in dir, created when first written, with no TTL or size cap
+    pub fn new(dir: impl Into<std::path::PathBuf>) -> Self {
+        DiskCache { dir: dir.into(), ttl: None, max_bytes: None }
+    }
+
+    /// Ignore replies older than this
+    pub fn with_ttl(mut self, ttl: std::time::Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Remove the oldest replies when the cache grows beyond this
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    fn path(&self, key: &str) -> std::path::PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+
+    fn expired(&self, modified: std::time::SystemTime) -> bool {
+        match self.ttl {
+            Some(ttl) => modified.elapsed().map(|age| age > ttl).unwrap_or(false),
+            None => false,
+        }
+    }
+
+    // Oldest first until the total size is within the cap
+    async fn evict(&self) -> std::io::Result<()> {
+        let Some(max_bytes) = self.max_bytes else {
+            return Ok(());
+        };
+
+        let mut entries = Vec::new();
+        let mut dir = tokio::fs::read_dir(&self.dir).await?;
+
+        while let Some(entry) = dir.next_entry().await? {
+            let metadata = entry.metadata().await?;
+
+            if metadata.is_file() && entry.path().extension().is_some_and(|e| e == "json") {
+                entries.push((metadata.modified()?, metadata.len(), entry.path()));
+            }
+        }
+
+        entries.sort();
+
+        let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+
+        for (_, len, path) in entries {
+            if total <= max_bytes {
+                break;
+            }
+            tokio::fs::remove_file(path).await?;
+            total -= len;
+        }
+
+        Ok(())
+    }
+}
+
+impl ResponseCache for DiskCache {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<LlmReturn>, Box<dyn std::error::Error + Send>>> {
+        Box::pin(async move {
+            let path = self.path(key);
+            let metadata = match tokio::fs::metadata(&path).await {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(Box::new(e) as Box<dyn std::error::Error + Send>),
+            };
+
+            if metadata.modified().map(|m| self.expired(m)).unwrap_or(false) {
+                let _ = tokio::fs::remove_file(&path).await;
+
+                return Ok(None);
+            }
+
+            let json = tokio::fs::read_to_string(&path).await
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+            serde_json::from_str(&json)
+                .map(Some)
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+        })
+    }
+
+    fn put<'a>(&'a self, key: &'a str, ret: &'a LlmReturn) -> BoxFuture<'a, Result<(), Box<dyn std::error::Error + Send>>> {
+        Box::pin(async move {
+            let json = serde_json::to_string(ret)
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+            // Written aside and renamed, so readers never see part of a reply
+            let partial = self.dir.join(format!("{key}.part"));
+
+            async {
+                tokio::fs::create_dir_all(&self.dir).await?;
+                tokio::fs::write(&partial, json).await?;
+                tokio::fs::rename(&partial, self.path(key)).await?;
+
+                self.evict().await
+            }
+            .await
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+        })
+    }
+}
+
+/// Replies shared between processes in Redis, as JSON under a key prefix
+/// and optionally expiring
+#[cfg(feature = "redis")]
+#[derive(Clone)]
+pub struct RedisCache {
+    connection: redis::aio::ConnectionManager,
+    prefix: String,
+    ttl: Option<std::time::Duration>,
+}
+
+#[cfg(feature = "redis")]
+impl RedisCache {
+    /// Connect to e.g. redis://127.0.0.1/, reconnecting as needed. Keys are
+    /// prefixed with "llmclient:" and never expire unless set otherwise.
+    pub async fn new(url: &str) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        let client = redis::Client::open(url)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+        let connection = redis::aio::ConnectionManager::new(client).await
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+        Ok(RedisCache { connection, prefix: "llmclient:".into(), ttl: None })
+    }
+
+    /// Namespace for keys, e.g. per application or environment
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Expire replies after this long, rounded up to whole seconds
+    pub fn with_ttl(mut self, ttl: std::time::Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{key}", self.prefix)
+    }
+}
+
+#[cfg(feature = "redis")]
+impl ResponseCache for RedisCache {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<LlmReturn>, Box<dyn std::error::Error + Send>>> {
+        Box::pin(async move {
+            let mut connection = self.connection.clone();
+            let json: Option<String> = redis::AsyncCommands::get(&mut connection, self.key(key)).await
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+            json.map(|json| serde_json::from_str(&json))
+                .transpose()
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+        })
+    }
+
+    fn put<'a>(&'a self, key: &'a str, ret: &'a LlmReturn) -> BoxFuture<'a, Result<(), Box<dyn std::error::Error + Send>>> {
+        Box::pin(async move {
+            let mut connection = self.connection.clone();
+            let json = serde_json::to_string(ret)
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+            let res: redis::RedisResult<()> = match self.ttl {
+                Some(ttl) => redis::AsyncCommands::set_ex(&mut connection, self.key(key), json, ttl.as_secs_f64().ceil() as u64).await,
+                None => redis::AsyncCommands::set(&mut connection, self.key(key), json).await,
+            };
+
+            res.map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use serial_test::serial;
+    use crate::common::call_llm_model;
+
+    #[derive(Default)]
+    struct MapCache(Mutex<HashMap<String, String>>);
+
+    impl ResponseCache for MapCache {
+        fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<LlmReturn>, Box<dyn std::error::Error + Send>>> {
+            Box::pin(async move {
+                Ok(self.0.lock().unwrap().get(key).map(|json| serde_json::from_str(json).unwrap()))
+            })
+        }
+
+        fn put<'a>(&'a self, key: &'a str, ret: &'a LlmReturn) -> BoxFuture<'a, Result<(), Box<dyn std::error::Error + Send>>> {
+            Box::pin(async move {
+                self.0.lock().unwrap().insert(key.into(), serde_json::to_string(ret).unwrap());
+
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_cache_key() {
+        let key = || cache_key("gpt", "gpt-4o", "", &["ab".into(), "c".into()], 0.2, false, true);
+        let first = key();
+
+        assert_eq!(first.len(), 64);
+        assert_eq!(first, key());
+        assert_ne!(first, cache_key("gpt", "gpt-4o", "", &["a".into(), "bc".into()], 0.2, false, true));
+        assert_ne!(first, cache_key("gpt", "gpt-4o", "", &["ab".into(), "c".into()], 0.3, false, true));
+
+        // Schemas and provider defaults change the reply, so the key
+        let schema = crate::common::with_response_schema(serde_json::json!({"type": "object"}), async { key() }).await;
+
+        assert_ne!(first, schema);
+
+        let defaults = crate::config::ProviderDefaults { max_tokens: Some(10), ..Default::default() };
+
+        crate::config::set_provider_defaults([("gpt".to_string(), defaults)].into());
+        let limited = key();
+        crate::config::set_provider_defaults(Default::default());
+
+        assert_ne!(first, limited);
+    }
+
+    #[tokio::test]
+    async fn test_disk_cache() {
+        let dir = std::env::temp_dir().join(format!("llmclient-cache-{}", std::process::id()));
+        let ret = LlmReturn::new(crate::common::LlmType::GPT, "Hello".into(), "stop".into(), (1, 2, 3), 0.5, None, None);
+        let json_len = serde_json::to_string(&ret).unwrap().len() as u64;
+        let cache = DiskCache::new(&dir).with_max_bytes(2 * json_len);
+
+        assert!(cache.get("a").await.unwrap().is_none());
+
+        for key in ["a", "b", "c"] {
+            cache.put(key, &ret).await.unwrap();
+            // Distinct modification times, so the oldest is well defined
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        assert!(cache.get("a").await.unwrap().is_none());
+        assert_eq!(cache.get("c").await.unwrap().unwrap().text, "Hello");
+
+        let expiring = DiskCache::new(&dir).with_ttl(std::time::Duration::ZERO);
+
+        assert!(expiring.get("b").await.unwrap().is_none());
+        assert!(!dir.join("b.json").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_response_cache() {
+        // The mock server answers only once, so the second call must be cached
+        let server = crate::mock::mock_post("/v1/chat/completions", 200, "gpt/success.json").await;
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+        set_response_cache(MapCache::default());
+
+        let prompts = ["Hi".to_string()];
+        let first = call_llm_model("gpt", "gpt-4o", "", &prompts, 0.2, false, true).await.unwrap();
+        let second = call_llm_model("gpt", "gpt-4o", "", &prompts, 0.2, false, true).await.unwrap();
+
+        clear_response_cache();
+
+        assert_eq!(first.text, second.text);
+        assert_eq!(first.usage, second.usage);
+        assert_eq!(first.timing, second.timing);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_rejected_not_cached() {
+        // Both answers are used, so the rejected reply was not cached
+        let server = crate::mock::mock_post_sequence("/v1/chat/completions", &[(200, "gpt/success.json"), (200, "gpt/success.json")]).await;
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+        set_response_cache(MapCache::default());
+
+        let prompts = ["Hi".to_string()];
+        let guardrails = crate::guardrails::Guardrails::new(crate::guardrails::GuardrailPolicy::Annotate)
+            .validator(crate::guardrails::FnValidator(|_: &str| Err("Not wanted".to_string())));
+        let guarded = guardrails.call_llm_model("gpt", "gpt-4o", "", &prompts, 0.2, false, true).await.unwrap();
+        let plain = call_llm_model("gpt", "gpt-4o", "", &prompts, 0.2, false, true).await;
+
+        clear_response_cache();
+
+        assert_eq!(guarded.violations, vec!["Not wanted"]);
+        assert!(plain.is_ok());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_response_cache_errors() {
+        // An error reply must not be cached, so the second call is answered
+        let server = crate::mock::mock_post_sequence("/v1/chat/completions", &[(500, "gpt/error.json"), (200, "gpt/success.json")]).await;
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+        set_response_cache(MapCache::default());
+
+        let prompts = ["Hi again".to_string()];
+        let first = call_llm_model("gpt", "gpt-4o", "", &prompts, 0.2, false, true).await.unwrap();
+        let second = call_llm_model("gpt", "gpt-4o", "", &prompts, 0.2, false, true).await.unwrap();
+
+        clear_response_cache();
+
+        assert!(first.llm_type.is_error());
+        assert_eq!(second.text.trim(), "Hello there.");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_response_cache_tenants() {
+        use crate::tenants::{Tenant, register_tenant, remove_tenant, with_tenant};
+
+        // Both tenants' calls must reach the server
+        let server = crate::mock::mock_post_sequence("/v1/chat/completions", &[(200, "gpt/success.json"), (200, "gpt/success.json")]).await;
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+        set_response_cache(MapCache::default());
+        register_tenant(Tenant::new("acme"));
+        register_tenant(Tenant::new("globex"));
+
+        let prompts = ["Hi".to_string()];
+
+        for tenant in ["acme", "globex"] {
+            with_tenant(tenant, call_llm_model("gpt", "gpt-4o", "", &prompts, 0.2, false, true)).await.unwrap().unwrap();
+        }
+
+        clear_response_cache();
+        remove_tenant("acme");
+        remove_tenant("globex");
+    }
+}
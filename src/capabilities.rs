@@ -0,0 +1,209 @@
+use crate::common::llm_name;
+use crate::error::LlmClientError;
+
+/// What a model can do and how much it can take
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities {
+    pub supports_tools: bool,
+    pub supports_vision: bool,
+    /// Native JSON output mode, otherwise JSON is a prompt hint only
+    pub supports_json_mode: bool,
+    /// Context window in tokens
+    pub max_context: usize,
+    /// Maximum tokens generated
+    pub max_output: usize,
+}
+
+impl Capabilities {
+    pub const fn new(supports_tools: bool, supports_vision: bool, supports_json_mode: bool, max_context: usize, max_output: usize) -> Self {
+        Capabilities { supports_tools, supports_vision, supports_json_mode, max_context, max_output }
+    }
+}
+
+// Model families, matched on longest model name prefix
+const CAPABILITIES: &[(&str, Capabilities)] = &[
+    // OpenAI
+    ("gpt-4o-mini", Capabilities::new(true, true, true, 128_000, 16_384)),
+    ("gpt-4o", Capabilities::new(true, true, true, 128_000, 4_096)),
+    ("gpt-4-turbo", Capabilities::new(true, true, true, 128_000, 4_096)),
+    ("gpt-4-vision", Capabilities::new(false, true, false, 128_000, 4_096)),
+    ("gpt-4", Capabilities::new(true, false, false, 8_192, 8_192)),
+    ("gpt-3.5-turbo-instruct", Capabilities::new(false, false, false, 4_096, 4_096)),
+    ("gpt-3.5-turbo", Capabilities::new(true, false, true, 16_385, 4_096)),
+    // Anthropic
+    ("claude-3-5-sonnet", Capabilities::new(true, true, false, 200_000, 8_192)),
+    ("claude-3", Capabilities::new(true, true, false, 200_000, 4_096)),
+    ("claude-2", Capabilities::new(false, false, false, 100_000, 4_096)),
+    // Google
+    ("gemini-1.5-pro", Capabilities::new(true, true, true, 2_097_152, 8_192)),
+    ("gemini-1.5-flash", Capabilities::new(true, true, true, 1_048_576, 8_192)),
+    ("gemini-1.0-pro-vision", Capabilities::new(false, true, false, 16_384, 2_048)),
+    ("gemini-1.0-pro", Capabilities::new(true, false, false, 32_760, 8_192)),
+    // Mistral
+    ("mistral-large", Capabilities::new(true, false, true, 128_000, 4_096)),
+    ("mistral-medium", Capabilities::new(false, false, true, 32_000, 4_096)),
+    ("mistral-small", Capabilities::new(true, false, true, 32_000, 4_096)),
+    ("codestral", Capabilities::new(false, false, true, 32_000, 4_096)),
+    ("open-mixtral-8x22b", Capabilities::new(true, false, true, 64_000, 4_096)),
+    ("open-mixtral-8x7b", Capabilities::new(false, false, true, 32_000, 4_096)),
+    ("open-mistral-7b", Capabilities::new(false, false, true, 32_000, 4_096)),
+    // Groq
+    ("llama3-70b", Capabilities::new(true, false, true, 8_192, 8_192)),
+    ("llama3-8b", Capabilities::new(true, false, true, 8_192, 8_192)),
+    ("mixtral-8x7b", Capabilities::new(true, false, true, 32_768, 32_768)),
+    ("gemma2-9b", Capabilities::new(true, false, true, 8_192, 8_192)),
+    ("gemma-7b", Capabilities::new(true, false, true, 8_192, 8_192)),
+];
+
+/// Capabilities of model, if known
+pub fn capabilities(model: &str) -> Option<Capabilities> {
+    CAPABILITIES.iter()
+        .filter(|(prefix, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, capabilities)| *capabilities)
+}
+
+/// Fail if a known model cannot use tools, JSON mode or images as asked.
+/// Unknown models are assumed to be capable and left to the provider.
+pub fn check_capabilities(model: &str, tools: bool, json: bool, vision: bool) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let Some(capabilities) = capabilities(model) else {
+        return Ok(());
+    };
+
+    let missing: Vec<&str> = [
+        (tools && !capabilities.supports_tools, "tools"),
+        (json && !capabilities.supports_json_mode, "JSON mode"),
+        (vision && !capabilities.supports_vision, "vision"),
+    ]
+    .iter()
+    .filter(|(missing, _)| *missing)
+    .map(|(_, name)| *name)
+    .collect();
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(Box::new(LlmClientError::Unsupported(format!("{model} does not support {}", missing.join(" or ")))))
+    }
+}
+
+/// Sampling parameter ranges a provider accepts, inclusive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParameterRanges {
+    pub temperature: (f32, f32),
+    pub top_p: (f32, f32),
+}
+
+/// Accepted ranges for the named LLM, OpenAI's if not known
+pub fn parameter_ranges(llm: &str) -> ParameterRanges {
+    let temperature = match llm_name(llm) {
+        Some("claude") => (0.0, 1.0),
+        Some("mistral") => (0.0, 1.5),
+        _ => (0.0, 2.0),
+    };
+
+    ParameterRanges { temperature, top_p: (0.0, 1.0) }
+}
+
+/// Fail if temperature, top_p or max_tokens are outside what the provider
+/// and model accept, rather than sending them and getting an opaque 400.
+/// max_tokens is only limited by the model's output size if it is known.
+pub fn check_parameters(llm: &str, model: &str, temperature: Option<f32>, top_p: Option<f32>, max_tokens: Option<usize>) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let ranges = parameter_ranges(llm);
+    let invalid = |name: &str, message: String| -> Result<(), Box<dyn std::error::Error + Send>> {
+        Err(Box::new(LlmClientError::InvalidParameter { name: name.into(), message }))
+    };
+    let outside = |value: f32, (min, max): (f32, f32)| !(min..=max).contains(&value);
+
+    if let Some(temperature) = temperature {
+        if outside(temperature, ranges.temperature) {
+            return invalid("temperature", format!("{temperature} is outside {} to {} for {llm}", ranges.temperature.0, ranges.temperature.1));
+        }
+    }
+    if let Some(top_p) = top_p {
+        if outside(top_p, ranges.top_p) {
+            return invalid("top_p", format!("{top_p} is outside {} to {} for {llm}", ranges.top_p.0, ranges.top_p.1));
+        }
+    }
+    if let Some(max_tokens) = max_tokens {
+        if max_tokens == 0 {
+            return invalid("max_tokens", "must be at least 1".into());
+        }
+        if let Some(capabilities) = capabilities(model) {
+            if max_tokens > capabilities.max_output {
+                return invalid("max_tokens", format!("{max_tokens} is more than the {} {model} can generate", capabilities.max_output));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Fail with ContextOverflow if the input tokens plus max_tokens for the
+/// reply will not fit a known model's context window
+pub fn check_context(model: &str, input_tokens: usize, max_tokens: Option<usize>) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let Some(capabilities) = capabilities(model) else {
+        return Ok(());
+    };
+    let tokens = input_tokens + max_tokens.unwrap_or(0);
+
+    if tokens > capabilities.max_context {
+        return Err(Box::new(LlmClientError::ContextOverflow {
+            model: model.into(),
+            tokens,
+            limit: capabilities.max_context,
+            overshoot: tokens - capabilities.max_context,
+        }));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities_longest_prefix() {
+        assert!(capabilities("gemini-1.0-pro-vision-001").unwrap().supports_vision);
+        assert!(!capabilities("gemini-1.0-pro-002").unwrap().supports_vision);
+        assert_eq!(capabilities("gpt-4o-mini").unwrap().max_output, 16_384);
+        assert_eq!(capabilities("unknown-model"), None);
+    }
+
+    #[test]
+    fn test_check_capabilities() {
+        assert!(check_capabilities("gpt-4o", true, true, true).is_ok());
+        assert!(check_capabilities("unknown-model", true, true, true).is_ok());
+
+        let err = check_capabilities("gpt-4", true, true, false).unwrap_err();
+
+        assert_eq!(err.to_string(), "Unsupported: gpt-4 does not support JSON mode");
+        assert!(err.downcast_ref::<LlmClientError>().is_some());
+    }
+
+    #[test]
+    fn test_check_context() {
+        assert!(check_context("gpt-4", 4_000, Some(4_192)).is_ok());
+        assert!(check_context("unknown-model", 1_000_000, None).is_ok());
+
+        let err = check_context("gpt-4", 4_000, Some(4_200)).unwrap_err();
+
+        assert!(matches!(err.downcast_ref::<LlmClientError>(), Some(LlmClientError::ContextOverflow { overshoot: 8, .. })));
+        assert_eq!(err.to_string(), "Context overflow: 8200 tokens is 8 more than the 8192 gpt-4 takes");
+    }
+
+    #[test]
+    fn test_check_parameters() {
+        assert!(check_parameters("gpt", "gpt-4o", Some(1.5), Some(0.9), Some(1000)).is_ok());
+        assert!(check_parameters("mistral", "unknown-model", Some(1.2), None, Some(1_000_000)).is_ok());
+
+        let err = check_parameters("anthropic", "claude-3-haiku-20240307", Some(1.5), None, None).unwrap_err();
+
+        assert_eq!(err.to_string(), "Invalid temperature: 1.5 is outside 0 to 1 for anthropic");
+        assert!(matches!(err.downcast_ref::<LlmClientError>(), Some(LlmClientError::InvalidParameter { .. })));
+        assert!(check_parameters("groq", "llama3-8b-8192", None, Some(-0.1), None).is_err());
+        assert!(check_parameters("gpt", "gpt-4o-mini", None, None, Some(0)).is_err());
+        assert!(check_parameters("claude", "claude-3-5-sonnet-20240620", None, None, Some(8_193)).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use crate::common::{LlmReturn, call_llm_model};
+use crate::template::PromptTemplate;
+
+/// Single step of a chain, by default using the chain's LLM and model
+#[derive(Debug, Clone)]
+pub struct ChainStep {
+    pub name: String,
+    pub template: PromptTemplate,
+    pub llm: Option<String>,
+    pub model: Option<String>,
+    pub system: String,
+    pub temperature: f32,
+    pub is_json: bool,
+}
+
+impl ChainStep {
+    pub fn new(name: &str, template: PromptTemplate) -> Self {
+        ChainStep { name: name.into(), template, llm: None, model: None, system: String::new(), temperature: 0.2, is_json: false }
+    }
+
+    /// Run this step on another LLM and model
+    pub fn llm(mut self, llm: &str, model: &str) -> Self {
+        self.llm = Some(llm.into());
+        self.model = Some(model.into());
+        self
+    }
+
+    pub fn system(mut self, system: &str) -> Self {
+        self.system = system.into();
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn json(mut self) -> Self {
+        self.is_json = true;
+        self
+    }
+}
+
+/// Output of a completed step
+#[derive(Debug, Clone)]
+pub struct StepResult {
+    pub name: String,
+    pub result: LlmReturn,
+}
+
+impl StepResult {
+    /// Output parsed as JSON, for steps asking for it
+    pub fn json(&self) -> Result<serde_json::Value, Box<dyn std::error::Error + Send>> {
+        serde_json::from_str(&self.result.text)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+    }
+}
+
+/// Multi-step workflow where each step's template can use the output of
+/// the previous step as `${input}` and of any earlier step by its name,
+/// e.g. outline -> draft -> critique. Stops at the first failing step.
+#[derive(Debug, Clone)]
+pub struct Chain {
+    pub llm: String,
+    pub model: String,
+    pub steps: Vec<ChainStep>,
+}
+
+impl Chain {
+    pub fn new(llm: &str, model: &str) -> Self {
+        Chain { llm: llm.into(), model: model.into(), steps: Vec::new() }
+    }
+
+    /// Add step using the chain's LLM and model
+    pub fn step(self, name: &str, template: PromptTemplate) -> Self {
+        self.then(ChainStep::new(name, template))
+    }
+
+    /// Add configured step
+    pub fn then(mut self, step: ChainStep) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    /// Run steps in order from initial variables, returning every step's result
+    pub async fn run<T: AsRef<str>>(&self, vars: &HashMap<&str, T>) -> Result<Vec<StepResult>, Box<dyn std::error::Error + Send>> {
+        let mut values: HashMap<String, String> = vars.iter()
+            .map(|(k, v)| (k.to_string(), v.as_ref().to_string()))
+            .collect();
+        let mut results = Vec::new();
+
+        for step in &self.steps {
+            let vars: HashMap<&str, &str> = values.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+            let prompt = step.template.render(&vars)
+                .map_err(|e| chain_error(&step.name, &e.to_string()))?;
+            let llm = step.llm.as_deref().unwrap_or(&self.llm);
+            let model = step.model.as_deref().unwrap_or(&self.model);
+
+            let result = call_llm_model(llm, model, &step.system, &[prompt], step.temperature, step.is_json, false).await?;
+
+            if result.llm_type.is_error() {
+                return Err(chain_error(&step.name, &result.text));
+            }
+
+            values.insert("input".into(), result.text.clone());
+            values.insert(step.name.clone(), result.text.clone());
+            results.push(StepResult { name: step.name.clone(), result });
+        }
+
+        Ok(results)
+    }
+
+    /// Run steps and return the final output text
+    pub async fn output<T: AsRef<str>>(&self, vars: &HashMap<&str, T>) -> Result<String, Box<dyn std::error::Error + Send>> {
+        Ok(self.run(vars).await?
+            .pop()
+            .map(|r| r.result.text)
+            .unwrap_or_default())
+    }
+}
+
+fn chain_error(step: &str, error: &str) -> Box<dyn std::error::Error + Send> {
+    Box::new(std::io::Error::other(format!("Chain step '{step}' failed: {error}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_chain_stops_at_bad_step() {
+        let chain = Chain::new("groq", "llama3-8b-8192")
+            .step("outline", PromptTemplate::new("Outline an article about ${subject}"))
+            .then(ChainStep::new("draft", PromptTemplate::new("Write from:\n${outline}")).llm("claude", "claude-3-haiku-20240307"));
+
+        assert_eq!(chain.steps[1].llm.as_deref(), Some("claude"));
+
+        // Missing variable fails before any call is made
+        let err = chain.run(&HashMap::from([("topic", "Rust")])).await.unwrap_err();
+
+        assert!(err.to_string().starts_with("Chain step 'outline' failed"));
+    }
+}
@@ -0,0 +1,213 @@
+use crate::tokens::estimate_tokens;
+
+// Document splitters for embedding or stuffing long contexts. Sizes are
+// in estimated tokens, consecutive chunks share up to 'overlap' tokens
+// of trailing text so that context is not lost at the boundaries.
+
+/// Split on words into chunks of at most max_tokens
+pub fn chunk_by_tokens(text: &str, max_tokens: usize, overlap: usize) -> Vec<String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+
+    pack(&words, " ", max_tokens, overlap)
+}
+
+/// Split on sentences into chunks of at most max_tokens. Sentences
+/// longer than that are split on words.
+pub fn chunk_by_sentences(text: &str, max_tokens: usize, overlap: usize) -> Vec<String> {
+    let pieces: Vec<String> = sentences(text).into_iter()
+        .flat_map(|s| {
+            if estimate_tokens(s) > max_tokens {
+                chunk_by_tokens(s, max_tokens, 0)
+            } else {
+                vec![s.to_string()]
+            }
+        })
+        .collect();
+    let pieces: Vec<&str> = pieces.iter().map(|s| s.as_str()).collect();
+
+    pack(&pieces, " ", max_tokens, overlap)
+}
+
+/// Split on blank lines into chunks of at most max_tokens, keeping the
+/// blank lines between paragraphs. Paragraphs longer than that are split
+/// on sentences.
+pub fn chunk_by_paragraphs(text: &str, max_tokens: usize, overlap: usize) -> Vec<String> {
+    let pieces: Vec<String> = text.split("\n\n")
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .flat_map(|p| {
+            if estimate_tokens(p) > max_tokens {
+                chunk_by_sentences(p, max_tokens, 0)
+            } else {
+                vec![p.to_string()]
+            }
+        })
+        .collect();
+    let pieces: Vec<&str> = pieces.iter().map(|s| s.as_str()).collect();
+
+    pack(&pieces, "\n\n", max_tokens, overlap)
+}
+
+/// Split markdown on headings, keeping each section whole where it fits.
+/// Larger sections are split on sentences, each part starting with the
+/// section heading so it still makes sense on its own.
+pub fn chunk_by_markdown(text: &str, max_tokens: usize, overlap: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+
+    for (heading, body) in sections(text) {
+        let section = match heading {
+            Some(heading) => format!("{heading}\n{body}"),
+            None => body.clone(),
+        };
+
+        if section.trim().is_empty() {
+            continue;
+        }
+
+        if estimate_tokens(&section) <= max_tokens {
+            chunks.push(section.trim().to_string());
+        } else {
+            let heading = heading.unwrap_or("");
+            let room = max_tokens.saturating_sub(estimate_tokens(heading)).max(1);
+
+            chunk_by_sentences(&body, room, overlap).into_iter()
+                .for_each(|c| chunks.push(format!("{heading}\n{c}").trim().to_string()));
+        }
+    }
+
+    chunks
+}
+
+// Greedily fill chunks with pieces, carrying trailing pieces over
+fn pack(pieces: &[&str], sep: &str, max_tokens: usize, overlap: usize) -> Vec<String> {
+    let overlap = overlap.min(max_tokens / 2);
+    let mut chunks = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    let mut size = 0;
+
+    for piece in pieces {
+        let tokens = estimate_tokens(piece);
+
+        if size + tokens > max_tokens && !current.is_empty() {
+            chunks.push(current.join(sep));
+
+            let mut kept = Vec::new();
+            let mut kept_size = 0;
+            for p in current.iter().rev() {
+                let t = estimate_tokens(p);
+                if kept_size + t > overlap {
+                    break;
+                }
+                kept_size += t;
+                kept.push(*p);
+            }
+            kept.reverse();
+            current = kept;
+            size = kept_size;
+        }
+
+        current.push(piece);
+        size += tokens;
+    }
+
+    if !current.is_empty() {
+        chunks.push(current.join(sep));
+    }
+
+    chunks
+}
+
+// Sentences end with . ! or ? followed by white space, or a blank line
+fn sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        let end = match (c, chars.peek()) {
+            ('.' | '!' | '?', Some((_, n))) if n.is_whitespace() => Some(i + c.len_utf8()),
+            ('\n', Some((_, '\n'))) => Some(i),
+            _ => None,
+        };
+
+        if let Some(end) = end {
+            let sentence = text[start..end].trim();
+            if !sentence.is_empty() {
+                sentences.push(sentence);
+            }
+            start = end;
+        }
+    }
+
+    let sentence = text[start..].trim();
+    if !sentence.is_empty() {
+        sentences.push(sentence);
+    }
+
+    sentences
+}
+
+// Heading and body of each section, ignoring '#' lines in code blocks
+fn sections(text: &str) -> Vec<(Option<&str>, String)> {
+    let mut sections: Vec<(Option<&str>, String)> = vec![(None, String::new())];
+    let mut in_code = false;
+
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+        }
+
+        let level = line.chars().take_while(|c| *c == '#').count();
+
+        if !in_code && (1..=6).contains(&level) && line[level..].starts_with(' ') {
+            sections.push((Some(line), String::new()));
+        } else {
+            let body = &mut sections.last_mut().unwrap().1;
+            body.push_str(line);
+            body.push('\n');
+        }
+    }
+
+    sections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_by_tokens_overlap() {
+        let text = "one two six ten red tan big fat";
+        let chunks = chunk_by_tokens(text, 4, 2);
+
+        assert_eq!(chunks, vec!["one two six ten", "six ten red tan", "red tan big fat"]);
+    }
+
+    #[test]
+    fn test_chunk_by_sentences() {
+        let text = "The cat sat. The dog ran off! Did the bird fly? It did.";
+        let chunks = chunk_by_sentences(text, 8, 0);
+
+        assert_eq!(chunks, vec!["The cat sat. The dog ran off!", "Did the bird fly? It did."]);
+    }
+
+    #[test]
+    fn test_chunk_by_paragraphs() {
+        let text = "First para.\n\nSecond one.\n\n\nA third, much longer paragraph. It has two sentences.";
+        let chunks = chunk_by_paragraphs(text, 8, 0);
+
+        assert_eq!(chunks, vec!["First para.\n\nSecond one.", "A third, much longer paragraph.", "It has two sentences."]);
+    }
+
+    #[test]
+    fn test_chunk_by_markdown() {
+        let text = "Intro text.\n# Setup\nInstall it.\n```sh\n# not a heading\n```\n## Usage\nRun it. Then stop it. Then start it again.\n";
+        let chunks = chunk_by_markdown(text, 12, 0);
+
+        assert_eq!(chunks[0], "Intro text.");
+        assert!(chunks[1].starts_with("# Setup\nInstall it."));
+        assert!(chunks[1].contains("# not a heading"));
+        assert!(chunks[2..].iter().all(|c| c.starts_with("## Usage\n")));
+        assert!(chunks.len() > 3);
+    }
+}
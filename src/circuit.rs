@@ -0,0 +1,195 @@
+//! Per provider circuit breaker. After a run of consecutive failures a
+//! provider's circuit opens and calls to it fail at once with
+//! `LlmClientError::CircuitOpen`, or go to a fallback LLM if one is set,
+//! rather than each waiting out the full timeout. After the cooldown calls
+//! are let through again: a success closes the circuit, a failure reopens
+//! it at once.
+//!
+//! Failures to get a response at all (connection errors, timeouts) count,
+//! as do error replies with a 5xx status, such as 529 when the provider is
+//! overloaded. Other error replies are the caller's problem as often as the
+//! provider's, so do not. Off unless set with `set_circuit_breaker` or
+//! LLM_CIRCUIT_THRESHOLD.
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use crate::common::{LlmReturn, llm_name};
+use crate::config::env_var;
+use crate::error::LlmClientError;
+
+/// When to open a circuit, for how long, and where calls go meanwhile
+#[derive(Debug, Clone, PartialEq)]
+pub struct CircuitBreaker {
+    /// Consecutive failures that open the circuit
+    pub threshold: u32,
+    pub cooldown: Duration,
+    /// LLM and model to call while a circuit is open, rather than failing
+    pub fallback: Option<(String, String)>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker { threshold: threshold.max(1), cooldown, fallback: None }
+    }
+
+    pub fn with_fallback(mut self, llm: &str, model: &str) -> Self {
+        self.fallback = Some((llm.into(), model.into()));
+        self
+    }
+
+    /// From LLM_CIRCUIT_THRESHOLD and LLM_CIRCUIT_COOLDOWN (seconds,
+    /// default 30), None if no threshold is set
+    pub fn from_env() -> Option<Self> {
+        let threshold = env_var("LLM_CIRCUIT_THRESHOLD")?.trim().parse().ok()?;
+        let cooldown = env_var("LLM_CIRCUIT_COOLDOWN")
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(30);
+
+        Some(CircuitBreaker::new(threshold, Duration::from_secs(cooldown)))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Circuit {
+    failures: u32,
+    opened: Option<Instant>,
+}
+
+static CIRCUIT_BREAKER: RwLock<Option<Option<CircuitBreaker>>> = RwLock::new(None);
+static CIRCUITS: Mutex<Option<HashMap<String, Circuit>>> = Mutex::new(None);
+
+/// Use this breaker in place of any from the environment, None to turn it off
+pub fn set_circuit_breaker(breaker: Option<CircuitBreaker>) {
+    *CIRCUIT_BREAKER.write().unwrap() = Some(breaker);
+    reset_circuits();
+}
+
+/// Breaker in use, if any
+pub fn circuit_breaker() -> Option<CircuitBreaker> {
+    if let Some(ref breaker) = *CIRCUIT_BREAKER.read().unwrap() {
+        return breaker.clone();
+    }
+
+    CIRCUIT_BREAKER.write().unwrap().get_or_insert_with(CircuitBreaker::from_env).clone()
+}
+
+/// Close all circuits and forget failures
+pub fn reset_circuits() {
+    *CIRCUITS.lock().unwrap() = None;
+}
+
+/// Whether the provider's circuit is open now
+pub fn is_open(llm: &str) -> bool {
+    circuit_breaker().is_some_and(|breaker| retry_in(&breaker, &provider(llm)).is_some())
+}
+
+fn provider(llm: &str) -> String {
+    llm_name(llm).unwrap_or(llm).to_string()
+}
+
+// Time left in the cooldown if the circuit is open
+fn retry_in(breaker: &CircuitBreaker, llm: &str) -> Option<Duration> {
+    let circuits = CIRCUITS.lock().unwrap();
+    let opened = circuits.as_ref()?.get(llm)?.opened?;
+
+    breaker.cooldown.checked_sub(opened.elapsed()).filter(|d| !d.is_zero())
+}
+
+/// LLM and model to call: those asked for, the fallback if their circuit
+/// is open, or CircuitOpen if there is no usable fallback
+pub(crate) fn route(llm: &str, model: &str) -> Result<(String, String), Box<dyn std::error::Error + Send>> {
+    let Some(breaker) = circuit_breaker() else {
+        return Ok((llm.into(), model.into()));
+    };
+    let Some(wait) = retry_in(&breaker, &provider(llm)) else {
+        return Ok((llm.into(), model.into()));
+    };
+
+    match breaker.fallback {
+        Some((ref fallback, ref fallback_model)) if retry_in(&breaker, &provider(fallback)).is_none() =>
+            Ok((fallback.clone(), fallback_model.clone())),
+        _ => Err(Box::new(LlmClientError::CircuitOpen { llm: provider(llm), retry_in: wait })),
+    }
+}
+
+/// Count a call's outcome against its provider's circuit
+pub(crate) fn record(llm: &str, res: &Result<LlmReturn, Box<dyn std::error::Error + Send>>) {
+    let Some(breaker) = circuit_breaker() else {
+        return;
+    };
+    let failed = match res {
+        Ok(res) => res.is_server_error(),
+        Err(e) => e.downcast_ref::<reqwest::Error>().is_some(),
+    };
+    let mut circuits = CIRCUITS.lock().unwrap();
+    let circuit = circuits.get_or_insert_with(HashMap::new).entry(provider(llm)).or_default();
+
+    if failed {
+        circuit.failures += 1;
+        if circuit.failures >= breaker.threshold {
+            circuit.opened = Some(Instant::now());
+        }
+    } else if res.is_ok() {
+        *circuit = Circuit::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    async fn failed() -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+        // Nothing listens on port 9 so the connection is refused
+        let e = reqwest::get("http://127.0.0.1:9").await.unwrap_err();
+
+        Err(Box::new(e))
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_circuit_breaker() {
+        set_circuit_breaker(Some(CircuitBreaker::new(2, Duration::from_millis(200)).with_fallback("claude", "claude-3-haiku")));
+
+        record("openai", &failed().await);
+        assert_eq!(route("gpt", "gpt-4o").unwrap(), ("gpt".into(), "gpt-4o".into()));
+
+        // Client errors don't count
+        record("gpt", &Err(Box::new(LlmClientError::Cancelled)));
+        assert!(!is_open("gpt"));
+
+        record("gpt", &failed().await);
+        assert!(is_open("gpt"));
+        assert_eq!(route("gpt", "gpt-4o").unwrap(), ("claude".into(), "claude-3-haiku".into()));
+
+        set_circuit_breaker(Some(CircuitBreaker::new(1, Duration::from_millis(200))));
+        record("gpt", &failed().await);
+
+        let err = route("gpt", "gpt-4o").unwrap_err();
+
+        assert!(matches!(err.downcast_ref::<LlmClientError>(), Some(LlmClientError::CircuitOpen { .. })));
+
+        // After the cooldown one failure reopens it and a success closes it
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(!is_open("gpt"));
+        record("gpt", &failed().await);
+        assert!(is_open("gpt"));
+
+        record("gpt", &Ok(LlmReturn::new(crate::common::LlmType::GPT, "Hi".into(), "stop".into(), (1, 1, 2), 0.1, None, None)));
+        assert!(!is_open("gpt"));
+
+        // Overloaded and other 5xx replies count, 4xx ones don't
+        let reply = |status: u16| Ok(LlmReturn::new(crate::common::LlmType::CLAUDE_ERROR, "Overloaded".into(), "ERROR".into(), (0, 0, 0), 0.1, None, None)
+            .with_status(reqwest::StatusCode::from_u16(status).unwrap()));
+
+        set_circuit_breaker(Some(CircuitBreaker::new(2, Duration::from_millis(200))));
+        record("claude", &reply(400));
+        record("claude", &reply(400));
+        assert!(!is_open("claude"));
+        record("claude", &reply(529));
+        record("claude", &reply(500));
+        assert!(is_open("claude"));
+
+        set_circuit_breaker(None);
+    }
+}
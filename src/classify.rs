@@ -0,0 +1,166 @@
+use serde_derive::Deserialize;
+use crate::common::call_llm_model;
+use crate::repair::repair_json;
+
+/// A label and how sure the model is of it, from 0 to 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct Classification {
+    pub label: String,
+    pub confidence: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Reply {
+    labels: Vec<Scored>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Scored {
+    label: String,
+    #[serde(default)]
+    confidence: f64,
+}
+
+/// Sorts text into one of a fixed set of labels, or any number of them
+/// if multi label. The model is asked for JSON, enforced natively where
+/// the provider can, and labels it invents are dropped.
+#[derive(Debug, Clone)]
+pub struct Classifier {
+    pub llm: String,
+    pub model: String,
+    pub labels: Vec<String>,
+    pub multi_label: bool,
+    /// What the labels mean or how to choose, if not obvious from their names
+    pub instructions: String,
+}
+
+impl Classifier {
+    pub fn new(llm: &str, model: &str, labels: &[&str]) -> Self {
+        Classifier {
+            llm: llm.into(),
+            model: model.into(),
+            labels: labels.iter().map(|l| l.to_string()).collect(),
+            multi_label: false,
+            instructions: String::new(),
+        }
+    }
+
+    pub fn multi_label(mut self, multi_label: bool) -> Self {
+        self.multi_label = multi_label;
+        self
+    }
+
+    pub fn instructions(mut self, instructions: &str) -> Self {
+        self.instructions = instructions.into();
+        self
+    }
+
+    fn system(&self) -> String {
+        let labels = self.labels.iter().map(|l| format!("- {l}")).collect::<Vec<_>>().join("\n");
+        let choose = if self.multi_label {
+            "every label that applies, or none"
+        } else {
+            "the single label that fits best"
+        };
+        let instructions = if self.instructions.is_empty() { String::new() } else { format!("\n\n{}", self.instructions) };
+
+        format!("Classify the text using only these labels, exactly as written:\n{labels}{instructions}\n\n\
+            Choose {choose}, with your confidence in each from 0 to 1. \
+            Reply only with JSON of the form {{\"labels\": [{{\"label\": \"<label>\", \"confidence\": <0-1>}}]}}")
+    }
+
+    /// Labels from the model's reply that are in the set, named as in the
+    /// set, most confident first. Only the first if not multi label.
+    pub fn parse(&self, text: &str) -> Result<Vec<Classification>, Box<dyn std::error::Error + Send>> {
+        let reply: Reply = serde_json::from_str(text)
+            .or_else(|_| serde_json::from_str(&repair_json(text)))
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+        let mut classifications: Vec<Classification> = Vec::new();
+
+        for scored in reply.labels {
+            let Some(label) = self.labels.iter().find(|l| l.trim().eq_ignore_ascii_case(scored.label.trim())) else {
+                continue;
+            };
+
+            if !classifications.iter().any(|c| &c.label == label) {
+                classifications.push(Classification { label: label.clone(), confidence: scored.confidence.clamp(0.0, 1.0) });
+            }
+        }
+
+        classifications.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+        if !self.multi_label {
+            classifications.truncate(1);
+        }
+
+        Ok(classifications)
+    }
+
+    /// Labels that apply, most confident first. Single label classifiers
+    /// fail if the model gives none of the labels.
+    pub async fn classify_all(&self, text: &str) -> Result<Vec<Classification>, Box<dyn std::error::Error + Send>> {
+        let ret = call_llm_model(&self.llm, &self.model, &self.system(), &[text.to_string()], 0.0, true, false).await?;
+
+        if ret.llm_type.is_error() {
+            return Err(Box::new(std::io::Error::other(format!("Classification failed: {}", ret.text))));
+        }
+
+        let classifications = self.parse(&ret.text)?;
+
+        if classifications.is_empty() && !self.multi_label {
+            return Err(Box::new(std::io::Error::other(format!("Classification gave none of the labels: {}", ret.text.trim()))));
+        }
+
+        Ok(classifications)
+    }
+
+    /// Most confident label
+    pub async fn classify(&self, text: &str) -> Result<Classification, Box<dyn std::error::Error + Send>> {
+        let mut classifications = self.classify_all(text).await?;
+
+        if classifications.is_empty() {
+            return Err(Box::new(std::io::Error::other("Classification gave none of the labels")));
+        }
+
+        Ok(classifications.swap_remove(0))
+    }
+}
+
+/// Single label that best fits the text
+pub async fn classify(llm: &str, model: &str, text: &str, labels: &[&str]) -> Result<Classification, Box<dyn std::error::Error + Send>> {
+    Classifier::new(llm, model, labels).classify(text).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[tokio::test]
+    #[serial]
+    async fn test_classify() {
+        let classifier = Classifier::new("gpt", "gpt-4o", &["Billing", "Refunds", "Technical"]).multi_label(true);
+
+        assert_eq!(classifier.parse("```json\n{\"labels\": [{\"label\": \"technical\", \"confidence\": 1.5},]}\n```").unwrap(),
+            vec![Classification { label: "Technical".into(), confidence: 1.0 }]);
+        assert!(classifier.parse("Refunds").is_err());
+
+        let server = crate::mock::mock_post_sequence("/v1/chat/completions", &[(200, "gpt/classify.json"), (200, "gpt/classify.json")]).await;
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        let text = "I was charged twice, please give me my money back.";
+
+        assert_eq!(classifier.classify_all(text).await.unwrap(), vec![
+            Classification { label: "Refunds".into(), confidence: 0.91 },
+            Classification { label: "Billing".into(), confidence: 0.62 },
+        ]);
+        assert_eq!(classify("gpt", "gpt-4o", text, &["Billing", "Refunds", "Technical"]).await.unwrap().label, "Refunds");
+
+        let requests = server.received_requests().await.unwrap();
+        let body = String::from_utf8_lossy(&requests[1].body);
+
+        assert!(body.contains("json_object"));
+        assert!(body.contains("the single label that fits best"));
+    }
+}
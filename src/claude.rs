@@ -1,10 +1,23 @@
 use reqwest::header::{HeaderMap, HeaderValue};
 use reqwest::Client;
-use std::env;
+use futures::StreamExt;
 use serde_derive::{Deserialize, Serialize};
 use crate::common::*;
-use crate::gpt::GptMessage as ClaudeMessage;
+use crate::config::{env_var, provider_defaults, required_env};
+use crate::credentials::credential;
+use crate::capabilities::check_parameters;
+use crate::error::ConfigError;
+use crate::providers::providers;
 use crate::functions::*;
+use crate::dates::days_from_civil;
+use crate::postprocess::strip_fences_by_default;
+
+// Used when neither the caller nor the configuration give max_tokens
+const MAX_TOKENS: usize = 4096;
+
+fn default_max_tokens() -> usize {
+    provider_defaults("claude").max_tokens.unwrap_or(MAX_TOKENS)
+}
 
 // Input structures
 // Chat
@@ -18,15 +31,57 @@ pub struct ClaudeCompletion {
     pub messages: Vec<ClaudeMessage>,
     pub temperature: f32,
     pub max_tokens: usize,
-    //pub stream: bool,     // Not for now
-    //pub top_p: u32,
-    //pub top_k: u32,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
+    // Claude has no JSON mode, the reply is instead prefilled with '{'
+    #[serde(skip)]
+    pub is_json: bool,
+    // Start of the reply, sent as a final assistant message
+    #[serde(skip)]
+    pub prefill: Option<String>,
 }
 
 impl ClaudeCompletion {
+    // Completion from common parameters, shared by the calls with and without attachments
+    fn completion(model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool, function: Option<Vec<Function>>) -> Self {
+        let mut messages = Vec::new();
+
+        user.iter()
+            .enumerate()
+            .for_each(|(i, c)| {
+                let role = if !is_chat || i % 2 == 0 { "user" } else { "assistant" };
+
+                messages.push(ClaudeMessage { role: role.into(), content: c.as_str().into() });
+            });
+
+        let defaults = provider_defaults("claude");
+
+        ClaudeCompletion {
+            model: model.into(),
+            tools: function,
+            system: if system.is_empty() { None } else { Some(system.to_string()) },
+            messages,
+            temperature,
+            max_tokens: defaults.max_tokens.unwrap_or(MAX_TOKENS),
+            stream: false,
+            top_p: defaults.top_p,
+            top_k: defaults.top_k,
+            stop_sequences: defaults.stop_sequences,
+            is_json,
+            prefill: None,
+        }
+    }
+
     /// Create chat completion
-    pub fn new(messages: Vec<ClaudeMessage>, temperature: f32, _is_json: bool) -> Self {
-        let model: String = env::var("CLAUDE_MODEL").expect("CLAUDE_MODEL not found in enviroment variables");
+    pub fn new(messages: Vec<ClaudeMessage>, temperature: f32, is_json: bool) -> Self {
+        let model: String = env_var("CLAUDE_MODEL").unwrap_or_default(); // checked when called
+        let defaults = provider_defaults("claude");
 
         ClaudeCompletion {
             model,
@@ -34,8 +89,13 @@ impl ClaudeCompletion {
             system: None,
             messages,
             temperature,
-            max_tokens: 4096,
-
+            max_tokens: defaults.max_tokens.unwrap_or(MAX_TOKENS),
+            stream: false,
+            top_p: defaults.top_p,
+            top_k: defaults.top_k,
+            stop_sequences: defaults.stop_sequences,
+            is_json,
+            prefill: None,
         }
     }
 
@@ -51,6 +111,21 @@ impl ClaudeCompletion {
         self.max_tokens = max_tokens;
     }
 
+    /// Nucleus sampling, 0.0 to 1.0. Anthropic advise altering this or temperature, not both
+    pub fn set_top_p(&mut self, top_p: Option<f32>) {
+        self.top_p = top_p;
+    }
+
+    /// Sample only from the k most likely tokens
+    pub fn set_top_k(&mut self, top_k: Option<usize>) {
+        self.top_k = top_k;
+    }
+
+    /// Text that ends generation, stop_reason is then "stop_sequence"
+    pub fn set_stop_sequences(&mut self, stop_sequences: &[&str]) {
+        self.stop_sequences = if stop_sequences.is_empty() { None } else { Some(stop_sequences.iter().map(|s| s.to_string()).collect()) };
+    }
+
     /// Add a single new message
     pub fn add_message(&mut self, message: &ClaudeMessage) {
         self.messages.push(message.clone());
@@ -60,30 +135,61 @@ impl ClaudeCompletion {
     pub fn add_messages(&mut self, messages: &[ClaudeMessage]) {
         messages.iter().for_each(|m| self.messages.push(m.clone()));
     }
+
+    /// Start of the reply sent as a final assistant turn, if any. The
+    /// JSON prefill is not used with tools, as it would stop Claude choosing one.
+    fn prefill(&self) -> Option<&str> {
+        match self.prefill {
+            Some(ref prefill) => Some(prefill),
+            None if self.is_json && self.tools.is_none() => Some("{"),
+            None => None,
+        }
+    }
 }
 
 impl Default for ClaudeCompletion {
     /// Create default chat completion
     fn default() -> Self {
-        let model: String = env::var("CLAUDE_MODEL").expect("CLAUDE_MODEL not found in enviroment variables");
+        let model: String = env_var("CLAUDE_MODEL").unwrap_or_default(); // checked when called
+
+        let defaults = provider_defaults("claude");
 
         ClaudeCompletion {
             model,
             tools: None,
             system: None,
             messages: Vec::new(),
-            temperature: 0.2,
-            max_tokens: 4096
+            temperature: defaults.temperature(),
+            max_tokens: defaults.max_tokens.unwrap_or(MAX_TOKENS),
+            stream: false,
+            top_p: defaults.top_p,
+            top_k: defaults.top_k,
+            stop_sequences: defaults.stop_sequences,
+            is_json: false,
+            prefill: None,
         }
     }
 }
 
 impl LlmCompletion for ClaudeCompletion {
-    /// Set output to be json. Hint in prompt still necessary.
+    /// Set temperature
     fn set_temperature(&mut self, temperature: f32) {
         self.temperature = temperature;
     }
 
+    /// Set output to be json by prefilling the reply with '{'. Hint in prompt still necessary.
+    fn set_json(&mut self, is_json: bool) {
+        self.is_json = is_json;
+    }
+
+    /// Claude continues from the prefill, which is put back at the start of
+    /// the reply text. Trailing whitespace is dropped as Claude rejects it.
+    fn set_prefill(&mut self, prefill: &str) {
+        let prefill = prefill.trim_end();
+
+        self.prefill = if prefill.is_empty() { None } else { Some(prefill.to_string()) };
+    }
+
     /// Add single role and single part text
     fn add_text(&mut self, role: &str, text: &str) {
         self.messages.push(ClaudeMessage::text(role, text));
@@ -125,42 +231,176 @@ impl LlmCompletion for ClaudeCompletion {
     }
 
     /// Create and call llm by supplying data and common parameters
-    async fn call(system: &str, user: &[String], temperature: f32, _is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-        let model: String = env::var("CLAUDE_MODEL").expect("CLAUDE_MODEL not found in enviroment variables");
+    async fn call(system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+        let model: String = required_env("CLAUDE_MODEL")?;
 
-        Self::call_model(&model, system, user, temperature, _is_json, is_chat).await
+        Self::call_model(&model, system, user, temperature, is_json, is_chat).await
     }
 
     /// Create and call llm by supplying data and common parameters
-    async fn call_model(model: &str, system: &str, user: &[String], temperature: f32, _is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-        Self::call_model_function(model, system, user, temperature, _is_json, is_chat, None).await
+    async fn call_model(model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+        Self::call_model_function(model, system, user, temperature, is_json, is_chat, None).await
     }
 
     /// Create and call llm with model/function by supplying data and common parameters
-    async fn call_model_function(model: &str, system: &str, user: &[String], temperature: f32, _is_json: bool, is_chat: bool, function: Option<Vec<Function>>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-        let mut messages = Vec::new();
+    async fn call_model_function(model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool, function: Option<Vec<Function>>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+        call_claude_completion(&Self::completion(model, system, user, temperature, is_json, is_chat, function)).await
+    }
+}
 
-        user.iter()
-            .enumerate()
-            .for_each(|(i, c)| {
-                let role = if !is_chat || i % 2 == 0 { "user" } else { "assistant" };
+/// Claude message, content is either plain text or typed blocks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClaudeMessage {
+    pub role: String,
+    pub content: ClaudeContent,
+}
 
-                messages.push(ClaudeMessage { role: role.into(), content: c.to_string() });
-            });
+impl ClaudeMessage {
+    /// Supply single role with multi-part content, for images, documents and tool results
+    pub fn from_content(role: &str, content: &[MessageContent]) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        let blocks = content.iter()
+            .map(|c| c.wire("claude").and_then(|v| serde_json::from_value(v)
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })))
+            .collect::<Result<Vec<ContentBlock>, _>>()?;
 
-        let completion = ClaudeCompletion {
-            model: model.into(),
-            tools: function,
-            system: if system.is_empty() { None } else { Some(system.to_string()) },
-            messages,
-            temperature,
-            max_tokens: 4096
+        Ok(Self { role: role.into(), content: ClaudeContent::Blocks(blocks) })
+    }
+
+    /// Add attachments as blocks after the existing content
+    pub fn attach(&mut self, llm: &str, attachments: &[Attachment]) -> Result<(), Box<dyn std::error::Error + Send>> {
+        let mut blocks = match self.content {
+            ClaudeContent::Text(ref text) => vec![ContentBlock::Text { text: text.clone() }],
+            ClaudeContent::Blocks(ref blocks) => blocks.clone(),
         };
 
-        call_claude_completion(&completion).await
+        for attachment in attachments {
+            blocks.push(serde_json::from_value(attachment.wire(llm)?)
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?);
+        }
+
+        self.content = ClaudeContent::Blocks(blocks);
+
+        Ok(())
+    }
+}
+
+impl LlmMessage for ClaudeMessage {
+    /// Supply single role and single part text
+    fn text(role: &str, content: &str) -> Self {
+        Self { role: role.into(), content: content.into() }
+    }
+
+    /// Supply single role with multi-string for iparts with single content
+    fn many_text(role: &str, prompt: &[String]) -> Self {
+        Self { role: role.into(), content: prompt.join("\n").into() }
+    }
+
+    /// Supply simple, 'system' content
+    fn system(system_prompt: &str) -> Vec<Self> {
+        vec![Self::text("system", system_prompt)]
+    }
+
+    /// Supply multi-parts and single 'system' content
+    fn multi_part_system(system_prompts: &[String]) -> Vec<Self> {
+        vec![Self::many_text("system", system_prompts)]
+    }
+
+    /// Supply multi-context 'system' content
+    fn systems(system_prompts: &[String]) -> Vec<Self> {
+        system_prompts.iter()
+            .map(|sp| Self::text("system", sp))
+            .collect()
+    }
+
+    /// Supply multi-String content with user and model alternating
+    fn dialogue(prompts: &[String], has_system: bool) -> Vec<Self> {
+        prompts.iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let role = if i % 2 == 0 {
+                    if i == 0 && has_system {
+                        "system"
+                    } else {
+                        "user"
+                    }
+                } else {
+                    "assistant"
+                };
+
+                Self::text(role, p)
+            })
+            .collect()
+    }
+
+    /// Return String of Object
+    fn debug(&self) -> String where Self: std::fmt::Debug {
+        format!("{:?}", self)
     }
 }
 
+/// Message content, either plain text or an array of blocks
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ClaudeContent {
+    Text(String),
+    Blocks(Vec<ContentBlock>),
+}
+
+impl ClaudeContent {
+    /// Plain text, or that of any text blocks joined by newlines
+    pub fn text(&self) -> String {
+        match self {
+            ClaudeContent::Text(text) => text.clone(),
+            ClaudeContent::Blocks(blocks) => blocks.iter()
+                .filter_map(|b| if let ContentBlock::Text { text } = b { Some(text.as_str()) } else { None })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+impl From<String> for ClaudeContent {
+    fn from(text: String) -> Self {
+        ClaudeContent::Text(text)
+    }
+}
+
+impl From<&str> for ClaudeContent {
+    fn from(text: &str) -> Self {
+        ClaudeContent::Text(text.into())
+    }
+}
+
+/// Typed content block, used in both requests and responses
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlock {
+    Text { text: String },
+    Image { source: Source },
+    Document { source: Source },
+    ToolUse { id: String, name: String, input: serde_json::Value },
+    ToolResult {
+        tool_use_id: String,
+        content: ClaudeContent,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        is_error: Option<bool>,
+    },
+    /// Extended thinking, the signature must be sent back unchanged
+    Thinking { thinking: String, #[serde(default)] signature: String },
+    RedactedThinking { data: String },
+    /// Block types not yet known to this client
+    #[serde(other)]
+    Other,
+}
+
+/// Image or document data
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Source {
+    Base64 { media_type: String, data: String },
+    Url { url: String },
+}
+
 // Output structures
 // Chat
 #[derive(Debug, Deserialize)]
@@ -168,18 +408,12 @@ pub struct ClaudeResponse {
     pub id: String,
     pub r#type: String,
     pub role: String,
-    pub content: Option<Vec<Content>>,
+    pub content: Option<Vec<ContentBlock>>,
     pub model: String,
     pub stop_reason: String,
     pub usage: Usage,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Content {
-    pub r#type: String,
-    pub text: String,
-}
-
 #[derive(Debug, Deserialize)]
 pub struct Usage {
     pub input_tokens: usize,
@@ -210,59 +444,93 @@ impl Default for Usage {
 
 /// Call Claude with some messages
 pub async fn call_claude(messages: Vec<ClaudeMessage>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-    call_claude_all(messages, 0.2, 4096).await
+    call_claude_all(messages, provider_defaults("claude").temperature(), default_max_tokens()).await
 }
 
 /// Call Claude with some messages and temperature
 pub async fn call_claude_temperature(messages: Vec<ClaudeMessage>, temperature: f32) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-    call_claude_all(messages, temperature, 4096).await
+    call_claude_all(messages, temperature, default_max_tokens()).await
 }
 
 /// Call Claude with some messages and max_tokens
 pub async fn call_claude_max_tokens(messages: Vec<ClaudeMessage>, max_tokens: usize) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-    call_claude_all(messages, 0.2, max_tokens).await
+    call_claude_all(messages, provider_defaults("claude").temperature(), max_tokens).await
 }
 
 /// Call Claude with some messages, temperature and max_tokens
 pub async fn call_claude_all(messages: Vec<ClaudeMessage>, temperature: f32, max_tokens: usize) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
     // Model/version of lln
     let model: String =
-        env::var("CLAUDE_MODEL").expect("CLAUDE_MODEL not found in enviroment variables");
+        required_env("CLAUDE_MODEL")?;
     let smess = extract_role("system", &messages);
     let umess = extract_role("user", &messages);
+    let defaults = provider_defaults("claude");
 
     // Create chat completion
     let claude_completion: ClaudeCompletion = ClaudeCompletion {
         model,
         tools: None,
         system: if smess.is_empty() { None } else { Some(smess) },
-        messages: vec![ClaudeMessage { role: "user".into(), content: umess }],
+        messages: vec![ClaudeMessage { role: "user".into(), content: umess.into() }],
         temperature,
         max_tokens,
+        stream: false,
+        top_p: defaults.top_p,
+        top_k: defaults.top_k,
+        stop_sequences: defaults.stop_sequences,
+        is_json: false,
+        prefill: None,
     };
 
     call_claude_completion(&claude_completion).await
 }
 
+/// Create and call Claude with attachments added as content parts to the final user message
+#[allow(clippy::too_many_arguments)]
+pub async fn call_claude_model_attachments(model: &str, system: &str, user: &[String], attachments: &[Attachment], temperature: f32, is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    let mut completion = ClaudeCompletion::completion(model, system, user, temperature, is_json, is_chat, None);
+
+    if let Some(message) = completion.messages.iter_mut().rev().find(|m| m.role == "user") {
+        message.attach("claude", attachments)?;
+    }
+
+    call_claude_completion(&completion).await
+}
+
 /// Call Claude with pre-assembled completion
 pub async fn call_claude_completion(claude_completion: &ClaudeCompletion) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    // Completions made by new or default have no model if CLAUDE_MODEL is unset
+    if claude_completion.model.is_empty() {
+        return Err(Box::new(ConfigError::MissingEnv("CLAUDE_MODEL".into())));
+    }
+    check_parameters("claude", &claude_completion.model, Some(claude_completion.temperature), claude_completion.top_p, Some(claude_completion.max_tokens))?;
     let start = std::time::Instant::now();
     // url to call anthropic
     let url: String =
-        env::var("CLAUDE_URL").expect("CLAUDE_URL not found in environment variables");
+        required_env("CLAUDE_URL")?;
 
 //println!("{:?}", claude_completion);
     let client = get_claude_client().await?;
+    let prefill = claude_completion.prefill();
+    let prefilled;
+    let claude_completion = match prefill {
+        Some(prefill) => {
+            prefilled = ClaudeCompletion { messages: [claude_completion.messages.clone(), vec![ClaudeMessage::text("assistant", prefill)]].concat(), ..claude_completion.clone() };
+
+            &prefilled
+        },
+        None => claude_completion,
+    };
 
     // Extract API Response
-    let res = client
+    let res = send_idempotent(client
         .post(url)
-        .json(&claude_completion)
-        .send()
+        .json(&claude_completion))
         .await;
     //let res: ClaudeResponse = res
+    let res = res?;
+    let status = res.status();
     let res = res
-        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
         //.json()
         .text()
         .await
@@ -270,21 +538,26 @@ pub async fn call_claude_completion(claude_completion: &ClaudeCompletion) -> Res
      
     let timing = start.elapsed().as_secs() as f64 + start.elapsed().subsec_millis() as f64 / 1000.0;
 
+    claude_response_to_return(&res, timing, prefill, claude_completion.is_json).map(|r| r.with_status(status))
+}
+
+/// Unpack the raw text of a Claude response into an LlmReturn
+pub fn claude_response_to_return(res: &str, timing: f64, prefill: Option<&str>, is_json: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
 //println!("{res}");
     if res.contains("\"error:\"") {
-        let ret: Result<LlmError,_> = serde_json::from_str(&res);
+        let ret: Result<LlmError,_> = serde_json::from_str(res);
 
         match ret {
             Ok(res) => 
-                Ok(LlmReturn::new(LlmType::CLAUDE_ERROR, res.error.to_string(), res.error.to_string(), (0, 0, 0), timing, None, None)),
+                Ok(LlmReturn::new(LlmType::CLAUDE_ERROR, res.error.to_string(), res.error.to_string().into(), (0, 0, 0), timing, None, None)),
             Err(e) => {
                 eprintln!("Error: {:?}", res);
 
-                Ok(LlmReturn::new(LlmType::CLAUDE_ERROR, e.to_string(), e.to_string(), (0, 0, 0), timing, None, None))
+                Ok(LlmReturn::new(LlmType::CLAUDE_ERROR, e.to_string(), e.to_string().into(), (0, 0, 0), timing, None, None))
             }
         }
     } else if res.contains("\"error\"") {
-        Ok(LlmReturn::new(LlmType::CLAUDE_ERROR, res.to_string(), res.to_string(), (0, 0, 0), timing, None, None))
+        Ok(LlmReturn::new(LlmType::CLAUDE_ERROR, res.to_string(), res.to_string().into(), (0, 0, 0), timing, None, None))
     } else if res.contains("\"tool_use\"") {
         let found = vec!["content:input:${args}".to_string(),
             "content:name:${func}".to_string(),
@@ -292,25 +565,31 @@ pub async fn call_claude_completion(claude_completion: &ClaudeCompletion) -> Res
             "usage:output_tokens:${out}".to_string(),
 //            "usage:${usage}".to_string(),
             "stop_reason:${finish}".to_string()];
-        let f: serde_json::Value = serde_json::from_str(&res).unwrap();
+        let f: serde_json::Value = match serde_json::from_str(res) {
+            Ok(f) => f,
+            Err(e) => return Ok(unexpected_response(LlmType::CLAUDE_ERROR, res, e, timing)),
+        };
         let h = get_functions(&f, &found);
         let funcs = unpack_functions(h.clone());
         let function_calls = serde_json::to_string(&funcs).unwrap();
-        let (i, o) = (h.get("in").unwrap()[0].clone(), h.get("out").unwrap()[0].clone());
-        let ip = i.parse::<usize>().unwrap();
-        let op = o.parse::<usize>().unwrap();
+        let (ip, op) = (found_count(&h, "in"), found_count(&h, "out"));
         let triple = (ip, op, ip + op);
-        let finish = h.get("finish").unwrap()[0].clone();
+        let finish = found_value(&h, "finish");
 
-        Ok(LlmReturn::new(LlmType::CLAUDE_TOOLS, function_calls, finish, triple, timing, None, None))
+        Ok(LlmReturn::new(LlmType::CLAUDE_TOOLS, function_calls, finish.into(), triple, timing, None, None))
     } else {
-        let res: ClaudeResponse = serde_json::from_str::<ClaudeResponse>(&res).unwrap();
+        let res: ClaudeResponse = match serde_json::from_str::<ClaudeResponse>(res) {
+            Ok(res) => res,
+            Err(e) => return Ok(unexpected_response(LlmType::CLAUDE_ERROR, res, e, timing)),
+        };
 
         // Send Response
         let text =
             match res.content {
-                Some(content) => {
-                    let text = content.iter().map(|s| s.text.lines().filter(|l| !l.starts_with("```")).fold(String::new(), |s, l| s + l + "\n")).collect();
+                Some(ref content) => {
+                    let text = content.iter()
+                        .filter_map(|b| if let ContentBlock::Text { text } = b { Some(text) } else { None })
+                        .map(|s| strip_fences_by_default(s)).collect();
 
                     text
                 },
@@ -319,11 +598,216 @@ pub async fn call_claude_completion(claude_completion: &ClaudeCompletion) -> Res
                     "No content found".to_string()
                 }
             };
-        let finish_reason = if res.stop_reason == "end_turn" { "STOP".to_string() } else { res.stop_reason };
+        let thinking: Vec<&str> = res.content.iter().flatten()
+            .filter_map(|b| if let ContentBlock::Thinking { thinking, .. } = b { Some(thinking.as_str()) } else { None })
+            .collect();
+        let text = match prefill {
+            Some(prefill) if is_json => json_from_prefill(prefill, &text),
+            Some(prefill) => format!("{prefill}{text}"),
+            None => text,
+        };
+        let finish_reason = FinishReason::from(res.stop_reason.as_str());
         let usage: Triple = res.usage.to_triple();
-        let timing = start.elapsed().as_secs() as f64 + start.elapsed().subsec_millis() as f64 / 1000.0;
 
-        Ok(LlmReturn::new(LlmType::CLAUDE, text, finish_reason, usage, timing, None, None))
+        Ok(LlmReturn::new(LlmType::CLAUDE, text, finish_reason, usage, timing, None, None)
+            .with_reasoning(if thinking.is_empty() { None } else { Some(thinking.join("\n")) }))
+    }
+}
+
+/// Server sent events of a streamed response, as Anthropic sends them
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClaudeStreamEvent {
+    /// Start of the message, with input token usage
+    MessageStart { message: StreamMessage },
+    ContentBlockStart { index: usize, content_block: ContentBlock },
+    ContentBlockDelta { index: usize, delta: ContentDelta },
+    ContentBlockStop { index: usize },
+    /// Stop reason and cumulative output token usage
+    MessageDelta { delta: MessageDelta, usage: StreamUsage },
+    MessageStop,
+    Ping,
+    Error { error: serde_json::Value },
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct StreamMessage {
+    pub id: String,
+    pub model: String,
+    pub usage: StreamUsage,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct StreamUsage {
+    #[serde(default)]
+    pub input_tokens: usize,
+    #[serde(default)]
+    pub output_tokens: usize,
+}
+
+/// Increment to the content block at the same index
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentDelta {
+    TextDelta { text: String },
+    /// Part of a tool_use block's input, only valid JSON once all are joined
+    InputJsonDelta { partial_json: String },
+    ThinkingDelta { thinking: String },
+    SignatureDelta { signature: String },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MessageDelta {
+    pub stop_reason: Option<String>,
+    pub stop_sequence: Option<String>,
+}
+
+// Take the events of any complete lines off the front of the buffer.
+// Only whole lines are decoded, as a chunk may end part way through a
+// multi-byte character.
+fn parse_claude_events(buffer: &mut Vec<u8>) -> Vec<Result<ClaudeStreamEvent, Box<dyn std::error::Error + Send>>> {
+    let mut events = Vec::new();
+
+    while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+        let line: Vec<u8> = buffer.drain(..=end).collect();
+        let line = String::from_utf8_lossy(&line);
+
+        if let Some(data) = line.strip_prefix("data:") {
+            events.push(serde_json::from_str(data.trim())
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) }));
+        }
+    }
+
+    events
+}
+
+/// Call Claude with pre-assembled completion, streaming the response as typed events
+pub async fn call_claude_stream_events(claude_completion: &ClaudeCompletion) -> Result<impl futures::Stream<Item = Result<ClaudeStreamEvent, Box<dyn std::error::Error + Send>>>, Box<dyn std::error::Error + Send>> {
+    let url: String =
+        required_env("CLAUDE_URL")?;
+    check_parameters("claude", &claude_completion.model, Some(claude_completion.temperature), claude_completion.top_p, Some(claude_completion.max_tokens))?;
+    let mut request = claude_completion.clone();
+
+    if let Some(prefill) = claude_completion.prefill() {
+        request.messages.push(ClaudeMessage::text("assistant", prefill));
+    }
+    request.stream = true;
+
+    let client = get_claude_client().await?;
+    let res = client
+        .post(url)
+        .json(&request)
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    if !res.status().is_success() {
+        let text = res.text().await
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+        return Err(Box::new(std::io::Error::other(text)));
+    }
+
+    let events = res.bytes_stream()
+        .scan(Vec::new(), |buffer, chunk| {
+            let events = match chunk {
+                Ok(bytes) => {
+                    buffer.extend_from_slice(&bytes);
+
+                    parse_claude_events(buffer)
+                },
+                Err(e) => vec![Err(Box::new(e) as Box<dyn std::error::Error + Send>)],
+            };
+
+            futures::future::ready(Some(futures::stream::iter(events)))
+        })
+        .flatten();
+
+    Ok(events)
+}
+
+/// Call Claude with pre-assembled completion, streaming just the text,
+/// starting with any prefill
+pub async fn call_claude_stream(claude_completion: &ClaudeCompletion) -> Result<impl futures::Stream<Item = Result<String, Box<dyn std::error::Error + Send>>>, Box<dyn std::error::Error + Send>> {
+    let prefill = claude_completion.prefill().map(|p| Ok(p.to_string()));
+    let events = call_claude_stream_events(claude_completion).await?;
+    let text = events.filter_map(|event| futures::future::ready(match event {
+        Ok(ClaudeStreamEvent::ContentBlockDelta { delta: ContentDelta::TextDelta { text }, .. }) => Some(Ok(text)),
+        Ok(ClaudeStreamEvent::Error { error }) => Some(Err(Box::new(std::io::Error::other(error.to_string())) as Box<dyn std::error::Error + Send>)),
+        Ok(_) => None,
+        Err(e) => Some(Err(e)),
+    }));
+
+    Ok(futures::stream::iter(prefill).chain(text))
+}
+
+// Token count request, only the prompt parts of a completion
+#[derive(Debug, Serialize)]
+struct ClaudeTokenCount<'a> {
+    model: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<&'a str>,
+    messages: Vec<ClaudeMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<&'a Vec<Function>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClaudeTokens {
+    pub input_tokens: usize,
+}
+
+/// Input tokens a completion would use, counted by Anthropic's own tokenizer
+/// so unlike `estimate_tokens` it is exact. Free, but rate limited. The url is
+/// CLAUDE_COUNT_TOKENS_URL, else CLAUDE_URL with "/count_tokens" appended.
+pub async fn count_claude_tokens(claude_completion: &ClaudeCompletion) -> Result<usize, Box<dyn std::error::Error + Send>> {
+    let url: String = match env_var("CLAUDE_COUNT_TOKENS_URL") {
+        Some(url) => url,
+        None => format!("{}/count_tokens", required_env("CLAUDE_URL")?),
+    };
+    let mut messages = claude_completion.messages.clone();
+
+    if let Some(prefill) = claude_completion.prefill() {
+        messages.push(ClaudeMessage::text("assistant", prefill));
+    }
+
+    let count = ClaudeTokenCount {
+        model: &claude_completion.model,
+        system: claude_completion.system.as_deref(),
+        messages,
+        tools: claude_completion.tools.as_ref(),
+    };
+    let client = get_claude_client().await?;
+
+    let res = client
+        .post(url)
+        .json(&count)
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    if res.contains("\"error\"") {
+        return Err(Box::new(std::io::Error::other(res)));
+    }
+
+    let tokens: ClaudeTokens = serde_json::from_str(&res)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    Ok(tokens.input_tokens)
+}
+
+// Put the prefill back and drop any commentary after the closing brace
+fn json_from_prefill(prefill: &str, text: &str) -> String {
+    let json = format!("{prefill}{}", text.trim_start());
+
+    match json.rfind('}') {
+        Some(end) => json[..=end].to_string(),
+        None => json,
     }
 }
 
@@ -334,19 +818,79 @@ fn extract_role(role: &str, messages: &[ClaudeMessage]) -> String {
             if !s.is_empty() {
                 s.push('\n');
             }
-            s.push_str(&i.content);
+            s.push_str(&i.content.text());
 
             s
         })
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ClaudeModels {
+    pub data: Vec<ClaudeModel>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClaudeModel {
+    pub id: String,
+    pub display_name: Option<String>,
+    pub created_at: Option<String>,
+}
+
+/// List models available from Anthropic
+pub async fn list_claude_models() -> Result<Vec<ModelInfo>, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("CLAUDE_MODELS_URL")?;
+    let client = get_claude_client().await?;
+
+    let res = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    claude_models_to_info(&res)
+}
+
+fn claude_models_to_info(res: &str) -> Result<Vec<ModelInfo>, Box<dyn std::error::Error + Send>> {
+    if res.contains("\"error\"") {
+        return Err(Box::new(std::io::Error::other(res.to_string())));
+    }
+
+    let models: ClaudeModels = serde_json::from_str(res)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    Ok(models.data.into_iter()
+        .map(|m| ModelInfo {
+            id: m.id,
+            context_window: None,
+            created: m.created_at.as_deref().and_then(unix_time),
+        })
+        .collect())
+}
+
+// Seconds since epoch of an RFC 3339 UTC time e.g. 2024-06-20T00:00:00Z
+fn unix_time(time: &str) -> Option<u64> {
+    let num = |r: std::ops::Range<usize>| time.get(r)?.parse::<i64>().ok();
+    let (y, m, d) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let (hh, mm, ss) = (num(11..13).unwrap_or(0), num(14..16).unwrap_or(0), num(17..19).unwrap_or(0));
+
+    let days = days_from_civil(y, m, d);
+
+    u64::try_from(days * 86400 + hh * 3600 + mm * 60 + ss).ok()
+}
+
 async fn get_claude_client() -> Result<Client, Box<dyn std::error::Error + Send>> {
+    providers().client("claude", new_claude_client()).await
+}
+
+pub(crate) async fn new_claude_client() -> Result<Client, Box<dyn std::error::Error + Send>> {
     // Extract API Key information
-    let api_key: String =
-        env::var("ANTHROPIC_API_KEY").expect("ANTHROPIC_API_KEY not found in environment variables");
+    let api_key: String = credential("ANTHROPIC_API_KEY")?;
     // Date when version was available
     let version: String =
-        env::var("CLAUDE_VERSION").expect("CLAUDE_VERSION not found in environment variables");
+        required_env("CLAUDE_VERSION")?;
 
     // Create headers
     let mut headers: HeaderMap = HeaderMap::new();
@@ -363,6 +907,16 @@ async fn get_claude_client() -> Result<Client, Box<dyn std::error::Error + Send>
         HeaderValue::from_str(&version)
             .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?,
     );
+    // Optional beta features, comma separated e.g. prompt-caching-2024-07-31
+    if let Some(beta) = env_var("CLAUDE_BETA") {
+        if !beta.trim().is_empty() {
+            headers.insert(
+                "anthropic-beta",
+                HeaderValue::from_str(beta.trim())
+                    .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?,
+            );
+        }
+    }
 
     get_client(headers).await
 }
@@ -370,109 +924,228 @@ async fn get_claude_client() -> Result<Client, Box<dyn std::error::Error + Send>
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mock::mock_post;
     use serial_test::serial;
+    use wiremock::MockServer;
 
-    async fn claude(content: Vec<ClaudeMessage>) {
-        match call_claude(content).await {
-            Ok(ret) => { println!("{ret}"); assert!(true) },
-            Err(e) => { println!("{e}"); assert!(false) },
-        }
+    #[test]
+    fn test_claude_models_to_info() {
+        let res = r#"{"data":[{"type":"model","id":"claude-3-5-sonnet-20240620","display_name":"Claude 3.5 Sonnet","created_at":"2024-06-20T00:00:00Z"}],"has_more":false}"#;
+        let models = claude_models_to_info(res).unwrap();
+
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].id, "claude-3-5-sonnet-20240620");
+        assert_eq!(models[0].created, Some(1718841600));
+    }
+    #[test]
+    #[serial]
+    fn test_claude_sampling() {
+        std::env::set_var("CLAUDE_MODEL", "claude-3-opus-20240229");
+
+        let mut completion = ClaudeCompletion::new(vec![ClaudeMessage::text("user", "Count to ten")], 0.2, false);
+        let json = serde_json::to_string(&completion).unwrap();
+
+        assert!(!json.contains("top_k") && !json.contains("stop_sequences"));
+
+        completion.set_top_p(Some(0.9));
+        completion.set_top_k(Some(40));
+        completion.set_stop_sequences(&["five"]);
+        let json = serde_json::to_string(&completion).unwrap();
+
+        assert!(json.contains("\"top_p\":0.9,\"top_k\":40,\"stop_sequences\":[\"five\"]"));
+    }
+
+    const FUNC_DEF: &str =
+r#"
+// Derive the value of the arithmetic expression
+// expr: An arithmetic expression
+fn arithmetic(expr)
+"#;
+
+    // Point Claude at a mock server answering with the given fixture
+    async fn mock_claude(status: u16, fixture: &str) -> MockServer {
+        let server = mock_post("/v1/messages", status, fixture).await;
+
+        std::env::set_var("CLAUDE_URL", format!("{}/v1/messages", server.uri()));
+        std::env::set_var("ANTHROPIC_API_KEY", "test-key");
+        std::env::set_var("CLAUDE_VERSION", "2023-06-01");
+        std::env::set_var("CLAUDE_MODEL", "claude-3-opus-20240229");
+
+        server
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_call_claude_success() {
+        let _server = mock_claude(200, "claude/success.json").await;
+        let messages = vec!["Hello".to_string()];
+        let res = ClaudeCompletion::call_model("claude-3-opus-20240229", "Be brief", &messages, 0.2, false, true).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::CLAUDE);
+        assert_eq!(res.text, "Hello there.\n");
+        assert_eq!(res.finish_reason, FinishReason::Stop);
+        assert_eq!(res.usage, (12, 3, 15));
+    }
+    #[tokio::test]
+    #[serial]
+    async fn test_call_claude_thinking() {
+        let _server = mock_claude(200, "claude/thinking.json").await;
+        let res = call_claude(vec![ClaudeMessage::text("user", "What is the capital of Australia?")]).await.unwrap();
+
+        assert_eq!(res.text, "The capital of Australia is Canberra.\n");
+        assert_eq!(res.reasoning.unwrap(), "Australia's capital is not its largest city, it is Canberra.");
     }
+    #[test]
+    fn test_claude_content_blocks() {
+        let message = ClaudeMessage::from_content("user", &[MessageContent::tool_result("toolu_01", "arithmetic", "525960"), MessageContent::text("Thanks")]).unwrap();
 
+        assert_eq!(serde_json::to_string(&message).unwrap(), r#"{"role":"user","content":[{"type":"tool_result","tool_use_id":"toolu_01","content":"525960"},{"type":"text","text":"Thanks"}]}"#);
+        assert_eq!(message.content.text(), "Thanks");
+
+        let block: ContentBlock = serde_json::from_str(r#"{"type":"server_tool_use","id":"srvtoolu_01"}"#).unwrap();
+
+        assert_eq!(block, ContentBlock::Other);
+    }
     #[tokio::test]
     #[serial]
-    async fn test_call_claude_basic() {
-        let messages: Vec<ClaudeMessage> = vec![ClaudeMessage { role: "user".into(), content: "What is the meaining of life?".into() }];
+    async fn test_count_claude_tokens() {
+        let server = mock_post("/v1/messages/count_tokens", 200, "claude/count_tokens.json").await;
+
+        std::env::set_var("CLAUDE_URL", format!("{}/v1/messages", server.uri()));
+        std::env::set_var("ANTHROPIC_API_KEY", "test-key");
+        std::env::set_var("CLAUDE_VERSION", "2023-06-01");
+        std::env::set_var("CLAUDE_MODEL", "claude-3-opus-20240229");
+
+        let completion = ClaudeCompletion::new(vec![ClaudeMessage::text("user", "Hello, Claude")], 0.2, false);
 
-        claude(messages).await;
+        assert_eq!(count_claude_tokens(&completion).await.unwrap(), 14);
+
+        let body = String::from_utf8(server.received_requests().await.unwrap()[0].body.clone()).unwrap();
+
+        assert!(!body.contains("max_tokens"));
+    }
+    #[test]
+    fn test_parse_claude_events_split() {
+        let data = "event: content_block_delta\r\ndata: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"café\"}}\r\n\r\n".as_bytes();
+        // Chunk ends between the two bytes of the é
+        let split = data.iter().position(|b| *b == 0xc3).unwrap() + 1;
+        let mut buffer = data[..split].to_vec();
+
+        assert!(parse_claude_events(&mut buffer).is_empty());
+
+        buffer.extend_from_slice(&data[split..]);
+
+        let events = parse_claude_events(&mut buffer);
+
+        assert!(matches!(&events[..], [Ok(ClaudeStreamEvent::ContentBlockDelta { delta: ContentDelta::TextDelta { text }, .. })] if text == "café"));
+        assert!(buffer.is_empty());
     }
     #[tokio::test]
     #[serial]
-    async fn test_call_claude_citation() {
-        let messages = 
-            vec![ClaudeMessage::text("user", "Give citations for the General theory of Relativity.")];
-        claude(messages).await;
+    async fn test_call_claude_stream() {
+        let _server = mock_claude(200, "claude/stream.txt").await;
+        let completion = ClaudeCompletion::new(vec![ClaudeMessage::text("user", "Hello")], 0.2, false);
+        let events: Vec<ClaudeStreamEvent> = call_claude_stream_events(&completion).await.unwrap()
+            .map(|e| e.unwrap())
+            .collect().await;
+
+        assert_eq!(events.len(), 8);
+        assert!(matches!(&events[0], ClaudeStreamEvent::MessageStart { message } if message.usage.input_tokens == 25));
+        assert!(matches!(&events[6], ClaudeStreamEvent::MessageDelta { delta, usage } if delta.stop_reason.as_deref() == Some("end_turn") && usage.output_tokens == 3));
+
+        let _server = mock_claude(200, "claude/stream.txt").await;
+        let text: Vec<String> = call_claude_stream(&completion).await.unwrap()
+            .map(|t| t.unwrap())
+            .collect().await;
+
+        assert_eq!(text.concat(), "Hello there.");
     }
     #[tokio::test]
     #[serial]
-    async fn test_call_claude_poem() {
-        let messages = 
-            vec![ClaudeMessage::text("user", "Write a creative poem about the interplay of artificial intelligence and the human spirit and provide citations")];
-        claude(messages).await;
+    async fn test_call_claude_error() {
+        let _server = mock_claude(401, "claude/error.json").await;
+        let res = call_claude(vec![ClaudeMessage::text("user", "Hello")]).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::CLAUDE_ERROR);
+        assert!(res.text.contains("invalid x-api-key"));
+        assert_eq!(res.usage, (0, 0, 0));
     }
     #[tokio::test]
     #[serial]
-    async fn test_call_claude_logic() {
-        let messages = 
-            vec![ClaudeMessage::text("user", "How many brains does an octopus have, when they have been injured and lost a leg?")];
-        claude(messages).await;
+    async fn test_call_claude_refusal() {
+        let _server = mock_claude(200, "claude/refusal.json").await;
+        let res = call_claude(vec![ClaudeMessage::text("user", "Hello")]).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::CLAUDE);
+        assert_eq!(res.text, "");
+        assert_eq!(res.finish_reason, FinishReason::ContentFilter);
+        assert_eq!(res.usage, (14, 0, 14));
     }
     #[tokio::test]
     #[serial]
-    async fn test_call_claude_dialogue() {
-        let system = "Use a Scottish accent to answer questions";
-        let mut messages = 
-            vec!["How many brains does an octopus have, when they have been injured and lost a leg?".to_string()];
-        let res = ClaudeCompletion::call(&system, &messages, 0.2, false, true).await;
-        println!("{res:?}");
+    async fn test_call_claude_bad_gateway() {
+        let _server = mock_claude(502, "claude/bad_gateway.html").await;
+        let res = call_claude(vec![ClaudeMessage::text("user", "Hello")]).await.unwrap();
 
-        messages.push(res.unwrap().to_string());
-        messages.push("Is a cuttle fish similar?".to_string());
+        assert_eq!(res.llm_type, LlmType::CLAUDE_ERROR);
+        assert!(res.text.contains("502 Bad Gateway"));
+        assert!(res.is_server_error());
+    }
+    #[tokio::test]
+    #[serial]
+    async fn test_call_claude_json() {
+        let server = mock_claude(200, "claude/json.json").await;
+        let messages = vec!["Capital and population of Australia as JSON".to_string()];
+        let res = ClaudeCompletion::call_model("claude-3-opus-20240229", "", &messages, 0.2, true, false).await.unwrap();
+        let json: serde_json::Value = serde_json::from_str(&res.text).unwrap();
+
+        assert_eq!(res.llm_type, LlmType::CLAUDE);
+        assert_eq!(json["capital"], "Canberra");
 
-        let res = ClaudeCompletion::call(&system, &messages, 0.2, false, true).await;
-        println!("{res:?}");
+        let body = String::from_utf8(server.received_requests().await.unwrap()[0].body.clone()).unwrap();
+
+        assert!(body.contains(r#"{"role":"assistant","content":"{"}"#));
     }
     #[tokio::test]
     #[serial]
-    async fn test_call_claude_dialogue_model() {
-        let model: String = std::env::var("CLAUDE_MODEL").expect("CLAUDE_MODEL not found in enviroment variables");
-        let messages = vec!["Hello".to_string()];
-        let res = ClaudeCompletion::call_model(&model, "", &messages, 0.2, false, true).await;
-        println!("{res:?}");
+    async fn test_call_claude_attachments() {
+        let server = mock_claude(200, "claude/success.json").await;
+        let attachments = [Attachment::raw("image/png", vec![1, 2, 3]), Attachment::image_url("https://example.com/chart.png")];
+        let res = call_llm_model_with_attachments("claude", "claude-3-opus-20240229", "", &["Compare these".to_string()], &attachments, 0.2, false, false).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&server.received_requests().await.unwrap()[0].body).unwrap();
+        let content = &body["messages"][0]["content"];
+
+        assert_eq!(res.llm_type, LlmType::CLAUDE);
+        assert_eq!(content[0], serde_json::json!({"type": "text", "text": "Compare these"}));
+        assert_eq!(content[1]["source"], serde_json::json!({"type": "base64", "media_type": "image/png", "data": "AQID"}));
+        assert_eq!(content[2]["source"], serde_json::json!({"type": "url", "url": "https://example.com/chart.png"}));
     }
+
     #[tokio::test]
     #[serial]
-    async fn test_call_function_claude() {
-        let model: String = std::env::var("CLAUDE_MODEL").expect("CLAUDE_MODEL not found in enviroment variables");
-        let messages =  vec!["The answer is (60 * 24) * 365.25".to_string()];
-        let func_def =
-r#"
-// Derive the value of the arithmetic expression
-// expr: An arithmetic expression
-fn arithmetic(expr)
-"#;
-        let functions = get_function_json("claude", &[func_def]);
-        let res = ClaudeCompletion::call_model_function(&model, "", &messages, 0.2, false, true, functions).await;
-        println!("{res:?}");
+    async fn test_call_claude_prefill() {
+        let server = mock_claude(200, "claude/prefill.json").await;
+        let mut completion = ClaudeCompletion::new(vec![ClaudeMessage::text("user", "What is the capital of Australia?")], 0.2, false);
+        completion.set_prefill("The capital is ");
+        let res = call_claude_completion(&completion).await.unwrap();
+
+        assert_eq!(res.text, "The capital is Canberra, it was chosen as a compromise between Sydney and Melbourne.\n");
+
+        let body = String::from_utf8(server.received_requests().await.unwrap()[0].body.clone()).unwrap();
 
-        let answer = call_actual_function(res.ok());
-        println!("{answer:?}");
+        assert!(body.contains(r#"{"role":"assistant","content":"The capital is"}"#));
     }
     #[tokio::test]
     #[serial]
-    async fn test_call_function_common_claude() {
-        let messages =  vec!["The answer is (60 * 24) * 365.25".to_string()];
-        //let messages = vec!["a fruit that is red with a sweet taste".to_string()];
-        let func_def =
-r#"
-// Derive the value of the arithmetic expression
-// expr: An arithmetic expression
-fn arithmetic(expr)
-"#;
-        // This does not work in Claude yet
-/*
-        let func_def2 =
-r#"
-// Find the color of an apple and its taste pass them to this function
-// color: The color of an apple
-// taste: The taste of an apple
-fn apple(color, taste)
-"#;
-*/
-        let res = call_function_llm("claude", &messages, &[func_def]).await;
-        println!("{res:?}");
-
-        let answer = call_actual_function(res.ok());
-        println!("{answer:?}");
+    async fn test_call_function_claude() {
+        let _server = mock_claude(200, "claude/tool_use.json").await;
+        let messages = vec!["The answer is (60 * 24) * 365.25".to_string()];
+        let functions = get_function_json("claude", &[FUNC_DEF]);
+        let res = ClaudeCompletion::call_model_function("claude-3-opus-20240229", "", &messages, 0.2, false, true, functions).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::CLAUDE_TOOLS);
+        assert_eq!(res.finish_reason, FinishReason::ToolCalls);
+        assert_eq!(res.usage, (395, 61, 456));
+        assert_eq!(call_actual_function(Some(res)), vec!["arithmetic -> 525960"]);
     }
 }
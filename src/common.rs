@@ -1,26 +1,47 @@
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 use reqwest::Client;
-use reqwest::header::{HeaderMap, HeaderValue};
-use crate::gemini::GeminiCompletion;
-use crate::gpt::GptCompletion;
-use crate::mistral::MistralCompletion;
-use crate::claude::ClaudeCompletion;
-use crate::groq::GroqCompletion;
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE};
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+pub use tokio_util::sync::CancellationToken;
+use futures::StreamExt;
+use crate::gemini::{GeminiCompletion, call_gemini_model_attachments, list_gemini_models};
+use crate::gpt::{GptCompletion, call_gpt_model_attachments, list_gpt_models};
+use crate::mistral::{MistralCompletion, call_mistral_model_attachments, list_mistral_models};
+use crate::claude::{ClaudeCompletion, call_claude_model_attachments, list_claude_models};
+use crate::groq::{GroqCompletion, call_groq_model_attachments, list_groq_models};
+use crate::replicate::call_replicate_model;
+use crate::capabilities::{check_capabilities, check_context};
+use crate::error::LlmClientError;
+use crate::filters::apply_prompt_filters;
+use crate::postprocess::apply_post_processors;
+use crate::language::wrong_language;
+use crate::repair::{json_repair_enabled, repair_json};
+use crate::config::{env_var, provider_defaults, required_env};
 use crate::functions::{Function, get_function_json};
+use crate::telemetry::record_call;
+use crate::usage::record_usage;
+use crate::tokens::{estimate_audio_tokens, estimate_tokens, estimate_tokens_all};
+use crate::cache::{cache_key, response_cache};
+use crate::tenants::current_tenant;
+use crate::circuit;
+use crate::quotas;
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum LlmType  {
     GEMINI,
     GPT,
     CLAUDE,
     MISTRAL,
     GROQ,
+    REPLICATE,
     GEMINI_ERROR,
     GPT_ERROR,
     CLAUDE_ERROR,
     MISTRAL_ERROR,
     GROQ_ERROR,
+    REPLICATE_ERROR,
     GEMINI_TOOLS,
     GPT_TOOLS,
     CLAUDE_TOOLS,
@@ -30,6 +51,18 @@ pub enum LlmType  {
 
 pub type Triple = (usize, usize, usize);
 
+impl LlmType {
+    /// Provider returned an error rather than an answer
+    pub fn is_error(&self) -> bool {
+        matches!(self, LlmType::GEMINI_ERROR | LlmType::GPT_ERROR | LlmType::CLAUDE_ERROR | LlmType::MISTRAL_ERROR | LlmType::GROQ_ERROR | LlmType::REPLICATE_ERROR)
+    }
+
+    /// Provider asked for a function call rather than answering
+    pub fn is_tools(&self) -> bool {
+        matches!(self, LlmType::GEMINI_TOOLS | LlmType::GPT_TOOLS | LlmType::CLAUDE_TOOLS | LlmType::MISTRAL_TOOLS | LlmType::GROQ_TOOLS)
+    }
+}
+
 impl std::fmt::Display for LlmType {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -38,11 +71,13 @@ impl std::fmt::Display for LlmType {
             LlmType::CLAUDE => write!(f, "CLAUDE"),
             LlmType::MISTRAL => write!(f, "MISTRAL"),
             LlmType::GROQ => write!(f, "GROQ"),
+            LlmType::REPLICATE => write!(f, "REPLICATE"),
             LlmType::GEMINI_ERROR => write!(f, "GEMINI_ERROR"),
             LlmType::GPT_ERROR => write!(f, "GPT_ERROR"),
             LlmType::CLAUDE_ERROR => write!(f, "CLAUDE_ERROR"),
             LlmType::MISTRAL_ERROR => write!(f, "MISTRAL_ERROR"),
             LlmType::GROQ_ERROR => write!(f, "GROQ_ERROR"),
+            LlmType::REPLICATE_ERROR => write!(f, "REPLICATE_ERROR"),
             LlmType::GEMINI_TOOLS => write!(f, "GEMINI_TOOLS"),
             LlmType::GPT_TOOLS => write!(f, "GPT_TOOLS"),
             LlmType::CLAUDE_TOOLS => write!(f, "CLAUDE_TOOLS"),
@@ -52,20 +87,137 @@ impl std::fmt::Display for LlmType {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Why a model stopped generating, normalised across providers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FinishReason {
+    /// Natural end of the reply or a stop sequence was hit
+    Stop,
+    /// Output was truncated at max_tokens or the context length
+    Length,
+    /// The model wants one or more functions called
+    ToolCalls,
+    /// Blocked by a safety or content filter
+    ContentFilter,
+    /// Anything else, as given by the provider (errors included)
+    Other(String),
+}
+
+impl From<&str> for FinishReason {
+    fn from(reason: &str) -> Self {
+        match reason.to_lowercase().as_str() {
+            "stop" | "end_turn" | "stop_sequence" => FinishReason::Stop,
+            "length" | "max_tokens" | "model_length" => FinishReason::Length,
+            "tool_calls" | "tool_use" | "function_call" => FinishReason::ToolCalls,
+            "content_filter" | "safety" | "recitation" | "blocklist" | "prohibited_content" | "spii" | "refusal" => FinishReason::ContentFilter,
+            _ => FinishReason::Other(reason.into()),
+        }
+    }
+}
+
+impl From<String> for FinishReason {
+    fn from(reason: String) -> Self {
+        FinishReason::from(reason.as_str())
+    }
+}
+
+impl std::fmt::Display for FinishReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FinishReason::Stop => write!(f, "STOP"),
+            FinishReason::Length => write!(f, "LENGTH"),
+            FinishReason::ToolCalls => write!(f, "TOOL_CALLS"),
+            FinishReason::ContentFilter => write!(f, "CONTENT_FILTER"),
+            FinishReason::Other(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+/// How long a call took, in seconds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Timing {
+    /// Whole call, from request to last byte of the response
+    pub total: f64,
+    /// Until the first token arrived, only known when streaming
+    pub first_token: Option<f64>,
+    /// Output tokens over the total time
+    pub tokens_per_sec: f64,
+}
+
+impl Timing {
+    pub fn new(total: f64, output_tokens: usize) -> Self {
+        let tokens_per_sec = if total > 0.0 { output_tokens as f64 / total } else { 0.0 };
+
+        Timing { total, first_token: None, tokens_per_sec }
+    }
+
+    pub fn with_first_token(mut self, first_token: f64) -> Self {
+        self.first_token = Some(first_token);
+        self
+    }
+
+    /// Output tokens per second once the first token arrived, so excluding
+    /// queueing and prompt processing. None unless streamed.
+    pub fn generation_tokens_per_sec(&self) -> Option<f64> {
+        let first_token = self.first_token?;
+        let generating = self.total - first_token;
+
+        (generating > 0.0).then(|| self.tokens_per_sec * self.total / generating)
+    }
+}
+
+impl std::fmt::Display for Timing {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:.4} secs", self.total)?;
+        if let Some(first_token) = self.first_token {
+            write!(f, ", first token {:.4} secs", first_token)?;
+        }
+        write!(f, ", {:.1} tokens/sec", self.tokens_per_sec)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LlmReturn {
     pub llm_type: LlmType,
     pub text: String,
-    pub finish_reason: String,
+    pub finish_reason: FinishReason,
     pub usage: Triple,
-    pub timing: f64,
+    pub timing: Timing,
     pub citations: Option<String>,
     pub safety_ratings: Option<Vec<String>>,
+    /// Thinking or reasoning the model gave separately from its answer
+    pub reasoning: Option<String>,
+    /// HTTP status of the response the reply came from, where known
+    #[serde(default)]
+    pub status: Option<u16>,
 }
 
 impl LlmReturn {
-    pub fn new(llm_type: LlmType, text: String, finish_reason: String, usage: Triple, timing: f64, citations: Option<String>, safety_ratings: Option<Vec<String>>) -> Self {
-        LlmReturn { llm_type, text, finish_reason, usage, timing, citations, safety_ratings }
+    /// Timing is the total seconds taken, tokens/sec follows from the usage
+    pub fn new(llm_type: LlmType, text: String, finish_reason: FinishReason, usage: Triple, timing: f64, citations: Option<String>, safety_ratings: Option<Vec<String>>) -> Self {
+        let timing = Timing::new(timing, usage.1);
+
+        LlmReturn { llm_type, text, finish_reason, usage, timing, citations, safety_ratings, reasoning: None, status: None }
+    }
+
+    pub fn with_reasoning(mut self, reasoning: Option<String>) -> Self {
+        self.reasoning = reasoning;
+        self
+    }
+
+    pub fn with_status(mut self, status: reqwest::StatusCode) -> Self {
+        self.status = Some(status.as_u16());
+        self
+    }
+
+    /// Error reply for a server side failure: a 5xx status, such as 529
+    /// when the provider is overloaded
+    pub fn is_server_error(&self) -> bool {
+        self.llm_type.is_error() && self.status.is_some_and(|s| (500..600).contains(&s))
+    }
+
+    /// Output stopped at max_tokens or the context length, so is incomplete
+    pub fn is_truncated(&self) -> bool {
+        self.finish_reason == FinishReason::Length
     }
 }
 
@@ -74,18 +226,21 @@ impl std::fmt::Display for LlmReturn {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         println!("---------- {} ----------", self.llm_type);
         let _ = writeln!(f, "{}", self.text);
-        if !self.finish_reason.is_empty() && self.finish_reason != "STOP" {
+        if self.finish_reason != FinishReason::Stop && self.finish_reason != FinishReason::Other(String::new()) {
             println!("Finish Reason: {}", self.finish_reason);
         }
         println!("Tokens: Input: {} + Output: {} -> Total: {}",
                  self.usage.0, self.usage.1, self.usage.2);
-        println!("Timing: {:.4} secs", self.timing);
+        println!("Timing: {}", self.timing);
         if let Some(ref citations) = self.citations {
             println!("Citations:\n{}", citations);
         }
         if let Some(ref safety_ratings) = self.safety_ratings {
             println!("Safety Settings: {:?}", safety_ratings);
         }
+        if let Some(ref reasoning) = self.reasoning {
+            println!("Reasoning:\n{}", reasoning);
+        }
 
         Ok(())
     }
@@ -100,6 +255,13 @@ pub trait LlmCompletion {
         // not applicable for all models
     }
 
+    /// Seed the start of the reply with a final assistant message. Claude
+    /// continues it and the prefill is returned as part of the reply text,
+    /// other models may treat it as an earlier turn.
+    fn set_prefill(&mut self, prefill: &str) {
+        self.add_text("assistant", prefill);
+    }
+
     /// Supply single role and single part text
     fn add_text(&mut self, role: &str, content: &str);
 
@@ -180,6 +342,416 @@ impl std::fmt::Display for LlmErrorMessage {
     }
 }
 
+/// Error return for a response body in a shape that was not expected, such
+/// as a proxy's HTML error page, rather than panicking on it
+pub(crate) fn unexpected_response(llm_type: LlmType, res: &str, error: impl std::fmt::Display, timing: f64) -> LlmReturn {
+    let text = format!("Unexpected response ({error}): {res}");
+
+    LlmReturn::new(llm_type, text, "ERROR".into(), (0, 0, 0), timing, None, None)
+}
+
+/// Model available from an LLM provider
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    pub id: String,
+    pub context_window: Option<usize>,
+    /// Unix time in seconds
+    pub created: Option<u64>,
+}
+
+impl std::fmt::Display for ModelInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.id)?;
+        if let Some(context_window) = self.context_window {
+            write!(f, " (context: {context_window})")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// File content to be sent alongside a prompt
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub name: String,
+    pub mime_type: String,
+    pub data: Vec<u8>,
+    /// Media already uploaded to the provider, sent by reference instead of data
+    pub uri: Option<String>,
+    /// Start and end of the part of a video or audio file to use
+    pub offsets: Option<(std::time::Duration, std::time::Duration)>,
+}
+
+impl Attachment {
+    pub fn new(name: &str, mime_type: &str, data: Vec<u8>) -> Self {
+        Attachment { name: name.into(), mime_type: mime_type.into(), data, uri: None, offsets: None }
+    }
+
+    /// Media referred to by a provider URI, e.g. gs://bucket/clip.mp4 for Gemini
+    pub fn from_uri(name: &str, mime_type: &str, uri: &str) -> Self {
+        Attachment { uri: Some(uri.into()), ..Attachment::new(name, mime_type, Vec::new()) }
+    }
+
+    /// Image read from a file
+    pub fn image_path(path: &str) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        Self::from_file(path)
+    }
+
+    /// Image fetched by the provider, the mime type guessed from the URL
+    pub fn image_url(url: &str) -> Self {
+        let name = url.split(['?', '#']).next().unwrap_or(url);
+        let mime_type = match mime_type(name) {
+            m if m.starts_with("image/") => m,
+            _ => "image/jpeg",
+        };
+
+        Self::from_uri(name.rsplit('/').next().unwrap_or(name), mime_type, url)
+    }
+
+    /// PDF document read from a file
+    pub fn pdf(path: &str) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        Ok(Attachment { mime_type: "application/pdf".into(), ..Self::from_file(path)? })
+    }
+
+    /// Audio read from a file
+    pub fn audio(path: &str) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        Self::from_file(path)
+    }
+
+    /// Bytes of any type
+    pub fn raw(mime_type: &str, data: Vec<u8>) -> Self {
+        Self::new("attachment", mime_type, data)
+    }
+
+    /// Only use the media between start and end
+    pub fn with_offsets(mut self, start: std::time::Duration, end: std::time::Duration) -> Self {
+        self.offsets = Some((start, end));
+        self
+    }
+
+    /// Read file, mime type is derived from the file extension
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        let data = std::fs::read(path)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+        let name = std::path::Path::new(path)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or(path.into());
+
+        Ok(Attachment::new(&name, mime_type(path), data))
+    }
+
+    /// Text can be inlined into a prompt for any LLM
+    pub fn is_text(&self) -> bool {
+        self.uri.is_none() && (self.mime_type.starts_with("text/") || self.mime_type == "application/json")
+    }
+
+    pub fn to_base64(&self) -> String {
+        BASE64_STANDARD.encode(&self.data)
+    }
+
+    /// Wire format for the named LLM: a Gemini part, Claude block or GPT
+    /// content part. `LlmClientError::UnsupportedAttachment` if it can't take it.
+    pub fn wire(&self, llm: &str) -> Result<serde_json::Value, Box<dyn std::error::Error + Send>> {
+        let unsupported = || -> Box<dyn std::error::Error + Send> {
+            Box::new(LlmClientError::UnsupportedAttachment { llm: llm.into(), mime_type: self.mime_type.clone() })
+        };
+        let image = self.mime_type.starts_with("image/");
+
+        match (&self.uri, llm_name(llm)) {
+            (None, _) => MessageContent::from(self).wire(llm).map_err(|_| unsupported()),
+            (Some(uri), Some("gemini")) =>
+                Ok(serde_json::json!({"fileData": {"mimeType": self.mime_type, "fileUri": uri}})),
+            (Some(uri), Some("gpt" | "groq" | "mistral")) if image =>
+                Ok(serde_json::json!({"type": "image_url", "image_url": {"url": uri}})),
+            (Some(uri), Some("claude")) if image =>
+                Ok(serde_json::json!({"type": "image", "source": {"type": "url", "url": uri}})),
+            (Some(uri), Some("claude")) if self.mime_type == "application/pdf" =>
+                Ok(serde_json::json!({"type": "document", "source": {"type": "url", "url": uri}})),
+            _ => Err(unsupported()),
+        }
+    }
+
+    /// Playing time of audio or video, from the offsets if given,
+    /// otherwise read from the header of a WAV file
+    pub fn duration(&self) -> Option<std::time::Duration> {
+        match self.offsets {
+            Some((start, end)) => Some(end.saturating_sub(start)),
+            None if self.mime_type == "audio/wav" => wav_duration(&self.data),
+            None => None,
+        }
+    }
+}
+
+// Length of PCM WAV data from its byte rate and the size of the data chunk
+fn wav_duration(data: &[u8]) -> Option<std::time::Duration> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return None;
+    }
+
+    let le32 = |at: usize| data.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    let mut byte_rate = None;
+    let mut pos = 12;
+
+    while let (Some(id), Some(size)) = (data.get(pos..pos + 4), le32(pos + 4)) {
+        match id {
+            b"fmt " => byte_rate = le32(pos + 16),
+            b"data" => return byte_rate.filter(|r| *r > 0).map(|r| std::time::Duration::from_secs_f64(size as f64 / r as f64)),
+            _ => (),
+        }
+        pos += 8 + size as usize + size as usize % 2;
+    }
+
+    None
+}
+
+/// Mime type from file extension
+pub fn mime_type(path: &str) -> &'static str {
+    let ext = std::path::Path::new(path)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    match ext.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "pdf" => "application/pdf",
+        "json" => "application/json",
+        "jsonl" => "application/jsonl",
+        "csv" => "text/csv",
+        "html" | "htm" => "text/html",
+        "md" => "text/markdown",
+        "txt" | "rs" | "py" | "js" | "ts" | "java" | "c" | "h" | "cpp" | "go" | "toml" | "yaml" | "yml" | "xml" | "sh" => "text/plain",
+        "mp3" => "audio/mp3",
+        "wav" => "audio/wav",
+        "aiff" | "aif" => "audio/aiff",
+        "aac" => "audio/aac",
+        "ogg" => "audio/ogg",
+        "flac" => "audio/flac",
+        "mp4" => "video/mp4",
+        "mov" => "video/quicktime",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Provider neutral piece of a message, converted to each provider's wire
+/// format with `wire`. Binary data is held base64 encoded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageContent {
+    Text(String),
+    Image { mime_type: String, data: String },
+    Audio { mime_type: String, data: String },
+    File { name: String, mime_type: String, data: String },
+    /// Function the model asked to be called, with its JSON arguments
+    ToolCall { id: String, name: String, arguments: serde_json::Value },
+    /// Output of a called function, for the call with the same id
+    ToolResult { id: String, name: String, content: String },
+}
+
+impl MessageContent {
+    pub fn text(text: &str) -> Self {
+        MessageContent::Text(text.into())
+    }
+
+    pub fn image(mime_type: &str, data: &[u8]) -> Self {
+        MessageContent::Image { mime_type: mime_type.into(), data: BASE64_STANDARD.encode(data) }
+    }
+
+    pub fn audio(mime_type: &str, data: &[u8]) -> Self {
+        MessageContent::Audio { mime_type: mime_type.into(), data: BASE64_STANDARD.encode(data) }
+    }
+
+    pub fn tool_call(id: &str, name: &str, arguments: serde_json::Value) -> Self {
+        MessageContent::ToolCall { id: id.into(), name: name.into(), arguments }
+    }
+
+    pub fn tool_result(id: &str, name: &str, content: &str) -> Self {
+        MessageContent::ToolResult { id: id.into(), name: name.into(), content: content.into() }
+    }
+
+    /// Wire format for the named LLM. For GPT compatible LLMs tool calls and
+    /// results are not content parts, so the tool call entry and the whole
+    /// 'tool' message are returned instead, as `GptMessage::from_content`
+    /// places them.
+    pub fn wire(&self, llm: &str) -> Result<serde_json::Value, Box<dyn std::error::Error + Send>> {
+        match llm_name(llm) {
+            Some("gpt") => self.to_gpt(false),
+            Some("groq") | Some("mistral") => self.to_gpt(true),
+            Some("claude") => self.to_claude(),
+            Some("gemini") => Ok(self.to_gemini()),
+            _ => Err(Box::new(LlmClientError::Unsupported(format!("{llm} message content")))),
+        }
+    }
+
+    fn data_url(mime_type: &str, data: &str) -> String {
+        format!("data:{mime_type};base64,{data}")
+    }
+
+    fn unsupported(&self, llm: &str) -> Box<dyn std::error::Error + Send> {
+        let kind = match self {
+            MessageContent::Audio { .. } => "audio",
+            MessageContent::File { .. } => "file",
+            _ => "this",
+        };
+
+        Box::new(LlmClientError::Unsupported(format!("{llm} {kind} content")))
+    }
+
+    // OpenAI chat format, the compatible LLMs only take text and images
+    fn to_gpt(&self, compatible: bool) -> Result<serde_json::Value, Box<dyn std::error::Error + Send>> {
+        Ok(match self {
+            MessageContent::Text(text) =>
+                serde_json::json!({"type": "text", "text": text}),
+            MessageContent::Image { mime_type, data } =>
+                serde_json::json!({"type": "image_url", "image_url": {"url": Self::data_url(mime_type, data)}}),
+            MessageContent::Audio { .. } | MessageContent::File { .. } if compatible =>
+                return Err(self.unsupported("OpenAI compatible")),
+            MessageContent::Audio { mime_type, data } => {
+                let format = match mime_type.rsplit('/').next() {
+                    Some("mpeg") | Some("mp3") => "mp3",
+                    _ => "wav",
+                };
+
+                serde_json::json!({"type": "input_audio", "input_audio": {"data": data, "format": format}})
+            },
+            MessageContent::File { name, mime_type, data } =>
+                serde_json::json!({"type": "file", "file": {"filename": name, "file_data": Self::data_url(mime_type, data)}}),
+            MessageContent::ToolCall { id, name, arguments } =>
+                serde_json::json!({"id": id, "type": "function", "function": {"name": name, "arguments": arguments.to_string()}}),
+            MessageContent::ToolResult { id, content, .. } =>
+                serde_json::json!({"role": "tool", "tool_call_id": id, "content": content}),
+        })
+    }
+
+    fn to_claude(&self) -> Result<serde_json::Value, Box<dyn std::error::Error + Send>> {
+        Ok(match self {
+            MessageContent::Text(text) =>
+                serde_json::json!({"type": "text", "text": text}),
+            MessageContent::Image { mime_type, data } =>
+                serde_json::json!({"type": "image", "source": {"type": "base64", "media_type": mime_type, "data": data}}),
+            MessageContent::Audio { .. } =>
+                return Err(self.unsupported("claude")),
+            MessageContent::File { mime_type, data, .. } =>
+                serde_json::json!({"type": "document", "source": {"type": "base64", "media_type": mime_type, "data": data}}),
+            MessageContent::ToolCall { id, name, arguments } =>
+                serde_json::json!({"type": "tool_use", "id": id, "name": name, "input": arguments}),
+            MessageContent::ToolResult { id, content, .. } =>
+                serde_json::json!({"type": "tool_result", "tool_use_id": id, "content": content}),
+        })
+    }
+
+    // Gemini matches calls to results by name rather than id
+    fn to_gemini(&self) -> serde_json::Value {
+        match self {
+            MessageContent::Text(text) =>
+                serde_json::json!({"text": text}),
+            MessageContent::Image { mime_type, data } | MessageContent::Audio { mime_type, data } | MessageContent::File { mime_type, data, .. } =>
+                serde_json::json!({"inlineData": {"mimeType": mime_type, "data": data}}),
+            MessageContent::ToolCall { name, arguments, .. } =>
+                serde_json::json!({"functionCall": {"name": name, "args": arguments}}),
+            MessageContent::ToolResult { name, content, .. } =>
+                serde_json::json!({"functionResponse": {"name": name, "response": {"name": name, "content": content}}}),
+        }
+    }
+}
+
+impl From<&str> for MessageContent {
+    fn from(text: &str) -> Self {
+        MessageContent::text(text)
+    }
+}
+
+impl From<&Attachment> for MessageContent {
+    /// Text attachments become text, others image, audio or file by mime type
+    fn from(attachment: &Attachment) -> Self {
+        if attachment.is_text() {
+            MessageContent::Text(String::from_utf8_lossy(&attachment.data).to_string())
+        } else if attachment.mime_type.starts_with("image/") {
+            MessageContent::image(&attachment.mime_type, &attachment.data)
+        } else if attachment.mime_type.starts_with("audio/") {
+            MessageContent::audio(&attachment.mime_type, &attachment.data)
+        } else {
+            MessageContent::File { name: attachment.name.clone(), mime_type: attachment.mime_type.clone(), data: attachment.to_base64() }
+        }
+    }
+}
+
+/// Call named LLM and model with files attached to the final prompt. Text
+/// files are inlined in the prompt, others are sent in the LLMs multimodal
+/// message format, or `LlmClientError::UnsupportedAttachment` if it has none.
+/// Replies are retried as for `call_llm_model`.
+#[allow(clippy::too_many_arguments)]
+pub async fn call_llm_model_with_attachments(llm: &str, model: &str, system: &str, user: &[String], attachments: &[Attachment], temperature: f32, is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    let (text, binary): (Vec<&Attachment>, Vec<&Attachment>) = attachments.iter().partition(|a| a.is_text());
+    let mut user = user.to_vec();
+
+    if let Some(prompt) = user.last_mut() {
+        text.iter()
+            .for_each(|a| {
+                prompt.push_str(&format!("\n\n--- {} ---\n{}", a.name, String::from_utf8_lossy(&a.data)));
+            });
+    }
+
+    if binary.is_empty() {
+        return call_llm_model(llm, model, system, &user, temperature, is_json, is_chat).await;
+    }
+
+    let binary: Vec<Attachment> = binary.into_iter().cloned().collect();
+    let (system, user) = apply_prompt_filters(system, &user, is_chat)?;
+
+    call_retried(llm, model, &system, &user, temperature, is_json, is_chat, &Extra::Attachments(&binary)).await
+}
+
+// Estimated prompt, with audio of known length, plus any configured
+// max_tokens must fit the context window, otherwise the provider would
+// only reject it
+fn context_checked(llm: &str, model: &str, system: &str, user: &[String], attachments: &[Attachment]) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let audio: usize = attachments.iter()
+        .filter(|a| a.mime_type.starts_with("audio/"))
+        .filter_map(|a| a.duration())
+        .map(estimate_audio_tokens)
+        .sum();
+
+    check_context(model, estimate_tokens(system) + estimate_tokens_all(user) + audio, provider_defaults(llm).max_tokens)
+}
+
+/// Run an LLM call until it completes or the token is cancelled. On
+/// cancellation the request is dropped, closing its connection, and
+/// `LlmClientError::Cancelled` returned.
+pub async fn cancellable<T>(cancel: Option<&CancellationToken>, call: impl std::future::Future<Output = Result<T, Box<dyn std::error::Error + Send>>>) -> Result<T, Box<dyn std::error::Error + Send>> {
+    match cancel {
+        None => call.await,
+        Some(cancel) => {
+            tokio::select! {
+                biased;
+                _ = cancel.cancelled() => Err(Box::new(LlmClientError::Cancelled)),
+                res = call => res,
+            }
+        }
+    }
+}
+
+/// Call named LLM and model, abandoning the call if cancelled
+#[allow(clippy::too_many_arguments)]
+pub async fn call_llm_model_cancellable(llm: &str, model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool, cancel: Option<&CancellationToken>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    cancellable(cancel, call_llm_model(llm, model, system, user, temperature, is_json, is_chat)).await
+}
+
+/// Call named LLM and model with functions, abandoning the call if cancelled
+#[allow(clippy::too_many_arguments)]
+pub async fn call_llm_model_function_cancellable(llm: &str, model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool, function: &[&str], cancel: Option<&CancellationToken>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    cancellable(cancel, call_llm_model_function(llm, model, system, user, temperature, is_json, is_chat, function)).await
+}
+
+/// Call named LLM and model with attachments, abandoning the call if cancelled
+#[allow(clippy::too_many_arguments)]
+pub async fn call_llm_model_with_attachments_cancellable(llm: &str, model: &str, system: &str, user: &[String], attachments: &[Attachment], temperature: f32, is_json: bool, is_chat: bool, cancel: Option<&CancellationToken>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    cancellable(cancel, call_llm_model_with_attachments(llm, model, system, user, attachments, temperature, is_json, is_chat)).await
+}
+
 /// Call named LLM and model to call functions
 pub async fn call_function_llm_model(llm: &str, model: &str, user: &[String], function: &[&str]) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
     call_llm_model_function(llm, model, "", user, 0.2, false, false, function).await
@@ -187,15 +759,15 @@ pub async fn call_function_llm_model(llm: &str, model: &str, user: &[String], fu
 
 /// Call named LLM and default model to call functions
 pub async fn call_function_llm(llm: &str, user: &[String], function: &[&str]) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-    let model = get_model(llm);
+    let model = get_model(llm)?;
 
     call_function_llm_model(llm, &model, user, function).await
 }
 
 /// Call default LLM and model to call functions
 pub async fn call_function(user: &[String], function: &[&str]) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-    let llm: &str = &std::env::var("LLM_TO_USE").map_err(|_| "groq".to_string()).unwrap();
-    let model = get_model(llm);
+    let llm: &str = &env_var("LLM_TO_USE").unwrap_or("groq".into());
+    let model = get_model(llm)?;
 
     call_function_llm_model(llm, &model, user, function).await
 }
@@ -204,81 +776,444 @@ pub async fn call_function(user: &[String], function: &[&str]) -> Result<LlmRetu
 #[allow(clippy::too_many_arguments)]
 pub async fn call_llm_model_function(llm: &str, model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool, function: &[&str]) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
 //println!("{:?}", function);
-    let function: Option<Vec<Function>> = get_function_json(llm, function);
+    let (system, user) = apply_prompt_filters(system, user, is_chat)?;
 
-    match llm {
-        "google" | "gemini" => {
-            GeminiCompletion::call_model_function(model, system, user, temperature, is_json, is_chat, function).await
-        },
-        "openai" | "gpt" => {
-            GptCompletion::call_model_function(model, system, user, temperature, is_json, is_chat, function).await
-        },
-        "mistral" => {
-            MistralCompletion::call_model_function(model, system, user, temperature, is_json, is_chat, function).await
-        },
-        "anthropic" | "claude" => {
-            ClaudeCompletion::call_model_function(model, system, user, temperature, is_json, is_chat, function).await
+    call_retried(llm, model, &system, &user, temperature, is_json, is_chat, &Extra::Functions(Some(function))).await
+}
+
+/// Call default named LLM with common parameters supplied. If JSON is
+/// asked for and the reply does not parse, the LLM is asked once to fix it.
+pub async fn call_llm_model(llm: &str, model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    call_llm_model_cached(llm, model, system, user, temperature, is_json, is_chat, |_| true).await
+}
+
+// As call_llm_model, only caching replies `accept` passes
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn call_llm_model_cached(llm: &str, model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool, accept: impl Fn(&LlmReturn) -> bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    let (system, user) = apply_prompt_filters(system, user, is_chat)?;
+    let (system, user) = (system.as_str(), user.as_slice());
+    // Tenants' calls are never cached, so one is never given another's
+    // reply and every call counts against its quotas
+    let cache = response_cache()
+        .filter(|_| current_tenant().is_none())
+        .map(|cache| (cache, cache_key(llm, model, system, user, temperature, is_json, is_chat)));
+
+    if let Some((ref cache, ref key)) = cache {
+        if let Ok(Some(res)) = cache.get(key).await {
+            return Ok(res);
+        }
+    }
+
+    let res = call_retried(llm, model, system, user, temperature, is_json, is_chat, &Extra::Functions(None)).await?;
+
+    if let Some((cache, key)) = cache {
+        if !res.llm_type.is_error() && accept(&res) {
+            let _ = cache.put(&key, &res).await;
+        }
+    }
+
+    Ok(res)
+}
+
+// What a call sends besides the prompts
+enum Extra<'a> {
+    /// Function definitions, made into tools only once the LLM is routed,
+    /// as each provider has its own tool format
+    Functions(Option<&'a [&'a str]>),
+    /// Binary attachments, sent in the LLM's multimodal message format
+    Attachments(&'a [Attachment]),
+}
+
+// One call, followed by a retry if JSON was wanted and the reply does not
+// parse, or a language is required and the reply is in another
+#[allow(clippy::too_many_arguments)]
+async fn call_retried(llm: &str, model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool, extra: &Extra<'_>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    let res = call_once(llm, model, system, user, temperature, is_json, is_chat, extra).await?;
+    let res = json_repaired(is_json, res);
+    let res = match json_retry_prompts(user, is_json, is_chat, &res) {
+        None => res,
+        Some(user) => {
+            let retry = call_once(llm, model, system, &user, temperature, is_json, is_chat, extra).await?;
+
+            json_retried(res, retry)?
+        }
+    };
+    let res = match language_retry_prompts(user, is_json, is_chat, &res) {
+        None => res,
+        Some(user) => {
+            let retry = call_once(llm, model, system, &user, temperature, is_json, is_chat, extra).await?;
+
+            retried(&res, retry)
+        }
+    };
+
+    Ok(res)
+}
+
+// Single call to the LLM the circuit breaker routes to, checked against
+// its capabilities, quotas and the context window first, then recorded in
+// telemetry, usage, quotas and the circuit breaker
+#[allow(clippy::too_many_arguments)]
+async fn call_once(llm: &str, model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool, extra: &Extra<'_>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    let (llm, model) = circuit::route(llm, model)?;
+    let (llm, model) = (llm.as_str(), model.as_str());
+    let attachments = match extra {
+        Extra::Attachments(attachments) => attachments,
+        Extra::Functions(_) => &[][..],
+    };
+    capabilities_checked(llm, model, is_json, extra)?;
+    quotas::check(llm)?;
+    context_checked(llm, model, system, user, attachments)?;
+    let start = std::time::Instant::now();
+    let res = dispatch(llm, model, system, user, temperature, is_json, is_chat, extra).await;
+
+    record_call(llm, model, &res, start.elapsed());
+    record_usage(llm, model, &res);
+    quotas::record(llm, model, &res);
+    circuit::record(llm, &res);
+
+    apply_post_processors(unknown_model_checked(llm, model, res).await?)
+}
+
+// Routed model must be able to use the tools, JSON mode or images asked for
+fn capabilities_checked(llm: &str, model: &str, is_json: bool, extra: &Extra<'_>) -> Result<(), Box<dyn std::error::Error + Send>> {
+    match extra {
+        // Only OpenAI and Groq act on is_json, elsewhere it is a prompt hint
+        Extra::Functions(Some(function)) =>
+            check_capabilities(model, !function.is_empty(), is_json && matches!(llm, "openai" | "gpt" | "groq"), false),
+        Extra::Functions(None) => Ok(()),
+        Extra::Attachments(attachments) =>
+            check_capabilities(model, false, false, attachments.iter().any(|a| a.mime_type.starts_with("image/"))),
+    }
+}
+
+// Provider call for the named LLM, Groq unless another is named
+#[allow(clippy::too_many_arguments)]
+async fn dispatch(llm: &str, model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool, extra: &Extra<'_>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    match extra {
+        Extra::Attachments(attachments) => match llm {
+            "google" | "gemini" => call_gemini_model_attachments(model, system, user, attachments, temperature, is_chat).await,
+            "openai" | "gpt" => call_gpt_model_attachments(model, system, user, attachments, temperature, is_json, is_chat).await,
+            "mistral" => call_mistral_model_attachments(model, system, user, attachments, temperature, is_json, is_chat).await,
+            "anthropic" | "claude" => call_claude_model_attachments(model, system, user, attachments, temperature, is_json, is_chat).await,
+            "groq" => call_groq_model_attachments(model, system, user, attachments, temperature, is_json, is_chat).await,
+            _ => Err(Box::new(LlmClientError::UnsupportedAttachment { llm: llm.into(), mime_type: attachments[0].mime_type.clone() })),
         },
-        _ => {
-            GroqCompletion::call_model_function(model, system, user, temperature, is_json, is_chat, function).await
+        Extra::Functions(function) => {
+            let function: Option<Vec<Function>> = function.and_then(|function| get_function_json(llm, function));
+
+            match llm {
+                "google" | "gemini" => {
+                    GeminiCompletion::call_model_function(model, system, user, temperature, is_json, is_chat, function).await
+                },
+                "openai" | "gpt" => {
+                    GptCompletion::call_model_function(model, system, user, temperature, is_json, is_chat, function).await
+                },
+                "mistral" => {
+                    MistralCompletion::call_model_function(model, system, user, temperature, is_json, is_chat, function).await
+                },
+                "anthropic" | "claude" => {
+                    ClaudeCompletion::call_model_function(model, system, user, temperature, is_json, is_chat, function).await
+                },
+                "replicate" => match function {
+                    Some(_) => Err(Box::new(LlmClientError::Unsupported("replicate functions".into()))),
+                    None => call_replicate_model(model, system, user, temperature, is_json, is_chat).await,
+                },
+                _ => {
+                    GroqCompletion::call_model_function(model, system, user, temperature, is_json, is_chat, function).await
+                },
+            }
         },
     }
 }
 
-/// Call default named LLM with common parameters supplied
-pub async fn call_llm_model(llm: &str, model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+// Prompts asking the LLM to fix its reply, if JSON was wanted but the
+// (non error, non tool) reply does not parse
+fn json_retry_prompts(user: &[String], is_json: bool, is_chat: bool, res: &LlmReturn) -> Option<Vec<String>> {
+    if !is_json || !is_text(res) {
+        return None;
+    }
+
+    let error = serde_json::from_str::<serde_json::Value>(&res.text).err()?;
+
+    Some(retry_prompts(user, is_chat, res, &format!("That is not valid JSON ({error}). Fix this JSON and reply with only the corrected JSON.")))
+}
+
+// Prompts asking the LLM to answer again in the required language, if one
+// is set and the (non error, non tool, non JSON) reply is in another
+fn language_retry_prompts(user: &[String], is_json: bool, is_chat: bool, res: &LlmReturn) -> Option<Vec<String>> {
+    if is_json || !is_text(res) {
+        return None;
+    }
+
+    let language = wrong_language(&res.text)?;
+
+    Some(retry_prompts(user, is_chat, res, &format!("Answer that again in {language}, using only {language}.")))
+}
+
+fn is_text(res: &LlmReturn) -> bool {
+    matches!(res.llm_type, LlmType::GEMINI | LlmType::GPT | LlmType::CLAUDE | LlmType::MISTRAL | LlmType::GROQ | LlmType::REPLICATE)
+}
+
+// The reply followed by the fix, as the next turn in a chat
+fn retry_prompts(user: &[String], is_chat: bool, res: &LlmReturn, fix: &str) -> Vec<String> {
+    let mut user = user.to_vec();
+
+    if is_chat {
+        user.push(res.text.clone());
+        user.push(fix.to_string());
+    } else {
+        user.push(format!("{}\n\n{fix}", res.text));
+    }
+
+    user
+}
+
+// Retried reply with the usage and time of both calls
+fn retried(first: &LlmReturn, mut retry: LlmReturn) -> LlmReturn {
+    retry.usage = (first.usage.0 + retry.usage.0, first.usage.1 + retry.usage.1, first.usage.2 + retry.usage.2);
+    retry.timing = Timing::new(first.timing.total + retry.timing.total, retry.usage.1);
+
+    retry
+}
+
+// Reply with JSON repaired, if enabled and needed
+fn json_repaired(is_json: bool, mut res: LlmReturn) -> LlmReturn {
+    if is_json && json_repair_enabled() && serde_json::from_str::<serde_json::Value>(&res.text).is_err() {
+        let repaired = repair_json(&res.text);
+
+        if serde_json::from_str::<serde_json::Value>(&repaired).is_ok() {
+            res.text = repaired;
+        }
+    }
+
+    res
+}
+
+// Retried reply with usage of both calls, or error if still not JSON
+fn json_retried(first: LlmReturn, retry: LlmReturn) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    let retry = retried(&first, json_repaired(true, retry));
+
+    match serde_json::from_str::<serde_json::Value>(&retry.text) {
+        Ok(_) => Ok(retry),
+        Err(e) => Err(Box::new(LlmClientError::InvalidJson { text: retry.text, error: e.to_string() })),
+    }
+}
+
+/// List models available from named LLM provider
+pub async fn list_models(llm: &str) -> Result<Vec<ModelInfo>, Box<dyn std::error::Error + Send>> {
     match llm {
-        "google" | "gemini" => {
-            GeminiCompletion::call_model(model, system, user, temperature, is_json, is_chat).await
-        },
-        "openai" | "gpt" => {
-            GptCompletion::call_model(model, system, user, temperature, is_json, is_chat).await
-        },
-        "mistral" => {
-            MistralCompletion::call_model(model, system, user, temperature, is_json, is_chat).await
-        },
-        "anthropic" | "claude" => {
-            ClaudeCompletion::call_model(model, system, user, temperature, is_json, is_chat).await
-        },
-        _ => {
-            GroqCompletion::call_model(model, system, user, temperature, is_json, is_chat).await
+        "google" | "gemini" => list_gemini_models().await,
+        "openai" | "gpt" => list_gpt_models().await,
+        "mistral" => list_mistral_models().await,
+        "anthropic" | "claude" => list_claude_models().await,
+        "replicate" => Err(Box::new(LlmClientError::Unsupported("replicate model listing".into()))),
+        _ => list_groq_models().await,
+    }
+}
+
+/// Outcome of a provider health check
+#[derive(Debug, Clone, PartialEq)]
+pub struct Health {
+    pub llm: String,
+    /// Round trip in seconds
+    pub latency: f64,
+    pub authenticated: bool,
+    pub error: Option<String>,
+}
+
+impl Health {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+impl std::fmt::Display for Health {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.error {
+            None => write!(f, "{}: ok in {:.3}s", self.llm, self.latency),
+            Some(_) if !self.authenticated => write!(f, "{}: authentication failed in {:.3}s", self.llm, self.latency),
+            Some(e) => write!(f, "{}: failed in {:.3}s: {e}", self.llm, self.latency),
+        }
+    }
+}
+
+/// Check that the named LLM provider is reachable and accepts our
+/// credentials, by making a cheap model listing request
+pub async fn ping(llm: &str) -> Health {
+    let start = std::time::Instant::now();
+    let res = list_models(llm).await;
+    let latency = start.elapsed().as_secs() as f64 + start.elapsed().subsec_millis() as f64 / 1000.0;
+    let llm = llm_name(llm).unwrap_or(llm).to_string();
+
+    match res {
+        Ok(_) => Health { llm, latency, authenticated: true, error: None },
+        Err(e) => {
+            let error = e.to_string();
+
+            Health { llm, latency, authenticated: !is_auth_error(&error), error: Some(error) }
+        }
+    }
+}
+
+// An error reply saying the model does not exist becomes
+// LlmClientError::UnknownModel, suggesting models the LLM does have
+async fn unknown_model_checked(llm: &str, model: &str, res: Result<LlmReturn, Box<dyn std::error::Error + Send>>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    match res {
+        Ok(ref ret) if ret.llm_type.is_error() && is_unknown_model_error(&ret.text, model) => {
+            let models: Vec<String> = list_models(llm).await
+                .map(|models| models.into_iter().map(|m| m.id).collect())
+                .unwrap_or_default();
+
+            Err(Box::new(LlmClientError::UnknownModel { llm: llm.into(), model: model.into(), suggestions: similar_models(model, &models) }))
         },
+        res => res,
     }
 }
 
-fn get_model(llm: &str) -> String {
-    let model =
-        match llm {
-            "google" | "gemini" => {
-                    std::env::var("GEMINI_MODEL")
-            },
-            "openai" | "gpt" => {
-                    std::env::var("GPT_MODEL")
-            },
-            "mistral" => {
-                    std::env::var("MISTRAL_MODEL")
-            },
-            "anthropic" | "claude" => {
-                    std::env::var("CLAUDE_MODEL")
-            },
-            _ => {
-                    std::env::var("GROQ_MODEL")
-            },
-        };
+// Providers report unknown models in different ways, but all name it
+fn is_unknown_model_error(error: &str, model: &str) -> bool {
+    let error = error.to_lowercase();
+
+    !model.is_empty() && error.contains(&model.to_lowercase()) &&
+        ["model_not_found", "invalid_model", "invalid model", "does not exist", "not_found", "not found"]
+            .iter()
+            .any(|s| error.contains(s))
+}
+
+/// Up to three models named most like `model`, closest first
+pub fn similar_models(model: &str, models: &[String]) -> Vec<String> {
+    let limit = (model.chars().count() / 3).max(3);
+    let mut close: Vec<(usize, &String)> = models.iter()
+        .map(|m| (edit_distance(model, m), m))
+        .filter(|(distance, _)| *distance <= limit)
+        .collect();
+
+    close.sort();
+    close.into_iter().take(3).map(|(_, m)| m.clone()).collect()
+}
+
+// Levenshtein distance in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+
+        row[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = previous + usize::from(ca != *cb);
+
+            previous = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(previous + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+// Providers report bad or missing keys in different ways
+fn is_auth_error(error: &str) -> bool {
+    let error = error.to_lowercase();
+
+    ["401", "403", "unauthorized", "unauthenticated", "authentication", "invalid_api_key", "invalid api key", "permission_denied"]
+        .iter()
+        .any(|s| error.contains(s))
+}
 
-    model.expect("{llm} MODEL not found in enviroment variables")
+/// Normalise LLM name, accepting vendor aliases. None if not supported.
+pub fn llm_name(llm: &str) -> Option<&'static str> {
+    match llm {
+        "google" | "gemini" => Some("gemini"),
+        "openai" | "gpt" => Some("gpt"),
+        "anthropic" | "claude" => Some("claude"),
+        "mistral" => Some("mistral"),
+        "groq" => Some("groq"),
+        "replicate" => Some("replicate"),
+        _ => None
+    }
+}
+
+/// Default model for named LLM from environment
+pub fn get_model(llm: &str) -> Result<String, Box<dyn std::error::Error + Send>> {
+    match llm {
+        "google" | "gemini" => required_env("GEMINI_MODEL"),
+        "openai" | "gpt" => required_env("GPT_MODEL"),
+        "mistral" => required_env("MISTRAL_MODEL"),
+        "anthropic" | "claude" => required_env("CLAUDE_MODEL"),
+        "replicate" => required_env("REPLICATE_MODEL"),
+        _ => required_env("GROQ_MODEL"),
+    }
 }
 
 /// Call default named LLM with common parameters supplied
 pub async fn call_llm(llm: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-    let model = get_model(llm);
+    let model = get_model(llm)?;
 
     call_llm_model(llm, &model, system, user, temperature, is_json, is_chat).await
 }
 
+/// One of a set of calls for `call_all`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptSpec {
+    pub llm: String,
+    pub model: String,
+    pub system: String,
+    pub user: Vec<String>,
+    pub temperature: f32,
+    pub is_json: bool,
+    pub is_chat: bool,
+}
+
+impl PromptSpec {
+    /// Single prompt at the provider's default temperature
+    pub fn new(llm: &str, model: &str, prompt: &str) -> Self {
+        PromptSpec {
+            llm: llm.into(),
+            model: model.into(),
+            system: String::new(),
+            user: vec![prompt.into()],
+            temperature: provider_defaults(llm).temperature(),
+            is_json: false,
+            is_chat: false,
+        }
+    }
+
+    pub fn with_system(mut self, system: &str) -> Self {
+        self.system = system.into();
+        self
+    }
+
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn with_json(mut self, is_json: bool) -> Self {
+        self.is_json = is_json;
+        self
+    }
+}
+
+/// Make the calls, which may be to different LLMs and models, up to
+/// concurrency at a time, returning each one's result in the order given.
+/// Errors are `LlmClientError`s, others being `LlmClientError::Failed`.
+pub async fn call_all(prompts: &[PromptSpec], concurrency: usize) -> Vec<Result<LlmReturn, LlmClientError>> {
+    futures::stream::iter(prompts.iter())
+        .map(|p| async move {
+            call_llm_model(&p.llm, &p.model, &p.system, &p.user, p.temperature, p.is_json, p.is_chat).await
+                .map_err(LlmClientError::from_boxed)
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await
+}
+
 /// Call default (see LLM_TO_USE env var) LLM with common parameters supplied
 pub async fn call(system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-    let llm: &str = &std::env::var("LLM_TO_USE").map_err(|_| "groq".to_string()).unwrap();
+    let llm: &str = &env_var("LLM_TO_USE").unwrap_or("groq".into());
 
     call_llm(llm, system, user, temperature, is_json, is_chat).await
 }
@@ -451,28 +1386,627 @@ pub async fn chat_call_json_temperature_llm_model(llm: &str, model: &str, system
     call_llm_model(llm, model, system, user, temperature, true, true).await
 }
 
+/// HTTP timeouts for LLM calls
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Timeouts {
+    /// Establishing a connection
+    pub connect: std::time::Duration,
+    /// Whole request including reading the response, None for no limit
+    /// e.g. for long streamed responses
+    pub request: Option<std::time::Duration>,
+}
+
+impl Timeouts {
+    pub fn new(connect: std::time::Duration, request: Option<std::time::Duration>) -> Self {
+        Timeouts { connect, request }
+    }
+
+    /// Defaults, overridden in seconds by LLM_CONNECT_TIMEOUT and
+    /// LLM_REQUEST_TIMEOUT (0 for no limit)
+    pub fn from_env() -> Self {
+        let secs = |name: &str| env_var(name).and_then(|s| s.trim().parse::<u64>().ok());
+        let default = Self::default();
+
+        Timeouts {
+            connect: secs("LLM_CONNECT_TIMEOUT").map(std::time::Duration::from_secs).unwrap_or(default.connect),
+            request: match secs("LLM_REQUEST_TIMEOUT") {
+                Some(0) => None,
+                Some(s) => Some(std::time::Duration::from_secs(s)),
+                None => default.request,
+            },
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts { connect: std::time::Duration::from_secs(10), request: Some(std::time::Duration::from_secs(120)) }
+    }
+}
+
+tokio::task_local! {
+    static TIMEOUTS: Timeouts;
+}
+
+/// Run LLM calls with timeouts other than the defaults e.g.
+/// `with_timeouts(Timeouts::new(connect, None), call_llm(...)).await`
+pub async fn with_timeouts<F: std::future::Future>(timeouts: Timeouts, call: F) -> F::Output {
+    TIMEOUTS.scope(timeouts, call).await
+}
+
+/// Timeouts in effect for the current call
+pub fn current_timeouts() -> Timeouts {
+    TIMEOUTS.try_with(|t| *t).unwrap_or_else(|_| Timeouts::from_env())
+}
+
+tokio::task_local! {
+    static RESPONSE_SCHEMA: serde_json::Value;
+}
+
+/// Run LLM calls asking for JSON with the reply held to a JSON schema by
+/// providers that support it (GPT and Gemini), e.g.
+/// `with_response_schema(schema, call_llm(...)).await`. Other providers
+/// are only asked for JSON.
+pub async fn with_response_schema<F: std::future::Future>(schema: serde_json::Value, call: F) -> F::Output {
+    RESPONSE_SCHEMA.scope(schema, call).await
+}
+
+/// Schema replies must match in the current call, if any
+pub fn current_response_schema() -> Option<serde_json::Value> {
+    RESPONSE_SCHEMA.try_with(|s| s.clone()).ok()
+}
+
+/// Connection pool and keep-alive settings for the HTTP clients, worth
+/// tuning for services making many calls to the same providers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoolOptions {
+    /// Idle connections kept per host
+    pub max_idle_per_host: usize,
+    /// How long an idle connection is kept, None for ever
+    pub idle_timeout: Option<std::time::Duration>,
+    /// Interval between HTTP/2 pings, None for none
+    pub http2_keep_alive: Option<std::time::Duration>,
+    /// TCP keepalive interval, None for the OS default
+    pub tcp_keepalive: Option<std::time::Duration>,
+}
+
+impl PoolOptions {
+    /// Defaults, overridden by LLM_POOL_MAX_IDLE and, in seconds (0 for
+    /// none), LLM_POOL_IDLE_TIMEOUT, LLM_HTTP2_KEEP_ALIVE and LLM_TCP_KEEPALIVE
+    pub fn from_env() -> Self {
+        let number = |name: &str| env_var(name).and_then(|s| s.trim().parse::<u64>().ok());
+        let secs = |name: &str, default: Option<std::time::Duration>| match number(name) {
+            Some(0) => None,
+            Some(s) => Some(std::time::Duration::from_secs(s)),
+            None => default,
+        };
+        let default = Self::default();
+
+        PoolOptions {
+            max_idle_per_host: number("LLM_POOL_MAX_IDLE").map(|n| n as usize).unwrap_or(default.max_idle_per_host),
+            idle_timeout: secs("LLM_POOL_IDLE_TIMEOUT", default.idle_timeout),
+            http2_keep_alive: secs("LLM_HTTP2_KEEP_ALIVE", default.http2_keep_alive),
+            tcp_keepalive: secs("LLM_TCP_KEEPALIVE", default.tcp_keepalive),
+        }
+    }
+
+    fn apply(&self, mut builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        builder = builder
+            .pool_max_idle_per_host(self.max_idle_per_host)
+            .pool_idle_timeout(self.idle_timeout)
+            .tcp_keepalive(self.tcp_keepalive);
+
+        if let Some(interval) = self.http2_keep_alive {
+            builder = builder
+                .http2_keep_alive_interval(interval)
+                .http2_keep_alive_while_idle(true);
+        }
+
+        builder
+    }
+}
+
+impl Default for PoolOptions {
+    /// As reqwest, plus TCP keepalive so dead connections are noticed
+    fn default() -> Self {
+        PoolOptions {
+            max_idle_per_host: usize::MAX,
+            idle_timeout: Some(std::time::Duration::from_secs(90)),
+            http2_keep_alive: None,
+            tcp_keepalive: Some(std::time::Duration::from_secs(60)),
+        }
+    }
+}
+
+static POOL_OPTIONS: std::sync::RwLock<Option<PoolOptions>> = std::sync::RwLock::new(None);
+
+/// Use these pool settings rather than those from the environment,
+/// dropping clients built with the old ones
+pub fn set_pool_options(options: PoolOptions) {
+    *POOL_OPTIONS.write().unwrap() = Some(options);
+    crate::providers::reset_providers();
+}
+
+/// Pool settings for new clients
+pub fn pool_options() -> PoolOptions {
+    POOL_OPTIONS.read().unwrap().unwrap_or_else(PoolOptions::from_env)
+}
+
+static USER_AGENT: std::sync::RwLock<Option<String>> = std::sync::RwLock::new(None);
+
+/// Identify the application to providers e.g. "myapp/1.2", in place of
+/// LLM_USER_AGENT
+pub fn set_user_agent(app: &str) {
+    *USER_AGENT.write().unwrap() = Some(app.to_string());
+    crate::providers::reset_providers();
+}
+
+/// User-Agent sent with every request: the application, if set by
+/// `set_user_agent` or LLM_USER_AGENT, then this crate and its version
+pub fn user_agent() -> String {
+    let crate_agent = concat!("llmclient/", env!("CARGO_PKG_VERSION"));
+    let app = USER_AGENT.read().unwrap().clone()
+        .or_else(|| env_var("LLM_USER_AGENT"))
+        .filter(|app| !app.trim().is_empty());
+
+    match app {
+        Some(app) => format!("{} {crate_agent}", app.trim()),
+        None => crate_agent.to_string(),
+    }
+}
+
+/// Random key identifying one logical request across all its attempts
+pub fn idempotency_key() -> String {
+    use sha2::{Digest, Sha256};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let hash = Sha256::new()
+        .chain_update(now.to_le_bytes())
+        .chain_update(std::process::id().to_le_bytes())
+        .chain_update(COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes())
+        .finalize();
+    let hex: String = hash[..16].iter().map(|b| format!("{b:02x}")).collect();
+
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Most retries LLM_RETRIES may ask for, and the longest backoff between them
+const MAX_RETRIES: u32 = 5;
+const MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(4);
+
+/// Send a request, retrying up to LLM_RETRIES (default 2, at most 5) more
+/// times if it could not connect, so was never received. A request that
+/// timed out may have been, and chat APIs do not honour idempotency keys, so
+/// it is not retried: that could generate and charge twice. Every attempt
+/// carries the same Idempotency-Key, for providers that do honour it.
+/// Requests with streamed bodies cannot be copied so are sent once.
+pub async fn send_idempotent(request: reqwest::RequestBuilder) -> Result<reqwest::Response, Box<dyn std::error::Error + Send>> {
+    let retries: u32 = env_var("LLM_RETRIES").and_then(|s| s.trim().parse().ok()).unwrap_or(2);
+
+    send_with_key(request, &idempotency_key(), retries.min(MAX_RETRIES)).await
+}
+
+// Backoff doubles from 250ms, up to MAX_BACKOFF
+async fn send_with_key(request: reqwest::RequestBuilder, key: &str, retries: u32) -> Result<reqwest::Response, Box<dyn std::error::Error + Send>> {
+    let request = request.header("Idempotency-Key", key);
+    let mut attempt = 0;
+
+    loop {
+        let Some(this) = request.try_clone().filter(|_| attempt < retries) else {
+            return request.send().await
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) });
+        };
+
+        match this.send().await {
+            Err(e) if e.is_connect() => {
+                let backoff = std::time::Duration::from_millis(250 << attempt.min(16));
+
+                tokio::time::sleep(backoff.min(MAX_BACKOFF)).await;
+                attempt += 1;
+            },
+            res => return res.map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) }),
+        }
+    }
+}
+
 /// Common HTTP client with header setup
 pub async fn get_client(mut headers: HeaderMap) -> Result<Client, Box<dyn std::error::Error + Send>> {
     // We would like json
-    headers.insert(
-        "Content-Type",
-        HeaderValue::from_str("appication/json; charset=utf-8")
-            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?,
-    );
-    headers.insert(
-        "Accept",
-        HeaderValue::from_str("appication/json")
-            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?,
-    );
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json; charset=utf-8"));
+    headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+
+    let timeouts = current_timeouts();
 
     // Create client
-    let client: Client = Client::builder()
-        .user_agent("TargetR")
-        .timeout(std::time::Duration::new(120, 0))
-        //.gzip(true)
+    let mut builder = Client::builder()
+        .user_agent(user_agent())
+        .connect_timeout(timeouts.connect);
+
+    if let Some(request) = timeouts.request {
+        builder = builder.timeout(request);
+    }
+
+    builder = pool_options().apply(builder);
+
+    // Responses may be compressed, Accept-Encoding is set to match
+    let client: Client = builder
+        .gzip(true)
+        .brotli(true)
         .default_headers(headers)
         .build()
         .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
 
     Ok(client)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    fn test_context_checked_audio() {
+        // Five minutes of audio is 9,600 tokens, more than gpt-4's 8,192
+        let audio = Attachment::from_uri("talk.flac", "audio/flac", "gs://bucket/talk.flac")
+            .with_offsets(std::time::Duration::ZERO, std::time::Duration::from_secs(300));
+        let prompt = ["Summarise this".to_string()];
+
+        assert!(context_checked("gpt", "gpt-4", "", &prompt, &[]).is_ok());
+
+        let err = context_checked("gpt", "gpt-4", "", &prompt, &[audio]).unwrap_err();
+
+        assert!(matches!(err.downcast_ref::<LlmClientError>(), Some(LlmClientError::ContextOverflow { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_cancellable() {
+        let cancel = CancellationToken::new();
+        let slow = async {
+            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+
+            Ok(())
+        };
+
+        cancel.cancel();
+
+        let err = cancellable(Some(&cancel), slow).await.unwrap_err();
+
+        assert!(matches!(err.downcast_ref::<LlmClientError>(), Some(LlmClientError::Cancelled)));
+        assert!(cancellable(None, async { Ok(1) }).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_with_timeouts() {
+        let timeouts = Timeouts::new(std::time::Duration::from_secs(3), None);
+
+        assert_eq!(with_timeouts(timeouts, async { current_timeouts() }).await, timeouts);
+    }
+
+    #[test]
+    fn test_user_agent() {
+        let version = env!("CARGO_PKG_VERSION");
+
+        set_user_agent("myapp/1.2");
+        assert_eq!(user_agent(), format!("myapp/1.2 llmclient/{version}"));
+        set_user_agent("");
+        assert_eq!(user_agent(), format!("llmclient/{version}"));
+        *USER_AGENT.write().unwrap() = None;
+    }
+
+    #[tokio::test]
+    async fn test_send_idempotent() {
+        let server = wiremock::MockServer::start().await;
+
+        // The first attempt times out, and may have been received, so is not retried
+        wiremock::Mock::given(wiremock::matchers::method("POST"))
+            .respond_with(wiremock::ResponseTemplate::new(200).set_delay(std::time::Duration::from_millis(500)))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        wiremock::Mock::given(wiremock::matchers::method("POST"))
+            .respond_with(wiremock::ResponseTemplate::new(200).set_body_string("{}"))
+            .mount(&server)
+            .await;
+
+        let client = Client::builder().timeout(std::time::Duration::from_millis(100)).build().unwrap();
+
+        assert!(send_idempotent(client.post(server.uri()).body("{}")).await.is_err());
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+
+        let res = send_idempotent(client.post(server.uri()).body("{}")).await.unwrap();
+        let requests = server.received_requests().await.unwrap();
+        let keys: Vec<_> = requests.iter().map(|r| r.headers.get("idempotency-key").unwrap()).collect();
+
+        assert_eq!(res.status(), 200);
+        assert_eq!(keys.len(), 2);
+        assert_ne!(keys[0], keys[1]);
+        assert_eq!(keys[0].len(), 36);
+        assert_ne!(idempotency_key(), idempotency_key());
+    }
+
+    #[tokio::test]
+    async fn test_send_idempotent_retries_refused() {
+        // Nothing listens on the port until just after the first attempt
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let client = Client::new();
+        let (res, server) = tokio::join!(
+            send_with_key(client.post(format!("http://{addr}")).body("{}"), "key-1", 3),
+            async {
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+                let server = wiremock::MockServer::builder().listener(std::net::TcpListener::bind(addr).unwrap()).start().await;
+
+                wiremock::Mock::given(wiremock::matchers::method("POST"))
+                    .respond_with(wiremock::ResponseTemplate::new(200).set_body_string("{}"))
+                    .mount(&server)
+                    .await;
+
+                server
+            }
+        );
+        let requests = server.received_requests().await.unwrap();
+
+        assert_eq!(res.unwrap().status(), 200);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].headers.get("idempotency-key").unwrap(), "key-1");
+    }
+
+    #[test]
+    #[serial]
+    fn test_pool_options() {
+        std::env::set_var("LLM_POOL_MAX_IDLE", "32");
+        std::env::set_var("LLM_POOL_IDLE_TIMEOUT", "0");
+        std::env::set_var("LLM_HTTP2_KEEP_ALIVE", "20");
+
+        let options = PoolOptions::from_env();
+
+        std::env::remove_var("LLM_POOL_MAX_IDLE");
+        std::env::remove_var("LLM_POOL_IDLE_TIMEOUT");
+        std::env::remove_var("LLM_HTTP2_KEEP_ALIVE");
+
+        assert_eq!(options, PoolOptions { max_idle_per_host: 32, idle_timeout: None, http2_keep_alive: Some(std::time::Duration::from_secs(20)), ..PoolOptions::default() });
+        assert!(options.apply(Client::builder()).build().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_client_headers() {
+        let server = wiremock::MockServer::start().await;
+
+        wiremock::Mock::given(wiremock::matchers::method("POST"))
+            .respond_with(wiremock::ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut headers = HeaderMap::new();
+
+        headers.insert("x-api-key", HeaderValue::from_static("test-key"));
+        get_client(headers).await.unwrap().post(server.uri()).body("{}").send().await.unwrap();
+
+        let request = &server.received_requests().await.unwrap()[0];
+        let header = |name: &str| request.headers.get(name).unwrap().to_str().unwrap().to_string();
+
+        assert_eq!(header("content-type"), "application/json; charset=utf-8");
+        assert_eq!(header("accept"), "application/json");
+        assert_eq!(header("x-api-key"), "test-key");
+        assert!(header("accept-encoding").contains("gzip"));
+        assert!(header("accept-encoding").contains("br"));
+        assert!(header("user-agent").contains("llmclient/"));
+    }
+
+    #[test]
+    fn test_message_content() {
+        let image = MessageContent::from(&Attachment::new("dot.png", "image/png", vec![1, 2, 3]));
+        let call = MessageContent::tool_call("call_1", "arithmetic", serde_json::json!({"expr": "2 + 2"}));
+
+        assert_eq!(image.wire("gpt").unwrap()["image_url"]["url"], "data:image/png;base64,AQID");
+        assert_eq!(image.wire("claude").unwrap()["source"]["media_type"], "image/png");
+        assert_eq!(image.wire("gemini").unwrap()["inlineData"]["data"], "AQID");
+        assert_eq!(call.wire("gpt").unwrap()["function"]["arguments"], "{\"expr\":\"2 + 2\"}");
+        assert_eq!(call.wire("claude").unwrap()["input"]["expr"], "2 + 2");
+        assert_eq!(MessageContent::tool_result("call_1", "arithmetic", "4").wire("groq").unwrap()["role"], "tool");
+        assert!(MessageContent::audio("audio/wav", &[0]).wire("claude").is_err());
+        assert!(MessageContent::from("Hi").wire("bard").is_err());
+    }
+
+    #[test]
+    fn test_attachment_wire() {
+        let chart = Attachment::image_url("https://example.com/chart.png?size=large");
+        let unsupported = |a: &Attachment, llm| match a.wire(llm).unwrap_err().downcast_ref::<LlmClientError>() {
+            Some(LlmClientError::UnsupportedAttachment { mime_type, .. }) => mime_type.clone(),
+            _ => String::new(),
+        };
+
+        assert_eq!(chart.mime_type, "image/png");
+        assert_eq!(chart.wire("groq").unwrap()["image_url"]["url"], "https://example.com/chart.png?size=large");
+        assert_eq!(chart.wire("claude").unwrap()["source"]["type"], "url");
+        assert_eq!(Attachment::raw("application/pdf", vec![1, 2, 3]).wire("claude").unwrap()["type"], "document");
+        assert_eq!(unsupported(&Attachment::raw("application/pdf", vec![1]), "mistral"), "application/pdf");
+        assert_eq!(unsupported(&Attachment::raw("audio/wav", vec![1]), "claude"), "audio/wav");
+    }
+
+    #[test]
+    fn test_finish_reason() {
+        assert_eq!(FinishReason::from("end_turn"), FinishReason::Stop);
+        assert_eq!(FinishReason::from("MAX_TOKENS"), FinishReason::Length);
+        assert_eq!(FinishReason::from("tool_use"), FinishReason::ToolCalls);
+        assert_eq!(FinishReason::from("SAFETY"), FinishReason::ContentFilter);
+        assert_eq!(FinishReason::from("pause_turn"), FinishReason::Other("pause_turn".into()));
+        assert!(LlmReturn::new(LlmType::CLAUDE, "Once upon".into(), "max_tokens".into(), (5, 2, 7), 1.0, None, None).is_truncated());
+    }
+
+    #[test]
+    fn test_timing() {
+        let ret = LlmReturn::new(LlmType::GPT, "Canberra".into(), FinishReason::Stop, (5, 40, 45), 2.0, None, None);
+
+        assert_eq!(ret.timing.tokens_per_sec, 20.0);
+        assert_eq!(ret.timing.generation_tokens_per_sec(), None);
+
+        let streamed = ret.timing.with_first_token(1.5);
+
+        assert_eq!(streamed.generation_tokens_per_sec(), Some(80.0));
+        assert_eq!(streamed.to_string(), "2.0000 secs, first token 1.5000 secs, 20.0 tokens/sec");
+        assert_eq!(Timing::new(0.0, 10).tokens_per_sec, 0.0);
+    }
+
+    #[test]
+    fn test_json_retry() {
+        let user = vec!["Give me JSON".to_string()];
+        let bad = LlmReturn::new(LlmType::GPT, "{\"a\": 1,}".into(), FinishReason::Stop, (10, 5, 15), 1.0, None, None);
+        let good = LlmReturn::new(LlmType::GPT, "{\"a\": 1}".into(), FinishReason::Stop, (20, 4, 24), 1.0, None, None);
+
+        assert!(json_retry_prompts(&user, true, true, &good).is_none());
+        assert!(json_retry_prompts(&user, false, true, &bad).is_none());
+
+        let retry = json_retry_prompts(&user, true, true, &bad).unwrap();
+
+        assert_eq!(retry.len(), 3);
+        assert!(retry[2].starts_with("That is not valid JSON"));
+
+        let res = json_retried(bad.clone(), good).unwrap();
+
+        assert_eq!(res.usage, (30, 9, 39));
+
+        let err = json_retried(bad.clone(), bad).unwrap_err();
+
+        assert!(matches!(err.downcast_ref::<LlmClientError>(), Some(LlmClientError::InvalidJson { .. })));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_language_retry() {
+        let server = crate::mock::mock_post_sequence("/v1/chat/completions", &[(200, "gpt/english.json"), (200, "gpt/french.json")]).await;
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+        crate::language::set_required_language(Some("fr"));
+
+        let res = call_llm_model("gpt", "gpt-4o", "", &["Quelle est la capitale de l'Australie ?".to_string()], 0.2, false, true).await;
+
+        crate::language::set_required_language(None);
+
+        let res = res.unwrap();
+
+        assert!(res.text.starts_with("La capitale"));
+        assert_eq!(res.usage, (52, 34, 86));
+
+        let requests = server.received_requests().await.unwrap();
+
+        assert!(String::from_utf8_lossy(&requests[1].body).contains("Answer that again in French"));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_attachments_json_retry() {
+        let server = crate::mock::mock_post_sequence("/v1/chat/completions", &[(200, "gpt/success.json"), (200, "gpt/sentiment.json")]).await;
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        let image = Attachment::new("receipt.png", "image/png", vec![1, 2, 3]);
+        let res = call_llm_model_with_attachments("gpt", "gpt-4o", "", &["How does the customer feel?".to_string()], &[image], 0.2, true, false).await.unwrap();
+
+        assert!(res.text.contains("\"sentiment\": \"negative\""));
+
+        let requests = server.received_requests().await.unwrap();
+        let retry = String::from_utf8_lossy(&requests[1].body);
+
+        assert!(retry.contains("That is not valid JSON"));
+        assert!(retry.contains("data:image/png;base64,AQID"));
+    }
+
+    #[test]
+    fn test_similar_models() {
+        let models: Vec<String> = ["gemini-1.5-pro-001", "gemini-1.5-pro-002", "gemini-1.5-flash-002", "text-embedding-004"].iter().map(|m| m.to_string()).collect();
+
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(similar_models("gemini-1.5-pro-02", &models), vec!["gemini-1.5-pro-002", "gemini-1.5-pro-001"]);
+        assert!(similar_models("claude-3-opus", &models).is_empty());
+        assert!(is_unknown_model_error(r#"{"error":{"message":"The model `gpt-4o-mni` does not exist or you do not have access to it.","type":"invalid_request_error","code":"model_not_found"}}"#, "gpt-4o-mni"));
+        assert!(is_unknown_model_error(r#"{"type":"error","error":{"type":"not_found_error","message":"model: claude-3-hiaku"}}"#, "claude-3-hiaku"));
+        assert!(!is_unknown_model_error(r#"{"error":{"message":"Rate limit reached for gpt-4o","code":"rate_limit_exceeded"}}"#, "gpt-4o"));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_unknown_model() {
+        let server = crate::mock::mock_post("/v1/chat/completions", 404, "gpt/model_not_found.json").await;
+
+        wiremock::Mock::given(wiremock::matchers::method("GET"))
+            .and(wiremock::matchers::path("/v1/models"))
+            .respond_with(wiremock::ResponseTemplate::new(200).set_body_string(crate::mock::fixture("gpt/models.json")))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("GPT_MODELS_URL", format!("{}/v1/models", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        let err = call_llm_model("gpt", "gpt-4o-mni", "", &["Hi".to_string()], 0.2, false, true).await.unwrap_err();
+
+        assert_eq!(err.to_string(), "Unknown model: gpt has no model gpt-4o-mni, did you mean gpt-4o-mini?");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_call_all() {
+        let gpt = crate::mock::mock_post("/v1/chat/completions", 200, "gpt/success.json").await;
+        let claude = crate::mock::mock_post("/v1/messages", 200, "claude/success.json").await;
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", gpt.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+        std::env::set_var("CLAUDE_URL", format!("{}/v1/messages", claude.uri()));
+        std::env::set_var("ANTHROPIC_API_KEY", "test-key");
+        std::env::set_var("CLAUDE_VERSION", "2023-06-01");
+
+        // No model, so the last fails before any request is sent
+        let results = call_all(&[
+            PromptSpec::new("gpt", "gpt-4o", "Hi").with_system("Be brief"),
+            PromptSpec::new("claude", "claude-3-opus-20240229", "Hi"),
+            PromptSpec::new("gpt", "", "Hi"),
+        ], 2).await;
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().llm_type, LlmType::GPT);
+        assert_eq!(results[1].as_ref().unwrap().llm_type, LlmType::CLAUDE);
+        assert!(matches!(results[2], Err(LlmClientError::Failed(ref msg)) if msg.contains("GPT_MODEL")));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_function_fallback() {
+        let claude = crate::mock::mock_post("/v1/messages", 200, "claude/tool_use.json").await;
+
+        std::env::set_var("CLAUDE_URL", format!("{}/v1/messages", claude.uri()));
+        std::env::set_var("ANTHROPIC_API_KEY", "test-key");
+        std::env::set_var("CLAUDE_VERSION", "2023-06-01");
+
+        crate::circuit::set_circuit_breaker(Some(crate::circuit::CircuitBreaker::new(1, std::time::Duration::from_secs(60)).with_fallback("claude", "claude-3-opus-20240229")));
+        crate::circuit::record("gpt", &Err(Box::new(reqwest::get("http://127.0.0.1:9").await.unwrap_err())));
+
+        let function = "\n// Derive the value of the arithmetic expression\n// expr: An arithmetic expression\nfn arithmetic(expr)\n";
+        let res = call_llm_model_function("gpt", "gpt-4o", "", &["What is 6 * 7?".to_string()], 0.2, false, false, &[function]).await;
+
+        crate::circuit::set_circuit_breaker(None);
+
+        let body: serde_json::Value = serde_json::from_slice(&claude.received_requests().await.unwrap()[0].body).unwrap();
+
+        assert_eq!(res.unwrap().llm_type, LlmType::CLAUDE_TOOLS);
+        assert!(body["tools"][0]["input_schema"].is_object());
+        assert!(body["tools"][0].get("parameters").is_none());
+    }
+
+    #[test]
+    fn test_is_auth_error() {
+        assert!(is_auth_error(r#"{"error":{"message":"Incorrect API key provided","type":"invalid_request_error","code":"invalid_api_key"}}"#));
+        assert!(is_auth_error(r#"{"type":"error","error":{"type":"authentication_error","message":"invalid x-api-key"}}"#));
+        assert!(!is_auth_error(r#"{"error":{"message":"Rate limit reached","code":"rate_limit_exceeded"}}"#));
+    }
+}
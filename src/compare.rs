@@ -0,0 +1,129 @@
+use crate::common::{call_llm_model, get_model, llm_name, LlmReturn};
+use crate::pricing::cost;
+
+/// Provider and model from "provider" or "provider:model", the model
+/// defaulting to the provider's from the environment. None if the provider
+/// is unknown or has no default model.
+pub fn configuration(spec: &str) -> Option<(&'static str, String)> {
+    let (name, model) = match spec.split_once(':') {
+        Some((name, model)) => (name, Some(model.to_string())),
+        None => (spec, None),
+    };
+    let llm = llm_name(name.trim())?;
+
+    let model = match model {
+        Some(model) => model,
+        None => get_model(llm).ok()?,
+    };
+
+    Some((llm, model))
+}
+
+/// One configuration's answer
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub llm: String,
+    pub model: String,
+    pub result: Result<LlmReturn, String>,
+    /// None if the call failed or the model price is unknown
+    pub cost: Option<f64>,
+}
+
+/// The same prompt answered by several configurations
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    pub prompt: String,
+    pub candidates: Vec<Candidate>,
+}
+
+impl Comparison {
+    /// Successful candidate with the lowest latency
+    pub fn fastest(&self) -> Option<&Candidate> {
+        self.candidates.iter()
+            .filter(|c| c.result.is_ok())
+            .min_by(|a, b| a.result.as_ref().unwrap().timing.total.total_cmp(&b.result.as_ref().unwrap().timing.total))
+    }
+
+    /// Successful candidate with the lowest known cost
+    pub fn cheapest(&self) -> Option<&Candidate> {
+        self.candidates.iter()
+            .filter(|c| c.cost.is_some())
+            .min_by(|a, b| a.cost.unwrap().total_cmp(&b.cost.unwrap()))
+    }
+}
+
+impl std::fmt::Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for c in &self.candidates {
+            writeln!(f, "---------- {}: {} ----------", c.llm, c.model)?;
+            match c.result {
+                Ok(ref ret) => {
+                    let cost = match c.cost {
+                        Some(cost) => format!("${cost:.4}"),
+                        None => "unknown".into(),
+                    };
+
+                    writeln!(f, "Tokens: {} + {} = {}  Timing: {:.2}s  Cost: {cost}", ret.usage.0, ret.usage.1, ret.usage.2, ret.timing.total)?;
+                    writeln!(f, "{}", ret.text.trim_end())?;
+                },
+                Err(ref e) => writeln!(f, "Error: {e}")?,
+            }
+        }
+        if let Some(c) = self.fastest() {
+            write!(f, "Fastest: {}: {}", c.llm, c.model)?;
+        }
+        if let Some(c) = self.cheapest() {
+            write!(f, "  Cheapest: {}: {}", c.llm, c.model)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Run the same prompt across provider and model pairs concurrently
+pub async fn compare(prompt: &str, configs: &[(&str, &str)]) -> Comparison {
+    compare_system("", prompt, configs).await
+}
+
+/// Run the same system and user prompt across provider and model pairs concurrently
+pub async fn compare_system(system: &str, prompt: &str, configs: &[(&str, &str)]) -> Comparison {
+    let user = [prompt.to_string()];
+    let candidates = futures::future::join_all(configs.iter().map(|(llm, model)| async {
+        let result = call_llm_model(llm, model, system, &user, 0.2, false, false).await
+            .map_err(|e| e.to_string())
+            .and_then(|r| if r.llm_type.is_error() { Err(r.text) } else { Ok(r) });
+        let cost = result.as_ref().ok().and_then(|r| cost(model, r.usage));
+
+        Candidate { llm: llm.to_string(), model: model.to_string(), result, cost }
+    })).await;
+
+    Comparison { prompt: prompt.into(), candidates }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{FinishReason, LlmType};
+
+    #[test]
+    fn test_comparison() {
+        assert_eq!(configuration("openai:gpt-4o-mini"), Some(("gpt", "gpt-4o-mini".into())));
+        assert_eq!(configuration("bard:x"), None);
+
+        let candidate = |model: &str, timing, cost| Candidate {
+            llm: "gpt".into(),
+            model: model.into(),
+            result: Ok(LlmReturn::new(LlmType::GPT, "Canberra".into(), FinishReason::Stop, (5, 1, 6), timing, None, None)),
+            cost,
+        };
+        let failed = Candidate { llm: "claude".into(), model: "claude-3-haiku".into(), result: Err("overloaded".into()), cost: None };
+        let comparison = Comparison {
+            prompt: "Capital of Oz?".into(),
+            candidates: vec![candidate("gpt-4o", 2.0, Some(0.01)), candidate("gpt-4o-mini", 3.0, Some(0.001)), failed],
+        };
+
+        assert_eq!(comparison.fastest().unwrap().model, "gpt-4o");
+        assert_eq!(comparison.cheapest().unwrap().model, "gpt-4o-mini");
+        assert!(comparison.to_string().contains("Error: overloaded"));
+    }
+}
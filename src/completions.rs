@@ -0,0 +1,38 @@
+//! Plain completions: a bare prompt continued as text, for self-hosted
+//! servers with no chat endpoint. Shares the transport with fill in the
+//! middle, so any provider complete_code supports works here too, as does
+//! "local", an OpenAI compatible /v1/completions server at COMPLETIONS_URL.
+use crate::common::*;
+use crate::fim::{call_completion, FimOptions};
+
+/// Optional settings for a completion, the same as for fill in the middle
+pub type CompletionOptions = FimOptions;
+
+/// Continue a prompt with named LLM provider, "local" being the OpenAI
+/// compatible server at COMPLETIONS_URL
+pub async fn complete_text(llm: &str, prompt: &str, options: &CompletionOptions) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    call_completion(llm, prompt, "", options).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::mock_post;
+    use serial_test::serial;
+
+    #[tokio::test]
+    #[serial]
+    async fn test_complete_text_local() {
+        let server = mock_post("/v1/completions", 200, "gpt/completion.json").await;
+
+        std::env::set_var("COMPLETIONS_URL", format!("{}/v1/completions", server.uri()));
+        std::env::set_var("COMPLETIONS_MODEL", "llama-3-8b");
+
+        let res = complete_text("local", "The capital of Australia is", &CompletionOptions::default().max_tokens(8)).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&server.received_requests().await.unwrap()[0].body).unwrap();
+
+        assert_eq!(body, serde_json::json!({"model": "llama-3-8b", "prompt": "The capital of Australia is", "max_tokens": 8, "temperature": 0.0}));
+        assert_eq!(res.text, " Canberra.");
+        assert_eq!(res.usage, (6, 3, 9));
+    }
+}
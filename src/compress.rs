@@ -0,0 +1,177 @@
+use futures::future::BoxFuture;
+use regex::Regex;
+use crate::common::call_llm_model;
+use crate::vector_store::Match;
+
+/// Shortens retrieved context before it goes into a prompt, to cut input
+/// tokens. Used between retrieval (and any reranking) and `context_prompt`:
+///
+/// ```ignore
+/// let matches = retrieve(&store, "gpt", "text-embedding-3-small", question, 8, None).await?;
+/// let matches = compress_matches(&StopwordCompressor::new(), question, matches).await?;
+/// let prompt = context_prompt(question, &matches);
+/// ```
+pub trait ContextCompressor: Send + Sync {
+    /// Text cut down to what matters for the query
+    fn compress<'a>(&'a self, query: &'a str, text: &'a str) -> BoxFuture<'a, Result<String, Box<dyn std::error::Error + Send>>>;
+}
+
+const STOPWORDS: &[&str] = &[
+    "a", "an", "the", "and", "or", "but", "of", "to", "in", "on", "at", "by", "for", "with", "from",
+    "is", "are", "was", "were", "be", "been", "being", "am", "it", "its", "this", "that", "these",
+    "those", "as", "so", "such", "very", "just", "also", "then", "there", "here", "which", "who",
+    "whom", "do", "does", "did", "has", "have", "had", "will", "would", "shall", "should", "can",
+    "could", "may", "might", "must", "i", "me", "my", "we", "our", "you", "your", "he", "him",
+    "his", "she", "her", "they", "them", "their", "about", "into", "over", "than", "too", "only",
+];
+
+/// Lines dropped whole, as boilerplate from web pages and documents
+const BOILERPLATE: &[&str] = &[
+    r"(?i)^\s*(copyright|©|\(c\))\s",
+    r"(?i)all rights reserved",
+    r"(?i)\b(accept|use)s? (all )?cookies\b",
+    r"(?i)^\s*(subscribe|sign up|log in|share this|click here|read more|skip to content)\b",
+    r"(?i)^\s*page \d+( of \d+)?\s*$",
+];
+
+/// Cheap, local compression: drops boilerplate lines and common English
+/// stop words and squeezes white space. Negations are kept, so meaning
+/// mostly survives; models read the result well enough for retrieval.
+#[derive(Debug, Clone)]
+pub struct StopwordCompressor {
+    stopwords: Vec<String>,
+    boilerplate: Vec<Regex>,
+}
+
+impl StopwordCompressor {
+    pub fn new() -> Self {
+        StopwordCompressor {
+            stopwords: STOPWORDS.iter().map(|s| s.to_string()).collect(),
+            boilerplate: BOILERPLATE.iter().map(|p| Regex::new(p).unwrap()).collect(),
+        }
+    }
+
+    /// Also drop lines matching pattern
+    pub fn boilerplate(mut self, pattern: &str) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        let pattern = Regex::new(pattern)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+        self.boilerplate.push(pattern);
+
+        Ok(self)
+    }
+
+    /// Use these stop words in place of the built in English ones
+    pub fn stopwords(mut self, stopwords: &[&str]) -> Self {
+        self.stopwords = stopwords.iter().map(|s| s.to_lowercase()).collect();
+        self
+    }
+
+    /// Compressed text, for use without the trait
+    pub fn strip(&self, text: &str) -> String {
+        text.lines()
+            .filter(|line| !self.boilerplate.iter().any(|b| b.is_match(line)))
+            .map(|line| {
+                line.split_whitespace()
+                    .filter(|word| {
+                        let bare = word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase();
+
+                        // Keep punctuation attached to dropped words, it separates clauses
+                        bare.is_empty() || !self.stopwords.contains(&bare) || word.ends_with(['.', ',', ';', ':', '?', '!'])
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl Default for StopwordCompressor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ContextCompressor for StopwordCompressor {
+    fn compress<'a>(&'a self, _query: &'a str, text: &'a str) -> BoxFuture<'a, Result<String, Box<dyn std::error::Error + Send>>> {
+        Box::pin(async move { Ok(self.strip(text)) })
+    }
+}
+
+/// Has a (preferably cheap) model keep only what bears on the query,
+/// word for word. Costs a call per document but can cut far more.
+#[derive(Debug, Clone)]
+pub struct LlmCompressor {
+    pub llm: String,
+    pub model: String,
+}
+
+impl LlmCompressor {
+    pub fn new(llm: &str, model: &str) -> Self {
+        LlmCompressor { llm: llm.into(), model: model.into() }
+    }
+}
+
+impl ContextCompressor for LlmCompressor {
+    fn compress<'a>(&'a self, query: &'a str, text: &'a str) -> BoxFuture<'a, Result<String, Box<dyn std::error::Error + Send>>> {
+        Box::pin(async move {
+            let prompt = format!("Question: {query}\n\nText:\n{text}");
+            let ret = call_llm_model(&self.llm, &self.model,
+                "Copy out only the sentences of the text that help answer the question, unchanged and in order. If none do, reply with nothing.",
+                &[prompt], 0.0, false, false).await?;
+
+            if ret.llm_type.is_error() {
+                return Err(Box::new(std::io::Error::other(format!("Compression failed: {}", ret.text))) as Box<dyn std::error::Error + Send>);
+            }
+
+            Ok(ret.text.trim().to_string())
+        })
+    }
+}
+
+/// Compress each match's text for the query concurrently, dropping any
+/// left empty
+pub async fn compress_matches(compressor: &impl ContextCompressor, query: &str, matches: Vec<Match>) -> Result<Vec<Match>, Box<dyn std::error::Error + Send>> {
+    let texts = futures::future::join_all(matches.iter().map(|m| compressor.compress(query, &m.document.text))).await;
+    let mut compressed = Vec::new();
+
+    for (mut m, text) in matches.into_iter().zip(texts) {
+        let text = text?;
+
+        if !text.trim().is_empty() {
+            m.document.text = text;
+            compressed.push(m);
+        }
+    }
+
+    Ok(compressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector_store::Document;
+
+    #[test]
+    fn test_stopword_compressor() {
+        let compressor = StopwordCompressor::new().boilerplate(r"^Advert").unwrap();
+        let text = "The cat is not on the mat.\nAdvert: buy now\nCopyright 2024 Example Ltd\n\nIt was in the garden, with a dog.";
+
+        assert_eq!(compressor.strip(text), "cat not mat.\ngarden, dog.");
+    }
+
+    #[tokio::test]
+    async fn test_compress_matches() {
+        let document = |id: &str, text: &str| Match {
+            document: Document { id: id.into(), text: text.into(), embedding: vec![], metadata: Default::default() },
+            score: 1.0,
+        };
+        let matches = vec![document("a", "The answer is 42."), document("b", "All rights reserved")];
+        let matches = compress_matches(&StopwordCompressor::new(), "What is the answer?", matches).await.unwrap();
+
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].document.text, "answer 42.");
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use serde_derive::Deserialize;
+use crate::common::llm_name;
+use crate::error::ConfigError;
+
+/// Optional settings file. Looked for in the file named by the
+/// LLMCLIENT_CONFIG environment variable, otherwise `llmclient.toml` in the
+/// current directory. All settings are optional, e.g.
+///
+/// ```toml
+/// provider = "claude"
+/// model = "claude-3-5-sonnet-20240620"
+/// temperature = 0.7
+/// system_file = "system.txt"
+/// sessions_dir = "sessions"
+/// env_file = ".env"
+///
+/// [providers.claude]
+/// max_tokens = 2048
+/// top_p = 0.9
+/// ```
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct Config {
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub system_file: Option<String>,
+    pub sessions_dir: Option<String>,
+    /// Settings such as API keys to load into the environment, see `load_env_file`
+    pub env_file: Option<String>,
+    /// CLI warns once the dialogue fills this percentage of the context window
+    pub context_warning: Option<u8>,
+    /// Generation defaults by provider name
+    #[serde(default)]
+    pub providers: HashMap<String, ProviderDefaults>,
+}
+
+/// Generation settings for a provider, used when callers don't supply them
+#[derive(Debug, Deserialize, Default, Clone, PartialEq)]
+pub struct ProviderDefaults {
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<usize>,
+    pub top_p: Option<f32>,
+    /// Claude and Gemini only
+    pub top_k: Option<usize>,
+    /// Claude and Gemini only
+    pub stop_sequences: Option<Vec<String>>,
+    /// Gemini content blocking: "none", "low" (block low and above), "medium" or "high" (block only high)
+    pub safety: Option<String>,
+}
+
+impl ProviderDefaults {
+    /// Temperature, 0.2 if not configured
+    pub fn temperature(&self) -> f32 {
+        self.temperature.unwrap_or(0.2)
+    }
+}
+
+static PROVIDER_DEFAULTS: RwLock<Option<HashMap<String, ProviderDefaults>>> = RwLock::new(None);
+
+/// Use these provider defaults rather than those from the configuration file
+pub fn set_provider_defaults(defaults: HashMap<String, ProviderDefaults>) {
+    let defaults = defaults.into_iter()
+        .map(|(llm, d)| (llm_name(&llm).map(|l| l.to_string()).unwrap_or(llm), d))
+        .collect();
+
+    *PROVIDER_DEFAULTS.write().unwrap() = Some(defaults);
+}
+
+/// Generation defaults for a provider. Read from the configuration file
+/// on first use unless set with `set_provider_defaults`; empty if none.
+pub fn provider_defaults(llm: &str) -> ProviderDefaults {
+    if PROVIDER_DEFAULTS.read().unwrap().is_none() {
+        set_provider_defaults(Config::load().map(|c| c.providers).unwrap_or_default());
+    }
+
+    let llm = llm_name(llm).unwrap_or(llm);
+
+    PROVIDER_DEFAULTS.read().unwrap().as_ref()
+        .and_then(|d| d.get(llm).cloned())
+        .unwrap_or_default()
+}
+
+impl Config {
+    /// Load configuration from default location, empty if no file exists
+    pub fn load() -> Result<Self, Box<dyn std::error::Error + Send>> {
+        let path = env_var("LLMCLIENT_CONFIG").unwrap_or_else(|| "llmclient.toml".into());
+
+        if std::path::Path::new(&path).exists() {
+            Self::load_from(&path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    /// Load configuration from named file
+    pub fn load_from(path: &str) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+        toml::from_str(&text)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+    }
+}
+
+/// Prefix for this crate's environment variables, to keep them apart from
+/// those of other tools in the same process
+pub const ENV_PREFIX: &str = "LLMCLIENT_";
+
+/// Setting from the environment, all settings are read through here. The
+/// prefixed name e.g. LLMCLIENT_OPENAI_API_KEY wins, otherwise the plain
+/// name e.g. OPENAI_API_KEY is still honoured.
+pub fn env_var(name: &str) -> Option<String> {
+    if name.starts_with(ENV_PREFIX) {
+        return std::env::var(name).ok();
+    }
+
+    std::env::var(format!("{ENV_PREFIX}{name}")).ok()
+        .or_else(|| std::env::var(name).ok())
+}
+
+/// NAME=value pairs of a .env style file. Blank lines, # comments, a
+/// leading 'export' and quotes around values are allowed, so shell env
+/// files like the one in this repository can be used as is.
+pub fn parse_env(text: &str) -> Vec<(String, String)> {
+    text.lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| l.trim_start_matches("export ").split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().trim_matches(|c| c == '"' || c == '\'').to_string()))
+        .collect()
+}
+
+/// Load a .env style file into the environment, for programs that would
+/// otherwise need a dozen variables exported by hand. Variables already set
+/// are left alone. Returns how many were set.
+pub fn load_env_file(path: &str) -> Result<usize, Box<dyn std::error::Error + Send>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+    let unset: Vec<(String, String)> = parse_env(&text).into_iter()
+        .filter(|(name, _)| std::env::var_os(name).is_none())
+        .collect();
+
+    unset.iter().for_each(|(name, value)| std::env::set_var(name, value));
+
+    Ok(unset.len())
+}
+
+/// Value of a required environment variable, a `ConfigError` if not set
+pub fn required_env(name: &str) -> Result<String, Box<dyn std::error::Error + Send>> {
+    env_var(name)
+        .ok_or_else(|| -> Box<dyn std::error::Error + Send> { Box::new(ConfigError::MissingEnv(name.into())) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    fn test_config_parse() {
+        let config: Config = toml::from_str("provider = \"claude\"\ntemperature = 0.7\n").unwrap();
+
+        assert_eq!(config.provider.as_deref(), Some("claude"));
+        assert_eq!(config.temperature, Some(0.7));
+        assert!(config.model.is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn test_provider_defaults() {
+        let config: Config = toml::from_str("[providers.anthropic]\nmax_tokens = 2048\ntop_p = 0.9\n").unwrap();
+
+        set_provider_defaults(config.providers);
+
+        let claude = provider_defaults("claude");
+        assert_eq!(claude.max_tokens, Some(2048));
+        assert_eq!(claude.top_p, Some(0.9));
+        assert_eq!(claude.temperature(), 0.2);
+        assert_eq!(provider_defaults("mistral"), ProviderDefaults::default());
+
+        set_provider_defaults(HashMap::new());
+    }
+
+    #[test]
+    #[serial]
+    fn test_env_var() {
+        std::env::set_var("LLMCLIENT_TEST_SETTING", "prefixed");
+        std::env::set_var("TEST_SETTING", "plain");
+        std::env::set_var("TEST_PLAIN_SETTING", "plain");
+
+        assert_eq!(env_var("TEST_SETTING").as_deref(), Some("prefixed"));
+        assert_eq!(env_var("TEST_PLAIN_SETTING").as_deref(), Some("plain"));
+        assert_eq!(env_var("LLMCLIENT_TEST_SETTING").as_deref(), Some("prefixed"));
+
+        std::env::remove_var("LLMCLIENT_TEST_SETTING");
+        std::env::remove_var("TEST_SETTING");
+        std::env::remove_var("TEST_PLAIN_SETTING");
+    }
+
+    #[test]
+    #[serial]
+    fn test_load_env_file() {
+        let path = std::env::temp_dir().join("llmclient_test.env");
+
+        std::fs::write(&path, "# keys\nexport LLMCLIENT_TEST_KEY='sk-123'\nLLMCLIENT_TEST_SET=new\n#LLMCLIENT_TEST_OFF=1\n").unwrap();
+        std::env::set_var("LLMCLIENT_TEST_SET", "old");
+
+        assert_eq!(load_env_file(path.to_str().unwrap()).unwrap(), 1);
+        assert_eq!(env_var("LLMCLIENT_TEST_KEY").as_deref(), Some("sk-123"));
+        assert_eq!(env_var("LLMCLIENT_TEST_SET").as_deref(), Some("old"));
+        assert_eq!(env_var("LLMCLIENT_TEST_OFF"), None);
+
+        std::env::remove_var("LLMCLIENT_TEST_KEY");
+        std::env::remove_var("LLMCLIENT_TEST_SET");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_required_env() {
+        let err = required_env("LLMCLIENT_TEST_UNSET").unwrap_err();
+
+        assert_eq!(err.downcast_ref::<ConfigError>(), Some(&ConfigError::MissingEnv("LLMCLIENT_TEST_UNSET".into())));
+        assert_eq!(err.to_string(), "LLMCLIENT_TEST_UNSET not found in environment variables");
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use crate::config::{env_var, parse_env};
+use crate::error::ConfigError;
+use crate::tenants::tenant_credential;
+
+/// Source of API keys and other secrets, looked up by name e.g.
+/// OPENAI_API_KEY. Install one with `set_credentials_provider` to use a
+/// vault or OS keychain instead of environment variables.
+pub trait CredentialsProvider: Send + Sync {
+    /// Secret for name, None if this provider does not hold it
+    fn get(&self, name: &str) -> Option<String>;
+}
+
+/// Secrets from environment variables, the default. LLMCLIENT_ prefixed
+/// names are tried first, see `config::env_var`.
+#[derive(Debug, Default, Clone)]
+pub struct EnvCredentials;
+
+impl CredentialsProvider for EnvCredentials {
+    fn get(&self, name: &str) -> Option<String> {
+        env_var(name)
+    }
+}
+
+/// Secrets from a file of NAME=value lines. Blank lines, # comments and
+/// a leading 'export' are allowed, so shell env files can be used as is.
+#[derive(Debug, Default, Clone)]
+pub struct FileCredentials {
+    values: HashMap<String, String>,
+}
+
+impl FileCredentials {
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+        Ok(Self::parse(&text))
+    }
+
+    pub fn parse(text: &str) -> Self {
+        FileCredentials { values: parse_env(text).into_iter().collect() }
+    }
+}
+
+impl CredentialsProvider for FileCredentials {
+    fn get(&self, name: &str) -> Option<String> {
+        self.values.get(name).cloned()
+    }
+}
+
+/// Secrets from the OS keychain, stored under a service name with the
+/// secret name as the user e.g. `keyring set llmclient OPENAI_API_KEY`
+#[cfg(feature = "keyring")]
+#[derive(Debug, Clone)]
+pub struct KeyringCredentials {
+    service: String,
+}
+
+#[cfg(feature = "keyring")]
+impl KeyringCredentials {
+    pub fn new(service: &str) -> Self {
+        KeyringCredentials { service: service.into() }
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl CredentialsProvider for KeyringCredentials {
+    fn get(&self, name: &str) -> Option<String> {
+        keyring::Entry::new(&self.service, name).ok()?.get_password().ok()
+    }
+}
+
+/// Secrets from a closure, for integration with anything else
+pub struct FnCredentials<F>(pub F);
+
+impl<F> CredentialsProvider for FnCredentials<F> where F: Fn(&str) -> Option<String> + Send + Sync {
+    fn get(&self, name: &str) -> Option<String> {
+        (self.0)(name)
+    }
+}
+
+/// Try each provider in turn, first found wins
+#[derive(Default)]
+pub struct ChainCredentials(pub Vec<Box<dyn CredentialsProvider>>);
+
+impl CredentialsProvider for ChainCredentials {
+    fn get(&self, name: &str) -> Option<String> {
+        self.0.iter().find_map(|p| p.get(name))
+    }
+}
+
+static CREDENTIALS: RwLock<Option<Arc<dyn CredentialsProvider>>> = RwLock::new(None);
+
+/// Replace the credentials provider used by all LLM calls. Clients built
+/// with the old credentials are dropped.
+pub fn set_credentials_provider(provider: impl CredentialsProvider + 'static) {
+    *CREDENTIALS.write().unwrap() = Some(Arc::new(provider));
+    crate::providers::reset_providers();
+}
+
+/// Revert to reading secrets from environment variables
+pub fn reset_credentials_provider() {
+    *CREDENTIALS.write().unwrap() = None;
+    crate::providers::reset_providers();
+}
+
+/// Look up named secret from the current tenant's keys, if any, or else
+/// the current credentials provider
+pub fn credential(name: &str) -> Result<String, Box<dyn std::error::Error + Send>> {
+    if let Some(value) = tenant_credential(name) {
+        return Ok(value);
+    }
+
+    let provider = CREDENTIALS.read().unwrap().clone();
+    let value = match provider {
+        Some(provider) => provider.get(name),
+        None => EnvCredentials.get(name),
+    };
+
+    value.ok_or_else(|| -> Box<dyn std::error::Error + Send> { Box::new(ConfigError::MissingCredential(name.into())) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_credentials() {
+        let creds = FileCredentials::parse("# keys\nexport OPENAI_API_KEY=sk-123\nGROQ_API_KEY = \"gsk-456\"\n");
+
+        assert_eq!(creds.get("OPENAI_API_KEY").as_deref(), Some("sk-123"));
+        assert_eq!(creds.get("GROQ_API_KEY").as_deref(), Some("gsk-456"));
+        assert_eq!(creds.get("MISTRAL_API_KEY"), None);
+    }
+
+    #[test]
+    fn test_chain_credentials() {
+        let creds = ChainCredentials(vec![
+            Box::new(FnCredentials(|name: &str| (name == "A").then(|| "first".to_string()))),
+            Box::new(FileCredentials::parse("A=second\nB=second")),
+        ]);
+
+        assert_eq!(creds.get("A").as_deref(), Some("first"));
+        assert_eq!(creds.get("B").as_deref(), Some("second"));
+    }
+}
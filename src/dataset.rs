@@ -0,0 +1,334 @@
+//! Prompts read from JSONL, run through a model and written back as JSONL,
+//! for labelling datasets and eval jobs. Each input line is an object with
+//! a `prompt` and optionally an `id`, a `system` prompt and an `expected`
+//! output; anything else on the line is carried through to the result.
+use std::io::Write;
+use futures::StreamExt;
+use serde_derive::{Deserialize, Serialize};
+use crate::common::call_llm_model;
+
+/// One line of a dataset
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DatasetItem {
+    /// Line number (from 1) if not given
+    #[serde(default)]
+    pub id: String,
+    pub prompt: String,
+    /// Overrides the runner's system prompt for this item
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl DatasetItem {
+    pub fn new(id: &str, prompt: &str) -> Self {
+        DatasetItem { id: id.into(), prompt: prompt.into(), system: None, expected: None, extra: serde_json::Map::new() }
+    }
+
+    pub fn expected(mut self, expected: &str) -> Self {
+        self.expected = Some(expected.into());
+        self
+    }
+}
+
+/// Items from JSONL text, skipping blank lines
+pub fn parse_dataset(jsonl: &str) -> Result<Vec<DatasetItem>, Box<dyn std::error::Error + Send>> {
+    let mut items = Vec::new();
+
+    for (n, line) in jsonl.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        let mut item: DatasetItem = serde_json::from_str(line)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(std::io::Error::other(format!("Dataset line {}: {e}", n + 1))) })?;
+
+        if item.id.is_empty() {
+            item.id = (n + 1).to_string();
+        }
+        items.push(item);
+    }
+
+    Ok(items)
+}
+
+/// Items from a JSONL file
+pub fn load_dataset(path: &str) -> Result<Vec<DatasetItem>, Box<dyn std::error::Error + Send>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    parse_dataset(&text)
+}
+
+/// Outcome of one item
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DatasetResult {
+    pub id: String,
+    pub prompt: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+    /// None if the call failed
+    pub response: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Whether the response equals the expected output, ignoring case and
+    /// surrounding white space. None if nothing was expected or the call failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matched: Option<bool>,
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+    /// Seconds
+    pub latency: f64,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Results as JSONL, one line each in order
+pub fn results_jsonl(results: &[DatasetResult]) -> Result<String, Box<dyn std::error::Error + Send>> {
+    let mut jsonl = String::new();
+
+    for result in results {
+        let line = serde_json::to_string(result)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+        jsonl.push_str(&line);
+        jsonl.push('\n');
+    }
+
+    Ok(jsonl)
+}
+
+/// Write results to a JSONL file
+pub fn write_results(path: &str, results: &[DatasetResult]) -> Result<(), Box<dyn std::error::Error + Send>> {
+    std::fs::write(path, results_jsonl(results)?)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+}
+
+/// Runs dataset items through a model, a bounded number at a time
+#[derive(Debug, Clone)]
+pub struct DatasetRunner {
+    pub llm: String,
+    pub model: String,
+    pub system: String,
+    pub temperature: f32,
+    pub is_json: bool,
+    /// Calls in flight at once, at least 1
+    pub concurrency: usize,
+    /// JSONL file results are appended to as they complete, see `run_resumable`
+    pub checkpoint: Option<std::path::PathBuf>,
+}
+
+/// Line of a checkpoint file: an item's position and its result
+#[derive(Debug, Deserialize)]
+struct Checkpointed {
+    index: usize,
+    result: DatasetResult,
+}
+
+impl DatasetRunner {
+    pub fn new(llm: &str, model: &str) -> Self {
+        DatasetRunner { llm: llm.into(), model: model.into(), system: String::new(), temperature: 0.2, is_json: false, concurrency: 4, checkpoint: None }
+    }
+
+    pub fn system(mut self, system: &str) -> Self {
+        self.system = system.into();
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn json(mut self, is_json: bool) -> Self {
+        self.is_json = is_json;
+        self
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn checkpoint(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.checkpoint = Some(path.into());
+        self
+    }
+
+    async fn call(&self, item: &DatasetItem) -> DatasetResult {
+        let start = std::time::Instant::now();
+        let system = item.system.as_deref().unwrap_or(&self.system);
+        let mut result = DatasetResult {
+            id: item.id.clone(),
+            prompt: item.prompt.clone(),
+            expected: item.expected.clone(),
+            response: None,
+            error: None,
+            matched: None,
+            input_tokens: 0,
+            output_tokens: 0,
+            latency: 0.0,
+            extra: item.extra.clone(),
+        };
+
+        match call_llm_model(&self.llm, &self.model, system, std::slice::from_ref(&item.prompt), self.temperature, self.is_json, false).await {
+            Ok(ret) if ret.llm_type.is_error() => result.error = Some(ret.text),
+            Ok(ret) => {
+                let response = ret.text.trim().to_string();
+
+                result.matched = item.expected.as_ref().map(|e| e.trim().to_lowercase() == response.to_lowercase());
+                result.response = Some(response);
+                (result.input_tokens, result.output_tokens) = (ret.usage.0, ret.usage.1);
+            },
+            Err(e) => result.error = Some(e.to_string()),
+        }
+        result.latency = start.elapsed().as_secs_f64();
+
+        result
+    }
+
+    /// Results in the same order as the items. Failed calls are recorded
+    /// in their result rather than stopping the run.
+    pub async fn run(&self, items: &[DatasetItem]) -> Vec<DatasetResult> {
+        futures::stream::iter(items.iter())
+            .map(|item| self.call(item))
+            .buffered(self.concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    /// As `run`, but with a checkpoint file set each successful result is
+    /// appended to it as soon as it completes. Run again after being
+    /// interrupted, items already in the checkpoint are taken from it rather
+    /// than called again; failed items are retried. The checkpoint must be
+    /// for the same items, and is left in place for the caller to remove.
+    pub async fn run_resumable(&self, items: &[DatasetItem]) -> Result<Vec<DatasetResult>, Box<dyn std::error::Error + Send>> {
+        let Some(ref path) = self.checkpoint else {
+            return Ok(self.run(items).await);
+        };
+        let mut results: Vec<Option<DatasetResult>> = vec![None; items.len()];
+
+        for done in read_checkpoint(path)? {
+            match items.get(done.index) {
+                Some(item) if item.id == done.result.id => results[done.index] = Some(done.result),
+                _ => return Err(Box::new(std::io::Error::other(format!("Checkpoint {} is not for this dataset: item {} is not {}", path.display(), done.index, done.result.id)))),
+            }
+        }
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+        // Start after any line cut short, so the next result is readable
+        if std::fs::read(path).is_ok_and(|b| b.last().is_some_and(|&c| c != b'\n')) {
+            writeln!(file).map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+        }
+
+        let todo: Vec<usize> = (0..items.len()).filter(|&i| results[i].is_none()).collect();
+        let mut completed = futures::stream::iter(todo)
+            .map(|i| async move { (i, self.call(&items[i]).await) })
+            .buffer_unordered(self.concurrency.max(1));
+
+        while let Some((index, result)) = completed.next().await {
+            if result.error.is_none() {
+                writeln!(file, "{}", serde_json::json!({ "index": index, "result": &result }))
+                    .and_then(|_| file.flush())
+                    .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+            }
+            results[index] = Some(result);
+        }
+
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    /// Load a JSONL dataset, run it and write the results as JSONL,
+    /// resuming from the checkpoint if one is set
+    pub async fn run_file(&self, input: &str, output: &str) -> Result<Vec<DatasetResult>, Box<dyn std::error::Error + Send>> {
+        let results = self.run_resumable(&load_dataset(input)?).await?;
+
+        write_results(output, &results)?;
+
+        Ok(results)
+    }
+}
+
+// Results saved so far, ignoring a last line cut short by a crash
+fn read_checkpoint(path: &std::path::Path) -> Result<Vec<Checkpointed>, Box<dyn std::error::Error + Send>> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => Ok(text.lines().filter_map(|l| serde_json::from_str(l).ok()).collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[tokio::test]
+    #[serial]
+    async fn test_dataset_runner() {
+        let items = parse_dataset("{\"id\":\"q1\",\"prompt\":\"Say hello\",\"expected\":\"hello there.\",\"label\":\"greeting\"}\n\n{\"prompt\":\"Say goodbye\",\"expected\":\"Goodbye.\"}\n").unwrap();
+
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1].id, "3");
+        assert_eq!(items[0].extra["label"], "greeting");
+        assert!(parse_dataset("{\"id\":\"x\"}").unwrap_err().to_string().starts_with("Dataset line 1:"));
+
+        let server = wiremock::MockServer::start().await;
+
+        wiremock::Mock::given(wiremock::matchers::method("POST"))
+            .respond_with(wiremock::ResponseTemplate::new(200).set_body_string(crate::mock::fixture("gpt/success.json")))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        let results = DatasetRunner::new("gpt", "gpt-4o").concurrency(2).run(&items).await;
+
+        assert_eq!(results[0].response.as_deref(), Some("Hello there."));
+        assert_eq!(results[0].matched, Some(true));
+        assert_eq!(results[1].matched, Some(false));
+        assert_eq!((results[0].input_tokens, results[0].output_tokens), (12, 3));
+
+        let jsonl = results_jsonl(&results).unwrap();
+        let first: serde_json::Value = serde_json::from_str(jsonl.lines().next().unwrap()).unwrap();
+
+        assert_eq!(jsonl.lines().count(), 2);
+        assert_eq!(first["id"], "q1");
+        assert_eq!(first["label"], "greeting");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_dataset_checkpoint() {
+        let path = std::env::temp_dir().join(format!("llmclient-checkpoint-{}.jsonl", std::process::id()));
+        let items = vec![DatasetItem::new("a", "Say hi"), DatasetItem::new("b", "Say hello")];
+        let done = DatasetResult {
+            id: "a".into(), prompt: "Say hi".into(), expected: None, response: Some("Hi.".into()), error: None, matched: None,
+            input_tokens: 3, output_tokens: 1, latency: 0.1, extra: serde_json::Map::new(),
+        };
+
+        // The first item completed before the run was interrupted mid write
+        std::fs::write(&path, format!("{}\n{{\"index\":1,\"res", serde_json::json!({ "index": 0, "result": &done }))).unwrap();
+
+        let server = crate::mock::mock_post("/v1/chat/completions", 200, "gpt/success.json").await;
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        let runner = DatasetRunner::new("gpt", "gpt-4o").checkpoint(&path);
+        let results = runner.run_resumable(&items).await.unwrap();
+
+        assert_eq!(results[0], done);
+        assert_eq!(results[1].response.as_deref(), Some("Hello there."));
+        assert_eq!(read_checkpoint(&path).unwrap().len(), 2);
+        assert!(runner.run_resumable(&items[1..]).await.is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
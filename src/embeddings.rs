@@ -0,0 +1,208 @@
+use serde_derive::{Deserialize, Serialize};
+use crate::common::Triple;
+use crate::config::{env_var, required_env};
+use crate::error::LlmClientError;
+use crate::gpt::get_gpt_client;
+use crate::mistral::get_mistral_client;
+
+/// Texts sent per Mistral request unless MISTRAL_EMBEDDING_BATCH says otherwise
+const MISTRAL_EMBEDDING_BATCH: usize = 64;
+
+// Input structures
+#[derive(Debug, Serialize)]
+pub struct EmbeddingRequest<'a> {
+    pub model: &'a str,
+    pub input: &'a [String],
+}
+
+// Output structures
+#[derive(Debug, Deserialize)]
+pub struct EmbeddingResponse {
+    pub data: Vec<EmbeddingData>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmbeddingData {
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+/// Embedding vectors in the same order as the texts supplied
+#[derive(Debug, Clone)]
+pub struct Embeddings {
+    pub vectors: Vec<Vec<f32>>,
+    pub usage: Triple,
+    pub timing: f64,
+}
+
+/// Default embedding model for named LLM provider
+pub fn get_embedding_model(llm: &str) -> String {
+    match llm {
+        "openai" | "gpt" => env_var("GPT_EMBEDDING_MODEL").unwrap_or("text-embedding-3-small".into()),
+        "mistral" => env_var("MISTRAL_EMBEDDING_MODEL").unwrap_or("mistral-embed".into()),
+        _ => String::new(),
+    }
+}
+
+/// Embed texts with named LLM provider and model
+pub async fn call_embeddings(llm: &str, model: &str, texts: &[String]) -> Result<Embeddings, Box<dyn std::error::Error + Send>> {
+    match llm {
+        "openai" | "gpt" => call_gpt_embeddings(model, texts).await,
+        "mistral" => call_mistral_embeddings(model, texts).await,
+        _ => Err(Box::new(LlmClientError::Unsupported(format!("{llm} embeddings")))),
+    }
+}
+
+/// Embed texts with OpenAI
+pub async fn call_gpt_embeddings(model: &str, texts: &[String]) -> Result<Embeddings, Box<dyn std::error::Error + Send>> {
+    let start = std::time::Instant::now();
+    let url: String = required_env("GPT_EMBEDDINGS_URL")?;
+    let client = get_gpt_client().await?;
+
+    let res = client
+        .post(url)
+        .json(&EmbeddingRequest { model, input: texts })
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    let timing = start.elapsed().as_secs() as f64 + start.elapsed().subsec_millis() as f64 / 1000.0;
+
+    counted(embeddings_response(&res, timing)?, texts)
+}
+
+/// Embed texts with Mistral, in batches of MISTRAL_EMBEDDING_BATCH, usage
+/// and timing being the totals over all batches
+pub async fn call_mistral_embeddings(model: &str, texts: &[String]) -> Result<Embeddings, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("MISTRAL_EMBEDDINGS_URL")?;
+    let batch: usize = env_var("MISTRAL_EMBEDDING_BATCH")
+        .and_then(|b| b.parse().ok())
+        .filter(|b| *b > 0)
+        .unwrap_or(MISTRAL_EMBEDDING_BATCH);
+    let client = get_mistral_client().await?;
+    let mut embeddings = Embeddings { vectors: Vec::with_capacity(texts.len()), usage: (0, 0, 0), timing: 0.0 };
+
+    for input in texts.chunks(batch) {
+        let start = std::time::Instant::now();
+        let res = client
+            .post(&url)
+            .json(&EmbeddingRequest { model, input })
+            .send()
+            .await
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+            .text()
+            .await
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+        let timing = start.elapsed().as_secs() as f64 + start.elapsed().subsec_millis() as f64 / 1000.0;
+        let res = counted(embeddings_response(&res, timing)?, input)?;
+
+        embeddings.vectors.extend(res.vectors);
+        embeddings.usage = (embeddings.usage.0 + res.usage.0, 0, embeddings.usage.2 + res.usage.2);
+        embeddings.timing += res.timing;
+    }
+
+    Ok(embeddings)
+}
+
+/// Unpack OpenAI compatible embeddings response
+pub fn embeddings_response(res: &str, timing: f64) -> Result<Embeddings, Box<dyn std::error::Error + Send>> {
+    if res.contains("\"error\"") {
+        return Err(Box::new(std::io::Error::other(res.to_string())));
+    }
+
+    let mut res: EmbeddingResponse = serde_json::from_str(res)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    res.data.sort_by_key(|d| d.index);
+
+    Ok(Embeddings {
+        vectors: res.data.into_iter().map(|d| d.embedding).collect(),
+        usage: (res.usage.prompt_tokens, 0, res.usage.total_tokens),
+        timing,
+    })
+}
+
+// Embeddings, if there is one for each text
+fn counted(embeddings: Embeddings, texts: &[String]) -> Result<Embeddings, Box<dyn std::error::Error + Send>> {
+    if embeddings.vectors.len() != texts.len() {
+        return Err(Box::new(std::io::Error::other(format!("{} embeddings returned for {} texts", embeddings.vectors.len(), texts.len()))));
+    }
+
+    Ok(embeddings)
+}
+
+/// Cosine similarity of two vectors, 0 if either is all zero
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+
+    if norms == 0.0 { 0.0 } else { dot / norms }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{mock_post, mock_post_sequence};
+    use serial_test::serial;
+
+    #[test]
+    fn test_embeddings_response() {
+        let res = r#"{"object":"list","data":[{"object":"embedding","index":1,"embedding":[0.0,1.0]},{"object":"embedding","index":0,"embedding":[1.0,0.0]}],"model":"text-embedding-3-small","usage":{"prompt_tokens":8,"total_tokens":8}}"#;
+        let embeddings = embeddings_response(res, 0.1).unwrap();
+
+        assert_eq!(embeddings.vectors, vec![vec![1.0, 0.0], vec![0.0, 1.0]]);
+        assert_eq!(embeddings.usage, (8, 0, 8));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_call_mistral_embeddings() {
+        let server = mock_post_sequence("/v1/embeddings", &[(200, "mistral/embeddings.json"), (200, "mistral/embeddings_single.json")]).await;
+
+        std::env::set_var("MISTRAL_EMBEDDINGS_URL", format!("{}/v1/embeddings", server.uri()));
+        std::env::set_var("MISTRAL_EMBEDDING_BATCH", "2");
+        std::env::set_var("MISTRAL_API_KEY", "test-key");
+
+        let texts: Vec<String> = ["Canberra", "Sydney", "Melbourne"].iter().map(|t| t.to_string()).collect();
+        let embeddings = call_embeddings("mistral", &get_embedding_model("mistral"), &texts).await;
+
+        std::env::remove_var("MISTRAL_EMBEDDING_BATCH");
+
+        let embeddings = embeddings.unwrap();
+        let requests = server.received_requests().await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&requests[1].body).unwrap();
+
+        assert_eq!(body["model"], "mistral-embed");
+        assert_eq!(body["input"], serde_json::json!(["Melbourne"]));
+        // In the order of the texts, whatever the order of the reply
+        assert_eq!(embeddings.vectors, vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![0.6, 0.8]]);
+        assert_eq!(embeddings.usage, (9, 0, 9));
+
+        // Two vectors for the one text sent
+        let server = mock_post("/v1/embeddings", 200, "mistral/embeddings.json").await;
+
+        std::env::set_var("MISTRAL_EMBEDDINGS_URL", format!("{}/v1/embeddings", server.uri()));
+
+        assert!(call_embeddings("mistral", "mistral-embed", &texts[..1]).await.is_err());
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+    }
+}
@@ -0,0 +1,87 @@
+/// Errors detected by the client itself, rather than transport or LLM
+/// provider errors. Returned boxed, so use `downcast_ref` to match on them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LlmClientError {
+    /// Model cannot do what was asked of it
+    Unsupported(String),
+    /// Call was cancelled before completion
+    Cancelled,
+    /// JSON was asked for but the reply, even after a retry, did not parse
+    InvalidJson { text: String, error: String },
+    /// Call would cross a session budget, given usage so far
+    BudgetExceeded { tokens: usize, cost: f64 },
+    /// Output or input failed guardrails, with the reasons
+    Rejected(Vec<String>),
+    /// Provider cannot take an attachment of this type
+    UnsupportedAttachment { llm: String, mime_type: String },
+    /// Generation parameter outside the range the provider accepts
+    InvalidParameter { name: String, message: String },
+    /// Provider has no such model, with similarly named ones it does have
+    UnknownModel { llm: String, model: String, suggestions: Vec<String> },
+    /// Prompt and room for the reply need more tokens than the model's
+    /// context window has, by overshoot tokens (estimated)
+    ContextOverflow { model: String, tokens: usize, limit: usize, overshoot: usize },
+    /// Provider has been failing, so is not being called until the cooldown ends
+    CircuitOpen { llm: String, retry_in: std::time::Duration },
+    /// Provider, as tenant/provider for a tenant, has reached a hard
+    /// quota, with its use this month
+    QuotaExceeded { llm: String, tokens: usize, cost: f64 },
+    /// Tenant has not been registered
+    UnknownTenant(String),
+    /// Any other error, such as a transport or configuration error, by its
+    /// message, where a call's error must be an `LlmClientError`
+    Failed(String),
+}
+
+impl LlmClientError {
+    /// The error itself if it is an `LlmClientError`, otherwise `Failed`
+    /// with its message
+    pub fn from_boxed(e: Box<dyn std::error::Error + Send>) -> Self {
+        e.downcast_ref::<LlmClientError>()
+            .cloned()
+            .unwrap_or_else(|| LlmClientError::Failed(e.to_string()))
+    }
+}
+
+impl std::fmt::Display for LlmClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LlmClientError::Unsupported(msg) => write!(f, "Unsupported: {msg}"),
+            LlmClientError::Cancelled => write!(f, "Cancelled"),
+            LlmClientError::InvalidJson { error, .. } => write!(f, "Invalid JSON: {error}"),
+            LlmClientError::BudgetExceeded { tokens, cost } => write!(f, "Budget exceeded: {tokens} tokens, ${cost:.4} used"),
+            LlmClientError::Rejected(reasons) => write!(f, "Rejected: {}", reasons.join("; ")),
+            LlmClientError::UnsupportedAttachment { llm, mime_type } => write!(f, "Unsupported attachment: {llm} cannot take {mime_type}"),
+            LlmClientError::InvalidParameter { name, message } => write!(f, "Invalid {name}: {message}"),
+            LlmClientError::UnknownModel { llm, model, suggestions } if suggestions.is_empty() => write!(f, "Unknown model: {llm} has no model {model}"),
+            LlmClientError::UnknownModel { llm, model, suggestions } => write!(f, "Unknown model: {llm} has no model {model}, did you mean {}?", suggestions.join(" or ")),
+            LlmClientError::ContextOverflow { model, tokens, limit, overshoot } => write!(f, "Context overflow: {tokens} tokens is {overshoot} more than the {limit} {model} takes"),
+            LlmClientError::CircuitOpen { llm, retry_in } => write!(f, "Circuit open: {llm} is failing, retry in {:.1} secs", retry_in.as_secs_f64()),
+            LlmClientError::QuotaExceeded { llm, tokens, cost } => write!(f, "Quota exceeded: {llm} has used {tokens} tokens, ${cost:.4} this month"),
+            LlmClientError::UnknownTenant(id) => write!(f, "Unknown tenant: {id}"),
+            LlmClientError::Failed(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for LlmClientError {}
+
+/// Required configuration is missing. Returned boxed, like `LlmClientError`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// Environment variable, such as a URL or model, is not set
+    MissingEnv(String),
+    /// Secret not held by the credentials provider
+    MissingCredential(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::MissingEnv(name) => write!(f, "{name} not found in environment variables"),
+            ConfigError::MissingCredential(name) => write!(f, "{name} not found in credentials"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
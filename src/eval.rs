@@ -0,0 +1,209 @@
+use serde_derive::{Deserialize, Serialize};
+use crate::common::call_llm_model;
+use crate::repair::repair_json;
+
+/// A prompt and the qualities a good answer should have
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EvalCase {
+    pub name: String,
+    pub prompt: String,
+    #[serde(default)]
+    pub qualities: Vec<String>,
+    /// Optional model answer for the judge to compare against
+    #[serde(default)]
+    pub reference: Option<String>,
+}
+
+impl EvalCase {
+    pub fn new(name: &str, prompt: &str) -> Self {
+        EvalCase { name: name.into(), prompt: prompt.into(), qualities: Vec::new(), reference: None }
+    }
+
+    /// Something the judge should look for e.g. "mentions Canberra"
+    pub fn quality(mut self, quality: &str) -> Self {
+        self.qualities.push(quality.into());
+        self
+    }
+
+    pub fn reference(mut self, reference: &str) -> Self {
+        self.reference = Some(reference.into());
+        self
+    }
+
+    /// Read cases from a JSON array
+    pub fn load(path: &str) -> Result<Vec<Self>, Box<dyn std::error::Error + Send>> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+        serde_json::from_str(&text)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+    }
+
+    fn judge_prompt(&self, response: &str) -> String {
+        let qualities = if self.qualities.is_empty() {
+            "- Correct, relevant and clearly expressed".to_string()
+        } else {
+            self.qualities.iter().map(|q| format!("- {q}")).collect::<Vec<_>>().join("\n")
+        };
+        let reference = match self.reference {
+            Some(ref r) => format!("\nReference answer:\n{r}\n"),
+            None => String::new(),
+        };
+
+        format!("Question:\n{}\n{reference}\nResponse to grade:\n{response}\n\nExpected qualities:\n{qualities}", self.prompt)
+    }
+}
+
+const JUDGE_SYSTEM: &str = "You are an impartial judge grading a response to a question. \
+Score it from 0 (useless) to 10 (perfect) against the expected qualities. \
+Reply only with JSON of the form {\"score\": <0-10>, \"reasoning\": \"<one or two sentences>\"}";
+
+#[derive(Debug, Deserialize)]
+struct Verdict {
+    score: f64,
+    #[serde(default)]
+    reasoning: String,
+}
+
+/// Judge's score out of 10 and reasoning from its reply
+pub fn parse_verdict(text: &str) -> Result<(f64, String), Box<dyn std::error::Error + Send>> {
+    let verdict: Verdict = serde_json::from_str(text)
+        .or_else(|_| serde_json::from_str(&repair_json(text)))
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    Ok((verdict.score.clamp(0.0, 10.0), verdict.reasoning))
+}
+
+/// Outcome of one case
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalScore {
+    pub case: String,
+    pub response: String,
+    /// Out of 10, 0 if the case could not be run or judged
+    pub score: f64,
+    pub reasoning: String,
+    pub error: Option<String>,
+}
+
+/// Scores of a candidate model over a set of cases
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalReport {
+    pub llm: String,
+    pub model: String,
+    pub scores: Vec<EvalScore>,
+}
+
+impl EvalReport {
+    /// Mean score over all cases, failures count as 0
+    pub fn mean(&self) -> f64 {
+        if self.scores.is_empty() {
+            0.0
+        } else {
+            self.scores.iter().map(|s| s.score).sum::<f64>() / self.scores.len() as f64
+        }
+    }
+
+    /// Fraction of cases scoring at least `threshold`
+    pub fn pass_rate(&self, threshold: f64) -> f64 {
+        if self.scores.is_empty() {
+            0.0
+        } else {
+            self.scores.iter().filter(|s| s.score >= threshold).count() as f64 / self.scores.len() as f64
+        }
+    }
+
+    pub fn errors(&self) -> usize {
+        self.scores.iter().filter(|s| s.error.is_some()).count()
+    }
+}
+
+impl std::fmt::Display for EvalReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{}: {}", self.llm, self.model)?;
+        for s in &self.scores {
+            match s.error {
+                Some(ref e) => writeln!(f, "  {:<24} ERROR {e}", s.case)?,
+                None => writeln!(f, "  {:<24} {:>4.1} {}", s.case, s.score, s.reasoning)?,
+            }
+        }
+        write!(f, "Mean: {:.2} / 10, passed (>= 7): {:.0}%, errors: {}", self.mean(), self.pass_rate(7.0) * 100.0, self.errors())
+    }
+}
+
+/// Runs cases against a candidate model and has a judge model score them
+#[derive(Debug, Clone)]
+pub struct Evaluator {
+    pub llm: String,
+    pub model: String,
+    pub system: String,
+    pub temperature: f32,
+    pub judge_llm: String,
+    pub judge_model: String,
+}
+
+impl Evaluator {
+    /// Candidate and judge, which should ideally be a stronger model from another provider
+    pub fn new(llm: &str, model: &str, judge_llm: &str, judge_model: &str) -> Self {
+        Evaluator { llm: llm.into(), model: model.into(), system: String::new(), temperature: 0.2, judge_llm: judge_llm.into(), judge_model: judge_model.into() }
+    }
+
+    pub fn system(mut self, system: &str) -> Self {
+        self.system = system.into();
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    async fn score(&self, case: &EvalCase) -> EvalScore {
+        let failed = |response: String, e: String| EvalScore { case: case.name.clone(), response, score: 0.0, reasoning: String::new(), error: Some(e) };
+
+        let response = match call_llm_model(&self.llm, &self.model, &self.system, std::slice::from_ref(&case.prompt), self.temperature, false, false).await {
+            Ok(ret) if ret.llm_type.is_error() => return failed(String::new(), ret.text),
+            Ok(ret) => ret.text,
+            Err(e) => return failed(String::new(), e.to_string()),
+        };
+
+        let verdict = match call_llm_model(&self.judge_llm, &self.judge_model, JUDGE_SYSTEM, &[case.judge_prompt(&response)], 0.0, true, false).await {
+            Ok(ret) if ret.llm_type.is_error() => return failed(response, format!("Judge: {}", ret.text)),
+            Ok(ret) => parse_verdict(&ret.text),
+            Err(e) => return failed(response, format!("Judge: {e}")),
+        };
+
+        match verdict {
+            Ok((score, reasoning)) => EvalScore { case: case.name.clone(), response, score, reasoning, error: None },
+            Err(e) => failed(response, format!("Judge: {e}")),
+        }
+    }
+
+    /// Run and judge every case concurrently
+    pub async fn run(&self, cases: &[EvalCase]) -> EvalReport {
+        let scores = futures::future::join_all(cases.iter().map(|c| self.score(c))).await;
+
+        EvalReport { llm: self.llm.clone(), model: self.model.clone(), scores }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eval_report() {
+        assert_eq!(parse_verdict(r#"{"score": 8, "reasoning": "Mostly right"}"#).unwrap(), (8.0, "Mostly right".into()));
+        assert_eq!(parse_verdict("```json\n{\"score\": 12,}\n```").unwrap(), (10.0, "".into()));
+        assert!(parse_verdict("Great answer!").is_err());
+
+        let case = EvalCase::new("oz", "What is the capital of Oz?").quality("Mentions Canberra");
+        assert!(case.judge_prompt("Canberra").contains("- Mentions Canberra"));
+
+        let score = |case: &str, score, error: Option<&str>| EvalScore { case: case.into(), response: String::new(), score, reasoning: String::new(), error: error.map(|e| e.into()) };
+        let report = EvalReport { llm: "gpt".into(), model: "gpt-4o".into(), scores: vec![score("a", 9.0, None), score("b", 6.0, None), score("c", 0.0, Some("timeout"))] };
+
+        assert_eq!(report.mean(), 5.0);
+        assert_eq!(report.pass_rate(7.0), 1.0 / 3.0);
+        assert_eq!(report.errors(), 1);
+    }
+}
@@ -0,0 +1,87 @@
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+use crate::common::with_response_schema;
+use crate::guardrails::{GuardrailPolicy, Guardrails, JsonSchemaValidator};
+
+/// Times the model is told what is wrong with its JSON and asked again
+const RETRIES: usize = 2;
+
+#[derive(Deserialize, JsonSchema)]
+struct Records<T> {
+    records: Vec<T>,
+}
+
+/// Pull one record of type T, such as an invoice, contact or event, out of
+/// free text. The model is given T's JSON schema and asked for JSON (schema
+/// mode on GPT and Gemini, JSON mode on providers with only that); replies
+/// that do not match the schema are sent back with the errors, up to
+/// twice, before failing with `LlmClientError::Rejected`. Doc comments on
+/// T's fields are passed on as descriptions, so use them to say what a
+/// field should hold.
+pub async fn extract<T: DeserializeOwned + JsonSchema>(llm: &str, model: &str, text: &str) -> Result<T, Box<dyn std::error::Error + Send>> {
+    extracted(llm, model, text, "Extract the record described by this JSON schema from the text").await
+}
+
+/// Every record of type T found in free text, as `extract` does for one
+pub async fn extract_all<T: DeserializeOwned + JsonSchema>(llm: &str, model: &str, text: &str) -> Result<Vec<T>, Box<dyn std::error::Error + Send>> {
+    let records: Records<T> = extracted(llm, model, text, "Extract every record found in the text into the records array of this JSON schema, which is empty if there are none").await?;
+
+    Ok(records.records)
+}
+
+// T from the reply to text, the task followed by T's schema as the system
+// prompt, which is also the response schema for providers that take one
+pub(crate) async fn extracted<T: DeserializeOwned + JsonSchema>(llm: &str, model: &str, text: &str, task: &str) -> Result<T, Box<dyn std::error::Error + Send>> {
+    let schema = serde_json::to_value(schemars::schema_for!(T))
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+    let system = format!("{task}:\n{schema}\n\n\
+        Use null for optional fields the text does not give and never invent values. \
+        Reply only with JSON matching the schema.");
+    let guardrails = Guardrails::new(GuardrailPolicy::Retry(RETRIES))
+        .validator(JsonSchemaValidator::new(&schema)?);
+    let guarded = with_response_schema(schema.clone(),
+        guardrails.call_llm_model(llm, model, &system, &[text.to_string()], 0.0, true, true))
+        .await?;
+
+    if guarded.result.llm_type.is_error() {
+        return Err(Box::new(std::io::Error::other(format!("Extraction failed: {}", guarded.result.text))));
+    }
+
+    serde_json::from_str(&guarded.result.text)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+    struct Contact {
+        name: String,
+        email: String,
+        phone: Option<String>,
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_extract() {
+        let server = crate::mock::mock_post_sequence("/v1/chat/completions", &[(200, "gpt/contact_partial.json"), (200, "gpt/contact.json")]).await;
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        let contact: Contact = extract("gpt", "gpt-4o", "Call Jane Smith on 555 0100 or mail jane@example.com").await.unwrap();
+
+        assert_eq!(contact, Contact { name: "Jane Smith".into(), email: "jane@example.com".into(), phone: Some("555 0100".into()) });
+
+        let requests = server.received_requests().await.unwrap();
+        let first: serde_json::Value = serde_json::from_slice(&requests[0].body).unwrap();
+        let retry = String::from_utf8_lossy(&requests[1].body);
+
+        assert_eq!(first["response_format"]["type"], "json_schema");
+        assert_eq!(first["response_format"]["json_schema"]["schema"]["required"], serde_json::json!(["email", "name"]));
+        assert!(retry.contains("The JSON does not match the schema"));
+    }
+}
@@ -0,0 +1,199 @@
+use reqwest::multipart::{Form, Part};
+use serde_derive::Deserialize;
+use crate::common::*;
+use crate::config::required_env;
+use crate::gpt::get_gpt_client;
+
+// OpenAI Files API: files uploaded once and referred to by id from the
+// Assistants, Batch and fine-tuning endpoints.
+
+/// What an uploaded file is for, which decides where it may be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilePurpose {
+    Assistants,
+    Batch,
+    FineTune,
+    Vision,
+}
+
+impl std::fmt::Display for FilePurpose {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FilePurpose::Assistants => write!(f, "assistants"),
+            FilePurpose::Batch => write!(f, "batch"),
+            FilePurpose::FineTune => write!(f, "fine-tune"),
+            FilePurpose::Vision => write!(f, "vision"),
+        }
+    }
+}
+
+// Output structures
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct FileObject {
+    pub id: String,
+    pub bytes: usize,
+    pub filename: String,
+    pub purpose: String,
+    #[serde(default)]
+    pub created_at: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileList {
+    data: Vec<FileObject>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileDeleted {
+    deleted: bool,
+}
+
+/// Upload a file for the given purpose
+pub async fn upload_file(filename: &str, data: &[u8], purpose: FilePurpose) -> Result<FileObject, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_FILES_URL")?;
+    let client = get_gpt_client().await?;
+
+    let part = Part::bytes(data.to_vec())
+        .file_name(filename.to_string())
+        .mime_str(mime_type(filename))
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+    let form = Form::new()
+        .text("purpose", purpose.to_string())
+        .part("file", part);
+
+    let res = client
+        .post(url)
+        .multipart(form)
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    files_json(&res)
+}
+
+/// Uploaded files, optionally only those for one purpose
+pub async fn list_files(purpose: Option<FilePurpose>) -> Result<Vec<FileObject>, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_FILES_URL")?;
+    let client = get_gpt_client().await?;
+    let mut request = client.get(url);
+
+    if let Some(purpose) = purpose {
+        request = request.query(&[("purpose", purpose.to_string())]);
+    }
+
+    let res = request
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    files_json::<FileList>(&res).map(|list| list.data)
+}
+
+/// Details of an uploaded file
+pub async fn get_file(file_id: &str) -> Result<FileObject, Box<dyn std::error::Error + Send>> {
+    let res = files_get(&format!("/{file_id}")).await?;
+
+    files_json(&String::from_utf8_lossy(&res))
+}
+
+/// Raw content of an uploaded or generated file
+pub async fn get_file_content(file_id: &str) -> Result<Vec<u8>, Box<dyn std::error::Error + Send>> {
+    files_get(&format!("/{file_id}/content")).await
+}
+
+/// Content of a text file such as batch output JSONL
+pub async fn get_file_text(file_id: &str) -> Result<String, Box<dyn std::error::Error + Send>> {
+    let content = get_file_content(file_id).await?;
+
+    String::from_utf8(content)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+}
+
+/// Delete an uploaded file, true if it was deleted
+pub async fn delete_file(file_id: &str) -> Result<bool, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_FILES_URL")?;
+    let client = get_gpt_client().await?;
+
+    let res = client
+        .delete(format!("{url}/{file_id}"))
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    files_json::<FileDeleted>(&res).map(|d| d.deleted)
+}
+
+async fn files_get(path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_FILES_URL")?;
+    let client = get_gpt_client().await?;
+
+    client
+        .get(format!("{url}{path}"))
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .bytes()
+        .await
+        .map(|b| b.to_vec())
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+}
+
+fn files_json<T: serde::de::DeserializeOwned>(res: &str) -> Result<T, Box<dyn std::error::Error + Send>> {
+    if let Ok(err) = serde_json::from_str::<LlmError>(res) {
+        return Err(Box::new(std::io::Error::other(err.error.to_string())));
+    }
+
+    serde_json::from_str(res)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::mock_post;
+    use serial_test::serial;
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, ResponseTemplate};
+
+    #[tokio::test]
+    #[serial]
+    async fn test_upload_and_list_files() {
+        let server = mock_post("/v1/files", 200, "gpt/file.json").await;
+
+        Mock::given(method("GET"))
+            .and(path("/v1/files"))
+            .and(query_param("purpose", "fine-tune"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(format!(r#"{{"object":"list","data":[{}]}}"#, crate::mock::fixture("gpt/file.json"))))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        std::env::set_var("GPT_FILES_URL", format!("{}/v1/files", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        let file = upload_file("train.jsonl", b"{}\n", FilePurpose::FineTune).await.unwrap();
+        let body = String::from_utf8_lossy(&server.received_requests().await.unwrap()[0].body).to_string();
+
+        assert_eq!(file.id, "file-abc123");
+        assert!(body.contains("fine-tune"));
+        assert!(body.contains("filename=\"train.jsonl\""));
+        assert_eq!(list_files(Some(FilePurpose::FineTune)).await.unwrap(), vec![file]);
+    }
+
+    #[test]
+    fn test_files_json_error() {
+        let res = r#"{"error":{"message":"No such File object: file-x","type":"invalid_request_error","param":"id","code":null}}"#;
+
+        assert!(files_json::<FileObject>(res).unwrap_err().to_string().contains("No such File"));
+    }
+}
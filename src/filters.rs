@@ -0,0 +1,162 @@
+use std::sync::{Arc, RwLock};
+use crate::error::LlmClientError;
+
+/// Outcome of filtering a prompt
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterAction {
+    Allow,
+    /// Send this instead
+    Rewrite(String),
+    /// Do not send, for this reason
+    Reject(String),
+}
+
+/// Check or rewrite prompt text before it is sent to any LLM
+pub trait PromptFilter: Send + Sync {
+    fn filter(&self, prompt: &str) -> FilterAction;
+}
+
+/// Reject prompts containing any of the words or phrases, ignoring case
+#[derive(Debug, Clone)]
+pub struct Blocklist {
+    words: Vec<String>,
+}
+
+impl Blocklist {
+    pub fn new(words: &[&str]) -> Self {
+        Blocklist { words: words.iter().map(|w| w.to_lowercase()).collect() }
+    }
+}
+
+impl PromptFilter for Blocklist {
+    fn filter(&self, prompt: &str) -> FilterAction {
+        let prompt = prompt.to_lowercase();
+
+        match self.words.iter().find(|w| prompt.contains(w.as_str())) {
+            Some(word) => FilterAction::Reject(format!("Prompt contains blocked term '{word}'")),
+            None => FilterAction::Allow,
+        }
+    }
+}
+
+/// Reject, or truncate, prompts longer than a number of characters
+#[derive(Debug, Clone, Copy)]
+pub struct LengthLimit {
+    pub max_chars: usize,
+    pub truncate: bool,
+}
+
+impl LengthLimit {
+    pub fn reject(max_chars: usize) -> Self {
+        LengthLimit { max_chars, truncate: false }
+    }
+
+    pub fn truncate(max_chars: usize) -> Self {
+        LengthLimit { max_chars, truncate: true }
+    }
+}
+
+impl PromptFilter for LengthLimit {
+    fn filter(&self, prompt: &str) -> FilterAction {
+        let len = prompt.chars().count();
+
+        if len <= self.max_chars {
+            FilterAction::Allow
+        } else if self.truncate {
+            FilterAction::Rewrite(prompt.chars().take(self.max_chars).collect())
+        } else {
+            FilterAction::Reject(format!("Prompt is {len} characters, the limit is {}", self.max_chars))
+        }
+    }
+}
+
+/// Custom filter e.g. a classifier
+pub struct FnFilter<F>(pub F);
+
+impl<F> PromptFilter for FnFilter<F> where F: Fn(&str) -> FilterAction + Send + Sync {
+    fn filter(&self, prompt: &str) -> FilterAction {
+        (self.0)(prompt)
+    }
+}
+
+/// Filters applied in order, each seeing the output of the last
+#[derive(Default)]
+pub struct FilterChain {
+    filters: Vec<Box<dyn PromptFilter>>,
+}
+
+impl FilterChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn filter(mut self, filter: impl PromptFilter + 'static) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    /// Filtered prompt, or error if any filter rejects it
+    pub fn apply(&self, prompt: &str) -> Result<String, Box<dyn std::error::Error + Send>> {
+        let mut prompt = prompt.to_string();
+
+        for filter in &self.filters {
+            match filter.filter(&prompt) {
+                FilterAction::Allow => {},
+                FilterAction::Rewrite(text) => prompt = text,
+                FilterAction::Reject(reason) => return Err(Box::new(LlmClientError::Rejected(vec![reason]))),
+            }
+        }
+
+        Ok(prompt)
+    }
+}
+
+static FILTERS: RwLock<Option<Arc<FilterChain>>> = RwLock::new(None);
+
+/// Install filters applied to every prompt sent by the common call functions
+pub fn set_prompt_filters(filters: FilterChain) {
+    *FILTERS.write().unwrap() = Some(Arc::new(filters));
+}
+
+pub fn clear_prompt_filters() {
+    *FILTERS.write().unwrap() = None;
+}
+
+/// Apply installed filters to system prompt and the user's turns. In a
+/// chat the LLM's own earlier answers are passed through unchanged.
+pub fn apply_prompt_filters(system: &str, user: &[String], is_chat: bool) -> Result<(String, Vec<String>), Box<dyn std::error::Error + Send>> {
+    let filters = FILTERS.read().unwrap().clone();
+
+    match filters {
+        None => Ok((system.to_string(), user.to_vec())),
+        Some(filters) => {
+            let system = if system.is_empty() { String::new() } else { filters.apply(system)? };
+            let user = user.iter()
+                .enumerate()
+                .map(|(i, u)| if is_chat && i % 2 == 1 { Ok(u.clone()) } else { filters.apply(u) })
+                .collect::<Result<Vec<String>, _>>()?;
+
+            Ok((system, user))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_chain() {
+        let filters = FilterChain::new()
+            .filter(Blocklist::new(&["Project Falcon"]))
+            .filter(FnFilter(|p: &str| FilterAction::Rewrite(p.replace("ACME", "the client"))))
+            .filter(LengthLimit::truncate(20));
+
+        assert_eq!(filters.apply("Tell ACME about the plan").unwrap(), "Tell the client abou");
+
+        let err = filters.apply("What is project falcon?").unwrap_err();
+
+        assert!(matches!(err.downcast_ref::<LlmClientError>(), Some(LlmClientError::Rejected(_))));
+        assert_eq!(LengthLimit::reject(3).filter("four"), FilterAction::Reject("Prompt is 4 characters, the limit is 3".into()));
+    }
+}
@@ -0,0 +1,224 @@
+//! Fill in the middle code completion: given the code before and after the
+//! cursor, the model writes what goes between. The same call works against
+//! Codestral, DeepSeek and OpenAI compatible completion endpoints.
+use reqwest::header::{HeaderMap, HeaderValue};
+use serde_derive::Serialize;
+use crate::common::*;
+use crate::config::{env_var, required_env};
+use crate::credentials::credential;
+use crate::providers::providers;
+use crate::error::LlmClientError;
+use crate::gpt::get_gpt_client;
+use crate::mistral::get_mistral_client;
+
+/// Optional settings for a code completion
+#[derive(Debug, Clone, PartialEq)]
+pub struct FimOptions {
+    /// Provider default if None
+    pub model: Option<String>,
+    pub max_tokens: Option<usize>,
+    pub temperature: f32,
+    /// Stop at any of these e.g. "\n\n" for a single block
+    pub stop: Vec<String>,
+}
+
+impl Default for FimOptions {
+    fn default() -> Self {
+        FimOptions { model: None, max_tokens: Some(256), temperature: 0.0, stop: Vec::new() }
+    }
+}
+
+impl FimOptions {
+    pub fn model(mut self, model: &str) -> Self {
+        self.model = Some(model.into());
+        self
+    }
+
+    pub fn max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn stop(mut self, stop: &str) -> Self {
+        self.stop.push(stop.into());
+        self
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct FimRequest<'a> {
+    model: &'a str,
+    prompt: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    suffix: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<usize>,
+    temperature: f32,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    stop: &'a [String],
+}
+
+/// Default code completion model for named LLM provider
+pub fn get_fim_model(llm: &str) -> String {
+    match llm {
+        "mistral" | "codestral" => env_var("MISTRAL_FIM_MODEL").unwrap_or("codestral-latest".into()),
+        "deepseek" => env_var("DEEPSEEK_FIM_MODEL").unwrap_or("deepseek-chat".into()),
+        "openai" | "gpt" => env_var("GPT_FIM_MODEL").unwrap_or("gpt-3.5-turbo-instruct".into()),
+        "local" => env_var("COMPLETIONS_MODEL").unwrap_or_default(),
+        _ => String::new(),
+    }
+}
+
+/// Complete the code between prefix and suffix with named LLM provider.
+/// The text returned is only the inserted code.
+pub async fn complete_code(llm: &str, prefix: &str, suffix: &str, options: &FimOptions) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    call_completion(llm, prefix, suffix, options).await
+}
+
+// Shared with the plain completions API, which sends no suffix
+pub(crate) async fn call_completion(llm: &str, prefix: &str, suffix: &str, options: &FimOptions) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    let model = options.model.clone().unwrap_or_else(|| get_fim_model(llm));
+    let request = FimRequest { model: &model, prompt: prefix, suffix, max_tokens: options.max_tokens, temperature: options.temperature, stop: &options.stop };
+
+    let (llm_type, url, client) = match llm {
+        "mistral" | "codestral" => {
+            let url = env_var("MISTRAL_FIM_URL").unwrap_or("https://api.mistral.ai/v1/fim/completions".into());
+
+            (LlmType::MISTRAL, url, get_mistral_client().await?)
+        },
+        "deepseek" => {
+            let url = env_var("DEEPSEEK_FIM_URL").unwrap_or("https://api.deepseek.com/beta/completions".into());
+
+            (LlmType::GPT, url, get_deepseek_client().await?)
+        },
+        "openai" | "gpt" => {
+            let url = required_env("GPT_COMPLETIONS_URL")?;
+
+            (LlmType::GPT, url, get_gpt_client().await?)
+        },
+        "local" => {
+            let url = required_env("COMPLETIONS_URL")?;
+
+            (LlmType::GPT, url, get_local_client().await?)
+        },
+        _ => return Err(Box::new(LlmClientError::Unsupported(format!("{llm} completion")))),
+    };
+
+    let start = std::time::Instant::now();
+    let res = client
+        .post(url)
+        .json(&request)
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    let timing = start.elapsed().as_secs() as f64 + start.elapsed().subsec_millis() as f64 / 1000.0;
+
+    fim_response_to_return(llm_type, &res, timing)
+}
+
+/// Unpack a completion response, either OpenAI style with choices[].text
+/// or Codestral style with choices[].message.content
+pub fn fim_response_to_return(llm_type: LlmType, res: &str, timing: f64) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    let error_type = match llm_type {
+        LlmType::MISTRAL => LlmType::MISTRAL_ERROR,
+        _ => LlmType::GPT_ERROR,
+    };
+
+    if res.contains("\"error\"") {
+        return Ok(LlmReturn::new(error_type, res.to_string(), res.to_string().into(), (0, 0, 0), timing, None, None));
+    }
+
+    let res: serde_json::Value = serde_json::from_str(res)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+    let choice = &res["choices"][0];
+    let text = choice["text"].as_str()
+        .or_else(|| choice["message"]["content"].as_str())
+        .unwrap_or_default();
+    let finish_reason: FinishReason = choice["finish_reason"].as_str().unwrap_or("None").into();
+    let usage = &res["usage"];
+    let count = |key: &str| usage[key].as_u64().unwrap_or(0) as usize;
+
+    Ok(LlmReturn::new(llm_type, text.into(), finish_reason, (count("prompt_tokens"), count("completion_tokens"), count("total_tokens")), timing, None, None))
+}
+
+async fn get_deepseek_client() -> Result<reqwest::Client, Box<dyn std::error::Error + Send>> {
+    providers().client("deepseek", new_deepseek_client()).await
+}
+
+async fn new_deepseek_client() -> Result<reqwest::Client, Box<dyn std::error::Error + Send>> {
+    let api_key: String = credential("DEEPSEEK_API_KEY")?;
+    let mut headers: HeaderMap = HeaderMap::new();
+
+    headers.insert(
+        "Authorization",
+        HeaderValue::from_str(&format!("Bearer {}", api_key))
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?,
+    );
+
+    get_client(headers).await
+}
+
+async fn get_local_client() -> Result<reqwest::Client, Box<dyn std::error::Error + Send>> {
+    providers().client("local", new_local_client()).await
+}
+
+// Local runtimes rarely want a key, so COMPLETIONS_API_KEY is optional
+async fn new_local_client() -> Result<reqwest::Client, Box<dyn std::error::Error + Send>> {
+    let mut headers: HeaderMap = HeaderMap::new();
+
+    if let Ok(api_key) = credential("COMPLETIONS_API_KEY") {
+        headers.insert(
+            "Authorization",
+            HeaderValue::from_str(&format!("Bearer {}", api_key))
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?,
+        );
+    }
+
+    get_client(headers).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::mock_post;
+    use serial_test::serial;
+
+    #[test]
+    fn test_fim_response_to_return() {
+        let res = r#"{"id":"cmpl-1","object":"text_completion","choices":[{"text":"a + b","index":0,"finish_reason":"stop"}],"usage":{"prompt_tokens":9,"completion_tokens":3,"total_tokens":12}}"#;
+        let res = fim_response_to_return(LlmType::GPT, res, 0.1).unwrap();
+
+        assert_eq!(res.text, "a + b");
+        assert_eq!(res.usage, (9, 3, 12));
+        assert_eq!(fim_response_to_return(LlmType::MISTRAL, r#"{"object":"error","message":"Unauthorized"}"#, 0.1).unwrap().llm_type, LlmType::MISTRAL_ERROR);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_complete_code_codestral() {
+        let server = mock_post("/v1/fim/completions", 200, "mistral/fim.json").await;
+
+        std::env::set_var("MISTRAL_FIM_URL", format!("{}/v1/fim/completions", server.uri()));
+        std::env::set_var("MISTRAL_API_KEY", "test-key");
+
+        let options = FimOptions::default().stop("\n\n");
+        let res = complete_code("codestral", "def add(a, b):\n    return ", "\n\nprint(add(1, 2))", &options).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&server.received_requests().await.unwrap()[0].body).unwrap();
+
+        assert_eq!(body["model"], "codestral-latest");
+        assert_eq!(body["suffix"], "\n\nprint(add(1, 2))");
+        assert_eq!(body["stop"], serde_json::json!(["\n\n"]));
+        assert_eq!(res.llm_type, LlmType::MISTRAL);
+        assert_eq!(res.text, "a + b");
+        assert_eq!(res.finish_reason, FinishReason::Stop);
+    }
+}
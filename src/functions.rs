@@ -8,7 +8,8 @@ use peg::*;
 use peg::error::ParseError;
 use peg::str::LineCol;
 use stemplate::*;
-use crate::common::{LlmType, LlmReturn};
+use crate::common::{LlmType, LlmReturn, MessageContent};
+use crate::error::LlmClientError;
 use crate::caller::call_my_functions;
 
 // Internal functions for parsing
@@ -16,11 +17,14 @@ use crate::caller::call_my_functions;
 pub struct ParseFunction {
     pub function: String,
     pub arguments: Vec<ParseArgument>,
+    /// Call id, where the LLM gives one, to match results to calls
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
 }
 
 impl ParseFunction {
     fn new(function: &str, arguments: Vec<ParseArgument>) -> Self {
-        ParseFunction { function: function.to_string(), arguments }
+        ParseFunction { function: function.to_string(), arguments, id: None }
     }
 }
 
@@ -36,6 +40,90 @@ impl ParseArgument {
     }
 }
 
+/// Tool call in a GPT compatible response
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    /// Only given by some LLMs, the position otherwise
+    #[serde(default)]
+    pub index: Option<usize>,
+    pub id: String,
+    pub function: ToolCallFunction,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCallFunction {
+    pub name: String,
+    /// JSON object, as a string
+    pub arguments: String,
+}
+
+impl ToolCall {
+    /// Name, arguments and id, as used by `call_actual_function`. Arguments
+    /// that are not a JSON object, other than none at all, are an error.
+    pub fn to_parse_function(&self) -> Result<ParseFunction, Box<dyn std::error::Error + Send>> {
+        let args: HashMap<String, Value> = if self.function.arguments.trim().is_empty() {
+            HashMap::new()
+        } else {
+            serde_json::from_str(&self.function.arguments)
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(LlmClientError::InvalidJson { text: self.function.arguments.clone(), error: e.to_string() }) })?
+        };
+        let mut arguments: Vec<ParseArgument> = args.iter()
+            .map(|(name, value)| match value {
+                String(s) => ParseArgument::new(name, s),
+                v => ParseArgument::new(name, &v.to_string()),
+            })
+            .collect();
+        arguments.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(ParseFunction { function: self.function.name.clone(), arguments, id: Some(self.id.clone()) })
+    }
+}
+
+/// Typed tool calls of the first choice of a GPT compatible response, in index order
+pub fn tool_calls(res: &Value) -> Result<Vec<ToolCall>, Box<dyn std::error::Error + Send>> {
+    let calls = res["choices"][0]["message"]["tool_calls"].clone();
+    let mut calls: Vec<ToolCall> = serde_json::from_value(calls)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    calls.iter_mut().enumerate().for_each(|(i, c)| { c.index.get_or_insert(i); });
+    calls.sort_by_key(|c| c.index);
+
+    Ok(calls)
+}
+
+/// Return for a GPT compatible tool call response, the text being the calls
+/// with their ids as `ParseFunction` JSON
+pub fn tool_calls_to_return(llm_type: LlmType, res: &str, timing: f64) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    let res: Value = serde_json::from_str(res)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+    let funcs: Vec<ParseFunction> = tool_calls(&res)?.iter()
+        .map(|c| c.to_parse_function())
+        .collect::<Result<_, _>>()?;
+    let function_calls = serde_json::to_string(&funcs)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+    let usage = &res["usage"];
+    let tokens = |k: &str| usage[k].as_u64().unwrap_or(0) as usize;
+    let finish = res["choices"][0]["finish_reason"].as_str().unwrap_or("tool_calls");
+
+    Ok(LlmReturn::new(llm_type, function_calls, finish.into(), (tokens("prompt_tokens"), tokens("completion_tokens"), tokens("total_tokens")), timing, None, None))
+}
+
+/// Results for a turn of calls, one per call id in call order, e.g. from
+/// `call_actual_function`, to send back with `GptMessage::from_contents`
+/// or the other providers' `from_content`
+pub fn tool_results(calls: &[ParseFunction], results: &[String]) -> Result<Vec<MessageContent>, Box<dyn std::error::Error + Send>> {
+    if calls.len() != results.len() {
+        return Err(Box::new(std::io::Error::other(format!("{} results for {} tool calls", results.len(), calls.len()))));
+    }
+
+    calls.iter().zip(results)
+        .map(|(call, result)| match call.id {
+            Some(ref id) => Ok(MessageContent::tool_result(id, &call.function, result)),
+            None => Err(Box::new(std::io::Error::other(format!("Tool call {} has no id", call.function))) as Box<dyn std::error::Error + Send>),
+        })
+        .collect()
+}
+
 /// Wrapper used by GPT, Mistral and Groq
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FunctionCall {
@@ -312,6 +400,16 @@ pub fn unpack_functions(h: HashMap<String, Vec<String>>) -> Option<Vec<ParseFunc
     None
 }
 
+/// First value `get_functions` found for a name, empty if there was none
+pub fn found_value(h: &HashMap<String, Vec<String>>, name: &str) -> String {
+    h.get(name).and_then(|v| v.first()).cloned().unwrap_or_default()
+}
+
+/// First value found for a name as a token count, 0 if missing
+pub fn found_count(h: &HashMap<String, Vec<String>>, name: &str) -> usize {
+    found_value(h, name).parse().unwrap_or(0)
+}
+
 pub fn get_functions(val: &Value, found: &Vec<String>) -> HashMap<String, Vec<String>> {
     fn getter(val: &Value, places: &str, found: &mut HashMap<String, Vec<String>>) -> HashMap<String, Vec<String>> {
         let mut v = val;
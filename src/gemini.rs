@@ -1,15 +1,21 @@
 use std::collections::HashMap;
 use reqwest::header::{HeaderMap, HeaderValue};
 use reqwest::Client;
-use std::process::Command;
 use serde_derive::{Deserialize, Serialize};
 use stemplate::Template;
 use base64::prelude::BASE64_STANDARD;
 use base64::Engine;
 use crate::common::*;
+use crate::config::{env_var, provider_defaults, required_env};
+use crate::capabilities::check_parameters;
+use crate::providers::providers;
 use crate::gpt::GptMessage;
 use crate::common::{LlmType, LlmCompletion};
 use crate::functions::*;
+use crate::postprocess::strip_fences_by_default;
+
+// Used when neither the caller nor the configuration give max_tokens
+const MAX_OUTPUT_TOKENS: usize = 8192;
 
 // Input structures
 // Chat
@@ -47,6 +53,59 @@ impl GeminiCompletion {
     pub fn set_tools(&mut self, tools: Option<Vec<FunctionDeclaration>>) {
         self.tools = tools;
     }
+
+    pub fn set_safety_settings(&mut self, safety_settings: Vec<SafetySettings>) {
+        self.safety_settings = safety_settings;
+    }
+
+    pub fn set_generation_config(&mut self, generation_config: GenerationConfig) {
+        self.generation_config = generation_config;
+    }
+
+    /// Assemble completion from common parameters
+    fn completion(system: &str, user: &[String], temperature: f32, is_chat: bool, function: Option<Vec<Function>>) -> Self {
+        let mut contents = Vec::new();
+
+        let system = if function.is_none() {
+            system.to_string()
+        } else {
+            let fc = "This is a function call, find arguments and return function call";
+            if !system.trim().is_empty() {
+                fc.to_string()
+            } else {
+                format!("{fc}. {system}")
+            }
+        };
+
+        if !system.is_empty() {
+            contents.push(Content::text("user", &system));
+            contents.push(Content::text("model", "Understood"));
+        }
+
+        user.iter()
+            .enumerate()
+            .for_each(|(i, c)| {
+                let role = if !is_chat || i % 2 == 0 { "user" } else { "model" };
+
+                contents.push(Content::text(role, c));
+            });
+
+//println!("{:?}", function);
+        GeminiCompletion {
+            contents,
+            system_instruction: None,
+            /*
+            system_instruction: if system.is_empty() {
+                None
+            } else {
+                Some(SystemInstruction { role: "object".to_string(), parts: vec![Part::text(&system)] })
+            },
+            */
+            tools: Some(FunctionDeclaration::functions(function)),
+            safety_settings: SafetySettings::configured(SafetySettings::low_block()),
+            generation_config: GenerationConfig::configured(temperature)
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -78,7 +137,7 @@ impl Default for GeminiCompletion {
             system_instruction: None,
             tools: None,
             safety_settings: Vec::new(),
-            generation_config: GenerationConfig::new(Some(0.2), None, None, 1, Some(8192), None)
+            generation_config: GenerationConfig::configured(provider_defaults("gemini").temperature())
         }
     }
 }
@@ -94,6 +153,11 @@ impl LlmCompletion for GeminiCompletion {
         self.contents.push(Content::text(role, text));
     }
 
+    /// Seed the start of the reply with a final model message
+    fn set_prefill(&mut self, prefill: &str) {
+        self.contents.push(Content::text("model", prefill));
+    }
+
     /// Add single role with multiple strings for parts as single large content
     fn add_many_text(&mut self, role: &str, texts: &[String]) {
         self.contents.push(Content::many_text(role, texts));
@@ -135,63 +199,125 @@ impl LlmCompletion for GeminiCompletion {
     //}
 
     /// Create and call llm by supplying data and common parameters
-    async fn call(system: &str, user: &[String], temperature: f32, _is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-        let model: String = std::env::var("GEMINI_MODEL").expect("GEMINI_MODEL not found in enviroment variables");
+    async fn call(system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+        let model: String = required_env("GEMINI_MODEL")?;
 
-        Self::call_model(&model, system, user, temperature, _is_json, is_chat).await
+        Self::call_model(&model, system, user, temperature, is_json, is_chat).await
     }
 
     /// Create and call llm by supplying data and common parameters
-    async fn call_model(model: &str, system: &str, user: &[String], temperature: f32, _is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-        Self::call_model_function(model, system, user, temperature, _is_json, is_chat, None).await
+    async fn call_model(model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+        Self::call_model_function(model, system, user, temperature, is_json, is_chat, None).await
     }
 
     /// Create and call llm with model/function by supplying data and common parameters
-    async fn call_model_function(model: &str, system: &str, user: &[String], temperature: f32, _is_json: bool, is_chat: bool, function: Option<Vec<Function>>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-        let mut contents = Vec::new();
+    async fn call_model_function(model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool, function: Option<Vec<Function>>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+        let is_json = is_json && function.is_none(); // JSON mode cannot be combined with function calling
+        let mut completion = GeminiCompletion::completion(system, user, temperature, is_chat, function);
 
-        let system = if function.is_none() {
-            system.to_string()
-        } else {
-            let fc = "This is a function call, find arguments and return function call";
-            if !system.trim().is_empty() {
-                fc.to_string()
-            } else {
-                format!("{fc}. {system}")
-            }
-        };
+        if is_json {
+            completion.generation_config.json(current_response_schema().as_ref());
+        }
 
-        if !system.is_empty() {
-            contents.push(Content::text("user", &system));
-            contents.push(Content::text("model", "Understood"));
+        call_gemini_completion_model(Some(model), &completion).await
+    }
+}
+
+/// Create and call Gemini with attachments added as media parts to the final user content
+pub async fn call_gemini_model_attachments(model: &str, system: &str, user: &[String], attachments: &[Attachment], temperature: f32, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    let mut completion = GeminiCompletion::completion(system, user, temperature, is_chat, None);
+
+    if let Some(content) = completion.contents.iter_mut().rev().find(|c| c.role == "user") {
+        attachments.iter()
+            .for_each(|a| content.parts.extend(Part::attachment(a)));
+    }
+
+    call_gemini_completion_model(Some(model), &completion).await
+}
+
+/// Upload audio, video or documents to the Cloud Storage bucket
+/// GEMINI_BUCKET, through GEMINI_FILES_URL, returning an attachment
+/// referring to it by gs:// URI, for media too large to send inline.
+/// Vertex AI only reads files from Cloud Storage, not the AI Studio File
+/// API. Uploads are kept until removed, e.g. by a bucket lifecycle rule.
+pub async fn upload_gemini_file(attachment: &Attachment) -> Result<Attachment, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GEMINI_FILES_URL")?;
+    let bucket: String = required_env("GEMINI_BUCKET")?;
+    let client = get_gemini_client().await?;
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let name = format!("llmclient/{nanos}-{}", attachment.name);
+
+    let res = client
+        .post(format!("{}/{bucket}/o", url.trim_end_matches('/')))
+        .query(&[("uploadType", "media"), ("name", &name)])
+        .header("Content-Type", attachment.mime_type.as_str())
+        .body(attachment.data.clone())
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    let uploaded: GeminiUpload = serde_json::from_str(&res)
+        .map_err(|_| -> Box<dyn std::error::Error + Send> { Box::new(std::io::Error::other(res.clone())) })?;
+
+    Ok(Attachment { uri: Some(format!("gs://{}/{}", uploaded.bucket, uploaded.name)), data: Vec::new(), ..attachment.clone() })
+}
+
+/// Cloud Storage object, as created
+#[derive(Debug, Deserialize)]
+struct GeminiUpload {
+    bucket: String,
+    name: String,
+}
+
+/// Call Gemini with functions, running each function call it makes with
+/// `call` and feeding the results back, until it answers in text. A call
+/// taking longer than `tool_timeout` is reported back to Gemini as timed out.
+/// Usage is totalled over all the iterations.
+#[allow(clippy::too_many_arguments)]
+pub async fn gemini_tool_loop<F, Fut>(model: &str, system: &str, user: &[String], temperature: f32, functions: Vec<Function>, call: F, max_iterations: usize, tool_timeout: std::time::Duration) -> Result<LlmReturn, Box<dyn std::error::Error + Send>>
+where
+    F: Fn(ParseFunction) -> Fut,
+    Fut: std::future::Future<Output = serde_json::Value>,
+{
+    let mut completion = GeminiCompletion::completion(system, user, temperature, false, Some(functions));
+    let mut usage: Triple = (0, 0, 0);
+    let mut timing = 0.0;
+
+    for _ in 0..max_iterations {
+        let mut res = call_gemini_completion_model(Some(model), &completion).await?;
+
+        usage = (usage.0 + res.usage.0, usage.1 + res.usage.1, usage.2 + res.usage.2);
+        timing += res.timing.total;
+
+        if res.llm_type != LlmType::GEMINI_TOOLS {
+            res.usage = usage;
+            res.timing = Timing::new(timing, usage.1);
+
+            return Ok(res);
         }
 
-        user.iter()
-            .enumerate()
-            .for_each(|(i, c)| {
-                let role = if !is_chat || i % 2 == 0 { "user" } else { "model" };
+        let calls: Vec<ParseFunction> = serde_json::from_str(&res.text)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+        let mut responses = Vec::new();
 
-                contents.push(Content::text(role, c));
-            });
+        for c in calls.iter() {
+            let response = tokio::time::timeout(tool_timeout, call(c.clone())).await
+                .unwrap_or_else(|_| serde_json::json!({"error": format!("{} timed out after {:?}", c.function, tool_timeout)}));
 
-//println!("{:?}", function);
-        let completion = GeminiCompletion {
-            contents,
-            system_instruction: None,
-            /*
-            system_instruction: if system.is_empty() {
-                None
-            } else {
-                Some(SystemInstruction { role: "object".to_string(), parts: vec![Part::text(&system)] })
-            },
-            */
-            tools: Some(FunctionDeclaration::functions(function)),
-            safety_settings: SafetySettings::low_block(),
-            generation_config: GenerationConfig::new(Some(temperature), None, None, 1, Some(8192), None)
-        };
+            responses.push(Part::function_response(&c.function, response));
+        }
 
-        call_gemini_completion_model(Some(model), &completion).await
+        completion.contents.push(Content::function_calls(&calls));
+        completion.contents.push(Content::many("function", responses));
     }
+
+    Err(Box::new(std::io::Error::other(format!("Gemini still calling functions after {max_iterations} iterations"))))
 }
 
 /// This is the primary structure for loading a call. See implementation.
@@ -225,9 +351,19 @@ impl Content {
         vec![Self { role: role.into(), parts: vec![Part::file_data(mime_type, file)] }]
     }
 
+    /// Supply the model's function calls, to precede their responses
+    pub fn function_calls(calls: &[ParseFunction]) -> Self {
+        Self::many("model", calls.iter().map(Part::from).collect())
+    }
+
+    /// Supply the result of a function call
+    pub fn function_response(name: &str, response: serde_json::Value) -> Self {
+        Self::one("function", Part::function_response(name, response))
+    }
+
     pub fn message_to_content(messages: &[GptMessage]) -> Vec<Self> {
         let parts: Vec<Part> = messages.iter()
-            .map(|m| Part::text(&m.content))
+            .map(|m| Part::text(&m.content.text()))
             .collect();
 
         vec![Self::many("user", parts)]
@@ -300,9 +436,13 @@ pub enum Part {
     #[serde(rename_all = "camelCase")]
     InlineData { mime_type: String, data: String },
     #[serde(rename_all = "camelCase")]
-    FileData { mime_type: String, file_url: String },
+    FileData { mime_type: String, #[serde(rename = "fileUri")] file_url: String },
     #[serde(rename_all = "camelCase")]
-    VideoMetadata { start_offset: Offset, end_offset: Offset }
+    VideoMetadata { start_offset: Offset, end_offset: Offset },
+    /// Function the model asked for, to be echoed back in the history
+    FunctionCall { name: String, args: serde_json::Value },
+    /// Result of calling a function, fed back for the model to carry on
+    FunctionResponse { name: String, response: serde_json::Value },
 }
 
 impl Part {
@@ -329,12 +469,54 @@ impl Part {
         Part::FileData { mime_type: mime_type.into(), file_url: file_url.into() }
     }
 
+    /// Create function call Part, as the model returned it
+    pub fn function_call(name: &str, args: serde_json::Value) -> Self {
+        Part::FunctionCall { name: name.into(), args }
+    }
+
+    /// Create function response Part. Gemini wants an object, so anything
+    /// else is wrapped as the content of one.
+    pub fn function_response(name: &str, response: serde_json::Value) -> Self {
+        let response = if response.is_object() {
+            response
+        } else {
+            serde_json::json!({"name": name, "content": response})
+        };
+
+        Part::FunctionResponse { name: name.into(), response }
+    }
+
     /// Create Offset Part for inline or uploaded files
     pub fn offset(start_secs: usize, start_nanos: usize, end_secs: usize, end_nanos: usize) -> Self {
         Part::VideoMetadata { start_offset: Offset { seconds: start_secs, nanos: start_nanos },
             end_offset: Offset { seconds: end_secs, nanos: end_nanos }
         }
     }
+
+    /// Create Parts for an attachment, inline or by file URI, followed by
+    /// its offsets if it has any
+    pub fn attachment(attachment: &Attachment) -> Vec<Self> {
+        let mut parts = match attachment.uri {
+            Some(ref uri) => vec![Part::file_data(&attachment.mime_type, uri)],
+            None => vec![Part::inline_data(&attachment.mime_type, &attachment.data)],
+        };
+
+        if let Some((start, end)) = attachment.offsets {
+            parts.push(Part::offset(start.as_secs() as usize, start.subsec_nanos() as usize, end.as_secs() as usize, end.subsec_nanos() as usize));
+        }
+
+        parts
+    }
+}
+
+impl From<&ParseFunction> for Part {
+    fn from(call: &ParseFunction) -> Self {
+        let args: serde_json::Map<String, serde_json::Value> = call.arguments.iter()
+            .map(|a| (a.name.clone(), serde_json::Value::String(a.desc.clone())))
+            .collect();
+
+        Part::function_call(&call.function, serde_json::Value::Object(args))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -409,6 +591,17 @@ impl SafetySettings {
         ]
     }
 
+    /// Blocking from the provider defaults' safety setting if any, otherwise `or`
+    pub fn configured(or: Vec<Self>) -> Vec<Self> {
+        match provider_defaults("gemini").safety.as_deref() {
+            Some("none") => Self::no_block(),
+            Some("low") => Self::low_block(),
+            Some("medium") => Self::med_block(),
+            Some("high") => Self::high_block(),
+            _ => or,
+        }
+    }
+
     /// Custom thresholds for 4 types of blocks
     pub fn blocks(blocks: Vec<(HarmCategory, HarmBlockThreshold)>) -> Vec<Self> {
         blocks.iter()
@@ -417,7 +610,8 @@ impl SafetySettings {
     }
 }
 
-#[derive(Debug, Serialize, Clone)]
+/// Sampling and output settings, build with `GenerationConfig::builder()`
+#[derive(Debug, Serialize, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct GenerationConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -425,17 +619,188 @@ pub struct GenerationConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     top_p: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    top_k: Option<f32>,
+    top_k: Option<usize>,
     candidate_count: usize,
     #[serde(skip_serializing_if = "Option::is_none")]
     max_output_tokens: Option<usize>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    stop_sequences: Option<Vec<String>>
+    stop_sequences: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_schema: Option<serde_json::Value>
 }
 
 impl GenerationConfig {
-    fn new(temperature: Option<f32>, top_p: Option<f32>, top_k: Option<f32>, candidate_count: usize, max_output_tokens: Option<usize>, stop_sequences: Option<Vec<String>>) -> Self {
-        GenerationConfig { temperature, top_p, top_k, candidate_count, max_output_tokens, stop_sequences }
+    fn new(temperature: Option<f32>, top_p: Option<f32>, top_k: Option<usize>, candidate_count: usize, max_output_tokens: Option<usize>, stop_sequences: Option<Vec<String>>) -> Self {
+        GenerationConfig { temperature, top_p, top_k, candidate_count, max_output_tokens, stop_sequences, response_mime_type: None, response_schema: None }
+    }
+
+    // JSON replies, matching the JSON schema given if any
+    fn json(&mut self, schema: Option<&serde_json::Value>) {
+        self.response_mime_type = Some("application/json".into());
+        self.response_schema = schema.map(|s| response_schema(s, s));
+    }
+
+    /// Temperature with top_p, top_k, max_tokens and stop_sequences from the provider defaults
+    fn configured(temperature: f32) -> Self {
+        let defaults = provider_defaults("gemini");
+
+        GenerationConfig::new(Some(temperature), defaults.top_p, defaults.top_k, 1, Some(defaults.max_tokens.unwrap_or(MAX_OUTPUT_TOKENS)), defaults.stop_sequences)
+    }
+
+    /// Start from the provider defaults, see `config::ProviderDefaults`
+    pub fn builder() -> GenerationConfigBuilder {
+        GenerationConfigBuilder { config: GenerationConfig::configured(provider_defaults("gemini").temperature()) }
+    }
+}
+
+// Gemini takes an OpenAPI subset of JSON schema: references inlined, one
+// type with nullable in place of a null type, and no other keywords
+fn response_schema(schema: &serde_json::Value, root: &serde_json::Value) -> serde_json::Value {
+    use serde_json::{Map, Value};
+
+    let Some(object) = schema.as_object() else {
+        return schema.clone();
+    };
+
+    if let Some(reference) = object.get("$ref").and_then(|r| r.as_str()) {
+        let target = reference.strip_prefix('#')
+            .and_then(|pointer| root.pointer(pointer))
+            .unwrap_or(&Value::Null);
+
+        return response_schema(target, root);
+    }
+
+    let mut out = Map::new();
+
+    for (key, value) in object {
+        match key.as_str() {
+            "type" => match value {
+                Value::Array(types) => {
+                    if let Some(t) = types.iter().find(|t| t.as_str() != Some("null")) {
+                        out.insert("type".into(), t.clone());
+                    }
+                    if types.iter().any(|t| t.as_str() == Some("null")) {
+                        out.insert("nullable".into(), Value::Bool(true));
+                    }
+                }
+                _ => { out.insert("type".into(), value.clone()); }
+            },
+            "anyOf" | "oneOf" => {
+                let variants = value.as_array().cloned().unwrap_or_default();
+                let (nulls, others): (Vec<_>, Vec<_>) = variants.iter()
+                    .partition(|v| v.get("type").and_then(|t| t.as_str()) == Some("null"));
+
+                if others.len() == 1 {
+                    if let Value::Object(inner) = response_schema(others[0], root) {
+                        out.extend(inner);
+                    }
+                } else {
+                    out.insert("anyOf".into(), Value::Array(others.iter().map(|v| response_schema(v, root)).collect()));
+                }
+                if !nulls.is_empty() {
+                    out.insert("nullable".into(), Value::Bool(true));
+                }
+            }
+            "allOf" => {
+                if let Some(Value::Object(inner)) = value.as_array().and_then(|a| a.first()).map(|v| response_schema(v, root)) {
+                    out.extend(inner);
+                }
+            }
+            "properties" => {
+                let properties = value.as_object()
+                    .map(|p| p.iter().map(|(k, v)| (k.clone(), response_schema(v, root))).collect())
+                    .unwrap_or_default();
+
+                out.insert(key.clone(), Value::Object(properties));
+            }
+            "items" => { out.insert(key.clone(), response_schema(value, root)); }
+            "description" | "enum" | "required" | "minItems" | "maxItems" | "minimum" | "maximum" => {
+                out.insert(key.clone(), value.clone());
+            }
+            "format" if matches!(value.as_str(), Some("date-time" | "int32" | "int64" | "float" | "double")) => {
+                out.insert(key.clone(), value.clone());
+            }
+            _ => {}
+        }
+    }
+
+    Value::Object(out)
+}
+
+/// Builder for `GenerationConfig`, values are checked against the API's
+/// documented ranges by `build`
+#[derive(Debug, Clone)]
+pub struct GenerationConfigBuilder {
+    config: GenerationConfig,
+}
+
+impl GenerationConfigBuilder {
+    /// 0.0 to 2.0
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.config.temperature = Some(temperature);
+        self
+    }
+
+    /// Nucleus sampling, 0.0 to 1.0
+    pub fn top_p(mut self, top_p: f32) -> Self {
+        self.config.top_p = Some(top_p);
+        self
+    }
+
+    /// Sample from the k most likely tokens, at least 1
+    pub fn top_k(mut self, top_k: usize) -> Self {
+        self.config.top_k = Some(top_k);
+        self
+    }
+
+    /// Responses to generate, 1 to 8
+    pub fn candidate_count(mut self, candidate_count: usize) -> Self {
+        self.config.candidate_count = candidate_count;
+        self
+    }
+
+    /// At least 1
+    pub fn max_output_tokens(mut self, max_output_tokens: usize) -> Self {
+        self.config.max_output_tokens = Some(max_output_tokens);
+        self
+    }
+
+    /// Up to 5 sequences that end generation
+    pub fn stop_sequences(mut self, stop_sequences: &[&str]) -> Self {
+        self.config.stop_sequences = Some(stop_sequences.iter().map(|s| s.to_string()).collect());
+        self
+    }
+
+    pub fn build(self) -> Result<GenerationConfig, Box<dyn std::error::Error + Send>> {
+        let c = &self.config;
+        let mut errors = Vec::new();
+
+        if c.temperature.is_some_and(|t| !(0.0..=2.0).contains(&t)) {
+            errors.push("temperature must be between 0.0 and 2.0");
+        }
+        if c.top_p.is_some_and(|p| !(0.0..=1.0).contains(&p)) {
+            errors.push("top_p must be between 0.0 and 1.0");
+        }
+        if c.top_k == Some(0) {
+            errors.push("top_k must be at least 1");
+        }
+        if !(1..=8).contains(&c.candidate_count) {
+            errors.push("candidate_count must be between 1 and 8");
+        }
+        if c.max_output_tokens == Some(0) {
+            errors.push("max_output_tokens must be at least 1");
+        }
+        if c.stop_sequences.as_ref().is_some_and(|s| s.len() > 5) {
+            errors.push("at most 5 stop_sequences are allowed");
+        }
+
+        if errors.is_empty() {
+            Ok(self.config)
+        } else {
+            Err(Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid Gemini generation config: {}", errors.join(", ")))))
+        }
     }
 }
 
@@ -497,9 +862,20 @@ impl FunctionDeclarations {
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GeminiResponse {
+    /// None at all when the prompt was blocked
+    #[serde(default)]
     pub candidates: Vec<Candidate>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub usage_metadata: Option<Usage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_feedback: Option<PromptFeedback>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PromptFeedback {
+    /// Why the prompt was blocked, e.g. SAFETY, if it was
+    pub block_reason: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -591,8 +967,12 @@ impl std::fmt::Display for PublicationDate {
 #[derive(Debug, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Usage {
+    #[serde(default)]
     pub prompt_token_count: usize,
+    /// Missing when the prompt was blocked
+    #[serde(default)]
     pub candidates_token_count: usize,
+    #[serde(default)]
     pub total_token_count: usize,
 }
 
@@ -636,7 +1016,7 @@ pub async fn call_gemini(contents: Vec<Content>) -> Result<LlmReturn, Box<dyn st
 
 /// Call Large Language Model (i.e. Google Gemini) with 'system context' and defaults
 pub async fn call_gemini_system(system: Option<&str>, contents: Vec<Content>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-    call_gemini_system_all(system, contents, SafetySettings::high_block(), GenerationConfig::new(Some(0.2), None, None, 1, Some(8192), None)).await
+    call_gemini_system_all(system, contents, SafetySettings::configured(SafetySettings::high_block()), GenerationConfig::configured(provider_defaults("gemini").temperature())).await
 }
 
 /// Call Large Language Model (i.e. Google Gemini) with all parameters supplied
@@ -659,32 +1039,38 @@ pub async fn call_gemini_completion(gemini_completion: &GeminiCompletion) -> Res
     call_gemini_completion_model(None, gemini_completion).await
 }
 
+// Settings the Gemini URL templates refer to, looked up here as they may be
+// LLMCLIENT_ prefixed
+fn gemini_url_vars() -> HashMap<&'static str, String> {
+    ["GEMINI_URL", "GEMINI_MODELS_URL", "GEMINI_REGION", "GEMINI_PROJECT_ID", "GEMINI_MODEL", "GEMINI_VERSION"].iter()
+        .filter_map(|name| env_var(name).map(|value| (*name, value)))
+        .collect()
+}
+
 /// Pass a pre-assembled completion object 
 pub async fn call_gemini_completion_model(model: Option<&str>, gemini_completion: &GeminiCompletion) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
     let start = std::time::Instant::now();
-    let mut env = HashMap::new();
-    match model {
-        None => if let Ok(gemini_model) = std::env::var("GEMINI_MODEL") {
-                    env.insert("GEMINI_MODEL", gemini_model);
-                },
-        Some(model) => {
-            env.insert("GEMINI_MODEL", model.into());
-        },
+    let mut env = gemini_url_vars();
+    if let Some(model) = model {
+        env.insert("GEMINI_MODEL", model.into());
     }
+    required_env("GEMINI_URL")?;
+    let config = &gemini_completion.generation_config;
+    check_parameters("gemini", env.get("GEMINI_MODEL").map(|m| m.as_str()).unwrap_or_default(), config.temperature, config.top_p, config.max_output_tokens)?;
     let url: String = Template::new("${GEMINI_URL}").render(&env);
     let client = get_gemini_client().await?;
 //println!("gemini_completion: {:?}", serde_json::to_string(&gemini_completion));
 
     // Extract Response
-    let res = client
+    let res = send_idempotent(client
         .post(url)
-        .json(gemini_completion)
-        .send()
+        .json(gemini_completion))
         .await;
 
     //let res: Vec<GeminiResponse> = res
+    let res = res?;
+    let status = res.status();
     let res = res
-        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
         //.json()
         .text()
         .await
@@ -692,11 +1078,23 @@ pub async fn call_gemini_completion_model(model: Option<&str>, gemini_completion
 
     let timing = start.elapsed().as_secs() as f64 + start.elapsed().subsec_millis() as f64 / 1000.0;
 
+    gemini_response_to_return(&res, timing).map(|r| r.with_status(status))
+}
+
+/// Unpack the raw text of a Gemini response into an LlmReturn
+pub fn gemini_response_to_return(res: &str, timing: f64) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
 //println!("res: {res}");
     if res.contains("\"error\":") {
-        let res: Vec<LlmError> = serde_json::from_str(&res).unwrap();
+        // Usually in an array like responses, but not always
+        let error = serde_json::from_str::<Vec<LlmError>>(res).ok()
+            .and_then(|errors| errors.into_iter().next())
+            .or_else(|| serde_json::from_str::<LlmError>(res).ok());
+        let text = match error {
+            Some(error) => error.error.to_string(),
+            None => res.to_string(),
+        };
 
-        Ok(LlmReturn::new(LlmType::GEMINI_ERROR, res[0].error.to_string(), res[0].error.to_string(), (0, 0, 0), timing, None, None))
+        Ok(LlmReturn::new(LlmType::GEMINI_ERROR, text.clone(), text.into(), (0, 0, 0), timing, None, None))
     } else if res.contains("\"functionCall\"") {
         let found = vec![
             "candidates:content:parts:functionCall:args:${args}".to_string(),
@@ -706,18 +1104,23 @@ pub async fn call_gemini_completion_model(model: Option<&str>, gemini_completion
             "usageMetadata:totalTokenCount:${total}".to_string(),
 //            "usageMetadata:${usage}".to_string(),
             "candidates:finishReason:${finish}".to_string()];
-        let f: serde_json::Value = serde_json::from_str(&res).unwrap();
+        let f: serde_json::Value = match serde_json::from_str(res) {
+            Ok(f) => f,
+            Err(e) => return Ok(unexpected_response(LlmType::GEMINI_ERROR, res, e, timing)),
+        };
         let h = get_functions(&f, &found);
         let funcs = unpack_functions(h.clone());
         let function_calls = serde_json::to_string(&funcs).unwrap();
 //println!("{:?}", serde_json::from_str::<Vec<ParseFunction>>(&function_calls).unwrap());
-        let (i, o, t) = (h.get("in").unwrap()[0].clone(), h.get("out").unwrap()[0].clone(), h.get("total").unwrap()[0].clone());
-        let triple = (i.parse::<usize>().unwrap(), o.parse::<usize>().unwrap(), t.parse::<usize>().unwrap());
-        let finish = h.get("finish").unwrap()[0].clone();
+        let triple = (found_count(&h, "in"), found_count(&h, "out"), found_count(&h, "total"));
+        let finish = found_value(&h, "finish");
 
-        Ok(LlmReturn::new(LlmType::GEMINI_TOOLS, function_calls, finish, triple, timing, None, None))
+        Ok(LlmReturn::new(LlmType::GEMINI_TOOLS, function_calls, finish.into(), triple, timing, None, None))
     } else {
-        let res: Vec<GeminiResponse> = serde_json::from_str(&res).unwrap();
+        let res: Vec<GeminiResponse> = match serde_json::from_str(res) {
+            Ok(res) => res,
+            Err(e) => return Ok(unexpected_response(LlmType::GEMINI_ERROR, res, e, timing)),
+        };
 
         // Now unpack it
         let text: String = res.iter()
@@ -738,6 +1141,12 @@ pub async fn call_gemini_completion_model(model: Option<&str>, gemini_completion
                 if let Some(finish) = &c.finish_reason { finish.clone() } else { "".into() }
             })
             .collect::<String>()).collect();
+        // A blocked prompt has no candidates, only the reason it was blocked
+        let finish_reason = if finish_reason.is_empty() {
+            res.iter().find_map(|gr| gr.prompt_feedback.as_ref()?.block_reason.clone()).unwrap_or_default()
+        } else {
+            finish_reason
+        };
         let safety_ratings: Vec<String> = res.iter()
             .map(|gr| gr.candidates.iter()
                 .map(|c| if c.safety_ratings.is_some() {
@@ -769,11 +1178,9 @@ pub async fn call_gemini_completion_model(model: Option<&str>, gemini_completion
             });
 
         // Remove any comments
-        let text = text.lines()
-            .filter(|l| !l.starts_with("```"))
-            .fold(String::new(), |s, l| s + l + "\n");
+        let text = strip_fences_by_default(&text);
 
-        Ok(LlmReturn::new(LlmType::GEMINI, text, finish_reason, usage, timing,
+        Ok(LlmReturn::new(LlmType::GEMINI, text, finish_reason.into(), usage, timing,
                           if citations.is_empty() { None } else { Some(citations) },
                           if safety_ratings.is_empty() { None } else { Some(safety_ratings) }
                           ))
@@ -789,16 +1196,65 @@ pub fn add_system_content(system: Option<&str>, contents: Vec<Content>) -> Vec<C
     }
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiModels {
+    #[serde(default)]
+    pub publisher_models: Vec<GeminiModel>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiModel {
+    /// Of the form publishers/google/models/<id>
+    pub name: String,
+    pub version_id: Option<String>,
+}
+
+/// List models available from Google
+pub async fn list_gemini_models() -> Result<Vec<ModelInfo>, Box<dyn std::error::Error + Send>> {
+    required_env("GEMINI_MODELS_URL")?;
+    let url: String = Template::new("${GEMINI_MODELS_URL}").render(&gemini_url_vars());
+    let client = get_gemini_client().await?;
+
+    let res = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    gemini_models_to_info(&res)
+}
+
+fn gemini_models_to_info(res: &str) -> Result<Vec<ModelInfo>, Box<dyn std::error::Error + Send>> {
+    if res.contains("\"error\"") {
+        return Err(Box::new(std::io::Error::other(res.to_string())));
+    }
+
+    let models: GeminiModels = serde_json::from_str(res)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    Ok(models.publisher_models.into_iter()
+        .map(|m| ModelInfo {
+            id: m.name.rsplit('/').next().unwrap_or(&m.name).to_string(),
+            context_window: None,
+            created: None,
+        })
+        .collect())
+}
+
 async fn get_gemini_client() -> Result<Client, Box<dyn std::error::Error + Send>> {
-    // Extract API Key information
-    let output = Command::new("gcloud")
-        .arg("auth")
-        .arg("print-access-token")
-        .output()
-        .expect("Failed to execute command");
+    let providers = providers();
+    // Token first, a refresh drops clients made with the old one
+    let api_key = providers.gemini_access_token().await?;
 
-    let api_key: String = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    providers.client("gemini", new_gemini_client(api_key)).await
+}
 
+pub(crate) async fn new_gemini_client(api_key: String) -> Result<Client, Box<dyn std::error::Error + Send>> {
     // Create headers
     let mut headers: HeaderMap = HeaderMap::new();
 
@@ -815,100 +1271,228 @@ async fn get_gemini_client() -> Result<Client, Box<dyn std::error::Error + Send>
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mock::{mock_post, mock_post_sequence};
+    use serial_test::serial;
+    use wiremock::MockServer;
 
-    async fn gemini(content: Vec<Content>) {
-        match call_gemini(content).await {
-            Ok(ret) => { println!("{ret}"); assert!(true) },
-            Err(e) => { println!("{e}"); assert!(false) },
+    #[test]
+    fn test_gemini_models_to_info() {
+        let res = r#"{"publisherModels":[{"name":"publishers/google/models/gemini-1.5-pro","versionId":"001"}]}"#;
+        let models = gemini_models_to_info(res).unwrap();
+
+        assert_eq!(models[0].id, "gemini-1.5-pro");
+    }
+
+    #[test]
+    fn test_function_response_round_trip() {
+        let call: ParseFunction = serde_json::from_str(r#"{"function":"arithmetic","arguments":[{"name":"expr","desc":"(60 * 24) * 365.25"}]}"#).unwrap();
+        let contents = vec![Content::function_calls(&[call]), Content::function_response("arithmetic", serde_json::json!(525960))];
+
+        assert_eq!(serde_json::to_string(&contents).unwrap(),
+            r#"[{"role":"model","parts":[{"functionCall":{"name":"arithmetic","args":{"expr":"(60 * 24) * 365.25"}}}]},{"role":"function","parts":[{"functionResponse":{"name":"arithmetic","response":{"content":525960,"name":"arithmetic"}}}]}]"#);
+    }
+
+    #[test]
+    fn test_generation_config_builder() {
+        let config = GenerationConfig::builder().temperature(0.7).top_k(40).stop_sequences(&["END"]).build().unwrap();
+        let json = serde_json::to_string(&config).unwrap();
+
+        assert!(json.contains("\"topK\":40"));
+        assert!(json.contains("\"stopSequences\":[\"END\"]"));
+        assert!(json.contains("\"candidateCount\":1"));
+
+        let err = GenerationConfig::builder().top_p(1.5).candidate_count(0).build().unwrap_err();
+        assert!(err.to_string().contains("top_p"));
+        assert!(err.to_string().contains("candidate_count"));
+    }
+
+    #[test]
+    fn test_response_schema() {
+        #[derive(schemars::JsonSchema)]
+        #[allow(dead_code)]
+        struct Address { city: String }
+
+        #[derive(schemars::JsonSchema)]
+        #[allow(dead_code)]
+        struct Contact {
+            /// Full name
+            name: String,
+            phone: Option<String>,
+            age: u32,
+            address: Option<Address>,
         }
+
+        let schema = serde_json::to_value(schemars::schema_for!(Contact)).unwrap();
+        let mut config = GenerationConfig::builder().build().unwrap();
+
+        config.json(Some(&schema));
+
+        let json = serde_json::to_value(&config).unwrap();
+        let properties = &json["responseSchema"]["properties"];
+
+        assert_eq!(json["responseMimeType"], "application/json");
+        assert!(json["responseSchema"].get("$schema").is_none());
+        assert_eq!(properties["name"], serde_json::json!({"type": "string", "description": "Full name"}));
+        assert_eq!(properties["phone"], serde_json::json!({"type": "string", "nullable": true}));
+        assert_eq!(properties["age"], serde_json::json!({"type": "integer", "minimum": 0.0}));
+        assert_eq!(properties["address"]["nullable"], true);
+        assert_eq!(properties["address"]["properties"]["city"]["type"], "string");
+    }
+
+    const FUNC_DEF: &str =
+r#"
+// Derive the value of the arithmetic expression
+// expr: An arithmetic expression
+fn arithmetic(expr)
+"#;
+
+    // Point Gemini at a mock server answering with the given fixture
+    async fn mock_gemini(status: u16, fixture: &str) -> MockServer {
+        let route = "/v1/publishers/google/models/gemini-1.5-pro:streamGenerateContent";
+        let server = mock_post(route, status, fixture).await;
+
+        std::env::set_var("GEMINI_URL", format!("{}{route}", server.uri()));
+        std::env::set_var("GEMINI_ACCESS_TOKEN", "test-token");
+        std::env::set_var("GEMINI_MODEL", "gemini-1.5-pro");
+
+        server
     }
 
     #[tokio::test]
-    async fn test_call_gemini_basic() {
-        let messages =
-            vec![Content::text("user", "What is the meaining of life?")];
-        gemini(messages).await;
+    #[serial]
+    async fn test_call_gemini_success() {
+        let _server = mock_gemini(200, "gemini/success.json").await;
+        let messages = vec!["Hello".to_string()];
+        let res = GeminiCompletion::call_model("gemini-1.5-pro", "Be brief", &messages, 0.2, false, true).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::GEMINI);
+        assert_eq!(res.text.trim(), "Hello there.");
+        assert_eq!(res.finish_reason, FinishReason::Stop);
+        assert_eq!(res.usage, (12, 3, 15));
     }
+
     #[tokio::test]
-    async fn test_call_gemini_citation() {
-        let messages =
-            vec![Content::text("user", "Give citations for the General theory of Relativity.")];
-        gemini(messages).await;
+    #[serial]
+    async fn test_upload_gemini_audio() {
+        let server = mock_post("/upload/storage/v1/b/llmclient-uploads/o", 200, "gemini/upload.json").await;
+
+        std::env::set_var("GEMINI_FILES_URL", format!("{}/upload/storage/v1/b", server.uri()));
+        std::env::set_var("GEMINI_BUCKET", "llmclient-uploads");
+        std::env::set_var("GEMINI_ACCESS_TOKEN", "test-token");
+
+        let audio = Attachment::new("talk.flac", "audio/flac", vec![1, 2, 3]).with_offsets(std::time::Duration::ZERO, std::time::Duration::from_secs(60));
+        let uploaded = upload_gemini_file(&audio).await.unwrap();
+        let request = &server.received_requests().await.unwrap()[0];
+
+        assert_eq!(request.headers.get("content-type").unwrap(), "audio/flac");
+        assert_eq!(request.body, vec![1, 2, 3]);
+        assert!(request.url.query().unwrap().starts_with("uploadType=media&name=llmclient%2F"));
+        assert_eq!(uploaded.uri.as_deref(), Some("gs://llmclient-uploads/llmclient/1720958400000000000-talk.flac"));
+        assert!(uploaded.data.is_empty());
+        assert_eq!(uploaded.offsets, audio.offsets);
     }
+
     #[tokio::test]
-    async fn test_call_gemini_poem() {
-        let messages =
-            vec![Content::text("user", "Write a creative poem about the interplay of artificial intelligence and the human spirit and provide citations")];
-        gemini(messages).await;
+    #[serial]
+    async fn test_call_gemini_video_offsets() {
+        let server = mock_gemini(200, "gemini/success.json").await;
+        let video = Attachment::from_uri("match.mp4", "video/mp4", "gs://bucket/match.mp4")
+            .with_offsets(std::time::Duration::from_secs(90), std::time::Duration::from_millis(120500));
+        let res = call_llm_model_with_attachments("gemini", "gemini-1.5-pro", "", &["Who scores?".to_string()], &[video], 0.2, false, false).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&server.received_requests().await.unwrap()[0].body).unwrap();
+        let parts = &body["contents"][0]["parts"];
+
+        assert_eq!(res.llm_type, LlmType::GEMINI);
+        assert_eq!(parts[1], serde_json::json!({"fileData": {"mimeType": "video/mp4", "fileUri": "gs://bucket/match.mp4"}}));
+        assert_eq!(parts[2]["videoMetadata"]["endOffset"], serde_json::json!({"seconds": 120, "nanos": 500000000}));
     }
+
     #[tokio::test]
-    async fn test_call_gemini_logic() {
-        let messages =
-            vec![Content::text("user", "How many brains does an octopus have, when they have been injured and lost a leg?")];
-        gemini(messages).await;
+    #[serial]
+    async fn test_call_gemini_error() {
+        let _server = mock_gemini(401, "gemini/error.json").await;
+        let res = call_gemini(vec![Content::text("user", "Hello")]).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::GEMINI_ERROR);
+        assert!(res.text.contains("invalid authentication credentials"));
+        assert_eq!(res.usage, (0, 0, 0));
     }
     #[tokio::test]
-    async fn test_call_gemini_dialogue() {
-        let system = "Use a Scottish accent to answer questions";
-        let mut messages = 
-            vec!["How many brains does an octopus have, when they have been injured and lost a leg?".to_string()];
-        let res = GeminiCompletion::call(&system, &messages, 0.2, false, true).await;
-        println!("{res:?}");
-
-        messages.push(res.unwrap().to_string());
-        messages.push("Is a cuttle fish similar?".to_string());
-
-        let res = GeminiCompletion::call(&system, &messages, 0.2, false, true).await;
-        println!("{res:?}");
+    #[serial]
+    async fn test_call_gemini_safety() {
+        let _server = mock_gemini(200, "gemini/safety.json").await;
+        let res = call_gemini(vec![Content::text("user", "Hello")]).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::GEMINI);
+        assert_eq!(res.text.trim(), "");
+        assert_eq!(res.finish_reason, FinishReason::ContentFilter);
+        assert_eq!(res.usage, (14, 0, 14));
+        assert!(res.safety_ratings.unwrap()[0].contains("HARM_CATEGORY_DANGEROUS_CONTENT"));
     }
     #[tokio::test]
-    async fn test_call_gemini_dialogue_model() {
-        let model: String = std::env::var("GEMINI_MODEL").expect("GEMINI_MODEL not found in enviroment variables");
-        let messages = vec!["Hello".to_string()];
-        let res = GeminiCompletion::call_model(&model, "", &messages, 0.2, false, true).await;
-        println!("{res:?}");
+    #[serial]
+    async fn test_call_gemini_blocked() {
+        let _server = mock_gemini(200, "gemini/blocked.json").await;
+        let res = call_gemini(vec![Content::text("user", "Hello")]).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::GEMINI);
+        assert_eq!(res.text, "");
+        assert_eq!(res.finish_reason, FinishReason::ContentFilter);
+        assert_eq!(res.usage, (9, 0, 9));
     }
     #[tokio::test]
-    async fn test_call_function_gemini() {
-        let model: String = std::env::var("GEMINI_MODEL").expect("GEMINI_MODEL not found in enviroment variables");
-        let messages =  vec!["The answer is (60 * 24) * 365.25".to_string()];
-        let func_def =
-r#"
-// Derive the value of the arithmetic expression
-// expr: An arithmetic expression
-fn arithmetic(expr)
-"#;
-        let functions = get_function_json("gemini", &[func_def]);
-        let res = GeminiCompletion::call_model_function(&model, "", &messages, 0.2, false, true, functions).await;
-        println!("{res:?}");
+    #[serial]
+    async fn test_call_gemini_empty_candidates() {
+        let _server = mock_gemini(200, "gemini/empty_candidates.json").await;
+        let res = call_gemini(vec![Content::text("user", "Hello")]).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::GEMINI);
+        assert_eq!(res.text, "");
+        assert_eq!(res.usage, (9, 0, 9));
+    }
+    #[tokio::test]
+    #[serial]
+    async fn test_call_gemini_error_object() {
+        let _server = mock_gemini(429, "gemini/error_object.json").await;
+        let res = call_gemini(vec![Content::text("user", "Hello")]).await.unwrap();
 
-        let answer = call_actual_function(res.ok());
-        println!("{answer:?}");
+        assert_eq!(res.llm_type, LlmType::GEMINI_ERROR);
+        assert!(res.text.contains("Resource has been exhausted"));
     }
     #[tokio::test]
-    async fn test_call_function_common_gemini() {
-        let messages =  vec!["The answer is (60 * 24) * 365.25".to_string()];
-        let func_def =
-r#"
-// Recognize and derive the value of an arithmetic expression
-// expr: An arithmetic expression
-fn arithmetic(expr)
-"#;
-        // This does not work in Gemnini yet
-/*
-        let messages = vec!["a fruit that is red with a sweet taste".to_string()];
-        let func_def2 =
-r#"
-// Find the color of an apple and its taste pass them to this function
-// color: The color of an apple
-// taste: The taste of an apple
-fn apple(color, taste)
-"#;
-*/
-        let res = call_function_llm("gemini", &messages, &[func_def]).await;
-        println!("{res:?}");
+    #[serial]
+    async fn test_call_function_gemini() {
+        let _server = mock_gemini(200, "gemini/function_call.json").await;
+        let messages = vec!["The answer is (60 * 24) * 365.25".to_string()];
+        let functions = get_function_json("gemini", &[FUNC_DEF]);
+        let res = GeminiCompletion::call_model_function("gemini-1.5-pro", "", &messages, 0.2, false, true, functions).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::GEMINI_TOOLS);
+        assert_eq!(res.finish_reason, FinishReason::Stop);
+        assert_eq!(res.usage, (48, 9, 57));
+        assert_eq!(call_actual_function(Some(res)), vec!["arithmetic -> 525960"]);
+    }
+    #[tokio::test]
+    #[serial]
+    async fn test_gemini_tool_loop() {
+        let route = "/v1/publishers/google/models/gemini-1.5-pro:streamGenerateContent";
+        let server = mock_post_sequence(route, &[(200, "gemini/function_call.json"), (200, "gemini/success.json")]).await;
+
+        std::env::set_var("GEMINI_URL", format!("{}{route}", server.uri()));
+        std::env::set_var("GEMINI_ACCESS_TOKEN", "test-token");
+
+        let messages = vec!["How many minutes in a year? (60 * 24) * 365.25".to_string()];
+        let functions: Vec<Function> = serde_json::from_str(r#"[{"name": "arithmetic", "description": "Derive the value of the arithmetic expression",
+            "parameters": {"type": "object", "properties": {"expr": {"type": "string", "description": "An arithmetic expression"}}, "required": ["expr"]}}]"#).unwrap();
+        let res = gemini_tool_loop("gemini-1.5-pro", "", &messages, 0.2, functions, |f| async move {
+            serde_json::json!(call_actual_function(Some(LlmReturn::new(LlmType::GEMINI_TOOLS, serde_json::to_string(&[f]).unwrap(), FinishReason::ToolCalls, (0, 0, 0), 0.0, None, None))))
+        }, 3, std::time::Duration::from_secs(5)).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::GEMINI);
+        assert_eq!(res.usage, (48 + 12, 9 + 3, 57 + 15));
+
+        let body = String::from_utf8(server.received_requests().await.unwrap()[1].body.clone()).unwrap();
 
-        let answer = call_actual_function(res.ok());
-        println!("{answer:?}");
+        assert!(body.contains(r#""functionResponse":{"name":"arithmetic","response":{"content":["arithmetic -> 525960"],"name":"arithmetic"}}"#));
     }
 }
@@ -1,9 +1,15 @@
 use reqwest::header::{HeaderMap, HeaderValue};
 use reqwest::Client;
-use std::env;
 use serde_derive::{Deserialize, Serialize};
 use crate::common::*;
+use crate::config::{env_var, provider_defaults, required_env};
+use crate::credentials::credential;
+use crate::capabilities::check_parameters;
+use crate::error::{ConfigError, LlmClientError};
+use crate::providers::providers;
 use crate::functions::*;
+use crate::postprocess::strip_fences_by_default;
+use crate::audio::{Transcription, TranscriptionOptions, whisper};
 
 // Input structures
 // Chat
@@ -17,18 +23,56 @@ pub struct GptCompletion {
     pub messages: Vec<GptMessage>,
     pub response_format: ResponseFormat,
     pub temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
 }
 
 impl GptCompletion {
+    // Completion from common parameters, shared by the calls with and without attachments
+    fn completion(model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool, function: Option<Vec<Function>>) -> Self {
+        let mut messages = Vec::new();
+
+        if !system.is_empty() {
+            messages.push(GptMessage::text("system", system));
+        }
+
+        user.iter()
+            .enumerate()
+            .for_each(|(i, c)| {
+                let role = if !is_chat || i % 2 == 0 { "user" } else { "assistant" };
+
+                messages.push(GptMessage::text(role, c));
+            });
+
+//println!("{:?}", function);
+        let defaults = provider_defaults("gpt");
+
+        GptCompletion {
+            model: model.into(),
+            tools: Some(FunctionCall::functions(function)),
+            messages,
+            temperature,
+            top_p: defaults.top_p,
+            max_tokens: defaults.max_tokens,
+            response_format: ResponseFormat::configured(is_json)
+        }
+    }
+
     /// Create chat completion
     pub fn new(messages: Vec<GptMessage>, temperature: f32, is_json: bool) -> Self {
-        let model: String = env::var("GPT_MODEL").expect("GPT_MODEL not found in enviroment variables");
+        let model: String = env_var("GPT_MODEL").unwrap_or_default(); // checked when called
+
+        let defaults = provider_defaults("gpt");
 
         GptCompletion {
             model,
             tools: None,
             messages,
             temperature,
+            top_p: defaults.top_p,
+            max_tokens: defaults.max_tokens,
             response_format: ResponseFormat::new(is_json)
         }
     }
@@ -54,18 +98,39 @@ impl GptCompletion {
     pub fn add_messages(&mut self, messages: &[GptMessage]) {
         messages.iter().for_each(|m| self.messages.push(m.clone()));
     }
+
+    /// Add a reply as the assistant turn of a dialogue. DeepSeek rejects
+    /// requests with reasoning in earlier turns, so only keep it if asked
+    pub fn add_reply(&mut self, reply: &LlmReturn, keep_reasoning: bool) {
+        let mut message = GptMessage::text("assistant", &reply.text);
+
+        if keep_reasoning {
+            message.reasoning_content = reply.reasoning.clone();
+        }
+
+        self.messages.push(message);
+    }
+
+    /// Drop reasoning from all messages before sending them back
+    pub fn strip_reasoning(&mut self) {
+        self.messages.iter_mut().for_each(|m| m.reasoning_content = None);
+    }
 }
 
 impl Default for GptCompletion {
     /// Create default chat completion
     fn default() -> Self {
-        let model: String = env::var("GPT_MODEL").expect("GPT_MODEL not found in enviroment variables");
+        let model: String = env_var("GPT_MODEL").unwrap_or_default(); // checked when called
+
+        let defaults = provider_defaults("gpt");
 
         GptCompletion {
             model,
             tools: None,
             messages: Vec::new(),
-            temperature: 0.2,
+            temperature: defaults.temperature(),
+            top_p: defaults.top_p,
+            max_tokens: defaults.max_tokens,
             response_format: ResponseFormat::new(false)
         }
     }
@@ -136,7 +201,7 @@ impl LlmCompletion for GptCompletion {
     */
     /// Create and call llm by supplying data and common parameters
     async fn call(system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-        let model: String = env::var("GPT_MODEL").expect("GPT_MODEL not found in enviroment variables");
+        let model: String = required_env("GPT_MODEL")?;
 
         Self::call_model(&model, system, user, temperature, is_json, is_chat).await
     }
@@ -148,31 +213,7 @@ impl LlmCompletion for GptCompletion {
 
     /// Create and call llm with model/function by supplying data and common parameters
    async fn call_model_function(model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool, function: Option<Vec<Function>>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-        let mut messages = Vec::new();
-
-        if !system.is_empty() {
-            messages.push(GptMessage { role: "system".into(), content: system.into() });
-        }
-
-        user.iter()
-            .enumerate()
-            .for_each(|(i, c)| {
-                let role = if !is_chat || i % 2 == 0 { "user" } else { "assistant" };
-
-                messages.push(GptMessage { role: role.into(), content: c.to_string() });
-            });
-
-//println!("{:?}", function);
-        let completion = GptCompletion {
-            model: model.into(),
-            tools: Some(FunctionCall::functions(function)),
-            messages,
-            temperature,
-            response_format: ResponseFormat::new(is_json)
-        };
-//println!("-- {:?}", serde_json::to_string(&completion));
-
-        call_gpt_completion(&completion).await
+        call_gpt_completion(&Self::completion(model, system, user, temperature, is_json, is_chat, function)).await
     }
 
 }
@@ -180,6 +221,8 @@ impl LlmCompletion for GptCompletion {
 #[derive(Debug, Serialize, Clone)]
 pub struct ResponseFormat {
     pub r#type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_schema: Option<JsonSchemaFormat>,
 }
 
 impl ResponseFormat {
@@ -189,22 +232,171 @@ impl ResponseFormat {
                 "json_object".to_string()
             } else {
                 "text".to_string()
-            }
+            },
+            json_schema: None
         }
     }
+
+    /// Structured output, the reply is JSON matching schema
+    pub fn schema(schema: &serde_json::Value) -> Self {
+        ResponseFormat { r#type: "json_schema".into(), json_schema: Some(JsonSchemaFormat { name: "response".into(), schema: schema.clone(), strict: false }) }
+    }
+
+    // Schema mode if the call has a response schema, otherwise JSON or text
+    fn configured(is_json: bool) -> Self {
+        match current_response_schema() {
+            Some(schema) if is_json => ResponseFormat::schema(&schema),
+            _ => ResponseFormat::new(is_json),
+        }
+    }
+}
+
+/// Schema for structured output. Strict mode is off, as it rejects
+/// schemas with optional fields or formats such as those schemars makes.
+#[derive(Debug, Serialize, Clone)]
+pub struct JsonSchemaFormat {
+    pub name: String,
+    pub schema: serde_json::Value,
+    pub strict: bool,
 }
 
 /// Main Message Object
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GptMessage {
     pub role: String,
-    pub content: String,
+    /// Null in replies that were filtered or only call tools, read as empty
+    #[serde(deserialize_with = "content_or_empty")]
+    pub content: GptContent,
+    /// Chain of thought of reasoning models such as DeepSeek R1, returned
+    /// apart from the answer (Groq calls it reasoning)
+    #[serde(default, alias = "reasoning", skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
+    /// Calls asked for by an 'assistant' message, in wire format
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<serde_json::Value>>,
+    /// Call a 'tool' message is the result of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl GptMessage {
+    /// Supply single role with multi-part content, in the wire format of the
+    /// named LLM, for images, audio, files and tool calls. Tool results are
+    /// messages of their own, so one must be the only content given; use
+    /// `from_contents` for several.
+    pub fn from_content(role: &str, llm: &str, content: &[MessageContent]) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        if let [MessageContent::ToolResult { id, content, .. }] = content {
+            return Ok(Self::tool_result(id, content));
+        }
+
+        let mut parts = Vec::new();
+        let mut tool_calls = Vec::new();
+
+        for c in content {
+            match c {
+                MessageContent::ToolCall { .. } => tool_calls.push(c.wire(llm)?),
+                MessageContent::ToolResult { .. } =>
+                    return Err(Box::new(LlmClientError::Unsupported(format!("{llm} tool result with other content")))),
+                _ => parts.push(c.wire(llm)?),
+            }
+        }
+
+        // Calls without text are sent with empty content rather than no parts
+        let content = if parts.is_empty() && !tool_calls.is_empty() { GptContent::Text(String::new()) } else { GptContent::Parts(parts) };
+        let tool_calls = (!tool_calls.is_empty()).then_some(tool_calls);
+
+        Ok(Self { role: role.into(), content, reasoning_content: None, tool_calls, tool_call_id: None })
+    }
+
+    /// Messages for content that may hold several tool results, such as
+    /// the answers to a turn of tool calls: a 'tool' message for each
+    /// result, in order, then the other content, if any, as one message
+    pub fn from_contents(role: &str, llm: &str, content: &[MessageContent]) -> Result<Vec<Self>, Box<dyn std::error::Error + Send>> {
+        let (results, others): (Vec<_>, Vec<_>) = content.iter()
+            .cloned()
+            .partition(|c| matches!(c, MessageContent::ToolResult { .. }));
+        let mut messages: Vec<Self> = results.iter()
+            .map(|r| Self::from_content("tool", llm, std::slice::from_ref(r)))
+            .collect::<Result<_, _>>()?;
+
+        if !others.is_empty() {
+            messages.push(Self::from_content(role, llm, &others)?);
+        }
+
+        Ok(messages)
+    }
+
+    /// 'tool' message with the result of the call with this id
+    pub fn tool_result(id: &str, content: &str) -> Self {
+        Self { role: "tool".into(), content: content.into(), reasoning_content: None, tool_calls: None, tool_call_id: Some(id.into()) }
+    }
+
+    /// Add attachments as parts after the existing content, in the wire
+    /// format of the named GPT compatible LLM
+    pub fn attach(&mut self, llm: &str, attachments: &[Attachment]) -> Result<(), Box<dyn std::error::Error + Send>> {
+        let mut parts = match self.content {
+            GptContent::Text(ref text) => vec![serde_json::json!({"type": "text", "text": text})],
+            GptContent::Parts(ref parts) => parts.clone(),
+        };
+
+        for attachment in attachments {
+            parts.push(attachment.wire(llm)?);
+        }
+
+        self.content = GptContent::Parts(parts);
+
+        Ok(())
+    }
+}
+
+/// Message content, either plain text or an array of parts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum GptContent {
+    Text(String),
+    Parts(Vec<serde_json::Value>),
+}
+
+fn content_or_empty<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<GptContent, D::Error> {
+    Ok(<Option<GptContent> as serde::Deserialize>::deserialize(deserializer)?
+        .unwrap_or_else(|| GptContent::Text(String::new())))
+}
+
+impl GptContent {
+    /// Plain text, or that of any text parts joined by newlines
+    pub fn text(&self) -> String {
+        match self {
+            GptContent::Text(text) => text.clone(),
+            GptContent::Parts(parts) => parts.iter()
+                .filter_map(|p| p["text"].as_str())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+impl From<String> for GptContent {
+    fn from(text: String) -> Self {
+        GptContent::Text(text)
+    }
+}
+
+impl From<&str> for GptContent {
+    fn from(text: &str) -> Self {
+        GptContent::Text(text.into())
+    }
+}
+
+impl std::fmt::Display for GptContent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.text())
+    }
 }
 
 impl LlmMessage for GptMessage {
     /// Supply single role and single part text
     fn text(role: &str, content: &str) -> Self {
-        Self { role: role.into(), content: content.into() }
+        Self { role: role.into(), content: content.into(), reasoning_content: None, tool_calls: None, tool_call_id: None }
     }
 
     /// Supply single role with multi-string for iparts with single content
@@ -218,7 +410,7 @@ impl LlmMessage for GptMessage {
                     s
                 });
 
-        Self { role: role.into(), content: prompt }
+        Self { role: role.into(), content: prompt.into(), reasoning_content: None, tool_calls: None, tool_call_id: None }
     }
 
     /// Supply simple, 'system' content
@@ -316,12 +508,12 @@ impl Default for Usage {
 
 /// Call GPT with some messages
 pub async fn call_gpt(messages: Vec<GptMessage>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-    call_gpt_all(messages, 0.2, false).await
+    call_gpt_all(messages, provider_defaults("gpt").temperature(), false).await
 }
 
 /// Call GPT with some messages and option for Json
 pub async fn call_gpt_json(messages: Vec<GptMessage>, is_json: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-    call_gpt_all(messages, 0.2, is_json).await
+    call_gpt_all(messages, provider_defaults("gpt").temperature(), is_json).await
 }
 
 /// Call GPT with some messages and temperature
@@ -337,24 +529,41 @@ pub async fn call_gpt_all(messages: Vec<GptMessage>, temperature: f32, is_json:
     call_gpt_completion(&gpt_completion).await
 }
 
+/// Create and call GPT with attachments added as content parts to the final user message
+#[allow(clippy::too_many_arguments)]
+pub async fn call_gpt_model_attachments(model: &str, system: &str, user: &[String], attachments: &[Attachment], temperature: f32, is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    let mut completion = GptCompletion::completion(model, system, user, temperature, is_json, is_chat, None);
+
+    if let Some(message) = completion.messages.iter_mut().rev().find(|m| m.role == "user") {
+        message.attach("gpt", attachments)?;
+    }
+
+    call_gpt_completion(&completion).await
+}
+
 /// Call GPT with pre-assembled completion
 pub async fn call_gpt_completion(gpt_completion: &GptCompletion) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    // Completions made by new or default have no model if GPT_MODEL is unset
+    if gpt_completion.model.is_empty() {
+        return Err(Box::new(ConfigError::MissingEnv("GPT_MODEL".into())));
+    }
+    check_parameters("gpt", &gpt_completion.model, Some(gpt_completion.temperature), gpt_completion.top_p, gpt_completion.max_tokens)?;
     let start = std::time::Instant::now();
     // Confirm endpoint
-    let url: String = env::var("GPT_CHAT_URL").expect("GPT_CHAT_URL not found in enviroment variables");
+    let url: String = required_env("GPT_CHAT_URL")?;
 
     let client = get_gpt_client().await?;
 
 //println!("completion: {:?}", gpt_completion);
     // Extract API Response
-    let res = client
+    let res = send_idempotent(client
         .post(url)
-        .json(&gpt_completion)
-        .send()
+        .json(&gpt_completion))
         .await;
     //let res: GptResponse = res
+    let res = res?;
+    let status = res.status();
     let res = res
-        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
         //.json()
         .text()
         .await
@@ -362,76 +571,146 @@ pub async fn call_gpt_completion(gpt_completion: &GptCompletion) -> Result<LlmRe
 
     let timing = start.elapsed().as_secs() as f64 + start.elapsed().subsec_millis() as f64 / 1000.0;
 
+    gpt_response_to_return(&res, timing).map(|r| r.with_status(status))
+}
+
+/// Unpack the raw text of a GPT response into an LlmReturn
+pub fn gpt_response_to_return(res: &str, timing: f64) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
     if res.contains("\"error:\"") {
-        let ret: Result<LlmError,_> = serde_json::from_str(&res);
+        let ret: Result<LlmError,_> = serde_json::from_str(res);
 
         match ret {
             Ok(res) => 
-                Ok(LlmReturn::new(LlmType::GPT_ERROR, res.error.to_string(), res.error.to_string(), (0, 0, 0), timing, None, None)),
+                Ok(LlmReturn::new(LlmType::GPT_ERROR, res.error.to_string(), res.error.to_string().into(), (0, 0, 0), timing, None, None)),
             Err(e) => {
                 eprintln!("Error: {:?}", res);
 
-                Ok(LlmReturn::new(LlmType::GPT_ERROR, e.to_string(), e.to_string(), (0, 0, 0), timing, None, None))
+                Ok(LlmReturn::new(LlmType::GPT_ERROR, e.to_string(), e.to_string().into(), (0, 0, 0), timing, None, None))
             }
         }
     } else if res.contains("\"error\"") {
-        Ok(LlmReturn::new(LlmType::GPT_ERROR, res.to_string(), res.to_string(), (0, 0, 0), timing, None, None))
+        Ok(LlmReturn::new(LlmType::GPT_ERROR, res.to_string(), res.to_string().into(), (0, 0, 0), timing, None, None))
     } else if res.contains("\"arguments\":") {
-//println!("res: {res:?}");
-        let found = vec!["choices:message:tool_calls:function:arguments:${args}".to_string(),
-            "choices:message:tool_calls:function:name:${func}".to_string(),
-            "usage:prompt_tokens:${in}".to_string(),
-            "usage:completion_tokens:${out}".to_string(),
-            "usage:total_tokens:${total}".to_string(),
-//            "usage:${usage}".to_string(),
-            "choices:finish_reason:${finish}".to_string()];
-        let f: serde_json::Value = serde_json::from_str(&res).unwrap();
-        let h = get_functions(&f, &found);
-        let funcs = unpack_functions(h.clone());
-        let function_calls = serde_json::to_string(&funcs).unwrap();
-        let (i, o, t) = (h.get("in").unwrap()[0].clone(), h.get("out").unwrap()[0].clone(), h.get("total").unwrap()[0].clone());
-        let triple = (i.parse::<usize>().unwrap(), o.parse::<usize>().unwrap(), t.parse::<usize>().unwrap());
-        let finish = h.get("finish").unwrap()[0].clone();
-
-        Ok(LlmReturn::new(LlmType::GPT_TOOLS, function_calls, finish, triple, timing, None, None))
+        tool_calls_to_return(LlmType::GPT_TOOLS, res, timing)
     } else {
-        // Todo: no unwrap
-        let res = serde_json::from_str::<GptResponse>(&res).unwrap();
+        let res = match serde_json::from_str::<GptResponse>(res) {
+            Ok(res) => res,
+            Err(e) => return Ok(unexpected_response(LlmType::GPT_ERROR, res, e, timing)),
+        };
 
         // Send Response
         let text: String =
             match res.choices {
                 Some(ref choices) if !choices.is_empty() => {
                     // For now they only return one choice!
-                    let text = choices[0].message.content.clone();
-                    let text = text.lines().filter(|l| !l.starts_with("```")).fold(String::new(), |s, l| s + l + "\n");
-
-                    text
+                    strip_fences_by_default(&choices[0].message.content.text())
                 },
                 Some(_) | None => {
                     "None".into()
                 }
             };
-        let finish_reason: String = 
+        let finish_reason: FinishReason = 
             match res.choices {
                 Some(ref choices) if !choices.is_empty() => {
                     // For now they only return one choice!
-                    choices[0].finish_reason.to_string().to_uppercase()
+                    choices[0].finish_reason.as_str().into()
                 },
                 Some(_) | None => {
                     "None".into()
                 }
             };
+        let reasoning: Option<String> = res.choices.as_ref()
+            .and_then(|choices| choices.first())
+            .and_then(|choice| choice.message.reasoning_content.clone());
         let usage: Triple = res.usage.to_triple();
 
-        Ok(LlmReturn::new(LlmType::GPT, text, finish_reason, usage, timing, None, None))
+        Ok(LlmReturn::new(LlmType::GPT, text, finish_reason, usage, timing, None, None)
+            .with_reasoning(reasoning))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GptModels {
+    pub data: Vec<GptModel>,
+}
+
+/// Model description shared by OpenAI compatible providers
+#[derive(Debug, Deserialize)]
+pub struct GptModel {
+    pub id: String,
+    pub created: Option<u64>,
+    // Groq
+    pub context_window: Option<usize>,
+    // Mistral
+    pub max_context_length: Option<usize>,
+}
+
+/// List models available from OpenAI
+pub async fn list_gpt_models() -> Result<Vec<ModelInfo>, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_MODELS_URL")?;
+    let client = get_gpt_client().await?;
+
+    let res = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    gpt_models_to_info(&res)
+}
+
+/// Unpack model list from OpenAI compatible providers
+pub fn gpt_models_to_info(res: &str) -> Result<Vec<ModelInfo>, Box<dyn std::error::Error + Send>> {
+    if res.contains("\"error\"") {
+        return Err(Box::new(std::io::Error::other(res.to_string())));
     }
+
+    let models: GptModels = serde_json::from_str(res)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    Ok(models.data.into_iter()
+        .map(|m| ModelInfo {
+            id: m.id,
+            context_window: m.context_window.or(m.max_context_length),
+            created: m.created,
+        })
+        .collect())
+}
+
+/// Transcribe audio in its own language with Whisper
+pub async fn gpt_transcribe(audio: &Attachment, options: &TranscriptionOptions) -> Result<Transcription, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_TRANSCRIPTION_URL")?;
+
+    gpt_audio(&url, audio, options, true).await
+}
+
+/// Translate audio into English text with Whisper
+pub async fn gpt_translate(audio: &Attachment, options: &TranscriptionOptions) -> Result<Transcription, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_TRANSLATION_URL")?;
+
+    gpt_audio(&url, audio, options, false).await
+}
+
+async fn gpt_audio(url: &str, audio: &Attachment, options: &TranscriptionOptions, is_transcription: bool) -> Result<Transcription, Box<dyn std::error::Error + Send>> {
+    let model = match options.model {
+        Some(ref model) => model.clone(),
+        None => env_var("GPT_AUDIO_MODEL").unwrap_or("whisper-1".into()),
+    };
+    let client = get_gpt_client().await?;
+
+    whisper(&client, url, &model, audio, options, is_transcription).await
 }
 
 pub async fn get_gpt_client() -> Result<Client, Box<dyn std::error::Error + Send>> {
+    providers().client("gpt", new_gpt_client()).await
+}
+
+pub(crate) async fn new_gpt_client() -> Result<Client, Box<dyn std::error::Error + Send>> {
     // Extract API Key information
-    let api_key: String =
-        env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY not found in enviroment variables");
+    let api_key: String = credential("OPENAI_API_KEY")?;
 
     // Create headers
     let mut headers: HeaderMap = HeaderMap::new();
@@ -449,96 +728,145 @@ pub async fn get_gpt_client() -> Result<Client, Box<dyn std::error::Error + Send
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mock::{fixture, mock_post};
+    use serial_test::serial;
+    use wiremock::MockServer;
 
-    async fn gpt(content: Vec<GptMessage>) {
-        match call_gpt(content).await {
-            Ok(ret) => { println!("{ret}"); assert!(true) },
-            Err(e) => { println!("{e}"); assert!(false) },
-        }
-    }
+    #[test]
+    fn test_gpt_multi_part_message() {
+        let text = GptMessage::text("user", "Describe this");
+        let parts = GptMessage::from_content("user", "gpt", &[MessageContent::text("Describe this"), MessageContent::image("image/png", &[1, 2, 3])]).unwrap();
 
-    #[tokio::test]
-    async fn test_call_gpt_basic() {
-        let messages = vec![GptMessage::text("user", "What is the meaining of life?")];
-        gpt(messages).await;
-    }
-    #[tokio::test]
-    async fn test_call_gpt_citation() {
-        let messages = 
-            vec![GptMessage::text("user", "Give citations for the General theory of Relativity.")];
-        gpt(messages).await;
-    }
-    #[tokio::test]
-    async fn test_call_gpt_poem() {
-        let messages = 
-            vec![GptMessage::text("user", "Write a creative poem about the interplay of artificial intelligence and the human spirit and provide citations")];
-        gpt(messages).await;
+        assert_eq!(serde_json::to_string(&text).unwrap(), r#"{"role":"user","content":"Describe this"}"#);
+        assert_eq!(serde_json::to_string(&parts).unwrap(), r#"{"role":"user","content":[{"text":"Describe this","type":"text"},{"image_url":{"url":"data:image/png;base64,AQID"},"type":"image_url"}]}"#);
+
+        let parts: GptMessage = serde_json::from_str(&serde_json::to_string(&parts).unwrap()).unwrap();
+
+        assert_eq!(parts.content.text(), "Describe this");
+        assert!(GptMessage::from_content("user", "groq", &[MessageContent::audio("audio/wav", &[0])]).is_err());
     }
-    #[tokio::test]
-    async fn test_call_gpt_logic() {
-        let messages = 
-            vec![GptMessage::text("user", "How many brains does an octopus have, when they have been injured and lost a leg?")];
-        gpt(messages).await;
+
+    #[test]
+    fn test_gpt_tool_messages() {
+        let call = GptMessage::from_content("assistant", "gpt", &[MessageContent::tool_call("call_1", "arithmetic", serde_json::json!({"expr": "2 + 2"}))]).unwrap();
+        let result = GptMessage::from_content("tool", "gpt", &[MessageContent::tool_result("call_1", "arithmetic", "4")]).unwrap();
+
+        assert_eq!(serde_json::to_string(&call).unwrap(), r#"{"role":"assistant","content":"","tool_calls":[{"function":{"arguments":"{\"expr\":\"2 + 2\"}","name":"arithmetic"},"id":"call_1","type":"function"}]}"#);
+        assert_eq!(serde_json::to_string(&result).unwrap(), r#"{"role":"tool","content":"4","tool_call_id":"call_1"}"#);
+        assert!(GptMessage::from_content("user", "gpt", &[MessageContent::tool_result("call_1", "arithmetic", "4"), MessageContent::text("Thanks")]).is_err());
+
+        let messages = GptMessage::from_contents("user", "gpt", &[MessageContent::tool_result("call_1", "arithmetic", "4"), MessageContent::tool_result("call_2", "arithmetic", "6"), MessageContent::text("Thanks")]).unwrap();
+
+        assert_eq!(messages.iter().map(|m| m.role.as_str()).collect::<Vec<_>>(), vec!["tool", "tool", "user"]);
+        assert_eq!(messages[1].tool_call_id.as_deref(), Some("call_2"));
+
+        let call = ToolCall { index: None, id: "call_3".into(), function: ToolCallFunction { name: "arithmetic".into(), arguments: "{\"expr\": ".into() } };
+        let err = call.to_parse_function().unwrap_err();
+
+        assert!(matches!(err.downcast_ref::<LlmClientError>(), Some(LlmClientError::InvalidJson { .. })));
     }
-    #[tokio::test]
-    async fn test_call_gpt_dialogue() {
-        let system = "Use a Scottish accent to answer questions";
-        let mut messages = 
-            vec!["How many brains does an octopus have, when they have been injured and lost a leg?".to_string()];
-        let res = GptCompletion::call(&system, &messages, 0.2, false, true).await;
-        println!("{res:?}");
 
-        messages.push(res.unwrap().to_string());
-        messages.push("Is a cuttle fish similar?".to_string());
+    #[test]
+    #[serial]
+    fn test_gpt_reasoning() {
+        let res = gpt_response_to_return(&fixture("gpt/reasoning.json"), 1.0).unwrap();
+
+        assert_eq!(res.text, "Canberra.\n");
+        assert_eq!(res.reasoning.as_deref(), Some("The largest city is Sydney, but the capital is Canberra."));
+
+        std::env::set_var("GPT_MODEL", "gpt-4o");
+
+        let mut completion = GptCompletion { messages: vec![GptMessage::text("user", "Capital of Oz?")], ..Default::default() };
 
-        let res = GptCompletion::call(&system, &messages, 0.2, false, true).await;
-        println!("{res:?}");
+        completion.add_reply(&res, true);
+        assert!(completion.messages[1].reasoning_content.is_some());
+        completion.strip_reasoning();
+        completion.add_reply(&res, false);
+        assert!(!serde_json::to_string(&completion).unwrap().contains("reasoning_content"));
     }
-    #[tokio::test]
-    async fn test_call_gpt_dialogue_model() {
-        let model: String = std::env::var("GPT_MODEL").expect("GPT_MODEL not found in enviroment variables");
-        let messages = vec!["Hello".to_string()];
-        let res = GptCompletion::call_model(&model, "", &messages, 0.2, false, true).await;
-        println!("{res:?}");
+
+    #[test]
+    fn test_gpt_models_to_info() {
+        let res = r#"{"object":"list","data":[{"id":"gpt-4o","object":"model","created":1715367049,"owned_by":"system"},{"id":"llama3-70b-8192","object":"model","created":1693721698,"owned_by":"Meta","context_window":8192}]}"#;
+        let models = gpt_models_to_info(res).unwrap();
+
+        assert_eq!(models[0], ModelInfo { id: "gpt-4o".into(), context_window: None, created: Some(1715367049) });
+        assert_eq!(models[1].context_window, Some(8192));
     }
-    #[tokio::test]
-    async fn test_call_function_gpt() {
-        let model: String = std::env::var("GPT_MODEL").expect("GPT_MODEL not found in enviroment variables");
-        let messages =  vec!["The answer is (60 * 24) * 365.25".to_string()];
-        let func_def =
+
+    const FUNC_DEF: &str =
 r#"
 // Derive the value of the arithmetic expression
 // expr: An arithmetic expression
 fn arithmetic(expr)
 "#;
-        let functions = get_function_json("gpt", &[func_def]);
-        let res = GptCompletion::call_model_function(&model, "", &messages, 0.2, false, true, functions).await;
-        println!("{res:?}");
 
-        let answer = call_actual_function(res.ok());
-        println!("{answer:?}");
+    // Point GPT at a mock server answering with the given fixture
+    async fn mock_gpt(status: u16, fixture: &str) -> MockServer {
+        let server = mock_post("/v1/chat/completions", status, fixture).await;
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+        std::env::set_var("GPT_MODEL", "gpt-4o");
+
+        server
     }
+
     #[tokio::test]
-    async fn test_call_function_common_gpt() {
-        //let messages =  vec!["The answer is (60 * 24) * 365.25".to_string()];
-        let messages = vec!["a fruit that is red with a sweet tast".to_string()];
-        let func_def =
-r#"
-// Derive the value of the arithmetic expression
-// expr: An arithmetic expression
-fn arithmetic(expr)
-"#;
-        let func_def2 =
-r#"
-// Find the color of an apple and its taste pass them to this function
-// color: The color of an apple
-// taste: The taste of an apple
-fn apple(color, taste)
-"#;
-        let res = call_function_llm("gpt", &messages, &[func_def, func_def2]).await;
-        println!("{res:?}");
+    #[serial]
+    async fn test_call_gpt_success() {
+        let _server = mock_gpt(200, "gpt/success.json").await;
+        let messages = vec!["Hello".to_string()];
+        let res = GptCompletion::call_model("gpt-4o", "Be brief", &messages, 0.2, false, true).await.unwrap();
 
-        let answer = call_actual_function(res.ok());
-        println!("{answer:?}");
+        assert_eq!(res.llm_type, LlmType::GPT);
+        assert_eq!(res.text, "Hello there.\n");
+        assert_eq!(res.finish_reason, FinishReason::Stop);
+        assert_eq!(res.usage, (12, 3, 15));
+    }
+    #[tokio::test]
+    #[serial]
+    async fn test_call_gpt_error() {
+        let _server = mock_gpt(401, "gpt/error.json").await;
+        let res = call_gpt(vec![GptMessage::text("user", "Hello")]).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::GPT_ERROR);
+        assert!(res.text.contains("Incorrect API key"));
+        assert_eq!(res.usage, (0, 0, 0));
+    }
+    #[tokio::test]
+    #[serial]
+    async fn test_call_gpt_content_filter() {
+        let _server = mock_gpt(200, "gpt/content_filter.json").await;
+        let res = call_gpt(vec![GptMessage::text("user", "Hello")]).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::GPT);
+        assert_eq!(res.text, "");
+        assert_eq!(res.finish_reason, FinishReason::ContentFilter);
+        assert_eq!(res.usage, (14, 0, 14));
+    }
+    #[test]
+    fn test_gpt_unexpected_response() {
+        let res = gpt_response_to_return(&crate::mock::fixture("gpt/bad_gateway.html"), 0.1).unwrap();
+
+        assert_eq!(res.llm_type, LlmType::GPT_ERROR);
+        assert!(res.text.contains("502 Bad Gateway"));
+
+        let res = gpt_response_to_return(r#"{"object":"list","data":[]}"#, 0.1).unwrap();
+
+        assert_eq!(res.llm_type, LlmType::GPT_ERROR);
+    }
+    #[tokio::test]
+    #[serial]
+    async fn test_call_function_gpt() {
+        let _server = mock_gpt(200, "gpt/tool_call.json").await;
+        let messages = vec!["The answer is (60 * 24) * 365.25".to_string()];
+        let functions = get_function_json("gpt", &[FUNC_DEF]);
+        let res = GptCompletion::call_model_function("gpt-4o", "", &messages, 0.2, false, true, functions).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::GPT_TOOLS);
+        assert_eq!(res.finish_reason, FinishReason::ToolCalls);
+        assert_eq!(res.usage, (58, 19, 77));
+        assert_eq!(call_actual_function(Some(res)), vec!["arithmetic -> 525960"]);
     }
 }
@@ -0,0 +1,325 @@
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::Client;
+use serde_derive::{Deserialize, Serialize};
+use crate::common::*;
+use crate::config::required_env;
+use crate::credentials::credential;
+use crate::providers::providers;
+
+// OpenAI hosted vector stores: uploaded files are chunked and embedded by
+// OpenAI and searched by the file_search tool of the Assistants and
+// Responses APIs, or directly. Unlike `vector_store`, nothing is held locally.
+
+// Input structures
+
+#[derive(Debug, Serialize)]
+struct VectorStoreCreate<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    file_ids: &'a [String],
+}
+
+#[derive(Debug, Serialize)]
+struct VectorStoreFileCreate<'a> {
+    file_id: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+struct VectorStoreSearch<'a> {
+    query: &'a str,
+    max_num_results: usize,
+}
+
+// Output structures
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct GptVectorStore {
+    pub id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// in_progress, completed or expired
+    pub status: String,
+    #[serde(default)]
+    pub usage_bytes: usize,
+    pub file_counts: FileCounts,
+}
+
+impl GptVectorStore {
+    /// All files have been processed, successfully or not
+    pub fn is_ready(&self) -> bool {
+        self.status != "in_progress" && self.file_counts.in_progress == 0
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct FileCounts {
+    pub in_progress: usize,
+    pub completed: usize,
+    pub failed: usize,
+    pub cancelled: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct VectorStoreFile {
+    pub id: String,
+    pub vector_store_id: String,
+    pub status: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct VectorStoreFileList {
+    data: Vec<VectorStoreFile>,
+}
+
+/// Chunk of a stored file matching a search
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchResult {
+    pub file_id: String,
+    pub filename: String,
+    pub score: f64,
+    pub text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchResults {
+    data: Vec<SearchResultData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchResultData {
+    file_id: String,
+    filename: String,
+    score: f64,
+    content: Vec<SearchContent>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchContent {
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Deleted {
+    deleted: bool,
+}
+
+/// Create a vector store, optionally from already uploaded files
+pub async fn create_vector_store(name: &str, file_ids: &[String]) -> Result<GptVectorStore, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_VECTOR_STORES_URL")?;
+    let client = get_vector_store_client().await?;
+
+    let res = client
+        .post(url)
+        .json(&VectorStoreCreate { name, file_ids })
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    store_json(&res)
+}
+
+pub async fn get_vector_store(store_id: &str) -> Result<GptVectorStore, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_VECTOR_STORES_URL")?;
+    let client = get_vector_store_client().await?;
+
+    let res = client
+        .get(format!("{url}/{store_id}"))
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    store_json(&res)
+}
+
+/// Delete a vector store, the files themselves are kept
+pub async fn delete_vector_store(store_id: &str) -> Result<bool, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_VECTOR_STORES_URL")?;
+    let client = get_vector_store_client().await?;
+
+    let res = client
+        .delete(format!("{url}/{store_id}"))
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    store_json::<Deleted>(&res).map(|d| d.deleted)
+}
+
+/// Add an uploaded file (see `files::upload_file`) to a vector store
+pub async fn add_vector_store_file(store_id: &str, file_id: &str) -> Result<VectorStoreFile, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_VECTOR_STORES_URL")?;
+    let client = get_vector_store_client().await?;
+
+    let res = client
+        .post(format!("{url}/{store_id}/files"))
+        .json(&VectorStoreFileCreate { file_id })
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    store_json(&res)
+}
+
+pub async fn list_vector_store_files(store_id: &str) -> Result<Vec<VectorStoreFile>, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_VECTOR_STORES_URL")?;
+    let client = get_vector_store_client().await?;
+
+    let res = client
+        .get(format!("{url}/{store_id}/files"))
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    store_json::<VectorStoreFileList>(&res).map(|l| l.data)
+}
+
+/// Poll until every file in the store has been processed, failing with a
+/// TimedOut error if max_wait passes first
+pub async fn wait_for_vector_store(store_id: &str, poll_interval: std::time::Duration, max_wait: std::time::Duration) -> Result<GptVectorStore, Box<dyn std::error::Error + Send>> {
+    let start = std::time::Instant::now();
+
+    loop {
+        let store = get_vector_store(store_id).await?;
+
+        if store.is_ready() {
+            return Ok(store);
+        }
+        if start.elapsed() + poll_interval > max_wait {
+            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::TimedOut, format!("Vector store {store_id} not ready in {max_wait:?}"))));
+        }
+
+        tokio::time::sleep(poll_interval).await;
+    }
+}
+
+/// Search a vector store directly, best matches first
+pub async fn search_vector_store(store_id: &str, query: &str, max_results: usize) -> Result<Vec<SearchResult>, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_VECTOR_STORES_URL")?;
+    let client = get_vector_store_client().await?;
+
+    let res = client
+        .post(format!("{url}/{store_id}/search"))
+        .json(&VectorStoreSearch { query, max_num_results: max_results })
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    let results: SearchResults = store_json(&res)?;
+
+    Ok(results.data.into_iter()
+        .map(|r| SearchResult {
+            file_id: r.file_id,
+            filename: r.filename,
+            score: r.score,
+            text: r.content.into_iter().map(|c| c.text).collect::<Vec<_>>().join("\n"),
+        })
+        .collect())
+}
+
+/// file_search tool for the Responses API, searching the given stores
+pub fn file_search_tool(store_ids: &[String], max_results: Option<usize>) -> serde_json::Value {
+    let mut tool = serde_json::json!({"type": "file_search", "vector_store_ids": store_ids});
+
+    if let Some(max_results) = max_results {
+        tool["max_num_results"] = max_results.into();
+    }
+
+    tool
+}
+
+/// tool_resources for an Assistants API assistant or thread using file_search
+pub fn file_search_resources(store_ids: &[String]) -> serde_json::Value {
+    serde_json::json!({"file_search": {"vector_store_ids": store_ids}})
+}
+
+async fn get_vector_store_client() -> Result<Client, Box<dyn std::error::Error + Send>> {
+    providers().client("gpt_vector_store", new_vector_store_client()).await
+}
+
+async fn new_vector_store_client() -> Result<Client, Box<dyn std::error::Error + Send>> {
+    let api_key: String = credential("OPENAI_API_KEY")?;
+    let mut headers: HeaderMap = HeaderMap::new();
+
+    headers.insert(
+        "Authorization",
+        HeaderValue::from_str(&format!("Bearer {}", api_key))
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?,
+    );
+    headers.insert("OpenAI-Beta", HeaderValue::from_static("assistants=v2"));
+
+    get_client(headers).await
+}
+
+fn store_json<T: serde::de::DeserializeOwned>(res: &str) -> Result<T, Box<dyn std::error::Error + Send>> {
+    if let Ok(err) = serde_json::from_str::<LlmError>(res) {
+        return Err(Box::new(std::io::Error::other(err.error.to_string())));
+    }
+
+    serde_json::from_str(res)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{mock_get, mock_post};
+    use serial_test::serial;
+
+    #[tokio::test]
+    #[serial]
+    async fn test_wait_for_vector_store_max_wait() {
+        let server = mock_get("/v1/vector_stores/vs_abc123", 200, "gpt/vector_store_in_progress.json").await;
+
+        std::env::set_var("GPT_VECTOR_STORES_URL", format!("{}/v1/vector_stores", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        let err = wait_for_vector_store("vs_abc123", std::time::Duration::from_millis(10), std::time::Duration::from_millis(50)).await.unwrap_err();
+
+        assert!(err.to_string().contains("vs_abc123 not ready in 50ms"));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_search_vector_store() {
+        let server = mock_post("/v1/vector_stores/vs_abc123/search", 200, "gpt/vector_store_search.json").await;
+
+        std::env::set_var("GPT_VECTOR_STORES_URL", format!("{}/v1/vector_stores", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        let results = search_vector_store("vs_abc123", "capital of Australia", 2).await.unwrap();
+        let request = &server.received_requests().await.unwrap()[0];
+
+        assert_eq!(request.headers.get("OpenAI-Beta").unwrap(), "assistants=v2");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].filename, "australia.md");
+        assert_eq!(results[0].text, "Canberra is the capital.\nSydney is the largest city.");
+    }
+
+    #[test]
+    fn test_file_search_tool() {
+        let ids = vec!["vs_abc123".to_string()];
+
+        assert_eq!(file_search_tool(&ids, Some(5)), serde_json::json!({"type": "file_search", "vector_store_ids": ["vs_abc123"], "max_num_results": 5}));
+        assert_eq!(file_search_resources(&ids)["file_search"]["vector_store_ids"][0], "vs_abc123");
+    }
+}
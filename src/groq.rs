@@ -1,10 +1,17 @@
 use reqwest::header::{HeaderMap, HeaderValue};
 use reqwest::Client;
-use std::env;
 use serde_derive::{Deserialize, Serialize};
 use crate::common::*;
-use crate::gpt::GptMessage as GroqMessage;
+use crate::config::{env_var, provider_defaults, required_env};
+use crate::credentials::credential;
+use crate::capabilities::check_parameters;
+use crate::error::ConfigError;
+use crate::providers::providers;
+use crate::gpt::{GptMessage as GroqMessage, gpt_models_to_info};
 use crate::functions::*;
+use crate::postprocess::strip_fences_by_default;
+use crate::audio::whisper;
+pub use crate::audio::{Transcription, TranscriptionFormat, TranscriptionOptions, TranscriptionSegment};
 
 // Input structures
 // Chat
@@ -20,18 +27,55 @@ pub struct GroqCompletion {
     pub messages: Vec<GroqMessage>,
     pub response_format: ResponseFormat,
     pub temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
 }
 
 impl GroqCompletion {
+    // Completion from common parameters, shared by the calls with and without attachments
+    fn completion(model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool, function: Option<Vec<Function>>) -> Self {
+        let mut messages = Vec::new();
+
+        if !system.is_empty() {
+            messages.push(GroqMessage::text("system", system));
+        }
+
+        user.iter()
+            .enumerate()
+            .for_each(|(i, c)| {
+                let role = if !is_chat || i % 2 == 0 { "user" } else { "assistant" };
+
+                messages.push(GroqMessage::text(role, c));
+            });
+
+        let defaults = provider_defaults("groq");
+
+        GroqCompletion {
+            model: model.into(),
+            tools: Some(FunctionCall::functions(function)),
+            messages,
+            temperature,
+            top_p: defaults.top_p,
+            max_tokens: defaults.max_tokens,
+            response_format: ResponseFormat::new(is_json)
+        }
+    }
+
     /// Create chat completion
     pub fn new(messages: Vec<GroqMessage>, temperature: f32, is_json: bool) -> Self {
-        let model: String = env::var("GROQ_MODEL").expect("GROQ_MODEL not found in enviroment variables");
+        let model: String = env_var("GROQ_MODEL").unwrap_or_default(); // checked when called
+
+        let defaults = provider_defaults("groq");
 
         GroqCompletion {
             model,
             tools: None,
             messages,
             temperature,
+            top_p: defaults.top_p,
+            max_tokens: defaults.max_tokens,
             response_format: ResponseFormat::new(is_json)
         }
     }
@@ -62,13 +106,17 @@ impl GroqCompletion {
 impl Default for GroqCompletion {
     /// Create default chat completion
     fn default() -> Self {
-        let model: String = env::var("GROQ_MODEL").expect("GROQ_MODEL not found in enviroment variables");
+        let model: String = env_var("GROQ_MODEL").unwrap_or_default(); // checked when called
+
+        let defaults = provider_defaults("groq");
 
         GroqCompletion {
             model,
             tools: None,
             messages: Vec::new(),
-            temperature: 0.2,
+            temperature: defaults.temperature(),
+            top_p: defaults.top_p,
+            max_tokens: defaults.max_tokens,
             response_format: ResponseFormat::new(false)
         }
     }
@@ -132,7 +180,7 @@ impl LlmCompletion for GroqCompletion {
 
     /// Create and call llm by supplying data and common parameters
     async fn call(system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-        let model: String = env::var("GROQ_MODEL").expect("GROQ_MODEL not found in enviroment variables");
+        let model: String = required_env("GROQ_MODEL")?;
 
         Self::call_model(&model, system, user, temperature, is_json, is_chat).await
     }
@@ -144,29 +192,7 @@ impl LlmCompletion for GroqCompletion {
 
     /// Create and call llm with model/function by supplying data and common parameters
     async fn call_model_function(model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool, function: Option<Vec<Function>>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-        let mut messages = Vec::new();
-
-        if !system.is_empty() {
-            messages.push(GroqMessage { role: "system".into(), content: system.into() });
-        }
-
-        user.iter()
-            .enumerate()
-            .for_each(|(i, c)| {
-                let role = if !is_chat || i % 2 == 0 { "user" } else { "assistant" };
-
-                messages.push(GroqMessage { role: role.into(), content: c.to_string() });
-            });
-
-        let completion = GroqCompletion {
-            model: model.into(),
-            tools: Some(FunctionCall::functions(function)),
-            messages,
-            temperature,
-            response_format: ResponseFormat::new(is_json)
-        };
-
-        call_groq_completion(&completion).await
+        call_groq_completion(&Self::completion(model, system, user, temperature, is_json, is_chat, function)).await
     }
 
 }
@@ -240,12 +266,12 @@ impl Default for Usage {
 
 /// Call GROQ with some messages
 pub async fn call_groq(messages: Vec<GroqMessage>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-    call_groq_all(messages, 0.2, false).await
+    call_groq_all(messages, provider_defaults("groq").temperature(), false).await
 }
 
 /// Call GROQ with some messages and option for Json
 pub async fn call_groq_json(messages: Vec<GroqMessage>, is_json: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-    call_groq_all(messages, 0.2, is_json).await
+    call_groq_all(messages, provider_defaults("groq").temperature(), is_json).await
 }
 
 /// Call GROQ with some messages and temperature
@@ -261,24 +287,41 @@ pub async fn call_groq_all(messages: Vec<GroqMessage>, temperature: f32, is_json
     call_groq_completion(&groq_completion).await
 }
 
+/// Create and call Groq with attachments added as content parts to the final user message
+#[allow(clippy::too_many_arguments)]
+pub async fn call_groq_model_attachments(model: &str, system: &str, user: &[String], attachments: &[Attachment], temperature: f32, is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    let mut completion = GroqCompletion::completion(model, system, user, temperature, is_json, is_chat, None);
+
+    if let Some(message) = completion.messages.iter_mut().rev().find(|m| m.role == "user") {
+        message.attach("groq", attachments)?;
+    }
+
+    call_groq_completion(&completion).await
+}
+
 /// Call Claude with pre-assembled completion
 pub async fn call_groq_completion(groq_completion: &GroqCompletion) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    // Completions made by new or default have no model if GROQ_MODEL is unset
+    if groq_completion.model.is_empty() {
+        return Err(Box::new(ConfigError::MissingEnv("GROQ_MODEL".into())));
+    }
+    check_parameters("groq", &groq_completion.model, Some(groq_completion.temperature), groq_completion.top_p, groq_completion.max_tokens)?;
     let start = std::time::Instant::now();
     // Confirm endpoint
-    let url: String = env::var("GROQ_CHAT_URL").expect("GROQ_CHAT_URL not found in enviroment variables");
+    let url: String = required_env("GROQ_CHAT_URL")?;
 
     let client = get_groq_client().await?;
 
 //println!("{:?}", serde_json::to_string(&groq_completion));
     // Extract API Response
-    let res = client
+    let res = send_idempotent(client
         .post(url)
-        .json(&groq_completion)
-        .send()
+        .json(&groq_completion))
         .await;
     //let res: GroqResponse = res
+    let res = res?;
+    let status = res.status();
     let res = res
-        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
         //.json()
         .text()
         .await
@@ -286,75 +329,102 @@ pub async fn call_groq_completion(groq_completion: &GroqCompletion) -> Result<Ll
 
     let timing = start.elapsed().as_secs() as f64 + start.elapsed().subsec_millis() as f64 / 1000.0;
 
+    groq_response_to_return(&res, timing).map(|r| r.with_status(status))
+}
+
+/// Unpack the raw text of a Groq response into an LlmReturn
+pub fn groq_response_to_return(res: &str, timing: f64) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
 //println!("{res}");
     if res.contains("\"error:\"") {
-        let ret: Result<LlmError,_> = serde_json::from_str(&res);
+        let ret: Result<LlmError,_> = serde_json::from_str(res);
 
         match ret {
             Ok(res) => 
-                Ok(LlmReturn::new(LlmType::GROQ_ERROR, res.error.to_string(), res.error.to_string(), (0, 0, 0), timing, None, None)),
+                Ok(LlmReturn::new(LlmType::GROQ_ERROR, res.error.to_string(), res.error.to_string().into(), (0, 0, 0), timing, None, None)),
             Err(e) => {
                 eprintln!("Error: {:?}", res);
 
-                Ok(LlmReturn::new(LlmType::GROQ_ERROR, e.to_string(), e.to_string(), (0, 0, 0), timing, None, None))
+                Ok(LlmReturn::new(LlmType::GROQ_ERROR, e.to_string(), e.to_string().into(), (0, 0, 0), timing, None, None))
             }
         }
     } else if res.contains("\"error\"") {
-        Ok(LlmReturn::new(LlmType::GROQ_ERROR, res.to_string(), res.to_string(), (0, 0, 0), timing, None, None))
+        Ok(LlmReturn::new(LlmType::GROQ_ERROR, res.to_string(), res.to_string().into(), (0, 0, 0), timing, None, None))
     } else if res.contains("\"arguments\":") {
-        let found = vec!["choices:message:tool_calls:function:arguments:${args}".to_string(),
-            "choices:message:tool_calls:function:name:${func}".to_string(),
-            "usage:prompt_tokens:${in}".to_string(),
-            "usage:completion_tokens:${out}".to_string(),
-            "usage:total_tokens:${total}".to_string(),
-//            "usage:${usage}".to_string(),
-            "choices:finish_reason:${finish}".to_string()];
-        let f: serde_json::Value = serde_json::from_str(&res).unwrap();
-        let h = get_functions(&f, &found);
-        let funcs = unpack_functions(h.clone());
-        let function_calls = serde_json::to_string(&funcs).unwrap();
-        let (i, o, t) = (h.get("in").unwrap()[0].clone(), h.get("out").unwrap()[0].clone(), h.get("total").unwrap()[0].clone());
-        let triple = (i.parse::<usize>().unwrap(), o.parse::<usize>().unwrap(), t.parse::<usize>().unwrap());
-        let finish = h.get("finish").unwrap()[0].clone();
-
-        Ok(LlmReturn::new(LlmType::GROQ_TOOLS, function_calls, finish, triple, timing, None, None))
+        tool_calls_to_return(LlmType::GROQ_TOOLS, res, timing)
     } else {
-        let res: GroqResponse = serde_json::from_str::<GroqResponse>(&res).unwrap();
+        let res: GroqResponse = match serde_json::from_str::<GroqResponse>(res) {
+            Ok(res) => res,
+            Err(e) => return Ok(unexpected_response(LlmType::GROQ_ERROR, res, e, timing)),
+        };
 
-        // Send Response
-        let text: String =
-            match res.choices {
-                Some(ref choices) if !choices.is_empty() => {
-                    // For now they only return one choice!
-                    let text = choices[0].message.content.clone();
-                    let text = text.lines().filter(|l| !l.starts_with("```")).fold(String::new(), |s, l| s + l + "\n");
-
-                    text
-                },
-                Some(_) | None => {
-                    "None".into()
-                }
-            };
-        let finish_reason: String = 
-            match res.choices {
-                Some(ref choices) if !choices.is_empty() => {
-                    // For now they only return one choice!
-                    choices[0].finish_reason.to_string().to_uppercase()
-                },
-                Some(_) | None => {
-                    "None".into()
-                }
-            };
         let usage: Triple = res.usage.to_triple();
 
-        Ok(LlmReturn::new(LlmType::GROQ, text, finish_reason, usage, timing, None, None))
+        // Send Response
+        match res.choices {
+            Some(ref choices) if !choices.is_empty() => {
+                // For now they only return one choice!
+                let text: String = strip_fences_by_default(&choices[0].message.content.text());
+                let finish_reason: FinishReason = choices[0].finish_reason.as_str().into();
+                let reasoning: Option<String> = choices[0].message.reasoning_content.clone();
+
+                Ok(LlmReturn::new(LlmType::GROQ, text, finish_reason, usage, timing, None, None)
+                    .with_reasoning(reasoning))
+            },
+            Some(_) | None => {
+                Ok(LlmReturn::new(LlmType::GROQ_ERROR, "No choices returned".into(), "ERROR".into(), usage, timing, None, None))
+            }
+        }
     }
 }
 
+/// List models available from Groq
+pub async fn list_groq_models() -> Result<Vec<ModelInfo>, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GROQ_MODELS_URL")?;
+    let client = get_groq_client().await?;
+
+    let res = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    gpt_models_to_info(&res)
+}
+
+/// Transcribe audio in its own language
+pub async fn groq_transcribe(audio: &Attachment, options: &TranscriptionOptions) -> Result<Transcription, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GROQ_TRANSCRIPTION_URL")?;
+
+    groq_audio(&url, audio, options, true).await
+}
+
+/// Translate audio into English text
+pub async fn groq_translate(audio: &Attachment, options: &TranscriptionOptions) -> Result<Transcription, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GROQ_TRANSLATION_URL")?;
+
+    groq_audio(&url, audio, options, false).await
+}
+
+async fn groq_audio(url: &str, audio: &Attachment, options: &TranscriptionOptions, is_transcription: bool) -> Result<Transcription, Box<dyn std::error::Error + Send>> {
+    let model = match options.model {
+        Some(ref model) => model.clone(),
+        None => env_var("GROQ_AUDIO_MODEL").unwrap_or("whisper-large-v3".into()),
+    };
+    let client = get_groq_client().await?;
+
+    whisper(&client, url, &model, audio, options, is_transcription).await
+}
+
 async fn get_groq_client() -> Result<Client, Box<dyn std::error::Error + Send>> {
+    providers().client("groq", new_groq_client()).await
+}
+
+pub(crate) async fn new_groq_client() -> Result<Client, Box<dyn std::error::Error + Send>> {
     // Extract API Key information
-    let api_key: String =
-        env::var("GROQ_API_KEY").expect("GROQ_API_KEY not found in enviroment variables");
+    let api_key: String = credential("GROQ_API_KEY")?;
 
     // Create headers
     let mut headers: HeaderMap = HeaderMap::new();
@@ -372,96 +442,135 @@ async fn get_groq_client() -> Result<Client, Box<dyn std::error::Error + Send>>
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mock::mock_post;
+    use serial_test::serial;
+    use wiremock::MockServer;
 
-    async fn groq(content: Vec<GroqMessage>) {
-        match call_groq(content).await {
-            Ok(ret) => { println!("{ret}"); assert!(true) },
-            Err(e) => { println!("{e}"); assert!(false) },
-        }
-    }
+    const FUNC_DEF: &str =
+r#"
+// Derive the value of the arithmetic expression
+// expr: An arithmetic expression
+fn arithmetic(expr)
+"#;
 
     #[tokio::test]
-    async fn test_call_groq_basic() {
-        let messages = vec![GroqMessage::text("user", "What is the meaining of life?")];
-        groq(messages).await;
+    #[serial]
+    async fn test_call_function_groq_ids() {
+        let _server = mock_groq(200, "groq/tool_calls.json").await;
+        let messages = vec!["Minutes in a day and hours in a week?".to_string()];
+        let functions = get_function_json("groq", &[FUNC_DEF]);
+        let res = GroqCompletion::call_model_function("llama3-70b-8192", "", &messages, 0.2, false, true, functions).await.unwrap();
+        let calls: Vec<ParseFunction> = serde_json::from_str(&res.text).unwrap();
+
+        assert_eq!(res.usage, (952, 71, 1023));
+        assert_eq!(calls.iter().map(|c| c.id.as_deref().unwrap()).collect::<Vec<_>>(), vec!["call_p4d8", "call_x7q2"]);
+        let results = call_actual_function(Some(res));
+
+        assert_eq!(results, vec!["arithmetic -> 1440", "arithmetic -> 168"]);
+
+        let messages = GroqMessage::from_contents("user", "groq", &tool_results(&calls, &results).unwrap()).unwrap();
+
+        assert_eq!(messages.iter().map(|m| m.tool_call_id.as_deref().unwrap()).collect::<Vec<_>>(), vec!["call_p4d8", "call_x7q2"]);
+        assert!(tool_results(&calls, &results[..1]).is_err());
     }
+
     #[tokio::test]
-    async fn test_call_groq_citation() {
-        let messages = 
-            vec![GroqMessage::text("user", "Give citations for the General theory of Relativity.")];
-        groq(messages).await;
+    #[serial]
+    async fn test_groq_transcribe() {
+        let server = mock_post("/openai/v1/audio/transcriptions", 200, "groq/transcription.json").await;
+
+        std::env::set_var("GROQ_TRANSCRIPTION_URL", format!("{}/openai/v1/audio/transcriptions", server.uri()));
+        std::env::set_var("GROQ_API_KEY", "test-key");
+
+        let audio = Attachment::new("capital.wav", "audio/wav", vec![0; 16]);
+        let options = TranscriptionOptions::new().language("en").response_format(TranscriptionFormat::VerboseJson);
+        let res = groq_transcribe(&audio, &options).await.unwrap();
+
+        assert_eq!(res.text.trim(), "The capital of Australia is Canberra.");
+        assert_eq!(res.duration, Some(3.42));
+        assert_eq!(res.segments.unwrap()[0].end, 3.42);
+
+        let body = String::from_utf8_lossy(&server.received_requests().await.unwrap()[0].body).to_string();
+
+        assert!(body.contains("whisper-large-v3") && body.contains("verbose_json") && body.contains("filename=\"capital.wav\""));
     }
-    #[tokio::test]
-    async fn test_call_groq_poem() {
-        let messages = 
-            vec![GroqMessage::text("user", "Write a creative poem about the interplay of artificial intelligence and the human spirit and provide citations")];
-        groq(messages).await;
+
+    // Point Groq at a mock server answering with the given fixture
+    async fn mock_groq(status: u16, fixture: &str) -> MockServer {
+        let server = mock_post("/openai/v1/chat/completions", status, fixture).await;
+
+        std::env::set_var("GROQ_CHAT_URL", format!("{}/openai/v1/chat/completions", server.uri()));
+        std::env::set_var("GROQ_API_KEY", "test-key");
+        std::env::set_var("GROQ_MODEL", "llama3-70b-8192");
+
+        server
     }
+
     #[tokio::test]
-    async fn test_call_groq_logic() {
-        let messages = 
-            vec![GroqMessage::text("user", "How many brains does an octopus have, when they have been injured and lost a leg?")];
-        groq(messages).await;
+    #[serial]
+    async fn test_call_groq_success() {
+        let _server = mock_groq(200, "groq/success.json").await;
+        let messages = vec!["Hello".to_string()];
+        let res = GroqCompletion::call_model("llama3-70b-8192", "Be brief", &messages, 0.2, false, true).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::GROQ);
+        assert_eq!(res.text, "Hello there.\n");
+        assert_eq!(res.finish_reason, FinishReason::Stop);
+        assert_eq!(res.usage, (12, 3, 15));
     }
     #[tokio::test]
-    async fn test_call_groq_dialogue() {
-        let system = "Use a Scottish accent to answer questions";
-        let mut messages = 
-            vec!["How many brains does an octopus have, when they have been injured and lost a leg?".to_string()];
-        let res = GroqCompletion::call(&system, &messages, 0.2, false, true).await;
-        println!("{res:?}");
-
-        messages.push(res.unwrap().to_string());
-        messages.push("Is a cuttle fish similar?".to_string());
+    #[serial]
+    async fn test_call_groq_error() {
+        let _server = mock_groq(401, "groq/error.json").await;
+        let res = call_groq(vec![GroqMessage::text("user", "Hello")]).await.unwrap();
 
-        let res = GroqCompletion::call(&system, &messages, 0.2, false, true).await;
-        println!("{res:?}");
+        assert_eq!(res.llm_type, LlmType::GROQ_ERROR);
+        assert!(res.text.contains("Invalid API Key"));
+        assert_eq!(res.usage, (0, 0, 0));
     }
     #[tokio::test]
-    async fn test_call_groq_dialogue_model() {
-        let model: String = std::env::var("GROQ_MODEL").expect("GROQ_MODEL not found in enviroment variables");
-        let messages = vec!["Hello".to_string()];
-        let res = GroqCompletion::call_model(&model, "", &messages, 0.2, false, true).await;
-        println!("{res:?}");
+    #[serial]
+    async fn test_call_groq_bad_gateway() {
+        let _server = mock_groq(502, "groq/bad_gateway.html").await;
+        let res = call_groq(vec![GroqMessage::text("user", "Hello")]).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::GROQ_ERROR);
+        assert!(res.text.contains("502 Bad Gateway"));
+        assert!(res.is_server_error());
+        assert_eq!(res.usage, (0, 0, 0));
     }
     #[tokio::test]
-    async fn test_call_function_groq() {
-        let model: String = std::env::var("GROQ_MODEL").expect("GROQ_MODEL not found in enviroment variables");
-        let messages =  vec!["The answer is (60 * 24) * 365.25".to_string()];
-        let func_def =
-r#"
-// Derive the value of the arithmetic expression
-// expr: An arithmetic expression
-fn arithmetic(expr)
-"#;
-        let functions = get_function_json("groq", &[func_def]);
-        let res = GroqCompletion::call_model_function(&model, "", &messages, 0.2, false, true, functions).await;
-        println!("{res:?}");
+    #[serial]
+    async fn test_call_groq_safety() {
+        let _server = mock_groq(200, "groq/safety.json").await;
+        let res = call_groq(vec![GroqMessage::text("user", "Hello")]).await.unwrap();
 
-        let answer = call_actual_function(res.ok());
-        println!("{answer:?}");
+        assert_eq!(res.llm_type, LlmType::GROQ);
+        assert_eq!(res.text, "");
+        assert_eq!(res.finish_reason, FinishReason::ContentFilter);
+        assert_eq!(res.usage, (14, 0, 14));
     }
     #[tokio::test]
-    async fn test_call_function_common_groq() {
-        //let messages =  vec!["The answer is (60 * 24) * 365.25".to_string()];
-        let messages = vec!["a fruit that is blue with a sour tast".to_string()];
-        let func_def =
-r#"
-// Derive the value of the arithmetic expression
-// expr: An arithmetic expression
-fn arithmetic(expr)
-"#;
-        let func_def2 =
-r#"
-// Find the color of an apple and it's taste pass them to this function.
-// color: The color of an apple
-// taste: The taste of an apple
-fn apple(color, taste)
-"#;
-        let res = call_function_llm("groq", &messages, &[func_def, func_def2]).await;
-        println!("{res:?}");
+    #[serial]
+    async fn test_call_groq_empty_choices() {
+        let _server = mock_groq(200, "groq/empty_choices.json").await;
+        let res = call_groq(vec![GroqMessage::text("user", "Hello")]).await.unwrap();
 
-        let answer = call_actual_function(res.ok());
-        println!("{answer:?}");
+        assert_eq!(res.llm_type, LlmType::GROQ_ERROR);
+        assert_eq!(res.text, "No choices returned");
+        assert_eq!(res.usage, (12, 0, 12));
+    }
+    #[tokio::test]
+    #[serial]
+    async fn test_call_function_groq() {
+        let _server = mock_groq(200, "groq/tool_call.json").await;
+        let messages = vec!["The answer is (60 * 24) * 365.25".to_string()];
+        let functions = get_function_json("groq", &[FUNC_DEF]);
+        let res = GroqCompletion::call_model_function("llama3-70b-8192", "", &messages, 0.2, false, true, functions).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::GROQ_TOOLS);
+        assert_eq!(res.finish_reason, FinishReason::ToolCalls);
+        assert_eq!(res.usage, (940, 48, 988));
+        assert_eq!(call_actual_function(Some(res)), vec!["arithmetic -> 525960"]);
     }
 }
@@ -0,0 +1,190 @@
+use regex::Regex;
+use jsonschema::JSONSchema;
+use crate::common::{LlmReturn, call_llm_model_cached};
+use crate::error::LlmClientError;
+
+/// Check on LLM output text
+pub trait Validator: Send + Sync {
+    /// Ok, or what is wrong, which is given to the LLM when retrying
+    fn validate(&self, text: &str) -> Result<(), String>;
+}
+
+/// Output must (or must not) match a regular expression
+#[derive(Debug, Clone)]
+pub struct RegexValidator {
+    regex: Regex,
+    must_match: bool,
+}
+
+impl RegexValidator {
+    pub fn matching(pattern: &str) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        Self::new(pattern, true)
+    }
+
+    pub fn not_matching(pattern: &str) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        Self::new(pattern, false)
+    }
+
+    fn new(pattern: &str, must_match: bool) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+        Ok(RegexValidator { regex, must_match })
+    }
+}
+
+impl Validator for RegexValidator {
+    fn validate(&self, text: &str) -> Result<(), String> {
+        match (self.must_match, self.regex.is_match(text)) {
+            (true, false) => Err(format!("The answer must match the pattern: {}", self.regex)),
+            (false, true) => Err(format!("The answer must not contain: {}", self.regex.find(text).unwrap().as_str())),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Output must be JSON conforming to a JSON schema
+pub struct JsonSchemaValidator {
+    schema: JSONSchema,
+}
+
+impl JsonSchemaValidator {
+    pub fn new(schema: &serde_json::Value) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        let schema = JSONSchema::compile(schema)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(std::io::Error::other(format!("Invalid JSON schema: {e}"))) })?;
+
+        Ok(JsonSchemaValidator { schema })
+    }
+}
+
+impl Validator for JsonSchemaValidator {
+    fn validate(&self, text: &str) -> Result<(), String> {
+        let json: serde_json::Value = serde_json::from_str(text)
+            .map_err(|e| format!("The answer is not valid JSON: {e}"))?;
+
+        self.schema.validate(&json)
+            .map_err(|errors| {
+                let errors: Vec<String> = errors.map(|e| format!("{} at '{}'", e, e.instance_path)).collect();
+
+                format!("The JSON does not match the schema: {}", errors.join("; "))
+            })
+    }
+}
+
+/// Custom check
+pub struct FnValidator<F>(pub F);
+
+impl<F> Validator for FnValidator<F> where F: Fn(&str) -> Result<(), String> + Send + Sync {
+    fn validate(&self, text: &str) -> Result<(), String> {
+        (self.0)(text)
+    }
+}
+
+/// What to do with output that fails validation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GuardrailPolicy {
+    /// Fail with LlmClientError::Rejected
+    Reject,
+    /// Ask the LLM this many times to correct its answer, then reject
+    Retry(usize),
+    /// Return the output with the failures listed
+    Annotate,
+}
+
+/// Output checked by guardrails, with any failures when annotating
+#[derive(Debug, Clone)]
+pub struct Guarded {
+    pub result: LlmReturn,
+    pub violations: Vec<String>,
+}
+
+/// Validators applied centrally to LLM output under one policy
+pub struct Guardrails {
+    validators: Vec<Box<dyn Validator>>,
+    policy: GuardrailPolicy,
+}
+
+impl Guardrails {
+    pub fn new(policy: GuardrailPolicy) -> Self {
+        Guardrails { validators: Vec::new(), policy }
+    }
+
+    pub fn validator(mut self, validator: impl Validator + 'static) -> Self {
+        self.validators.push(Box::new(validator));
+        self
+    }
+
+    /// Failures of text against all validators
+    pub fn check(&self, text: &str) -> Vec<String> {
+        self.validators.iter()
+            .filter_map(|v| v.validate(text).err())
+            .collect()
+    }
+
+    /// Call named LLM and model and apply guardrails to the answer
+    #[allow(clippy::too_many_arguments)]
+    pub async fn call_llm_model(&self, llm: &str, model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool) -> Result<Guarded, Box<dyn std::error::Error + Send>> {
+        let retries = match self.policy {
+            GuardrailPolicy::Retry(n) => n,
+            _ => 0,
+        };
+        let mut user = user.to_vec();
+        let mut attempt = 0;
+
+        loop {
+            // Rejected replies are not cached, so are not given again
+            let result = call_llm_model_cached(llm, model, system, &user, temperature, is_json, is_chat, |res| self.check(&res.text).is_empty()).await?;
+
+            // Provider errors are not ours to judge
+            if result.llm_type.is_error() {
+                return Ok(Guarded { result, violations: Vec::new() });
+            }
+
+            let violations = self.check(&result.text);
+
+            if violations.is_empty() {
+                return Ok(Guarded { result, violations });
+            }
+
+            match self.policy {
+                GuardrailPolicy::Annotate => return Ok(Guarded { result, violations }),
+                _ if attempt >= retries => return Err(Box::new(LlmClientError::Rejected(violations))),
+                _ => {
+                    let feedback = format!("Your answer is not acceptable: {}. Please answer again, correcting this.", violations.join(". "));
+
+                    if is_chat {
+                        user.push(result.text);
+                        user.push(feedback);
+                    } else if let Some(last) = user.last_mut() {
+                        last.push_str(&format!("\n\nPrevious answer:\n{}\n\n{feedback}", result.text));
+                    }
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_guardrails_check() {
+        let schema = json!({"type": "object", "required": ["sentiment"], "properties": {"sentiment": {"enum": ["positive", "negative"]}}});
+        let guardrails = Guardrails::new(GuardrailPolicy::Reject)
+            .validator(JsonSchemaValidator::new(&schema).unwrap())
+            .validator(RegexValidator::not_matching(r"(?i)\bguarantee").unwrap())
+            .validator(FnValidator(|t: &str| if t.len() < 100 { Ok(()) } else { Err("Too long".to_string()) }));
+
+        assert!(guardrails.check(r#"{"sentiment": "positive"}"#).is_empty());
+
+        let violations = guardrails.check(r#"{"sentiment": "neutral", "note": "Guaranteed"}"#);
+
+        assert_eq!(violations.len(), 2);
+        assert!(violations[0].starts_with("The JSON does not match the schema"));
+        assert_eq!(violations[1], "The answer must not contain: Guarantee");
+        assert_eq!(guardrails.check("not json").len(), 1);
+    }
+}
@@ -0,0 +1,415 @@
+//! Images ready to attach: read from a file or fetched from a URL and, with
+//! the `images` feature, downscaled and re-encoded to fit what the provider
+//! accepts, as providers reject oversized images outright. Also OpenAI image
+//! generation, edits (with a mask) and variations.
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use reqwest::multipart::{Form, Part};
+use serde_derive::Deserialize;
+use crate::common::{Attachment, LlmError, llm_name, mime_type};
+use crate::config::{env_var, required_env};
+use crate::gpt::get_gpt_client;
+
+/// Longest side in pixels and largest size in bytes worth sending to the
+/// named LLM. Larger images are either rejected or downscaled by the
+/// provider, costing tokens and upload time for nothing.
+pub fn image_limits(llm: &str) -> (u32, usize) {
+    match llm_name(llm) {
+        Some("claude") => (1568, 5 * 1024 * 1024),
+        Some("gpt") => (2048, 20 * 1024 * 1024),
+        Some("gemini") => (3072, 20 * 1024 * 1024),
+        // Groq limits the base64 encoding to 4MB
+        Some("groq") => (2048, 3 * 1024 * 1024),
+        Some("mistral") => (2048, 10 * 1024 * 1024),
+        _ => (2048, 5 * 1024 * 1024),
+    }
+}
+
+/// Image from an http(s) URL or a file. The mime type comes from the
+/// response, or failing that the extension.
+pub async fn load_image(source: &str) -> Result<Attachment, Box<dyn std::error::Error + Send>> {
+    if !source.starts_with("http://") && !source.starts_with("https://") {
+        return Attachment::image_path(source);
+    }
+
+    let res = reqwest::get(source).await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+    let path = source.split(['?', '#']).next().unwrap_or(source);
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let mime = res.headers().get("content-type")
+        .and_then(|m| m.to_str().ok())
+        .and_then(|m| m.split(';').next())
+        .filter(|m| m.starts_with("image/"))
+        .map(|m| m.trim().to_string())
+        .unwrap_or(mime_type(path).to_string());
+    let data = res.bytes().await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    Ok(Attachment::new(name, &mime, data.to_vec()))
+}
+
+/// Image loaded and fitted to the limits of the named LLM. Without the
+/// `images` feature it is only checked against the size limit.
+pub async fn prepare_image(llm: &str, source: &str) -> Result<Attachment, Box<dyn std::error::Error + Send>> {
+    let image = load_image(source).await?;
+    let (max_side, max_bytes) = image_limits(llm);
+
+    #[cfg(feature = "images")]
+    return fit_image(&image, max_side, max_bytes);
+
+    #[cfg(not(feature = "images"))]
+    {
+        let _ = max_side;
+
+        if image.data.len() > max_bytes {
+            return Err(Box::new(std::io::Error::other(format!("{} is {} bytes, over the {llm} limit of {max_bytes}, the images feature can downscale it", image.name, image.data.len()))));
+        }
+
+        Ok(image)
+    }
+}
+
+/// Downscale an image so that its longest side is at most `max_side`, then
+/// re-encode it until it is at most `max_bytes`. PNGs stay PNG if that is
+/// small enough, otherwise the image becomes a JPEG at falling quality.
+#[cfg(feature = "images")]
+pub fn fit_image(attachment: &Attachment, max_side: u32, max_bytes: usize) -> Result<Attachment, Box<dyn std::error::Error + Send>> {
+    use image::codecs::jpeg::JpegEncoder;
+    use image::imageops::FilterType;
+    use image::ImageFormat;
+
+    let image = image::load_from_memory(&attachment.data)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+    let oversized = image.width() > max_side || image.height() > max_side;
+
+    if !oversized && attachment.data.len() <= max_bytes {
+        return Ok(attachment.clone());
+    }
+
+    let image = if oversized { image.resize(max_side, max_side, FilterType::Lanczos3) } else { image };
+    let stem = attachment.name.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(&attachment.name);
+
+    if attachment.mime_type == "image/png" {
+        let mut png = std::io::Cursor::new(Vec::new());
+
+        image.write_to(&mut png, ImageFormat::Png)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+        if png.get_ref().len() <= max_bytes {
+            return Ok(Attachment::new(&attachment.name, "image/png", png.into_inner()));
+        }
+    }
+
+    let rgb = image.to_rgb8();
+
+    for quality in [90, 80, 70, 60, 50, 40] {
+        let mut jpeg = Vec::new();
+
+        JpegEncoder::new_with_quality(&mut jpeg, quality).encode_image(&rgb)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+        if jpeg.len() <= max_bytes {
+            return Ok(Attachment::new(&format!("{stem}.jpg"), "image/jpeg", jpeg));
+        }
+    }
+
+    Err(Box::new(std::io::Error::other(format!("{} cannot be made smaller than {max_bytes} bytes", attachment.name))))
+}
+
+/// How generated images are returned
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    /// Link valid for an hour
+    Url,
+    B64Json,
+}
+
+/// Options for image generation, edits and variations. Unset options are
+/// left to the provider. The model defaults to GPT_IMAGE_MODEL, or the
+/// provider's default.
+#[derive(Debug, Clone, Default)]
+pub struct ImageOptions {
+    pub model: Option<String>,
+    /// Number of images
+    pub n: Option<u32>,
+    /// e.g. "1024x1024"
+    pub size: Option<String>,
+    pub response_format: Option<ImageFormat>,
+}
+
+impl ImageOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn model(mut self, model: &str) -> Self {
+        self.model = Some(model.into());
+        self
+    }
+
+    pub fn n(mut self, n: u32) -> Self {
+        self.n = Some(n);
+        self
+    }
+
+    pub fn size(mut self, size: &str) -> Self {
+        self.size = Some(size.into());
+        self
+    }
+
+    pub fn response_format(mut self, response_format: ImageFormat) -> Self {
+        self.response_format = Some(response_format);
+        self
+    }
+
+    fn model_or_default(&self) -> Option<String> {
+        self.model.clone().or_else(|| env_var("GPT_IMAGE_MODEL"))
+    }
+
+    fn response_format_str(&self) -> Option<&'static str> {
+        self.response_format.map(|f| match f {
+            ImageFormat::Url => "url",
+            ImageFormat::B64Json => "b64_json",
+        })
+    }
+}
+
+/// An image made by the provider, as a URL or base64 data
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GeneratedImage {
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub b64_json: Option<String>,
+    /// Prompt as rewritten by the provider, if it did
+    #[serde(default)]
+    pub revised_prompt: Option<String>,
+}
+
+impl GeneratedImage {
+    /// Image bytes, decoded or downloaded
+    pub async fn data(&self) -> Result<Vec<u8>, Box<dyn std::error::Error + Send>> {
+        match (&self.b64_json, &self.url) {
+            (Some(b64), _) => BASE64_STANDARD.decode(b64)
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) }),
+            (None, Some(url)) => Ok(load_image(url).await?.data),
+            (None, None) => Err(Box::new(std::io::Error::other("Image has neither data nor a URL"))),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ImagesResponse {
+    data: Vec<GeneratedImage>,
+}
+
+/// Images generated from a prompt
+pub async fn generate_image(prompt: &str, options: &ImageOptions) -> Result<Vec<GeneratedImage>, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_IMAGE_GENERATIONS_URL")?;
+    let mut body = serde_json::json!({ "prompt": prompt });
+
+    if let Some(model) = options.model_or_default() {
+        body["model"] = model.into();
+    }
+    if let Some(n) = options.n {
+        body["n"] = n.into();
+    }
+    if let Some(ref size) = options.size {
+        body["size"] = size.clone().into();
+    }
+    if let Some(format) = options.response_format_str() {
+        body["response_format"] = format.into();
+    }
+
+    let res = get_gpt_client().await?
+        .post(url)
+        .json(&body)
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    images_json(&res)
+}
+
+/// Image edited as the prompt describes. Where a mask is given only its
+/// transparent area is changed; without one the image itself must have
+/// a transparent area. Both must be square PNGs of the same size for
+/// dall-e-2.
+pub async fn edit_image(image: &Attachment, mask: Option<&Attachment>, prompt: &str, options: &ImageOptions) -> Result<Vec<GeneratedImage>, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_IMAGE_EDITS_URL")?;
+    let mut form = image_form(image, options)?.text("prompt", prompt.to_string());
+
+    if let Some(mask) = mask {
+        form = form.part("mask", image_part(mask)?);
+    }
+
+    post_image_form(&url, form).await
+}
+
+/// Variations on an image, a square PNG
+pub async fn image_variations(image: &Attachment, options: &ImageOptions) -> Result<Vec<GeneratedImage>, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("GPT_IMAGE_VARIATIONS_URL")?;
+
+    post_image_form(&url, image_form(image, options)?).await
+}
+
+fn image_part(image: &Attachment) -> Result<Part, Box<dyn std::error::Error + Send>> {
+    Part::bytes(image.data.clone())
+        .file_name(image.name.clone())
+        .mime_str(&image.mime_type)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+}
+
+fn image_form(image: &Attachment, options: &ImageOptions) -> Result<Form, Box<dyn std::error::Error + Send>> {
+    let mut form = Form::new().part("image", image_part(image)?);
+
+    if let Some(model) = options.model_or_default() {
+        form = form.text("model", model);
+    }
+    if let Some(n) = options.n {
+        form = form.text("n", n.to_string());
+    }
+    if let Some(ref size) = options.size {
+        form = form.text("size", size.clone());
+    }
+    if let Some(format) = options.response_format_str() {
+        form = form.text("response_format", format);
+    }
+
+    Ok(form)
+}
+
+async fn post_image_form(url: &str, form: Form) -> Result<Vec<GeneratedImage>, Box<dyn std::error::Error + Send>> {
+    let res = get_gpt_client().await?
+        .post(url)
+        .multipart(form)
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    images_json(&res)
+}
+
+fn images_json(res: &str) -> Result<Vec<GeneratedImage>, Box<dyn std::error::Error + Send>> {
+    if res.contains("\"error\"") {
+        let err = serde_json::from_str::<LlmError>(res)
+            .map(|e| e.error.to_string())
+            .unwrap_or(res.to_string());
+
+        return Err(Box::new(std::io::Error::other(err)));
+    }
+
+    serde_json::from_str::<ImagesResponse>(res)
+        .map(|r| r.data)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Mask for `edit_image` from a file or URL: it must be a PNG, with the
+/// area to edit transparent
+pub async fn load_mask(source: &str) -> Result<Attachment, Box<dyn std::error::Error + Send>> {
+    let mask = load_image(source).await?;
+
+    if !mask.data.starts_with(PNG_SIGNATURE) {
+        return Err(Box::new(std::io::Error::other(format!("{} is not a PNG, masks need an alpha channel", mask.name))));
+    }
+
+    Ok(Attachment { mime_type: "image/png".into(), ..mask })
+}
+
+/// Mask for `edit_image` of a width x height image that is transparent,
+/// and so edited, only in the rectangle at x, y of size w x h
+#[cfg(feature = "images")]
+pub fn rect_mask(width: u32, height: u32, x: u32, y: u32, w: u32, h: u32) -> Result<Attachment, Box<dyn std::error::Error + Send>> {
+    let mask = image::RgbaImage::from_fn(width, height, |px, py| {
+        let inside = px >= x && px < x.saturating_add(w) && py >= y && py < y.saturating_add(h);
+
+        image::Rgba([0, 0, 0, if inside { 0 } else { 255 }])
+    });
+    let mut png = std::io::Cursor::new(Vec::new());
+
+    mask.write_to(&mut png, image::ImageFormat::Png)
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    Ok(Attachment::new("mask.png", "image/png", png.into_inner()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    #[serial]
+    async fn test_load_image_url() {
+        let server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/photos/dot"))
+            .respond_with(ResponseTemplate::new(200).insert_header("content-type", "image/webp").set_body_bytes(vec![1, 2, 3]))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let image = load_image(&format!("{}/photos/dot?w=1", server.uri())).await.unwrap();
+
+        assert_eq!((image.name.as_str(), image.mime_type.as_str(), image.to_base64().as_str()), ("dot", "image/webp", "AQID"));
+        assert_eq!(image_limits("anthropic").0, 1568);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_edit_image() {
+        let server = crate::mock::mock_post("/v1/images/edits", 200, "gpt/images.json").await;
+
+        std::env::set_var("GPT_IMAGE_EDITS_URL", format!("{}/v1/images/edits", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        let image = Attachment::new("room.png", "image/png", PNG_SIGNATURE.to_vec());
+        let mask = Attachment::new("mask.png", "image/png", PNG_SIGNATURE.to_vec());
+        let options = ImageOptions::new().model("dall-e-2").size("256x256").response_format(ImageFormat::B64Json);
+        let images = edit_image(&image, Some(&mask), "Add a window", &options).await.unwrap();
+
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].data().await.unwrap(), b"PNG");
+
+        let body = String::from_utf8_lossy(&server.received_requests().await.unwrap()[0].body).to_string();
+
+        assert!(body.contains("name=\"mask\"; filename=\"mask.png\"") && body.contains("Add a window") && body.contains("b64_json"));
+    }
+
+    #[cfg(feature = "images")]
+    #[test]
+    fn test_fit_image() {
+        let mut png = std::io::Cursor::new(Vec::new());
+
+        image::DynamicImage::new_rgb8(2000, 500).write_to(&mut png, image::ImageFormat::Png).unwrap();
+
+        let wide = Attachment::new("wide.png", "image/png", png.into_inner());
+        let fitted = fit_image(&wide, 1000, 5 * 1024 * 1024).unwrap();
+        let size = image::load_from_memory(&fitted.data).unwrap();
+
+        assert_eq!((size.width(), size.height()), (1000, 250));
+        assert_eq!(fitted.mime_type, "image/png");
+
+        let photo = Attachment { mime_type: "image/webp".into(), ..wide };
+        let fitted = fit_image(&photo, 1000, 5 * 1024 * 1024).unwrap();
+
+        assert_eq!((fitted.name.as_str(), fitted.mime_type.as_str()), ("wide.jpg", "image/jpeg"));
+        assert!(fit_image(&photo, 1000, 100).is_err());
+
+        let mask = image::load_from_memory(&rect_mask(4, 4, 1, 1, 2, 2).unwrap().data).unwrap().to_rgba8();
+
+        assert_eq!((mask.get_pixel(0, 0)[3], mask.get_pixel(1, 2)[3], mask.get_pixel(3, 3)[3]), (255, 0, 255));
+    }
+}
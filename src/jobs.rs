@@ -0,0 +1,341 @@
+//! Prompt jobs kept in a local directory, one JSON file per job, so an
+//! unattended run can be stopped or crash and carry on where it was when
+//! run again. Jobs are queued, run a bounded number at a time and retried
+//! with exponential backoff on transient failures (no response, rate limits
+//! and server errors); their status and results can be read at any point.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use futures::StreamExt;
+use serde_derive::{Deserialize, Serialize};
+use crate::common::{LlmReturn, call_llm_model};
+use crate::error::LlmClientError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    /// Out of retries, the last error is kept
+    Failed,
+}
+
+/// A prompt to run and what became of it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub llm: String,
+    pub model: String,
+    pub system: String,
+    pub prompt: String,
+    pub temperature: f32,
+    pub is_json: bool,
+    pub status: JobStatus,
+    pub attempts: usize,
+    pub result: Option<LlmReturn>,
+    pub error: Option<String>,
+    /// Unix seconds
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Job {
+    /// Job to run prompt with a model, given an id when queued
+    pub fn new(llm: &str, model: &str, prompt: &str) -> Self {
+        Job {
+            id: String::new(),
+            llm: llm.into(),
+            model: model.into(),
+            system: String::new(),
+            prompt: prompt.into(),
+            temperature: 0.2,
+            is_json: false,
+            status: JobStatus::Queued,
+            attempts: 0,
+            result: None,
+            error: None,
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    pub fn system(mut self, system: &str) -> Self {
+        self.system = system.into();
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn json(mut self, is_json: bool) -> Self {
+        self.is_json = is_json;
+        self
+    }
+}
+
+/// Jobs in each status
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JobCounts {
+    pub queued: usize,
+    pub running: usize,
+    pub done: usize,
+    pub failed: usize,
+}
+
+/// Directory of jobs, created when first written
+#[derive(Debug, Clone)]
+pub struct JobQueue {
+    dir: std::path::PathBuf,
+}
+
+impl JobQueue {
+    pub fn new(dir: impl Into<std::path::PathBuf>) -> Self {
+        JobQueue { dir: dir.into() }
+    }
+
+    fn path(&self, id: &str) -> std::path::PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    /// Queue a job, returning its id
+    pub fn enqueue(&self, mut job: Job) -> Result<String, Box<dyn std::error::Error + Send>> {
+        static SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        // Ids sort in the order jobs were queued
+        job.id = format!("{:020}-{:06}", now.as_nanos(), SEQUENCE.fetch_add(1, Ordering::Relaxed) % 1_000_000);
+        job.status = JobStatus::Queued;
+        job.created_at = now.as_secs();
+        job.updated_at = now.as_secs();
+        self.save(&job)?;
+
+        Ok(job.id)
+    }
+
+    /// Write a job, aside then renamed so a crash never leaves half a file
+    pub fn save(&self, job: &Job) -> Result<(), Box<dyn std::error::Error + Send>> {
+        let json = serde_json::to_string(job)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+        let partial = self.dir.join(format!("{}.part", job.id));
+
+        std::fs::create_dir_all(&self.dir)
+            .and_then(|_| std::fs::write(&partial, json))
+            .and_then(|_| std::fs::rename(&partial, self.path(&job.id)))
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+    }
+
+    /// Job by id, None if there is no such job
+    pub fn get(&self, id: &str) -> Result<Option<Job>, Box<dyn std::error::Error + Send>> {
+        match std::fs::read_to_string(self.path(id)) {
+            Ok(json) => serde_json::from_str(&json)
+                .map(Some)
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    /// Every job, in the order queued
+    pub fn jobs(&self) -> Result<Vec<Job>, Box<dyn std::error::Error + Send>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Box::new(e)),
+        };
+        let mut jobs = Vec::new();
+
+        for entry in entries {
+            let path = entry.map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?.path();
+
+            if path.extension().is_some_and(|e| e == "json") {
+                if let Some(job) = self.get(&path.file_stem().unwrap_or_default().to_string_lossy())? {
+                    jobs.push(job);
+                }
+            }
+        }
+
+        jobs.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(jobs)
+    }
+
+    /// Jobs with the given status, in the order queued
+    pub fn jobs_with_status(&self, status: JobStatus) -> Result<Vec<Job>, Box<dyn std::error::Error + Send>> {
+        Ok(self.jobs()?.into_iter().filter(|j| j.status == status).collect())
+    }
+
+    pub fn counts(&self) -> Result<JobCounts, Box<dyn std::error::Error + Send>> {
+        Ok(self.jobs()?.iter().fold(JobCounts::default(), |mut counts, job| {
+            match job.status {
+                JobStatus::Queued => counts.queued += 1,
+                JobStatus::Running => counts.running += 1,
+                JobStatus::Done => counts.done += 1,
+                JobStatus::Failed => counts.failed += 1,
+            }
+            counts
+        }))
+    }
+
+    /// Queue failed jobs again, with their attempts reset
+    pub fn requeue_failed(&self) -> Result<usize, Box<dyn std::error::Error + Send>> {
+        let failed = self.jobs_with_status(JobStatus::Failed)?;
+
+        for mut job in failed.iter().cloned() {
+            job.status = JobStatus::Queued;
+            job.attempts = 0;
+            self.save(&job)?;
+        }
+
+        Ok(failed.len())
+    }
+}
+
+/// Runs the queued jobs of a queue, a bounded number at a time
+#[derive(Debug, Clone)]
+pub struct JobRunner {
+    pub queue: JobQueue,
+    /// Calls in flight at once, at least 1
+    pub concurrency: usize,
+    /// Further attempts after a transient failure before the job fails
+    pub retries: usize,
+    /// Wait before the first retry, doubled for each one after
+    pub backoff: std::time::Duration,
+}
+
+impl JobRunner {
+    pub fn new(queue: JobQueue) -> Self {
+        JobRunner { queue, concurrency: 4, retries: 2, backoff: std::time::Duration::from_secs(1) }
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn backoff(mut self, backoff: std::time::Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    // Call until done, failed for good or out of attempts, saving after each
+    async fn process(&self, mut job: Job) -> Result<Job, Box<dyn std::error::Error + Send>> {
+        while job.status == JobStatus::Queued || job.status == JobStatus::Running {
+            job.status = JobStatus::Running;
+            job.attempts += 1;
+            self.queue.save(&job)?;
+
+            let res = call_llm_model(&job.llm, &job.model, &job.system, std::slice::from_ref(&job.prompt), job.temperature, job.is_json, false).await;
+            let transient = is_transient(&res);
+
+            match res {
+                Ok(ret) if !ret.llm_type.is_error() => {
+                    job.status = JobStatus::Done;
+                    job.result = Some(ret);
+                    job.error = None;
+                },
+                Ok(ret) => job.error = Some(ret.text),
+                Err(e) => job.error = Some(e.to_string()),
+            }
+
+            if job.status == JobStatus::Running && (!transient || job.attempts > self.retries) {
+                job.status = JobStatus::Failed;
+            }
+            job.updated_at = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            self.queue.save(&job)?;
+
+            if job.status == JobStatus::Running {
+                tokio::time::sleep(self.backoff * 2u32.saturating_pow(job.attempts as u32 - 1)).await;
+            }
+        }
+
+        Ok(job)
+    }
+
+    /// Run every queued job, and any left running when a previous run
+    /// stopped, until each is done or failed. Returns the counts after.
+    pub async fn run(&self) -> Result<JobCounts, Box<dyn std::error::Error + Send>> {
+        let pending: Vec<Job> = self.queue.jobs()?
+            .into_iter()
+            .filter(|j| j.status == JobStatus::Queued || j.status == JobStatus::Running)
+            .collect();
+        let processed: Vec<_> = futures::stream::iter(pending)
+            .map(|job| self.process(job))
+            .buffer_unordered(self.concurrency.max(1))
+            .collect()
+            .await;
+
+        for res in processed {
+            res?;
+        }
+
+        self.queue.counts()
+    }
+}
+
+// Failures worth another attempt: no response, an open circuit, rate
+// limits and server errors, but not requests the provider rejected as
+// they stand. Error replies of unknown status are retried.
+fn is_transient(res: &Result<LlmReturn, Box<dyn std::error::Error + Send>>) -> bool {
+    match res {
+        Ok(ret) => ret.llm_type.is_error() && ret.status.is_none_or(|s| s == 429 || s >= 500),
+        Err(e) => e.downcast_ref::<reqwest::Error>().is_some()
+            || matches!(e.downcast_ref::<LlmClientError>(), Some(LlmClientError::CircuitOpen { .. })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[tokio::test]
+    #[serial]
+    async fn test_job_runner() {
+        let dir = std::env::temp_dir().join(format!("llmclient-jobs-{}", std::process::id()));
+        let queue = JobQueue::new(&dir);
+        let id = queue.enqueue(Job::new("gpt", "gpt-4o", "Say hello").system("Be brief")).unwrap();
+
+        assert_eq!(queue.get(&id).unwrap().unwrap().status, JobStatus::Queued);
+        assert!(queue.get("missing").unwrap().is_none());
+
+        let server = crate::mock::mock_post_sequence("/v1/chat/completions", &[(503, "gpt/error.json"), (200, "gpt/success.json")]).await;
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        let runner = JobRunner::new(JobQueue::new(&dir)).retries(1).backoff(std::time::Duration::from_millis(10));
+        let counts = runner.run().await.unwrap();
+
+        assert_eq!(counts, JobCounts { done: 1, ..Default::default() });
+
+        // As read back after a restart
+        let job = JobQueue::new(&dir).get(&id).unwrap().unwrap();
+
+        assert_eq!(job.attempts, 2);
+        assert_eq!(job.result.unwrap().text.trim(), "Hello there.");
+        assert_eq!(JobRunner::new(queue.clone()).run().await.unwrap().done, 1);
+
+        // A rejected request is not retried
+        let server = crate::mock::mock_post("/v1/chat/completions", 400, "gpt/error.json").await;
+        let id = queue.enqueue(Job::new("gpt", "gpt-4o", "Say hello")).unwrap();
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+
+        assert_eq!(runner.run().await.unwrap().failed, 1);
+        assert_eq!(queue.get(&id).unwrap().unwrap().attempts, 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+//! Rough language detection of replies, enough to notice a model drifting
+//! into English when asked to answer in another language. Non Latin scripts
+//! are told apart by their characters, Latin script languages by their most
+//! common words.
+use std::sync::RwLock;
+
+// ISO 639-1 code, English name and most common words of Latin script languages
+const LATIN: &[(&str, &str, &[&str])] = &[
+    ("en", "English", &["the", "and", "is", "are", "of", "to", "in", "that", "it", "with", "for", "this", "was", "you", "not", "which", "be", "have"]),
+    ("fr", "French", &["le", "la", "les", "de", "et", "est", "des", "du", "un", "une", "que", "qui", "dans", "pour", "pas", "sur", "au", "avec"]),
+    ("de", "German", &["der", "die", "das", "und", "ist", "nicht", "ein", "eine", "zu", "den", "mit", "von", "sich", "auf", "für", "auch", "es", "dem"]),
+    ("es", "Spanish", &["el", "la", "los", "las", "y", "es", "de", "que", "en", "un", "una", "por", "para", "con", "no", "del", "se", "su"]),
+    ("it", "Italian", &["il", "la", "di", "che", "è", "e", "un", "una", "per", "non", "gli", "del", "della", "con", "sono", "nel", "le", "si"]),
+    ("pt", "Portuguese", &["o", "a", "os", "as", "e", "é", "de", "que", "do", "da", "em", "um", "uma", "para", "com", "não", "no", "na"]),
+    ("nl", "Dutch", &["de", "het", "een", "en", "is", "van", "dat", "niet", "zijn", "op", "te", "met", "voor", "ook", "er", "maar", "die", "in"]),
+];
+
+// First and last characters of a script's blocks
+type Ranges = &'static [(char, char)];
+
+// Other scripts, by character range, with the language written in each
+const SCRIPTS: &[(&str, &str, Ranges)] = &[
+    ("ja", "Japanese", &[('\u{3040}', '\u{30ff}')]),
+    ("ko", "Korean", &[('\u{ac00}', '\u{d7af}'), ('\u{1100}', '\u{11ff}')]),
+    ("zh", "Chinese", &[('\u{4e00}', '\u{9fff}')]),
+    ("ru", "Russian", &[('\u{0400}', '\u{04ff}')]),
+    ("ar", "Arabic", &[('\u{0600}', '\u{06ff}')]),
+    ("el", "Greek", &[('\u{0370}', '\u{03ff}')]),
+    ("he", "Hebrew", &[('\u{0590}', '\u{05ff}')]),
+    ("hi", "Hindi", &[('\u{0900}', '\u{097f}')]),
+    ("th", "Thai", &[('\u{0e00}', '\u{0e7f}')]),
+];
+
+/// Fewest common words needed to name a Latin script language
+const MIN_WORDS: usize = 2;
+
+/// English name of a language code this module knows, e.g. "fr" is "French"
+pub fn language_name(code: &str) -> Option<&'static str> {
+    let code = code.to_lowercase();
+
+    LATIN.iter().map(|(c, n, _)| (c, n))
+        .chain(SCRIPTS.iter().map(|(c, n, _)| (c, n)))
+        .find(|(c, n)| **c == code || n.to_lowercase() == code)
+        .map(|(_, n)| *n)
+}
+
+/// ISO 639-1 code of the text's language, or None if it is too short or
+/// mixed to tell. Japanese is told from Chinese by its kana; Cyrillic is
+/// taken to be Russian.
+pub fn detect_language(text: &str) -> Option<&'static str> {
+    let letters: Vec<char> = text.chars().filter(|c| c.is_alphabetic()).collect();
+
+    if letters.is_empty() {
+        return None;
+    }
+
+    let in_script = |ranges: &[(char, char)]| letters.iter().filter(|c| ranges.iter().any(|(lo, hi)| (lo..=hi).contains(c))).count();
+    let scripts: Vec<(&str, usize)> = SCRIPTS.iter().map(|(code, _, ranges)| (*code, in_script(ranges))).collect();
+
+    // Japanese mixes kanji with kana, so any real share of kana decides it
+    if scripts[0].1 * 10 >= letters.len() {
+        return Some("ja");
+    }
+    if let Some((code, _)) = scripts.iter().filter(|(_, n)| n * 2 > letters.len()).max_by_key(|(_, n)| *n) {
+        return Some(code);
+    }
+
+    let words: Vec<String> = text.split(|c: char| !c.is_alphabetic())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect();
+    let mut counts: Vec<(&str, usize)> = LATIN.iter()
+        .map(|(code, _, common)| (*code, words.iter().filter(|w| common.contains(&w.as_str())).count()))
+        .collect();
+
+    counts.sort_by_key(|(_, n)| std::cmp::Reverse(*n));
+
+    match counts[..] {
+        [(code, n), (_, next), ..] if n >= MIN_WORDS && n > next => Some(code),
+        _ => None,
+    }
+}
+
+static REQUIRED_LANGUAGE: RwLock<Option<String>> = RwLock::new(None);
+
+/// Language (code or English name) replies must be in. A reply detected as
+/// another language is asked for again once, in this language. None, the
+/// default, accepts any language.
+pub fn set_required_language(language: Option<&str>) {
+    *REQUIRED_LANGUAGE.write().unwrap() = language.map(|l| l.to_string());
+}
+
+pub fn required_language() -> Option<String> {
+    REQUIRED_LANGUAGE.read().unwrap().clone()
+}
+
+/// Name of the required language if the text is detected as another one
+pub fn wrong_language(text: &str) -> Option<&'static str> {
+    let required = language_name(&required_language()?)?;
+    let detected = language_name(detect_language(text)?)?;
+
+    if detected != required { Some(required) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_language() {
+        assert_eq!(detect_language("The capital of Australia is Canberra, which is in the south east."), Some("en"));
+        assert_eq!(detect_language("La capitale de l'Australie est Canberra, dans le sud-est du pays."), Some("fr"));
+        assert_eq!(detect_language("Die Hauptstadt von Australien ist Canberra und nicht Sydney."), Some("de"));
+        assert_eq!(detect_language("La capital de Australia es Canberra, no Sídney."), Some("es"));
+        assert_eq!(detect_language("Столица Австралии — Канберра."), Some("ru"));
+        assert_eq!(detect_language("オーストラリアの首都はキャンベラです。"), Some("ja"));
+        assert_eq!(detect_language("澳大利亚的首都是堪培拉。"), Some("zh"));
+        assert_eq!(detect_language("Canberra"), None);
+        assert_eq!(detect_language("42"), None);
+        assert_eq!(language_name("FR"), Some("French"));
+        assert_eq!(language_name("german"), Some("German"));
+    }
+}
@@ -4,5 +4,58 @@ pub mod gpt;
 pub mod claude;
 pub mod mistral;
 pub mod groq;
+pub mod replicate;
 pub mod functions;
 pub mod caller;
+pub mod batch;
+pub mod pending;
+pub mod files;
+pub mod config;
+pub mod pricing;
+pub mod error;
+pub mod capabilities;
+pub mod credentials;
+pub mod providers;
+pub mod repair;
+pub mod template;
+pub mod tokens;
+pub mod chunking;
+pub mod embeddings;
+pub mod images;
+pub mod audio;
+pub mod fim;
+pub mod completions;
+pub mod vector_store;
+pub mod gpt_vector_store;
+pub mod rerank;
+pub mod compress;
+pub mod session;
+pub mod chain;
+pub mod mapreduce;
+pub mod translate;
+pub mod classify;
+pub mod extract;
+pub mod scoring;
+pub mod guardrails;
+pub mod filters;
+pub mod postprocess;
+pub mod language;
+pub mod redact;
+pub mod bench;
+pub mod eval;
+pub mod compare;
+pub mod dataset;
+pub mod jobs;
+pub mod telemetry;
+pub mod dates;
+pub mod usage;
+pub mod quotas;
+pub mod tenants;
+pub mod cache;
+pub mod circuit;
+pub mod stream;
+#[cfg(feature = "realtime")]
+pub mod realtime;
+
+#[cfg(test)]
+mod mock;
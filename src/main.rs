@@ -1,39 +1,279 @@
+use clap::Parser;
 use crossterm::{
     style::{Color, ResetColor, SetForegroundColor},
     ExecutableCommand,
 };
+use std::collections::BTreeMap;
 use std::io::{stdin, stdout};
-use llmclient::common::call_llm_model;
+use serde_derive::{Deserialize, Serialize};
+use llmclient::capabilities::capabilities;
+use llmclient::common::{Attachment, call_llm_model_with_attachments, get_model, list_models, llm_name, ping};
+use llmclient::compare::{compare_system, configuration};
+use llmclient::config::{Config, env_var, load_env_file, provider_defaults, set_provider_defaults};
+use llmclient::pricing::cost;
+use llmclient::session::{MemoryPolicy, SummaryMemory};
+use llmclient::tokens::context_used;
+
+mod markdown;
+mod transcript;
+
+/// Interactive dialogue with an LLM
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// LLM provider: gemini, gpt, claude, mistral or groq
+    #[arg(short, long)]
+    provider: Option<String>,
+
+    /// Model to run, must be compatible with the provider
+    #[arg(short, long)]
+    model: Option<String>,
+
+    /// Sampling temperature
+    #[arg(short, long)]
+    temperature: Option<f32>,
+
+    /// File containing optional 'system' instructions
+    #[arg(short, long)]
+    system_file: Option<String>,
+
+    /// Configuration file supplying defaults for the above
+    #[arg(short, long)]
+    config: Option<String>,
+
+    /// .env file of settings such as API keys, loaded before anything else.
+    /// Variables already set are left alone.
+    #[arg(long)]
+    env_file: Option<String>,
+
+    /// Resume a previously saved session
+    #[arg(short, long)]
+    resume: Option<String>,
+
+    /// Print answers as returned, without markdown rendering
+    #[arg(long)]
+    plain: bool,
+
+    /// Show tokens and estimated cost after each answer
+    #[arg(long)]
+    stats: bool,
+
+    /// Warn when the dialogue fills this percentage of the model's context window [default: 80]
+    #[arg(long)]
+    context_warning: Option<u8>,
+
+    /// List models available from the provider and exit
+    #[arg(long)]
+    list_models: bool,
+
+    /// Check the provider is reachable and credentials are valid, then exit
+    #[arg(long)]
+    ping: bool,
+
+    /// Ask one question of several providers side by side, then exit.
+    /// Repeat for each provider, optionally with a model, e.g. gpt:gpt-4o
+    #[arg(long)]
+    compare: Vec<String>,
+}
+
+/// Dialogue state that can be saved and resumed
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+struct Session {
+    system: String,
+    prompts: Vec<String>,
+    timer: f64,
+    in_tok: usize,
+    out_tok: usize,
+    all_tok: usize,
+    #[serde(default)]
+    cost: f64,
+    /// When each prompt was added, seconds since the Unix epoch
+    #[serde(default)]
+    times: Vec<u64>,
+}
+
+impl Session {
+    // File for a session, names that would reach outside dir are refused
+    fn path(dir: &str, name: &str) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.contains("..") {
+            return Err("session names cannot be empty or contain path separators or '..'".into());
+        }
+
+        Ok(std::path::Path::new(dir).join(format!("{name}.json")))
+    }
+
+    fn save(&self, dir: &str, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let path = Self::path(dir, name)?;
+
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+
+        Ok(())
+    }
+
+    fn load(dir: &str, name: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let text = std::fs::read_to_string(Self::path(dir, name)?)?;
+
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    fn push(&mut self, prompt: String) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        // Sessions saved before times were kept have none for earlier prompts
+        self.times.resize(self.prompts.len(), 0);
+        self.prompts.push(prompt);
+        self.times.push(now);
+    }
+
+    /// Replace older turns with a summary by the model in use, so the
+    /// dialogue fits in the given percentage of the model's context window.
+    /// The summary gets what the system prompt and kept turns leave.
+    async fn summarise(&mut self, llm: &str, model: &str, percent: f64) -> Result<(), Box<dyn std::error::Error + Send>> {
+        let Some(capabilities) = capabilities(model) else {
+            return Ok(());
+        };
+        let budget = (capabilities.max_context as f64 * percent / 100.0) as usize;
+        let prompts = SummaryMemory::new(llm, model, budget).apply(&self.system, self.prompts.clone()).await?;
+
+        if prompts.len() == self.prompts.len() {
+            return Ok(());
+        }
+
+        // The summary and its acknowledgement are new, later turns keep their times
+        let kept = prompts.len() - 2;
+        self.times.resize(self.prompts.len(), 0);
+        self.times = [0, 0].into_iter().chain(self.times.split_off(self.times.len() - kept)).collect();
+        self.prompts = prompts;
+
+        Ok(())
+    }
+
+    /// Write the dialogue as HTML if the file ends in .html or .htm, otherwise as Markdown
+    fn export(&self, path: &str, llm: &str, model: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let turns = transcript::turns(&self.prompts, &self.times);
+        let title = format!("Conversation with {llm} ({model})");
+        let stats = format!("Elapsed time: {:.2} secs, Tokens in: {} out: {} all: {}, Estimated cost: ${:.4}",
+                            self.timer, self.in_tok, self.out_tok, self.all_tok, self.cost);
+        let extension = std::path::Path::new(path).extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let text = match extension.as_str() {
+            "html" | "htm" => transcript::to_html(&title, &self.system, &turns, &stats),
+            _ => transcript::to_markdown(&title, &self.system, &turns, &stats),
+        };
+
+        std::fs::write(path, text)?;
+
+        Ok(())
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    let mut llm = "4";
-    let mut model = "llama3-70b-8192";
-    let args: Vec<String> = std::env::args().collect();
-
-    if args.len() <= 1 {
-        highlight("Please supply first argument to indicate the LLM to run : 0 = Gemini, 1 = GPT, Claude = 2, Mistral = 3, Groq = 4");
-        highlight(&format!("This run will default to {llm}\n"));
-    } else {
-        llm = &args[1];
+    let args = Args::parse();
+
+    if let Some(ref path) = args.env_file {
+        if let Err(e) = load_env_file(path) {
+            highlight(&format!("Cannot load {path}: {e}"));
+
+            return;
+        }
+    }
+
+    let config = match args.config {
+        Some(ref path) => Config::load_from(path),
+        None => Config::load(),
+    };
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            highlight(&format!("Invalid configuration file: {e}"));
+
+            return;
+        }
+    };
+
+    if let Some(ref path) = config.env_file {
+        if let Err(e) = load_env_file(path) {
+            highlight(&format!("Cannot load {path}: {e}"));
+
+            return;
+        }
     }
 
-    if args.len() <= 2 {
-        highlight("Please supply argument to indicate the model to run");
-        highlight(&format!("This run will default to {model}\n"));
-        highlight("Please ensure model exists and is compatable with llm\n");
-    } else {
-        model = &args[2];
+    set_provider_defaults(config.providers);
+
+    let provider: String = args.provider
+        .or(config.provider)
+        .or(env_var("LLM_TO_USE"))
+        .unwrap_or("groq".into());
+    let llm = match llm_name(&provider) {
+        Some(llm) => llm,
+        None => {
+            highlight(&format!("Unknown provider '{provider}': use gemini, gpt, claude, mistral or groq"));
+
+            return;
+        }
+    };
+
+    if args.ping {
+        let health = ping(llm).await;
+
+        if health.is_ok() {
+            println!("{health}");
+        } else {
+            highlight(&health.to_string());
+        }
+
+        return;
+    }
+
+    if args.list_models {
+        match list_models(llm).await {
+            Ok(models) => models.iter().for_each(|m| println!("{m}")),
+            Err(e) => highlight(&format!("Cannot list {llm} models: {e}")),
+        }
+
+        return;
     }
 
-    highlight(&format!("Running {llm}: {model}\n"));
+    let model: String = match args.model.or(config.model).map(Ok).unwrap_or_else(|| get_model(llm)) {
+        Ok(model) => model,
+        Err(e) => {
+            highlight(&format!("No {llm} model: {e}"));
+
+            return;
+        }
+    };
+    let temperature: f32 = args.temperature
+        .or(config.temperature)
+        .unwrap_or_else(|| provider_defaults(llm).temperature());
+    let system_file: String = args.system_file
+        .or(config.system_file)
+        .unwrap_or("system.txt".into());
+    let sessions_dir: String = config.sessions_dir
+        .unwrap_or("sessions".into());
+    let context_warning: f64 = args.context_warning
+        .or(config.context_warning)
+        .unwrap_or(80)
+        .into();
+
+    highlight(&format!("Running {llm}: {model} at temperature {temperature}\n"));
     highlight("Type multiple lines and then end with ^D [or ^Z on Windows] for answer.");
     highlight("'quit' or 'exit' work too. To clear history 'new' or 'clear'");
     highlight("To show dialogue history 'show' or 'history'");
-    highlight("To show optional system content 'system'");
+    highlight("To show optional system content 'system', to change it 'system <text>' or 'system edit'");
+    highlight("To save or restore the dialogue 'save <name>' or 'load <name>'");
+    highlight("To try another line and keep this one 'branch <name>', then 'switch <name>' to go back; 'branches' lists them");
+    highlight("To share the dialogue as Markdown or HTML 'export <file.md>' or 'export <file.html>'");
+    highlight("To send a file or image with the next question 'attach <path>'");
 
     // Are 'system' context instructions available?
-    let system_data = std::fs::read_to_string("system.txt");
+    let system_data = std::fs::read_to_string(&system_file);
 
     let system: String =
         if let Ok(system) = system_data {
@@ -42,13 +282,43 @@ async fn main() {
             "".into()
         };
 
-    let mut prompts: Vec<String> = Vec::new();
+    if !args.compare.is_empty() {
+        let mut configs: Vec<(&str, String)> = Vec::new();
+
+        for provider in &args.compare {
+            match configuration(provider) {
+                Some(config) => configs.push(config),
+                None => {
+                    highlight(&format!("Unknown provider '{provider}' or no model set for it: use gemini, gpt, claude, mistral or groq"));
+
+                    return;
+                }
+            }
+        }
 
-    // Statistics
-    let mut timer = 0.0;
-    let mut in_tok = 0;
-    let mut out_tok = 0;
-    let mut all_tok = 0;
+        let prompt = get_user_response("Your question: ");
+        let configs: Vec<(&str, &str)> = configs.iter().map(|(llm, model)| (*llm, model.as_str())).collect();
+
+        println!("{}", compare_system(&system, &prompt, &configs).await);
+
+        return;
+    }
+
+    let mut session = Session { system, ..Session::default() };
+    let mut attachments: Vec<Attachment> = Vec::new();
+    // Other lines of the dialogue, by name
+    let mut branches: BTreeMap<String, Session> = BTreeMap::new();
+    let mut current_branch = String::from("main");
+
+    if let Some(ref name) = args.resume {
+        match Session::load(&sessions_dir, name) {
+            Ok(saved) => {
+                highlight(&format!("Resumed session '{name}' with {} prompts", saved.prompts.len()));
+                session = saved;
+            },
+            Err(e) => highlight(&format!("Cannot resume session '{name}': {e}")),
+        }
+    }
 
     loop {
         let prompt = get_user_response("Your question: ");
@@ -65,51 +335,171 @@ async fn main() {
                     break
                 },
                 "new" | "clear" => {
-                    prompts.truncate(0);
+                    session.prompts.truncate(0);
+                    session.times.truncate(0);
 
                     continue
                 },
                 "show" | "history" => {
-                    println!("{:?}", prompts);
+                    println!("{:?}", session.prompts);
 
                     continue
                 },
                 "system" => {
-                    println!("{:?}", system);
+                    println!("{:?}", session.system);
+
+                    continue;
+                },
+                "system edit" => {
+                    match edit_text(&session.system) {
+                        Ok(system) => {
+                            session.system = system;
+                            highlight("System content updated");
+                        },
+                        Err(e) => highlight(&format!("Cannot edit system content: {e}")),
+                    }
+
+                    continue;
+                },
+                _ if prompt_lower.starts_with("system ") => {
+                    session.system = prompt[7..].trim().to_string();
+                    highlight("System content updated");
+
+                    continue;
+                },
+                _ if prompt_lower.starts_with("save ") => {
+                    let name = prompt[5..].trim();
+
+                    match session.save(&sessions_dir, name) {
+                        Ok(()) => highlight(&format!("Saved session '{name}'")),
+                        Err(e) => highlight(&format!("Cannot save session '{name}': {e}")),
+                    }
+
+                    continue;
+                },
+                "branches" => {
+                    println!("* {current_branch} ({} prompts)", session.prompts.len());
+                    for (name, branch) in &branches {
+                        println!("  {name} ({} prompts)", branch.prompts.len());
+                    }
+
+                    continue;
+                },
+                _ if prompt_lower.starts_with("branch ") => {
+                    let name = prompt[7..].trim().to_string();
+
+                    if name == current_branch || branches.contains_key(&name) {
+                        highlight(&format!("Branch '{name}' already exists"));
+                    } else {
+                        highlight(&format!("Branch '{name}' keeps the dialogue as it is now, carrying on in '{current_branch}'"));
+                        branches.insert(name, session.clone());
+                    }
+
+                    continue;
+                },
+                _ if prompt_lower.starts_with("switch ") => {
+                    let name = prompt[7..].trim().to_string();
+
+                    match branches.remove(&name) {
+                        Some(branch) => {
+                            highlight(&format!("Switched to '{name}' with {} prompts", branch.prompts.len()));
+                            branches.insert(std::mem::replace(&mut current_branch, name), std::mem::replace(&mut session, branch));
+                        },
+                        None => highlight(&format!("No branch '{name}'")),
+                    }
+
+                    continue;
+                },
+                _ if prompt_lower.starts_with("export ") => {
+                    let path = prompt[7..].trim();
+
+                    match session.export(path, llm, &model) {
+                        Ok(()) => highlight(&format!("Exported conversation to {path}")),
+                        Err(e) => highlight(&format!("Cannot export to '{path}': {e}")),
+                    }
+
+                    continue;
+                },
+                _ if prompt_lower.starts_with("attach ") => {
+                    let path = prompt[7..].trim();
+
+                    match Attachment::from_file(path) {
+                        Ok(attachment) => {
+                            highlight(&format!("Attached {} ({}, {} bytes) to next question",
+                                               attachment.name, attachment.mime_type, attachment.data.len()));
+                            attachments.push(attachment);
+                        },
+                        Err(e) => highlight(&format!("Cannot attach '{path}': {e}")),
+                    }
+
+                    continue;
+                },
+                _ if prompt_lower.starts_with("load ") => {
+                    let name = prompt[5..].trim();
+
+                    match Session::load(&sessions_dir, name) {
+                        Ok(saved) => {
+                            highlight(&format!("Loaded session '{name}' with {} prompts", saved.prompts.len()));
+                            session = saved;
+                        },
+                        Err(e) => highlight(&format!("Cannot load session '{name}': {e}")),
+                    }
 
                     continue;
                 },
                 _ => prompt,
             };
 
-        prompts.push(prompt);
-
-        let res = match llm {
-            "0" | "gemini" =>
-                call_llm_model("gemini", model, &system, &prompts, 0.2, false, true).await,
-            "1" | "gpt" =>
-                call_llm_model("gpt", model, &system, &prompts, 0.2, false, true).await,
-            "2" | "claude" =>
-                call_llm_model("claude", model, &system, &prompts, 0.2, false, true).await,
-            "3" | "mistral" =>
-                call_llm_model("mistral", model, &system, &prompts, 0.2, false, true).await,
-            "4" | "groq" =>
-                call_llm_model("groq", model, &system, &prompts, 0.2, false, true).await,
-            _ => todo!()
-        };
+        session.push(prompt);
+
+        let res = call_llm_model_with_attachments(llm, &model, &session.system, &session.prompts, &attachments, temperature, false, true).await;
+
+        attachments.clear();
 
         match res {
             Ok(ret) => {
+                let usage = ret.usage;
+
+                session.timer += ret.timing.total;
+                session.in_tok += ret.usage.0;
+                session.out_tok += ret.usage.1;
+                session.all_tok += ret.usage.2;
 
-                timer += ret.timing;
-                in_tok += ret.usage.0;
-                out_tok += ret.usage.1;
-                all_tok += ret.usage.2;
+                let turn_cost = cost(&model, usage);
+                session.cost += turn_cost.unwrap_or(0.0);
 
                 let ret = ret.to_string();
-                println!("> {}", ret);
+                if args.plain {
+                    println!("> {}", ret);
+                } else {
+                    println!("{}", markdown::render(&ret));
+                }
 
-                prompts.push(ret);
+                if args.stats {
+                    let turn_cost = match turn_cost {
+                        Some(c) => format!("${c:.4}"),
+                        None => "unknown".into(),
+                    };
+
+                    highlight(&format!("This turn: Tokens in: {} out: {} cost: {turn_cost} | Running total: Tokens: {} cost: ${:.4}",
+                                       usage.0, usage.1, session.all_tok, session.cost));
+                }
+
+                session.push(ret);
+
+                if let Some(used) = context_used(&model, &session.system, &session.prompts) {
+                    if used >= context_warning {
+                        highlight(&format!("The dialogue is using about {used:.0}% of the {model} context window"));
+
+                        if confirm("Summarise older turns to make room? [y/N] ") {
+                            match session.summarise(llm, &model, context_warning).await {
+                                Ok(()) => highlight(&format!("Summarised, now using about {:.0}%",
+                                                             context_used(&model, &session.system, &session.prompts).unwrap_or(0.0))),
+                                Err(e) => highlight(&format!("Cannot summarise: {e}")),
+                            }
+                        }
+                    }
+                }
             },
             Err(e) => {
                 println!("Error (aborting): {}", e);
@@ -119,8 +509,8 @@ async fn main() {
         }
     }
 
-    println!("Statistics: Elapsed time: {} secs, Tokens in: {} out: {} all: {}",
-             timer, in_tok, out_tok, all_tok);
+    println!("Statistics: Elapsed time: {} secs, Tokens in: {} out: {} all: {}, Estimated cost: ${:.4}",
+             session.timer, session.in_tok, session.out_tok, session.all_tok, session.cost);
 }
 
 fn highlight(text: &str) {
@@ -134,6 +524,33 @@ fn highlight(text: &str) {
     stdout.execute(ResetColor).unwrap();
 }
 
+// Edit text in $VISUAL or $EDITOR, falling back to vi (notepad on Windows)
+fn edit_text(text: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let default = if cfg!(windows) { "notepad" } else { "vi" };
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or(default.into());
+    let path = std::env::temp_dir().join(format!("llmclient-system-{}.txt", std::process::id()));
+
+    std::fs::write(&path, text)?;
+
+    // The editor may have arguments e.g. "code --wait"
+    let mut words = editor.split_whitespace();
+    let status = std::process::Command::new(words.next().unwrap_or(default))
+        .args(words)
+        .arg(&path)
+        .status()?;
+    let edited = std::fs::read_to_string(&path);
+
+    std::fs::remove_file(&path)?;
+
+    if !status.success() {
+        return Err(format!("{editor} exited with {status}").into());
+    }
+
+    Ok(edited?.trim().to_string())
+}
+
 // Get user request
 fn get_user_response(question: &str) -> String {
     println!();
@@ -152,3 +569,11 @@ fn get_user_response(question: &str) -> String {
     // Trim whitespace and return
     user_response.trim().to_string()
 }
+
+fn confirm(question: &str) -> bool {
+    highlight(question);
+
+    let mut answer = String::new();
+
+    stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
+}
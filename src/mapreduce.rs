@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use futures::StreamExt;
+use crate::capabilities::capabilities;
+use crate::chunking::chunk_by_sentences;
+use crate::common::{LlmReturn, Timing, call_llm_model};
+use crate::template::PromptTemplate;
+use crate::tokens::estimate_tokens;
+
+/// Chunk size when the model's context window is not known
+const DEFAULT_CHUNK_TOKENS: usize = 4_000;
+
+/// Process a document too long for one call: split it into chunks, run the
+/// map prompt on each (`${chunk}`) concurrently, then the reduce prompt on
+/// the partial results joined together (`${results}`), e.g. summarise each
+/// section then combine the summaries. A document that fits in one chunk
+/// is only mapped.
+#[derive(Debug, Clone)]
+pub struct MapReduce {
+    pub llm: String,
+    pub model: String,
+    pub map: PromptTemplate,
+    pub reduce: PromptTemplate,
+    pub system: String,
+    pub temperature: f32,
+    /// Chunk size, by default a quarter of the model's context window
+    pub chunk_tokens: Option<usize>,
+    /// Tokens shared by consecutive chunks
+    pub overlap: usize,
+    /// Map calls in flight at once, at least 1
+    pub concurrency: usize,
+}
+
+impl MapReduce {
+    pub fn new(llm: &str, model: &str, map: PromptTemplate, reduce: PromptTemplate) -> Self {
+        MapReduce {
+            llm: llm.into(),
+            model: model.into(),
+            map,
+            reduce,
+            system: String::new(),
+            temperature: 0.2,
+            chunk_tokens: None,
+            overlap: 0,
+            concurrency: 4,
+        }
+    }
+
+    pub fn system(mut self, system: &str) -> Self {
+        self.system = system.into();
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn chunk_tokens(mut self, chunk_tokens: usize) -> Self {
+        self.chunk_tokens = Some(chunk_tokens.max(1));
+        self
+    }
+
+    pub fn overlap(mut self, overlap: usize) -> Self {
+        self.overlap = overlap;
+        self
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Chunks the document will be split into
+    pub fn chunks(&self, text: &str) -> Vec<String> {
+        let chunk_tokens = self.chunk_tokens
+            .or_else(|| capabilities(&self.model).map(|c| c.max_context / 4))
+            .unwrap_or(DEFAULT_CHUNK_TOKENS);
+
+        chunk_by_sentences(text, chunk_tokens, self.overlap)
+    }
+
+    /// Final reply, with the usage of every call added up and timed from start to finish
+    pub async fn run(&self, text: &str) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+        let start = std::time::Instant::now();
+        let chunks = self.chunks(text);
+        let partials: Vec<LlmReturn> = futures::stream::iter(chunks.iter())
+            .map(|chunk| self.call("map", &self.map, "chunk", chunk))
+            .buffered(self.concurrency.max(1))
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<_, _>>()?;
+
+        let mut ret = if partials.len() == 1 {
+            partials[0].clone()
+        } else {
+            let results: Vec<&str> = partials.iter().map(|p| p.text.trim()).collect();
+
+            self.call("reduce", &self.reduce, "results", &results.join("\n\n")).await?
+        };
+
+        if partials.len() > 1 {
+            ret.usage = partials.iter()
+                .fold(ret.usage, |(i, o, t), p| (i + p.usage.0, o + p.usage.1, t + p.usage.2));
+        }
+        ret.timing = Timing::new(start.elapsed().as_secs_f64(), ret.usage.1);
+
+        Ok(ret)
+    }
+
+    async fn call(&self, stage: &str, template: &PromptTemplate, name: &str, value: &str) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+        let prompt = template.render(&HashMap::from([(name, value)]))?;
+        let ret = call_llm_model(&self.llm, &self.model, &self.system, &[prompt], self.temperature, false, false).await?;
+
+        if ret.llm_type.is_error() {
+            return Err(Box::new(std::io::Error::other(format!("Map reduce {stage} failed: {}", ret.text))));
+        }
+
+        Ok(ret)
+    }
+}
+
+/// Fewest tokens `summarize_to_fit` will aim for
+const MIN_SUMMARY_TOKENS: usize = 200;
+
+/// Summarise text until it fits in target_tokens (at least 200), returning
+/// it unchanged if it already does. Text too long for the model is split
+/// into chunks summarised concurrently, and the joined summaries summarised
+/// again, until the whole fits. Fails if a round does not shorten it.
+pub async fn summarize_to_fit(llm: &str, model: &str, text: &str, target_tokens: usize) -> Result<String, Box<dyn std::error::Error + Send>> {
+    let target_tokens = target_tokens.max(MIN_SUMMARY_TOKENS);
+    let chunk_tokens = capabilities(model).map(|c| c.max_context / 4).unwrap_or(DEFAULT_CHUNK_TOKENS);
+    let mut text = text.to_string();
+
+    while estimate_tokens(&text) > target_tokens {
+        let chunks = chunk_by_sentences(&text, chunk_tokens, 0);
+        // Share of the target for each chunk's summary
+        let words = (target_tokens * 3 / 4 / chunks.len()).max(20);
+        let summaries: Vec<String> = futures::stream::iter(chunks.into_iter().map(|chunk| async move {
+                summarise(llm, model, &chunk, words).await
+            }))
+            .buffered(4)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<_, _>>()?;
+        let summary = summaries.join("\n\n");
+
+        if estimate_tokens(&summary) >= estimate_tokens(&text) {
+            return Err(Box::new(std::io::Error::other(format!("Summaries from {model} are no shorter than the text"))));
+        }
+
+        text = summary;
+    }
+
+    Ok(text)
+}
+
+async fn summarise(llm: &str, model: &str, text: &str, words: usize) -> Result<String, Box<dyn std::error::Error + Send>> {
+    let system = format!("Summarise the text in under {words} words, keeping names, facts, figures, decisions and open questions. Reply with the summary only.");
+    let ret = call_llm_model(llm, model, &system, &[text.to_string()], 0.2, false, false).await?;
+
+    if ret.llm_type.is_error() {
+        return Err(Box::new(std::io::Error::other(format!("Summary failed: {}", ret.text))));
+    }
+
+    Ok(ret.text.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[tokio::test]
+    #[serial]
+    async fn test_map_reduce() {
+        let server = wiremock::MockServer::start().await;
+
+        wiremock::Mock::given(wiremock::matchers::method("POST"))
+            .respond_with(wiremock::ResponseTemplate::new(200).set_body_string(crate::mock::fixture("gpt/success.json")))
+            .expect(3)
+            .mount(&server)
+            .await;
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        let map_reduce = MapReduce::new("gpt", "gpt-4o", PromptTemplate::new("Summarise: ${chunk}"), PromptTemplate::new("Combine: ${results}"))
+            .chunk_tokens(7);
+
+        assert_eq!(map_reduce.chunks("The first part is here. The second part is there."), vec!["The first part is here.", "The second part is there."]);
+
+        let ret = map_reduce.run("The first part is here. The second part is there.").await.unwrap();
+
+        assert_eq!(ret.text.trim(), "Hello there.");
+        assert_eq!(ret.usage, (36, 9, 45));
+
+        let requests = server.received_requests().await.unwrap();
+
+        assert!(String::from_utf8_lossy(&requests[2].body).contains("Combine: Hello there.\\n\\nHello there."));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_summarize_to_fit() {
+        let server = crate::mock::mock_post("/v1/chat/completions", 200, "gpt/success.json").await;
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        // Fits already, so no call
+        assert_eq!(summarize_to_fit("gpt", "gpt-4o", "Short text.", 10).await.unwrap(), "Short text.");
+
+        let long = "This sentence says very little. ".repeat(100);
+
+        assert_eq!(summarize_to_fit("gpt", "gpt-4o", &long, 200).await.unwrap(), "Hello there.");
+    }
+}
@@ -0,0 +1,174 @@
+use crossterm::style::{Color, Stylize};
+
+// Minimal terminal rendering of the markdown commonly returned by LLMs:
+// headings, bullets, bold, italic, inline code and fenced code blocks
+// with simple keyword highlighting.
+
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "case", "class", "const", "continue", "def",
+    "else", "enum", "except", "export", "extends", "false", "fn", "for", "from",
+    "func", "function", "if", "impl", "import", "in", "interface", "let", "loop",
+    "match", "mod", "mut", "new", "None", "null", "pub", "raise", "return", "self",
+    "Self", "static", "struct", "switch", "trait", "True", "False", "true", "try",
+    "type", "use", "var", "while", "with", "yield",
+];
+
+/// Render markdown text with terminal styling
+pub fn render(text: &str) -> String {
+    let mut out = String::new();
+    let mut in_code = false;
+
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+
+        if trimmed.starts_with("```") {
+            in_code = !in_code;
+            if in_code {
+                let lang = trimmed.trim_start_matches('`').trim();
+                out.push_str(&format!("{}\n", format!("--- {lang}").with(Color::DarkGrey)));
+            } else {
+                out.push_str(&format!("{}\n", "---".with(Color::DarkGrey)));
+            }
+        } else if in_code {
+            out.push_str(&highlight_code(line));
+            out.push('\n');
+        } else if let Some(heading) = heading(trimmed) {
+            out.push_str(&format!("{}\n", heading.bold().with(Color::Cyan)));
+        } else if let Some(item) = trimmed.strip_prefix("- ").or(trimmed.strip_prefix("* ")) {
+            let indent = &line[..line.len() - trimmed.len()];
+            out.push_str(&format!("{indent}  • {}\n", inline(item)));
+        } else {
+            out.push_str(&inline(line));
+            out.push('\n');
+        }
+    }
+
+    out
+}
+
+fn heading(line: &str) -> Option<&str> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+
+    if (1..=6).contains(&level) && line[level..].starts_with(' ') {
+        Some(line[level..].trim())
+    } else {
+        None
+    }
+}
+
+// Bold, italic and inline code spans
+fn inline(line: &str) -> String {
+    let mut out = String::new();
+    let mut rest = line;
+
+    while !rest.is_empty() {
+        if let Some((span, after)) = delimited(rest, "**") {
+            out.push_str(&span.bold().to_string());
+            rest = after;
+        } else if let Some((span, after)) = delimited(rest, "`") {
+            out.push_str(&span.with(Color::Yellow).to_string());
+            rest = after;
+        } else if let Some((span, after)) = delimited(rest, "*") {
+            out.push_str(&span.italic().to_string());
+            rest = after;
+        } else {
+            let c = rest.chars().next().unwrap();
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    out
+}
+
+// Span starting with delimiter and closed on the same line
+fn delimited<'a>(text: &'a str, delim: &str) -> Option<(&'a str, &'a str)> {
+    let body = text.strip_prefix(delim)?;
+
+    if body.starts_with(' ') || body.starts_with(delim) {
+        return None;
+    }
+
+    let end = body.find(delim)?;
+
+    if end == 0 {
+        None
+    } else {
+        Some((&body[..end], &body[end + delim.len()..]))
+    }
+}
+
+fn highlight_code(line: &str) -> String {
+    let trimmed = line.trim_start();
+
+    if trimmed.starts_with("//") || trimmed.starts_with('#') || trimmed.starts_with("--") {
+        return line.with(Color::DarkGrey).to_string();
+    }
+
+    let mut out = String::new();
+    let mut word = String::new();
+    let mut quote: Option<char> = None;
+
+    for c in line.chars() {
+        if let Some(q) = quote {
+            word.push(c);
+            if c == q {
+                out.push_str(&word.as_str().with(Color::Green).to_string());
+                word.clear();
+                quote = None;
+            }
+        } else if c == '"' || c == '\'' {
+            out.push_str(&code_word(&word));
+            word = c.to_string();
+            quote = Some(c);
+        } else if c.is_alphanumeric() || c == '_' {
+            word.push(c);
+        } else {
+            out.push_str(&code_word(&word));
+            word.clear();
+            out.push(c);
+        }
+    }
+
+    if quote.is_some() {
+        out.push_str(&word.as_str().with(Color::Green).to_string());
+    } else {
+        out.push_str(&code_word(&word));
+    }
+
+    out
+}
+
+fn code_word(word: &str) -> String {
+    if KEYWORDS.contains(&word) {
+        word.with(Color::Magenta).to_string()
+    } else if !word.is_empty() && word.chars().all(|c| c.is_ascii_digit()) {
+        word.with(Color::Blue).to_string()
+    } else {
+        word.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_heading_and_bullets() {
+        let out = render("## Title\n- one\nplain **bold** text");
+
+        assert!(out.contains("Title"));
+        assert!(!out.contains("##"));
+        assert!(out.contains("• one"));
+        assert!(!out.contains("**"));
+    }
+
+    #[test]
+    fn test_render_code_block() {
+        let out = render("```rust\nlet x = \"s\";\n```");
+
+        assert!(out.contains("--- rust"));
+        assert!(out.contains("let".with(Color::Magenta).to_string().as_str()));
+        assert!(!out.contains("```"));
+    }
+}
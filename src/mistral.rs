@@ -1,10 +1,22 @@
 use reqwest::header::{HeaderMap, HeaderValue};
 use reqwest::Client;
-use std::env;
 use serde_derive::{Deserialize, Serialize};
 use crate::common::*;
-use crate::gpt::GptMessage as MistralMessage;
+use crate::config::{env_var, provider_defaults, required_env};
+use crate::credentials::credential;
+use crate::capabilities::check_parameters;
+use crate::error::ConfigError;
+use crate::providers::providers;
+use crate::gpt::{GptMessage as MistralMessage, gpt_models_to_info};
 use crate::functions::*;
+use crate::postprocess::strip_fences_by_default;
+
+// Used when neither the caller nor the configuration give max_tokens
+const MAX_TOKENS: usize = 4096;
+
+fn default_max_tokens() -> usize {
+    provider_defaults("mistral").max_tokens.unwrap_or(MAX_TOKENS)
+}
 
 // Input structures
 // Chat
@@ -15,23 +27,58 @@ pub struct MistralCompletion {
     pub tools: Option<Vec<FunctionCall>>,
     pub messages: Vec<MistralMessage>,
     pub temperature: f32,
-    //pub top_p: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
     pub max_tokens: usize,
-    //pub stream: bool,
-    //pub random_seed: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub random_seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub safe_prompt: Option<bool>,
+    //pub stream: bool,     // When responses can be streamed
 }
 
 impl MistralCompletion {
+    // Completion from common parameters, shared by the calls with and without attachments
+    fn completion(model: &str, system: &str, user: &[String], temperature: f32, _is_json: bool, is_chat: bool, function: Option<Vec<Function>>) -> Self {
+        let mut messages = Vec::new();
+
+        if !system.is_empty() {
+            messages.push(MistralMessage::text("system", system));
+        }
+
+        user.iter()
+            .enumerate()
+            .for_each(|(i, c)| {
+                let role = if !is_chat || i % 2 == 0 { "user" } else { "assistant" };
+
+                messages.push(MistralMessage::text(role, c));
+            });
+
+        MistralCompletion {
+            model: model.into(),
+            tools: Some(FunctionCall::functions(function)),
+            messages,
+            temperature,
+            top_p: provider_defaults("mistral").top_p,
+            max_tokens: default_max_tokens(),
+            random_seed: None,
+            safe_prompt: None,
+        }
+    }
+
     /// Create chat completion
     pub fn new(messages: Vec<MistralMessage>, temperature: f32, max_tokens: usize, _is_json: bool) -> Self {
-        let model: String = env::var("MISTRAL_MODEL").expect("MISTRAL_MODEL not found in enviroment variables");
+        let model: String = env_var("MISTRAL_MODEL").unwrap_or_default(); // checked when called
 
         MistralCompletion {
             model,
             tools: None,
             messages,
             temperature,
+            top_p: provider_defaults("mistral").top_p,
             max_tokens,
+            random_seed: None,
+            safe_prompt: None,
         }
     }
 
@@ -47,6 +94,21 @@ impl MistralCompletion {
         self.max_tokens = max_tokens;
     }
 
+    /// Nucleus sampling, 0.0 to 1.0
+    pub fn set_top_p(&mut self, top_p: Option<f32>) {
+        self.top_p = top_p;
+    }
+
+    /// Seed for repeatable sampling
+    pub fn set_random_seed(&mut self, random_seed: Option<u64>) {
+        self.random_seed = random_seed;
+    }
+
+    /// Have Mistral prepend its safety guardrail prompt
+    pub fn set_safe_prompt(&mut self, safe_prompt: bool) {
+        self.safe_prompt = Some(safe_prompt);
+    }
+
     /// Add a single new message
     pub fn add_message(&mut self, message: &MistralMessage) {
         self.messages.push(message.clone());
@@ -61,14 +123,19 @@ impl MistralCompletion {
 impl Default for MistralCompletion {
     /// Create default chat completion
     fn default() -> Self {
-        let model: String = env::var("MISTRAL_MODEL").expect("MISTRAL_MODEL not found in enviroment variables");
+        let model: String = env_var("MISTRAL_MODEL").unwrap_or_default(); // checked when called
+
+        let defaults = provider_defaults("mistral");
 
         MistralCompletion {
             model,
             tools: None,
             messages: Vec::new(),
-            temperature: 0.2,
-            max_tokens: 4096
+            temperature: defaults.temperature(),
+            top_p: defaults.top_p,
+            max_tokens: defaults.max_tokens.unwrap_or(MAX_TOKENS),
+            random_seed: None,
+            safe_prompt: None,
         }
     }
 }
@@ -121,7 +188,7 @@ impl LlmCompletion for MistralCompletion {
 
     /// Create and call llm by supplying data and common parameters
     async fn call(system: &str, user: &[String], temperature: f32, _is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-        let model: String = env::var("MISTRAL_MODEL").expect("MISTRAL_MODEL not found in enviroment variables");
+        let model: String = required_env("MISTRAL_MODEL")?;
 
         Self::call_model(&model, system, user, temperature, _is_json, is_chat).await
     }
@@ -133,29 +200,7 @@ impl LlmCompletion for MistralCompletion {
 
     /// Create and call llm with model/function by supplying data and common parameters
     async fn call_model_function(model: &str, system: &str, user: &[String], temperature: f32, _is_json: bool, is_chat: bool, function: Option<Vec<Function>>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-        let mut messages = Vec::new();
-
-        if !system.is_empty() {
-            messages.push(MistralMessage { role: "system".into(), content: system.into() });
-        }
-
-        user.iter()
-            .enumerate()
-            .for_each(|(i, c)| {
-                let role = if !is_chat || i % 2 == 0 { "user" } else { "assistant" };
-
-                messages.push(MistralMessage { role: role.into(), content: c.to_string() });
-            });
-
-        let completion = MistralCompletion {
-            model: model.into(),
-            tools: Some(FunctionCall::functions(function)),
-            messages,
-            temperature,
-            max_tokens: 4096
-        };
-
-        call_mistral_completion(&completion).await
+        call_mistral_completion(&Self::completion(model, system, user, temperature, _is_json, is_chat, function)).await
     }
 }
 
@@ -209,17 +254,17 @@ impl std::fmt::Display for Usage {
 
 /// Call Mistral with some messages
 pub async fn call_mistral(messages: Vec<MistralMessage>) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-    call_mistral_all(messages, 0.2, 4096).await
+    call_mistral_all(messages, provider_defaults("mistral").temperature(), default_max_tokens()).await
 }
 
 /// Call Mistral with some messages and temperature
 pub async fn call_mistral_temperature(messages: Vec<MistralMessage>, temperature: f32) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-    call_mistral_all(messages, temperature, 4096).await
+    call_mistral_all(messages, temperature, default_max_tokens()).await
 }
 
 /// Call Mistral with some messages and max_tokens
 pub async fn call_mistral_max_tokens(messages: Vec<MistralMessage>, max_tokens: usize) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
-    call_mistral_all(messages, 0.2, max_tokens).await
+    call_mistral_all(messages, provider_defaults("mistral").temperature(), max_tokens).await
 }
 
 /// Call Mistral with some messages, temperature and max_tokens
@@ -229,23 +274,40 @@ pub async fn call_mistral_all(messages: Vec<MistralMessage>, temperature: f32, m
     call_mistral_completion(&mistral_completion).await
 }
 
+/// Create and call Mistral with attachments added as content parts to the final user message
+#[allow(clippy::too_many_arguments)]
+pub async fn call_mistral_model_attachments(model: &str, system: &str, user: &[String], attachments: &[Attachment], temperature: f32, is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    let mut completion = MistralCompletion::completion(model, system, user, temperature, is_json, is_chat, None);
+
+    if let Some(message) = completion.messages.iter_mut().rev().find(|m| m.role == "user") {
+        message.attach("mistral", attachments)?;
+    }
+
+    call_mistral_completion(&completion).await
+}
+
 pub async fn call_mistral_completion(mistral_completion: &MistralCompletion) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    // Completions made by new or default have no model if MISTRAL_MODEL is unset
+    if mistral_completion.model.is_empty() {
+        return Err(Box::new(ConfigError::MissingEnv("MISTRAL_MODEL".into())));
+    }
+    check_parameters("mistral", &mistral_completion.model, Some(mistral_completion.temperature), mistral_completion.top_p, Some(mistral_completion.max_tokens))?;
     let start = std::time::Instant::now();
     // Endpoint
     let url: String =
-        env::var("MISTRAL_URL").expect("MISTRAL_URL not found in enviroment variables");
+        required_env("MISTRAL_URL")?;
 
     let client = get_mistral_client().await?;
 
     // Extract API Response
-    let res = client
+    let res = send_idempotent(client
         .post(url)
-        .json(&mistral_completion)
-        .send()
+        .json(&mistral_completion))
         .await;
     //let res: MistralRespinse = res
+    let res = res?;
+    let status = res.status();
     let res = res
-        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
         //.json()
         .text()
         .await
@@ -253,21 +315,26 @@ pub async fn call_mistral_completion(mistral_completion: &MistralCompletion) ->
 
     let timing = start.elapsed().as_secs() as f64 + start.elapsed().subsec_millis() as f64 / 1000.0;
 
+    mistral_response_to_return(&res, timing).map(|r| r.with_status(status))
+}
+
+/// Unpack the raw text of a Mistral response into an LlmReturn
+pub fn mistral_response_to_return(res: &str, timing: f64) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
 //println!("{res:?}");
     if res.contains("\"error:\"") {
-        let ret: Result<LlmError,_> = serde_json::from_str(&res);
+        let ret: Result<LlmError,_> = serde_json::from_str(res);
 
         match ret {
             Ok(res) => 
-                Ok(LlmReturn::new(LlmType::MISTRAL_ERROR, res.error.to_string(), res.error.to_string(), (0, 0, 0), timing, None, None)),
+                Ok(LlmReturn::new(LlmType::MISTRAL_ERROR, res.error.to_string(), res.error.to_string().into(), (0, 0, 0), timing, None, None)),
             Err(e) => {
                 eprintln!("Error: {:?}", res);
 
-                Ok(LlmReturn::new(LlmType::MISTRAL_ERROR, e.to_string(), e.to_string(), (0, 0, 0), timing, None, None))
+                Ok(LlmReturn::new(LlmType::MISTRAL_ERROR, e.to_string(), e.to_string().into(), (0, 0, 0), timing, None, None))
             }
         }
     } else if res.contains("\"error\"") {
-        Ok(LlmReturn::new(LlmType::MISTRAL_ERROR, res.to_string(), res.to_string(), (0, 0, 0), timing, None, None))
+        Ok(LlmReturn::new(LlmType::MISTRAL_ERROR, res.to_string(), res.to_string().into(), (0, 0, 0), timing, None, None))
     } else if res.contains("\"arguments\":") {
         let found = vec!["choices:message:tool_calls:function:arguments:${args}".to_string(),
             "choices:message:tool_calls:function:name:${func}".to_string(),
@@ -276,47 +343,67 @@ pub async fn call_mistral_completion(mistral_completion: &MistralCompletion) ->
             "usage:total_tokens:${total}".to_string(),
 //            "usage:${usage}".to_string(),
             "choices:finish_reason:${finish}".to_string()];
-        let f: serde_json::Value = serde_json::from_str(&res).unwrap();
+        let f: serde_json::Value = match serde_json::from_str(res) {
+            Ok(f) => f,
+            Err(e) => return Ok(unexpected_response(LlmType::MISTRAL_ERROR, res, e, timing)),
+        };
         let h = get_functions(&f, &found);
         let funcs = unpack_functions(h.clone());
         let function_calls = serde_json::to_string(&funcs).unwrap();
-        let (i, o, t) = (h.get("in").unwrap()[0].clone(), h.get("out").unwrap()[0].clone(), h.get("total").unwrap()[0].clone());
-        let triple = (i.parse::<usize>().unwrap(), o.parse::<usize>().unwrap(), t.parse::<usize>().unwrap());
-        let finish = h.get("finish").unwrap()[0].clone();
+        let triple = (found_count(&h, "in"), found_count(&h, "out"), found_count(&h, "total"));
+        let finish = found_value(&h, "finish");
 
-        Ok(LlmReturn::new(LlmType::MISTRAL_TOOLS, function_calls, finish, triple, timing, None, None))
+        Ok(LlmReturn::new(LlmType::MISTRAL_TOOLS, function_calls, finish.into(), triple, timing, None, None))
     } else {
-        let res: MistralResponse = serde_json::from_str::<MistralResponse>(&res).unwrap();
+        let res: MistralResponse = match serde_json::from_str::<MistralResponse>(res) {
+            Ok(res) => res,
+            Err(e) => return Ok(unexpected_response(LlmType::MISTRAL_ERROR, res, e, timing)),
+        };
+
+        let usage: Triple = res.usage.to_triple();
 
         // Send Response
-        let (text, finish_reason) =
-            match res.choices {
-                Some(choices) => {
-                    if choices.len() > 1 {
-                        eprintln!("There are {:?} choices available now. Code needs to change to reflect this.", choices.len());
-                    }
-                    let text = choices[0].message.content.clone();
-                    let finish_reason = choices[0].finish_reason.to_uppercase().clone();
-                    let text = text.lines().filter(|l| !l.starts_with("```")).fold(String::new(), |s, l| s + l + "\n");
-
-                    (text, finish_reason)
-                },
-                None => {
-                    ("None".into(), "ERROR".into())
+        match res.choices {
+            Some(choices) if !choices.is_empty() => {
+                if choices.len() > 1 {
+                    eprintln!("There are {:?} choices available now. Code needs to change to reflect this.", choices.len());
                 }
-            };
-
-        let usage: Triple = res.usage.to_triple();
-        let timing = start.elapsed().as_secs() as f64 + start.elapsed().subsec_millis() as f64 / 1000.0;
+                let text = strip_fences_by_default(&choices[0].message.content.text());
+                let finish_reason = choices[0].finish_reason.as_str().into();
 
-        Ok(LlmReturn::new(LlmType::MISTRAL, text, finish_reason, usage, timing, None, None))
+                Ok(LlmReturn::new(LlmType::MISTRAL, text, finish_reason, usage, timing, None, None))
+            },
+            Some(_) | None => {
+                Ok(LlmReturn::new(LlmType::MISTRAL_ERROR, "No choices returned".into(), "ERROR".into(), usage, timing, None, None))
+            }
+        }
     }
 }
 
-async fn get_mistral_client() -> Result<Client, Box<dyn std::error::Error + Send>> {
+/// List models available from Mistral
+pub async fn list_mistral_models() -> Result<Vec<ModelInfo>, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("MISTRAL_MODELS_URL")?;
+    let client = get_mistral_client().await?;
+
+    let res = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    gpt_models_to_info(&res)
+}
+
+pub(crate) async fn get_mistral_client() -> Result<Client, Box<dyn std::error::Error + Send>> {
+    providers().client("mistral", new_mistral_client()).await
+}
+
+pub(crate) async fn new_mistral_client() -> Result<Client, Box<dyn std::error::Error + Send>> {
     // Extract API Key information
-    let api_key: String =
-        env::var("MISTRAL_API_KEY").expect("MISTRAL_API_KEY not found in enviroment variables");
+    let api_key: String = credential("MISTRAL_API_KEY")?;
 
     // Create headers
     let mut headers: HeaderMap = HeaderMap::new();
@@ -334,99 +421,100 @@ async fn get_mistral_client() -> Result<Client, Box<dyn std::error::Error + Send
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mock::mock_post;
+    use serial_test::serial;
+    use wiremock::MockServer;
 
-    async fn mistral(content: Vec<MistralMessage>) {
-        match call_mistral(content).await {
-            Ok(ret) => { println!("{ret}"); assert!(true) },
-            Err(e) => { println!("{e}"); assert!(false) },
-        }
-    }
+    #[test]
+    #[serial]
+    fn test_mistral_options() {
+        std::env::set_var("MISTRAL_MODEL", "mistral-small-latest");
 
-    #[tokio::test]
-    async fn test_call_mistral_basic() {
-        let messages: Vec<MistralMessage> = vec![MistralMessage { role: "user".into(), content: "What is the meaining of life?".into() }];
+        let mut completion = MistralCompletion::new(vec![MistralMessage::text("user", "Hello")], 0.2, 100, false);
+        let json = serde_json::to_string(&completion).unwrap();
 
-        mistral(messages).await;
-    }
-    #[tokio::test]
-    async fn test_call_mistral_citation() {
-        let messages = 
-            vec![MistralMessage::text("user", "Give citations for the General theory of Relativity.")];
-        mistral(messages).await;
+        assert!(!json.contains("random_seed") && !json.contains("safe_prompt"));
+
+        completion.set_top_p(Some(0.5));
+        completion.set_random_seed(Some(42));
+        completion.set_safe_prompt(true);
+        let json = serde_json::to_string(&completion).unwrap();
+
+        assert!(json.contains("\"top_p\":0.5,\"max_tokens\":100,\"random_seed\":42,\"safe_prompt\":true"));
     }
-    #[tokio::test]
-    //#[serial]
-    async fn test_call_mistral_poem() {
-        let messages = 
-            vec![MistralMessage::text("user", "Write a creative poem about the interplay of artificial intelligence and the human spirit and provide citations")];
-        mistral(messages).await;
+
+    const FUNC_DEF: &str =
+r#"
+// Derive the value of the arithmetic expression
+// expr: An arithmetic expression
+fn arithmetic(expr)
+"#;
+
+    // Point Mistral at a mock server answering with the given fixture
+    async fn mock_mistral(status: u16, fixture: &str) -> MockServer {
+        let server = mock_post("/v1/chat/completions", status, fixture).await;
+
+        std::env::set_var("MISTRAL_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("MISTRAL_API_KEY", "test-key");
+        std::env::set_var("MISTRAL_MODEL", "mistral-large-latest");
+
+        server
     }
+
     #[tokio::test]
-    //#[serial]
-    async fn test_call_mistral_logic() {
-        let messages = 
-            vec![MistralMessage::text("user", "How many brains does an octopus have, when they have been injured and lost a leg?")];
-        mistral(messages).await;
+    #[serial]
+    async fn test_call_mistral_success() {
+        let _server = mock_mistral(200, "mistral/success.json").await;
+        let messages = vec!["Hello".to_string()];
+        let res = MistralCompletion::call_model("mistral-large-latest", "Be brief", &messages, 0.2, false, true).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::MISTRAL);
+        assert_eq!(res.text, "Hello there.\n");
+        assert_eq!(res.finish_reason, FinishReason::Stop);
+        assert_eq!(res.usage, (12, 3, 15));
     }
     #[tokio::test]
-    async fn test_call_mistral_dialogue() {
-        let system = "Use a Scottish accent to answer questions";
-        let mut messages = 
-            vec!["How many brains does an octopus have, when they have been injured and lost a leg?".to_string()];
-        let res = MistralCompletion::call(&system, &messages, 0.2, false, true).await;
-        println!("{res:?}");
+    #[serial]
+    async fn test_call_mistral_error() {
+        let _server = mock_mistral(401, "mistral/error.json").await;
+        let res = call_mistral(vec![MistralMessage::text("user", "Hello")]).await.unwrap();
 
-        messages.push(res.unwrap().to_string());
-        messages.push("Is a cuttle fish similar?".to_string());
-
-        let res = MistralCompletion::call(&system, &messages, 0.2, false, true).await;
-        println!("{res:?}");
+        assert_eq!(res.llm_type, LlmType::MISTRAL_ERROR);
+        assert!(res.text.contains("Unauthorized"));
+        assert_eq!(res.usage, (0, 0, 0));
     }
     #[tokio::test]
-    async fn test_call_mistral_dialogue_model() {
-        let model: String = std::env::var("MISTRAL_MODEL").expect("MISTRAL_MODEL not found in enviroment variables");
-        let messages = vec!["Hello".to_string()];
-        let res = MistralCompletion::call_model(&model, "", &messages, 0.2, false, true).await;
-        println!("{res:?}");
+    #[serial]
+    async fn test_call_mistral_empty_choices() {
+        let _server = mock_mistral(200, "mistral/empty_choices.json").await;
+        let res = call_mistral(vec![MistralMessage::text("user", "Hello")]).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::MISTRAL_ERROR);
+        assert_eq!(res.text, "No choices returned");
+        assert_eq!(res.usage, (12, 0, 12));
     }
     #[tokio::test]
-    async fn test_call_function_mistral() {
-        let model: String = std::env::var("MISTRAL_MODEL").expect("MISTRAL_MODEL not found in enviroment variables");
-        let messages =  vec!["The answer is (60 * 24) * 365.25".to_string()];
-        let func_def =
-r#"
-// Derive the value of the arithmetic expression
-// expr: An arithmetic expression
-fn arithmetic(expr)
-"#;
-        let functions = get_function_json("mistral", &[func_def]);
-        let res = MistralCompletion::call_model_function(&model, "", &messages, 0.2, false, true, functions).await;
-        println!("{res:?}");
+    #[serial]
+    async fn test_call_mistral_safety() {
+        let _server = mock_mistral(200, "mistral/safety.json").await;
+        let res = call_mistral(vec![MistralMessage::text("user", "Hello")]).await.unwrap();
 
-        let answer = call_actual_function(res.ok());
-        println!("{answer:?}");
+        assert_eq!(res.llm_type, LlmType::MISTRAL);
+        assert_eq!(res.text, "");
+        assert_eq!(res.finish_reason, FinishReason::ContentFilter);
+        assert_eq!(res.usage, (14, 0, 14));
     }
     #[tokio::test]
-    async fn test_call_function_common_mistral() {
-        //let messages =  vec!["The answer is (60 * 24) * 365.25".to_string()];
-        let messages = vec!["a fruit that is blue with a sour tast".to_string()];
-        let func_def =
-r#"
-// Derive the value of the arithmetic expression
-// expr: An arithmetic expression
-fn arithmetic(expr)
-"#;
-        let func_def2 =
-r#"
-// Find the color of an apple and it's taste pass them to this function.
-// color: The color of an apple
-// taste: The taste of an apple
-fn apple(color, taste)
-"#;
-        let res = call_function_llm("mistral", &messages, &[func_def, func_def2]).await;
-        println!("{res:?}");
-
-        let answer = call_actual_function(res.ok());
-        println!("{answer:?}");
+    #[serial]
+    async fn test_call_function_mistral() {
+        let _server = mock_mistral(200, "mistral/tool_call.json").await;
+        let messages = vec!["The answer is (60 * 24) * 365.25".to_string()];
+        let functions = get_function_json("mistral", &[FUNC_DEF]);
+        let res = MistralCompletion::call_model_function("mistral-large-latest", "", &messages, 0.2, false, true, functions).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::MISTRAL_TOOLS);
+        assert_eq!(res.finish_reason, FinishReason::ToolCalls);
+        assert_eq!(res.usage, (91, 33, 124));
+        assert_eq!(call_actual_function(Some(res)), vec!["arithmetic -> 525960"]);
     }
 }
@@ -0,0 +1,61 @@
+//! Local stand-in for provider endpoints, serving canned payloads from
+//! tests/fixtures so response parsing can be tested without API keys.
+use wiremock::{Mock, MockServer, ResponseTemplate};
+use wiremock::matchers::{method, path};
+
+/// Canned provider payload, relative to tests/fixtures
+pub fn fixture(name: &str) -> String {
+    let file = format!("{}/tests/fixtures/{name}", env!("CARGO_MANIFEST_DIR"));
+
+    std::fs::read_to_string(&file).unwrap_or_else(|e| panic!("{file}: {e}"))
+}
+
+/// Start a server answering one POST to `route` with the given status and fixture
+pub async fn mock_post(route: &str, status: u16, name: &str) -> MockServer {
+    let server = MockServer::start().await;
+
+    Mock::given(method("POST"))
+        .and(path(route))
+        .respond_with(ResponseTemplate::new(status)
+            .insert_header("content-type", "application/json")
+            .set_body_string(fixture(name)))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    server
+}
+
+/// Start a server answering any GETs of `route` with the given status and fixture
+pub async fn mock_get(route: &str, status: u16, name: &str) -> MockServer {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path(route))
+        .respond_with(ResponseTemplate::new(status)
+            .insert_header("content-type", "application/json")
+            .set_body_string(fixture(name)))
+        .mount(&server)
+        .await;
+
+    server
+}
+
+/// Start a server answering successive POSTs to `route` with each status and fixture in turn
+pub async fn mock_post_sequence(route: &str, responses: &[(u16, &str)]) -> MockServer {
+    let server = MockServer::start().await;
+
+    for (status, name) in responses {
+        Mock::given(method("POST"))
+            .and(path(route))
+            .respond_with(ResponseTemplate::new(*status)
+                .insert_header("content-type", "application/json")
+                .set_body_string(fixture(name)))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+    }
+
+    server
+}
@@ -0,0 +1,157 @@
+//! Work that a provider finishes in its own time, such as a batch or a
+//! Replicate prediction. Rather than blocking a worker task until it is
+//! done, a service can keep the id and check with `poll`, wait with
+//! `await_result`, or hand over a callback with `on_complete`.
+use std::sync::Arc;
+use std::time::Duration;
+use futures::future::BoxFuture;
+use tokio::task::JoinHandle;
+use crate::tenants::{current_tenant, with_tenant};
+
+/// One check of the provider: the result if finished, None if not yet
+type Poll<T> = Arc<dyn Fn(String) -> BoxFuture<'static, Result<Option<T>, Box<dyn std::error::Error + Send>>> + Send + Sync>;
+
+/// Handle to a result still being produced by the provider
+pub struct PendingResult<T> {
+    id: String,
+    poll: Poll<T>,
+    poll_interval: Duration,
+    deadline: Option<Duration>,
+}
+
+impl<T> Clone for PendingResult<T> {
+    fn clone(&self) -> Self {
+        PendingResult { id: self.id.clone(), poll: self.poll.clone(), poll_interval: self.poll_interval, deadline: self.deadline }
+    }
+}
+
+impl<T> std::fmt::Debug for PendingResult<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("PendingResult").field("id", &self.id).field("poll_interval", &self.poll_interval).field("deadline", &self.deadline).finish()
+    }
+}
+
+impl<T: Send + 'static> PendingResult<T> {
+    /// Handle to the work with this id, `poll` checking on it once
+    pub fn new<F>(id: &str, poll_interval: Duration, poll: F) -> Self
+    where
+        F: Fn(String) -> BoxFuture<'static, Result<Option<T>, Box<dyn std::error::Error + Send>>> + Send + Sync + 'static,
+    {
+        PendingResult { id: id.into(), poll: Arc::new(poll), poll_interval, deadline: None }
+    }
+
+    /// Provider's id for the work, to store and resume from later
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Time between checks when waiting
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Longest to wait for the result, by default for as long as it takes
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Check once, without waiting: the result if finished
+    pub async fn poll(&self) -> Result<Option<T>, Box<dyn std::error::Error + Send>> {
+        (self.poll)(self.id.clone()).await
+    }
+
+    /// Wait for the result, checking every poll interval, failing with a
+    /// TimedOut error if there is a deadline and it passes first. The work
+    /// itself carries on, so can be waited for again.
+    pub async fn await_result(&self) -> Result<T, Box<dyn std::error::Error + Send>> {
+        let start = std::time::Instant::now();
+
+        loop {
+            if let Some(result) = self.poll().await? {
+                return Ok(result);
+            }
+            if let Some(deadline) = self.deadline.filter(|d| start.elapsed() + self.poll_interval > *d) {
+                return Err(Box::new(std::io::Error::new(std::io::ErrorKind::TimedOut, format!("{} not finished in {deadline:?}", self.id))));
+            }
+
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    /// Wait in a background task and call back with the result, or the
+    /// error that stopped it. The caller is free to carry on; abort the
+    /// returned handle to stop waiting. Polls are made for the caller's
+    /// tenant, if any.
+    pub fn on_complete<F>(self, callback: F) -> JoinHandle<()>
+    where
+        F: FnOnce(Result<T, Box<dyn std::error::Error + Send>>) + Send + 'static,
+    {
+        let tenant = current_tenant();
+
+        tokio::spawn(async move {
+            let res = match tenant {
+                Some(ref id) => with_tenant(id, self.await_result()).await.and_then(|res| res),
+                None => self.await_result().await,
+            };
+
+            callback(res);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use serial_test::serial;
+
+    #[tokio::test]
+    async fn test_pending_result() {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let counter = polls.clone();
+        // Finished on the third check
+        let pending = PendingResult::new("job-1", Duration::from_millis(1), move |id| {
+            let n = counter.fetch_add(1, Ordering::SeqCst);
+
+            Box::pin(async move { Ok((n >= 2).then(|| format!("{id} done"))) })
+        });
+
+        assert_eq!(pending.poll().await.unwrap(), None);
+        assert_eq!(pending.await_result().await.unwrap(), "job-1 done");
+        assert_eq!(polls.load(Ordering::SeqCst), 3);
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+
+        pending.on_complete(move |res| { let _ = tx.send(res.unwrap()); });
+
+        assert_eq!(rx.await.unwrap(), "job-1 done");
+    }
+
+    #[tokio::test]
+    async fn test_pending_deadline() {
+        let pending = PendingResult::new("job-2", Duration::from_millis(5), |_| Box::pin(async { Ok(None::<String>) }))
+            .deadline(Duration::from_millis(20));
+        let err = pending.await_result().await.unwrap_err();
+
+        assert_eq!(err.to_string(), "job-2 not finished in 20ms");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_on_complete_tenant() {
+        crate::tenants::register_tenant(crate::tenants::Tenant::new("acme"));
+
+        let pending = PendingResult::new("job-3", Duration::from_millis(1), |_| Box::pin(async { Ok(Some(current_tenant())) }));
+        let (tx, rx) = tokio::sync::oneshot::channel();
+
+        with_tenant("acme", async {
+            pending.on_complete(move |res| { let _ = tx.send(res.unwrap()); });
+        }).await.unwrap();
+
+        assert_eq!(rx.await.unwrap().as_deref(), Some("acme"));
+
+        crate::tenants::remove_tenant("acme");
+    }
+}
@@ -0,0 +1,231 @@
+use std::sync::{Arc, RwLock};
+use regex::Regex;
+use crate::common::LlmReturn;
+use crate::error::LlmClientError;
+
+/// Rewrite reply text after it is received from any LLM
+pub trait PostProcessor: Send + Sync {
+    fn process(&self, text: &str) -> Result<String, Box<dyn std::error::Error + Send>>;
+}
+
+/// Drop markdown fence lines, keeping what they enclose. This is what
+/// the default post processors do.
+pub fn strip_fences(text: &str) -> String {
+    text.lines().filter(|l| !l.starts_with("```")).fold(String::new(), |s, l| s + l + "\n")
+}
+
+/// Drop markdown fence lines
+#[derive(Debug, Clone, Copy)]
+pub struct StripFences;
+
+impl PostProcessor for StripFences {
+    fn process(&self, text: &str) -> Result<String, Box<dyn std::error::Error + Send>> {
+        Ok(strip_fences(text))
+    }
+}
+
+/// Remove surrounding white space
+#[derive(Debug, Clone, Copy)]
+pub struct Trim;
+
+impl PostProcessor for Trim {
+    fn process(&self, text: &str) -> Result<String, Box<dyn std::error::Error + Send>> {
+        Ok(text.trim().to_string())
+    }
+}
+
+/// Keep only the first JSON object or array in the text, dropping any
+/// prose around it, or `LlmClientError::InvalidJson` if there is none
+#[derive(Debug, Clone, Copy)]
+pub struct ExtractJson;
+
+impl PostProcessor for ExtractJson {
+    fn process(&self, text: &str) -> Result<String, Box<dyn std::error::Error + Send>> {
+        for (start, _) in text.match_indices(['{', '[']) {
+            let mut values = serde_json::Deserializer::from_str(&text[start..]).into_iter::<serde_json::Value>();
+
+            if let Some(Ok(_)) = values.next() {
+                return Ok(text[start..start + values.byte_offset()].to_string());
+            }
+        }
+
+        Err(Box::new(LlmClientError::InvalidJson { text: text.into(), error: "no JSON object or array found".into() }))
+    }
+}
+
+/// Keep only a capture group (0 for the whole match) of the first match of
+/// a regular expression, or fail if it does not match
+#[derive(Debug, Clone)]
+pub struct RegexCapture {
+    regex: Regex,
+    group: usize,
+}
+
+impl RegexCapture {
+    pub fn new(pattern: &str, group: usize) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+        Ok(RegexCapture { regex, group })
+    }
+}
+
+impl PostProcessor for RegexCapture {
+    fn process(&self, text: &str) -> Result<String, Box<dyn std::error::Error + Send>> {
+        match self.regex.captures(text).and_then(|c| c.get(self.group)) {
+            Some(m) => Ok(m.as_str().to_string()),
+            None => Err(Box::new(std::io::Error::other(format!("Reply does not match {}", self.regex)))),
+        }
+    }
+}
+
+/// Custom post processor
+pub struct FnProcessor<F>(pub F);
+
+impl<F> PostProcessor for FnProcessor<F> where F: Fn(&str) -> Result<String, Box<dyn std::error::Error + Send>> + Send + Sync {
+    fn process(&self, text: &str) -> Result<String, Box<dyn std::error::Error + Send>> {
+        (self.0)(text)
+    }
+}
+
+/// Post processors applied in order, each seeing the output of the last
+#[derive(Default)]
+pub struct PostProcessorChain {
+    processors: Vec<Box<dyn PostProcessor>>,
+}
+
+impl PostProcessorChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn processor(mut self, processor: impl PostProcessor + 'static) -> Self {
+        self.processors.push(Box::new(processor));
+        self
+    }
+
+    pub fn strip_fences(self) -> Self {
+        self.processor(StripFences)
+    }
+
+    pub fn trim(self) -> Self {
+        self.processor(Trim)
+    }
+
+    pub fn extract_json(self) -> Self {
+        self.processor(ExtractJson)
+    }
+
+    pub fn regex_capture(self, pattern: &str, group: usize) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        Ok(self.processor(RegexCapture::new(pattern, group)?))
+    }
+
+    /// Processed text, or the first error
+    pub fn apply(&self, text: &str) -> Result<String, Box<dyn std::error::Error + Send>> {
+        let mut text = text.to_string();
+
+        for processor in &self.processors {
+            text = processor.process(&text)?;
+        }
+
+        Ok(text)
+    }
+}
+
+static POST_PROCESSORS: RwLock<Option<Arc<PostProcessorChain>>> = RwLock::new(None);
+
+/// Install post processors applied to every reply from the common call
+/// functions, in place of the default of `strip_fences`. Install an empty
+/// chain to have replies as the LLM sent them. The provider APIs strip
+/// fences only while none are installed, see `strip_fences_by_default`.
+pub fn set_post_processors(processors: PostProcessorChain) {
+    *POST_PROCESSORS.write().unwrap() = Some(Arc::new(processors));
+}
+
+/// Go back to the default post processors
+pub fn clear_post_processors() {
+    *POST_PROCESSORS.write().unwrap() = None;
+}
+
+/// Apply installed, or default, post processors to a reply's text. Error
+/// replies are passed through unchanged.
+pub fn apply_post_processors(mut ret: LlmReturn) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    if ret.llm_type.is_error() {
+        return Ok(ret);
+    }
+
+    let processors = POST_PROCESSORS.read().unwrap().clone();
+
+    ret.text = match processors {
+        Some(processors) => processors.apply(&ret.text)?,
+        None => PostProcessorChain::new().strip_fences().apply(&ret.text)?,
+    };
+
+    Ok(ret)
+}
+
+/// Reply text as the provider parsers return it: fences stripped, as the
+/// default post processors do, so direct callers of the provider APIs get
+/// the same text as ever. Once other post processors are installed the
+/// text is left for them, as the common call functions apply them.
+pub fn strip_fences_by_default(text: &str) -> String {
+    if POST_PROCESSORS.read().unwrap().is_some() {
+        text.to_string()
+    } else {
+        strip_fences(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    fn test_post_processor_chain() {
+        let reply = "Here you are:\n```json\n{\"answer\": [1, 2]}\n```\nAnything else?";
+        let processors = PostProcessorChain::new().strip_fences().extract_json();
+
+        assert_eq!(processors.apply(reply).unwrap(), "{\"answer\": [1, 2]}");
+        assert_eq!(PostProcessorChain::new().trim().apply("  Canberra.\n").unwrap(), "Canberra.");
+
+        let err = ExtractJson.process("No JSON {here").unwrap_err();
+
+        assert!(matches!(err.downcast_ref::<LlmClientError>(), Some(LlmClientError::InvalidJson { .. })));
+
+        let processors = PostProcessorChain::new()
+            .regex_capture(r"(?i)answer:\s*(\w+)", 1).unwrap()
+            .processor(FnProcessor(|t: &str| Ok(t.to_uppercase())));
+
+        assert_eq!(processors.apply("Thinking... Answer: yes").unwrap(), "YES");
+        assert!(processors.apply("No idea").is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_default_post_processors() {
+        let reply = || LlmReturn::new(crate::common::LlmType::GPT, "```\nCanberra.\n```".into(), "stop".into(), (1, 2, 3), 0.5, None, None);
+
+        assert_eq!(apply_post_processors(reply()).unwrap().text, "Canberra.\n");
+
+        set_post_processors(PostProcessorChain::new());
+
+        assert_eq!(apply_post_processors(reply()).unwrap().text, "```\nCanberra.\n```");
+
+        clear_post_processors();
+    }
+
+    #[test]
+    #[serial]
+    fn test_direct_caller_fences() {
+        let res = r#"{"id":"c1","object":"chat.completion","created":1,"model":"gpt-4o","choices":[{"index":0,"message":{"role":"assistant","content":"```\nCanberra.\n```"},"logprobs":null,"finish_reason":"stop"}],"usage":{"prompt_tokens":5,"completion_tokens":4,"total_tokens":9}}"#;
+
+        assert_eq!(crate::gpt::gpt_response_to_return(res, 0.1).unwrap().text, "Canberra.\n");
+
+        set_post_processors(PostProcessorChain::new());
+
+        assert_eq!(crate::gpt::gpt_response_to_return(res, 0.1).unwrap().text, "```\nCanberra.\n```");
+
+        clear_post_processors();
+    }
+}
@@ -0,0 +1,86 @@
+use crate::common::Triple;
+
+/// Price in US dollars per million tokens
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Price {
+    pub input: f64,
+    pub output: f64,
+}
+
+impl Price {
+    pub const fn new(input: f64, output: f64) -> Self {
+        Price { input, output }
+    }
+
+    /// Cost in US dollars of tokens used
+    pub fn cost(&self, usage: Triple) -> f64 {
+        (usage.0 as f64 * self.input + usage.1 as f64 * self.output) / 1_000_000.0
+    }
+}
+
+// Published list prices, matched on longest model name prefix.
+// These change frequently, check provider pricing pages.
+const PRICES: &[(&str, Price)] = &[
+    // OpenAI
+    ("gpt-4o-mini", Price::new(0.15, 0.60)),
+    ("gpt-4o", Price::new(5.0, 15.0)),
+    ("gpt-4-turbo", Price::new(10.0, 30.0)),
+    ("gpt-4", Price::new(30.0, 60.0)),
+    ("gpt-3.5-turbo", Price::new(0.5, 1.5)),
+    // Anthropic
+    ("claude-3-opus", Price::new(15.0, 75.0)),
+    ("claude-3-5-sonnet", Price::new(3.0, 15.0)),
+    ("claude-3-sonnet", Price::new(3.0, 15.0)),
+    ("claude-3-haiku", Price::new(0.25, 1.25)),
+    // Google
+    ("gemini-1.5-pro", Price::new(3.5, 10.5)),
+    ("gemini-1.5-flash", Price::new(0.35, 1.05)),
+    ("gemini-1.0-pro", Price::new(0.5, 1.5)),
+    // Mistral
+    ("mistral-large", Price::new(4.0, 12.0)),
+    ("mistral-medium", Price::new(2.7, 8.1)),
+    ("mistral-small", Price::new(1.0, 3.0)),
+    ("codestral", Price::new(1.0, 3.0)),
+    ("open-mixtral-8x22b", Price::new(2.0, 6.0)),
+    ("open-mixtral-8x7b", Price::new(0.7, 0.7)),
+    ("open-mistral-7b", Price::new(0.25, 0.25)),
+    // Groq
+    ("llama3-70b", Price::new(0.59, 0.79)),
+    ("llama3-8b", Price::new(0.05, 0.08)),
+    ("mixtral-8x7b", Price::new(0.24, 0.24)),
+    ("gemma2-9b", Price::new(0.20, 0.20)),
+    ("gemma-7b", Price::new(0.07, 0.07)),
+];
+
+/// Price for model, if known
+pub fn price(model: &str) -> Option<Price> {
+    PRICES.iter()
+        .filter(|(prefix, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, price)| *price)
+}
+
+/// Estimated cost in US dollars of a call, if model price is known
+pub fn cost(model: &str, usage: Triple) -> Option<f64> {
+    price(model).map(|p| p.cost(usage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_price_longest_prefix() {
+        assert_eq!(price("gpt-4o-mini-2024-07-18"), Some(Price::new(0.15, 0.60)));
+        assert_eq!(price("gpt-4o-2024-05-13"), Some(Price::new(5.0, 15.0)));
+        assert_eq!(price("gpt-4-turbo"), Some(Price::new(10.0, 30.0)));
+        assert_eq!(price("unknown-model"), None);
+    }
+
+    #[test]
+    fn test_cost() {
+        let cost = cost("claude-3-opus-20240229", (1_000_000, 100_000, 1_100_000)).unwrap();
+
+        assert!((cost - 22.5).abs() < 1e-9);
+    }
+}
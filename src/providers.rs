@@ -0,0 +1,212 @@
+//! Long-lived per-provider HTTP clients and auth tokens. Clients are built
+//! once and reused, so connections are pooled across calls rather than set up
+//! afresh every time, and the Gemini access token is only refreshed as it
+//! nears expiry rather than running gcloud for every call.
+//!
+//! All `call_*` functions use the installed `Providers`, built lazily on
+//! first use. Create one with `Providers::new` at start up to find missing
+//! credentials then rather than on the first call, and install it with
+//! `set_providers`.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use reqwest::Client;
+use crate::common::{current_timeouts, llm_name, Timeouts};
+use crate::credentials::credential;
+use crate::tenants::current_tenant;
+
+/// Access tokens from gcloud last an hour, refresh a little before then
+const GEMINI_TOKEN_LIFETIME: Duration = Duration::from_secs(55 * 60);
+
+#[derive(Debug, Clone)]
+struct AccessToken {
+    token: String,
+    expires: Instant,
+}
+
+/// HTTP clients by provider (and timeouts, which are fixed per client,
+/// and tenant, whose keys may be its own) plus cached auth tokens by tenant
+#[derive(Debug, Default)]
+pub struct Providers {
+    clients: RwLock<HashMap<(String, Timeouts, String), Client>>,
+    gemini_tokens: Mutex<HashMap<String, AccessToken>>,
+}
+
+impl Providers {
+    /// Build the clients for the named LLMs now, so missing or bad
+    /// credentials are an error here rather than on first use
+    pub async fn new(llms: &[&str]) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        let providers = Providers::default();
+
+        for llm in llms {
+            let _ = match llm_name(llm) {
+                Some("gpt") => providers.client("gpt", crate::gpt::new_gpt_client()).await?,
+                Some("claude") => providers.client("claude", crate::claude::new_claude_client()).await?,
+                Some("gemini") => {
+                    let token = providers.gemini_access_token().await?;
+
+                    providers.client("gemini", crate::gemini::new_gemini_client(token)).await?
+                },
+                Some("mistral") => providers.client("mistral", crate::mistral::new_mistral_client()).await?,
+                Some("groq") => providers.client("groq", crate::groq::new_groq_client()).await?,
+                Some("replicate") => providers.client("replicate", crate::replicate::new_replicate_client()).await?,
+                _ => return Err(Box::new(std::io::Error::other(format!("{llm} is not a known LLM")))),
+            };
+        }
+
+        Ok(providers)
+    }
+
+    /// Client for a provider with the current timeouts and tenant, built
+    /// by `build` the first time it is asked for
+    pub(crate) async fn client<F>(&self, name: &str, build: F) -> Result<Client, Box<dyn std::error::Error + Send>>
+    where
+        F: std::future::Future<Output = Result<Client, Box<dyn std::error::Error + Send>>>,
+    {
+        let key = (name.to_string(), current_timeouts(), current_tenant().unwrap_or_default());
+
+        if let Some(client) = self.clients.read().unwrap().get(&key) {
+            return Ok(client.clone());
+        }
+
+        let client = build.await?;
+
+        self.clients.write().unwrap().insert(key, client.clone());
+
+        Ok(client)
+    }
+
+    /// Gemini access token for the current tenant, from GEMINI_ACCESS_TOKEN
+    /// or else gcloud, refreshed once it is near expiry along with the
+    /// tenant's Gemini clients using it
+    pub async fn gemini_access_token(&self) -> Result<String, Box<dyn std::error::Error + Send>> {
+        let tenant = current_tenant().unwrap_or_default();
+
+        if let Some(token) = self.gemini_tokens.lock().unwrap().get(&tenant) {
+            if token.expires > Instant::now() {
+                return Ok(token.token.clone());
+            }
+        }
+
+        // Not locked while gcloud runs, so other tenants are not held up
+        let token = match credential("GEMINI_ACCESS_TOKEN") {
+            Ok(token) => token,
+            Err(_) => gcloud_access_token().await?,
+        };
+
+        self.clients.write().unwrap().retain(|(name, _, t), _| name != "gemini" || *t != tenant);
+        self.gemini_tokens.lock().unwrap().insert(tenant, AccessToken { token: token.clone(), expires: Instant::now() + GEMINI_TOKEN_LIFETIME });
+
+        Ok(token)
+    }
+
+    /// Forget a tenant's clients and tokens e.g. after its keys have changed
+    pub(crate) fn clear_tenant(&self, tenant: &str) {
+        self.clients.write().unwrap().retain(|(_, _, t), _| t != tenant);
+        self.gemini_tokens.lock().unwrap().remove(tenant);
+    }
+
+    /// Forget all clients and tokens e.g. after credentials have changed
+    pub fn clear(&self) {
+        self.clients.write().unwrap().clear();
+        self.gemini_tokens.lock().unwrap().clear();
+    }
+}
+
+async fn gcloud_access_token() -> Result<String, Box<dyn std::error::Error + Send>> {
+    let output = tokio::process::Command::new("gcloud")
+        .arg("auth")
+        .arg("print-access-token")
+        .output()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    if !output.status.success() {
+        return Err(Box::new(std::io::Error::other(format!("gcloud auth print-access-token failed: {}", String::from_utf8_lossy(&output.stderr).trim()))));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+static PROVIDERS: RwLock<Option<Arc<Providers>>> = RwLock::new(None);
+
+/// Use these clients for all LLM calls from now on
+pub fn set_providers(providers: Providers) {
+    *PROVIDERS.write().unwrap() = Some(Arc::new(providers));
+}
+
+/// Clients in use, created empty on first use if none were set
+pub fn providers() -> Arc<Providers> {
+    if let Some(ref providers) = *PROVIDERS.read().unwrap() {
+        return providers.clone();
+    }
+
+    PROVIDERS.write().unwrap().get_or_insert_with(|| Arc::new(Providers::default())).clone()
+}
+
+/// Drop all clients and tokens of the installed `Providers`, so they are
+/// rebuilt with current settings
+pub fn reset_providers() {
+    if let Some(ref providers) = *PROVIDERS.read().unwrap() {
+        providers.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[tokio::test]
+    #[serial]
+    async fn test_providers() {
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+        std::env::set_var("GEMINI_ACCESS_TOKEN", "test-token");
+
+        let providers = Providers::new(&["openai", "gemini"]).await.unwrap();
+
+        assert_eq!(providers.clients.read().unwrap().len(), 2);
+        assert_eq!(providers.gemini_access_token().await.unwrap(), "test-token");
+        assert!(Providers::new(&["bard"]).await.is_err());
+
+        // Timeouts are fixed per client, so other timeouts need another client
+        let timeouts = Timeouts::new(Duration::from_secs(1), None);
+
+        crate::common::with_timeouts(timeouts, providers.client("gpt", crate::gpt::new_gpt_client())).await.unwrap();
+        assert_eq!(providers.clients.read().unwrap().len(), 3);
+
+        // An expired token is refreshed, dropping the clients built with it
+        providers.gemini_tokens.lock().unwrap().get_mut("").unwrap().expires = Instant::now();
+        std::env::set_var("GEMINI_ACCESS_TOKEN", "fresh-token");
+
+        assert_eq!(providers.gemini_access_token().await.unwrap(), "fresh-token");
+        assert_eq!(providers.clients.read().unwrap().len(), 2);
+
+        // Tenants' tokens are their own
+        crate::tenants::register_tenant(crate::tenants::Tenant::new("acme").with_key("GEMINI_ACCESS_TOKEN", "acme-token"));
+
+        let token = crate::tenants::with_tenant("acme", providers.gemini_access_token()).await.unwrap().unwrap();
+
+        assert_eq!(token, "acme-token");
+        assert_eq!(providers.gemini_access_token().await.unwrap(), "fresh-token");
+
+        crate::tenants::remove_tenant("acme");
+
+        std::env::set_var("GEMINI_ACCESS_TOKEN", "test-token");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_reset_keeps_installed() {
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        set_providers(Providers::new(&["openai"]).await.unwrap());
+
+        let installed = providers();
+
+        assert_eq!(installed.clients.read().unwrap().len(), 1);
+        reset_providers();
+        assert!(Arc::ptr_eq(&installed, &providers()));
+        assert!(installed.clients.read().unwrap().is_empty());
+    }
+}
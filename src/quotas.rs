@@ -0,0 +1,247 @@
+//! Monthly token and dollar quotas per provider, and so per API key. Each
+//! call's tokens, and cost at list price where the model's price is known,
+//! are counted against its provider for the current UTC month. Crossing a
+//! soft limit calls the warning callback, once a month; once a hard limit
+//! is reached further calls fail with `LlmClientError::QuotaExceeded`
+//! until the month ends. Counts are kept in memory, so a long running
+//! service should restore them with `add_quota_usage` on start up.
+//!
+//! Within `tenants::with_tenant` the tenant's own quotas apply instead,
+//! and its use is counted apart from the service's and other tenants'.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use crate::common::{LlmReturn, llm_name};
+use crate::error::LlmClientError;
+use crate::pricing::cost;
+use crate::tenants::{current_tenant, tenant_quota};
+use crate::usage::utc_day;
+
+/// Limits on a provider's use in a month, any or all may be set
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Quota {
+    pub soft_tokens: Option<usize>,
+    pub hard_tokens: Option<usize>,
+    /// US dollars
+    pub soft_cost: Option<f64>,
+    pub hard_cost: Option<f64>,
+}
+
+impl Quota {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_soft_tokens(mut self, tokens: usize) -> Self {
+        self.soft_tokens = Some(tokens);
+        self
+    }
+
+    pub fn with_hard_tokens(mut self, tokens: usize) -> Self {
+        self.hard_tokens = Some(tokens);
+        self
+    }
+
+    pub fn with_soft_cost(mut self, cost: f64) -> Self {
+        self.soft_cost = Some(cost);
+        self
+    }
+
+    pub fn with_hard_cost(mut self, cost: f64) -> Self {
+        self.hard_cost = Some(cost);
+        self
+    }
+
+    fn soft_reached(&self, tokens: usize, cost: f64) -> bool {
+        self.soft_tokens.is_some_and(|t| tokens >= t) || self.soft_cost.is_some_and(|c| cost >= c)
+    }
+
+    fn hard_reached(&self, tokens: usize, cost: f64) -> bool {
+        self.hard_tokens.is_some_and(|t| tokens >= t) || self.hard_cost.is_some_and(|c| cost >= c)
+    }
+}
+
+/// Given to the warning callback when a provider crosses a soft limit
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuotaWarning {
+    pub tenant: Option<String>,
+    pub llm: String,
+    /// This month so far
+    pub tokens: usize,
+    pub cost: f64,
+    pub quota: Quota,
+}
+
+#[derive(Debug, Default)]
+struct Consumption {
+    /// YYYY-MM
+    month: String,
+    tokens: usize,
+    cost: f64,
+    warned: bool,
+}
+
+type QuotaCallback = Arc<dyn Fn(&QuotaWarning) + Send + Sync>;
+
+static QUOTAS: RwLock<Option<HashMap<String, Quota>>> = RwLock::new(None);
+static CONSUMPTION: Mutex<Option<HashMap<String, Consumption>>> = Mutex::new(None);
+static QUOTA_WARNING: RwLock<Option<QuotaCallback>> = RwLock::new(None);
+
+fn provider(llm: &str) -> String {
+    llm_name(llm).unwrap_or(llm).to_string()
+}
+
+// Provider, prefixed by the tenant of the current call if any
+fn scoped(llm: &str) -> String {
+    match current_tenant() {
+        Some(tenant) => format!("{tenant}/{}", provider(llm)),
+        None => provider(llm),
+    }
+}
+
+fn this_month() -> String {
+    utc_day(std::time::SystemTime::now())[..7].to_string()
+}
+
+/// Limit a provider from now on, None to remove its quota
+pub fn set_quota(llm: &str, quota: Option<Quota>) {
+    let mut quotas = QUOTAS.write().unwrap();
+    let quotas = quotas.get_or_insert_with(HashMap::new);
+
+    match quota {
+        Some(quota) => quotas.insert(provider(llm), quota),
+        None => quotas.remove(&provider(llm)),
+    };
+}
+
+/// Remove all quotas and forget consumption
+pub fn clear_quotas() {
+    *QUOTAS.write().unwrap() = None;
+    *CONSUMPTION.lock().unwrap() = None;
+}
+
+/// Quota for a provider, the current tenant's if in `with_tenant`
+pub fn quota(llm: &str) -> Option<Quota> {
+    match current_tenant() {
+        Some(tenant) => tenant_quota(&tenant, &provider(llm)),
+        None => QUOTAS.read().unwrap().as_ref()?.get(&provider(llm)).copied(),
+    }
+}
+
+/// Called when a provider crosses a soft limit, from the task whose call crossed it
+pub fn set_quota_warning(callback: impl Fn(&QuotaWarning) + Send + Sync + 'static) {
+    *QUOTA_WARNING.write().unwrap() = Some(Arc::new(callback));
+}
+
+pub fn clear_quota_warning() {
+    *QUOTA_WARNING.write().unwrap() = None;
+}
+
+/// Tokens and dollars used by a provider this month, by the current
+/// tenant if in `with_tenant`
+pub fn quota_usage(llm: &str) -> (usize, f64) {
+    let month = this_month();
+    let consumption = CONSUMPTION.lock().unwrap();
+
+    match consumption.as_ref().and_then(|c| c.get(&scoped(llm))) {
+        Some(c) if c.month == month => (c.tokens, c.cost),
+        _ => (0, 0.0),
+    }
+}
+
+/// Count usage made elsewhere this month, e.g. by this service before a restart
+pub fn add_quota_usage(llm: &str, tokens: usize, cost: f64) {
+    let warning = consume(llm, tokens, cost);
+
+    warn(warning);
+}
+
+// Add to this month's consumption, a warning if that crosses the soft limit
+fn consume(llm: &str, tokens: usize, cost: f64) -> Option<QuotaWarning> {
+    let month = this_month();
+    let mut consumption = CONSUMPTION.lock().unwrap();
+    let c = consumption.get_or_insert_with(HashMap::new).entry(scoped(llm)).or_default();
+
+    if c.month != month {
+        *c = Consumption { month, ..Default::default() };
+    }
+    c.tokens += tokens;
+    c.cost += cost;
+
+    let quota = quota(llm)?;
+
+    if c.warned || !quota.soft_reached(c.tokens, c.cost) {
+        return None;
+    }
+    c.warned = true;
+
+    Some(QuotaWarning { tenant: current_tenant(), llm: provider(llm), tokens: c.tokens, cost: c.cost, quota })
+}
+
+fn warn(warning: Option<QuotaWarning>) {
+    let callback = QUOTA_WARNING.read().unwrap().clone();
+
+    if let (Some(warning), Some(callback)) = (warning, callback) {
+        callback(&warning);
+    }
+}
+
+/// QuotaExceeded if the provider has reached a hard limit this month
+pub(crate) fn check(llm: &str) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let Some(quota) = quota(llm) else {
+        return Ok(());
+    };
+    let (tokens, cost) = quota_usage(llm);
+
+    if quota.hard_reached(tokens, cost) {
+        return Err(Box::new(LlmClientError::QuotaExceeded { llm: scoped(llm), tokens, cost }));
+    }
+
+    Ok(())
+}
+
+/// Count a call's usage against its provider
+pub(crate) fn record(llm: &str, model: &str, res: &Result<LlmReturn, Box<dyn std::error::Error + Send>>) {
+    if let Ok(ret) = res {
+        let warning = consume(llm, ret.usage.0 + ret.usage.1, cost(model, ret.usage).unwrap_or(0.0));
+
+        warn(warning);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    #[serial]
+    fn test_quotas() {
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let seen = warnings.clone();
+
+        clear_quotas();
+        set_quota("openai", Some(Quota::new().with_soft_tokens(100).with_hard_cost(0.01)));
+        set_quota_warning(move |w| seen.lock().unwrap().push(w.tokens));
+
+        let ret = Ok(LlmReturn::new(crate::common::LlmType::GPT, "Hi".into(), "stop".into(), (50, 30, 80), 0.1, None, None));
+
+        record("gpt", "gpt-4o", &ret);
+        assert!(check("gpt").is_ok());
+
+        // Crosses the soft limit, warned once only
+        record("gpt", "gpt-4o", &ret);
+        record("gpt", "gpt-4o", &ret);
+        assert_eq!(*warnings.lock().unwrap(), vec![160]);
+        assert_eq!(quota_usage("gpt").0, 240);
+
+        add_quota_usage("gpt", 0, 0.01);
+
+        let err = check("gpt").unwrap_err();
+
+        assert!(matches!(err.downcast_ref::<LlmClientError>(), Some(LlmClientError::QuotaExceeded { tokens: 240, .. })));
+        assert!(check("claude").is_ok());
+
+        clear_quota_warning();
+        clear_quotas();
+    }
+}
@@ -0,0 +1,244 @@
+//! OpenAI Realtime API: a WebSocket session streaming text and audio both
+//! ways as events, the basis for voice agents. Enabled by the `realtime`
+//! feature. Audio is 16 bit PCM, 24kHz mono, unless the session says otherwise.
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use futures::{SinkExt, StreamExt};
+use serde_derive::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+use crate::config::env_var;
+use crate::credentials::credential;
+
+const REALTIME_URL: &str = "wss://api.openai.com/v1/realtime";
+
+/// Events sent to the server
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type")]
+pub enum ClientEvent {
+    /// Instructions, voice, modalities, turn detection etc.
+    #[serde(rename = "session.update")]
+    SessionUpdate { session: serde_json::Value },
+    /// Base64 audio added to the input buffer
+    #[serde(rename = "input_audio_buffer.append")]
+    InputAudioBufferAppend { audio: String },
+    #[serde(rename = "input_audio_buffer.commit")]
+    InputAudioBufferCommit,
+    #[serde(rename = "input_audio_buffer.clear")]
+    InputAudioBufferClear,
+    #[serde(rename = "conversation.item.create")]
+    ConversationItemCreate { item: serde_json::Value },
+    /// Ask for a response, with optional overrides for just this one
+    #[serde(rename = "response.create")]
+    ResponseCreate {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        response: Option<serde_json::Value>,
+    },
+    #[serde(rename = "response.cancel")]
+    ResponseCancel,
+}
+
+/// Events received from the server. Those not needed to drive a
+/// conversation are Other, use `RealtimeSession::next_raw` to see them.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type")]
+pub enum ServerEvent {
+    #[serde(rename = "session.created")]
+    SessionCreated { session: serde_json::Value },
+    #[serde(rename = "session.updated")]
+    SessionUpdated { session: serde_json::Value },
+    #[serde(rename = "input_audio_buffer.speech_started")]
+    SpeechStarted { audio_start_ms: usize },
+    #[serde(rename = "input_audio_buffer.speech_stopped")]
+    SpeechStopped { audio_end_ms: usize },
+    #[serde(rename = "response.text.delta")]
+    TextDelta { response_id: String, delta: String },
+    /// Base64 audio, see `ServerEvent::audio`
+    #[serde(rename = "response.audio.delta")]
+    AudioDelta { response_id: String, delta: String },
+    #[serde(rename = "response.audio_transcript.delta")]
+    AudioTranscriptDelta { response_id: String, delta: String },
+    /// Response complete, including its status and usage
+    #[serde(rename = "response.done")]
+    ResponseDone { response: serde_json::Value },
+    #[serde(rename = "error")]
+    Error { error: RealtimeError },
+    #[serde(other)]
+    Other,
+}
+
+impl ServerEvent {
+    /// Decoded audio of an audio delta
+    pub fn audio(&self) -> Option<Vec<u8>> {
+        match self {
+            ServerEvent::AudioDelta { delta, .. } => BASE64_STANDARD.decode(delta).ok(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RealtimeError {
+    pub r#type: String,
+    #[serde(default)]
+    pub code: Option<String>,
+    pub message: String,
+}
+
+/// Open Realtime connection
+pub struct RealtimeSession {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl RealtimeSession {
+    /// Connect to GPT_REALTIME_URL (default the OpenAI endpoint) for a model
+    /// e.g. gpt-4o-realtime-preview
+    pub async fn connect(model: &str) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        let url = env_var("GPT_REALTIME_URL").unwrap_or(REALTIME_URL.into());
+        let api_key: String = credential("OPENAI_API_KEY")?;
+        let mut request = format!("{url}?model={model}").into_client_request()
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+        let headers = request.headers_mut();
+
+        headers.insert("Authorization", HeaderValue::from_str(&format!("Bearer {api_key}"))
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?);
+        headers.insert("OpenAI-Beta", HeaderValue::from_static("realtime=v1"));
+
+        let (socket, _) = connect_async(request).await
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+        Ok(RealtimeSession { socket })
+    }
+
+    pub async fn send(&mut self, event: &ClientEvent) -> Result<(), Box<dyn std::error::Error + Send>> {
+        let text = serde_json::to_string(event)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+        self.socket.send(Message::Text(text)).await
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+    }
+
+    /// Set the session instructions and output modalities e.g. ["text"] or ["text", "audio"]
+    pub async fn update_session(&mut self, instructions: &str, modalities: &[&str]) -> Result<(), Box<dyn std::error::Error + Send>> {
+        self.send(&ClientEvent::SessionUpdate { session: serde_json::json!({"instructions": instructions, "modalities": modalities}) }).await
+    }
+
+    /// Add a user text message to the conversation
+    pub async fn add_text(&mut self, text: &str) -> Result<(), Box<dyn std::error::Error + Send>> {
+        let item = serde_json::json!({"type": "message", "role": "user", "content": [{"type": "input_text", "text": text}]});
+
+        self.send(&ClientEvent::ConversationItemCreate { item }).await
+    }
+
+    /// Stream a chunk of input audio
+    pub async fn append_audio(&mut self, audio: &[u8]) -> Result<(), Box<dyn std::error::Error + Send>> {
+        self.send(&ClientEvent::InputAudioBufferAppend { audio: BASE64_STANDARD.encode(audio) }).await
+    }
+
+    /// End of user audio, only needed if server turn detection is off
+    pub async fn commit_audio(&mut self) -> Result<(), Box<dyn std::error::Error + Send>> {
+        self.send(&ClientEvent::InputAudioBufferCommit).await
+    }
+
+    pub async fn create_response(&mut self) -> Result<(), Box<dyn std::error::Error + Send>> {
+        self.send(&ClientEvent::ResponseCreate { response: None }).await
+    }
+
+    /// Next event as JSON, None once the connection is closed
+    pub async fn next_raw(&mut self) -> Option<Result<serde_json::Value, Box<dyn std::error::Error + Send>>> {
+        while let Some(message) = self.socket.next().await {
+            match message {
+                Ok(Message::Text(text)) => return Some(serde_json::from_str(&text)
+                    .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })),
+                Ok(Message::Close(_)) => return None,
+                Ok(_) => continue,
+                Err(e) => return Some(Err(Box::new(e))),
+            }
+        }
+
+        None
+    }
+
+    /// Next event, None once the connection is closed
+    pub async fn next_event(&mut self) -> Option<Result<ServerEvent, Box<dyn std::error::Error + Send>>> {
+        let raw = self.next_raw().await?;
+
+        Some(raw.and_then(|v| serde_json::from_value(v)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })))
+    }
+
+    /// Send a text message and wait for the whole text (or audio transcript) reply
+    pub async fn text_reply(&mut self, text: &str) -> Result<String, Box<dyn std::error::Error + Send>> {
+        self.add_text(text).await?;
+        self.create_response().await?;
+
+        let mut reply = String::new();
+
+        while let Some(event) = self.next_event().await {
+            match event? {
+                ServerEvent::TextDelta { delta, .. } | ServerEvent::AudioTranscriptDelta { delta, .. } => reply.push_str(&delta),
+                ServerEvent::ResponseDone { .. } => return Ok(reply),
+                ServerEvent::Error { error } => return Err(Box::new(std::io::Error::other(error.message))),
+                _ => (),
+            }
+        }
+
+        Err(Box::new(std::io::Error::other("Realtime connection closed before the response was done")))
+    }
+
+    pub async fn close(mut self) -> Result<(), Box<dyn std::error::Error + Send>> {
+        self.socket.close(None).await
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_realtime_events() {
+        assert_eq!(serde_json::to_string(&ClientEvent::InputAudioBufferCommit).unwrap(), r#"{"type":"input_audio_buffer.commit"}"#);
+        assert_eq!(serde_json::to_string(&ClientEvent::ResponseCreate { response: None }).unwrap(), r#"{"type":"response.create"}"#);
+
+        let event: ServerEvent = serde_json::from_str(r#"{"type":"response.audio.delta","event_id":"e1","response_id":"r1","item_id":"i1","output_index":0,"content_index":0,"delta":"AQID"}"#).unwrap();
+
+        assert_eq!(event.audio(), Some(vec![1, 2, 3]));
+        assert_eq!(serde_json::from_str::<ServerEvent>(r#"{"type":"rate_limits.updated","rate_limits":[]}"#).unwrap(), ServerEvent::Other);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_realtime_text_reply() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Stand-in server: wait for response.create then answer in two deltas
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+            while let Some(Ok(Message::Text(text))) = socket.next().await {
+                if text.contains("response.create") {
+                    for event in [r#"{"type":"response.text.delta","response_id":"r1","delta":"Can"}"#,
+                                  r#"{"type":"response.text.delta","response_id":"r1","delta":"berra"}"#,
+                                  r#"{"type":"response.done","response":{"status":"completed"}}"#] {
+                        socket.send(Message::Text(event.into())).await.unwrap();
+                    }
+                }
+            }
+        });
+
+        std::env::set_var("GPT_REALTIME_URL", format!("ws://{addr}/v1/realtime"));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        let mut session = RealtimeSession::connect("gpt-4o-realtime-preview").await.unwrap();
+
+        assert_eq!(session.text_reply("What is the capital of Australia?").await.unwrap(), "Canberra");
+    }
+}
@@ -0,0 +1,142 @@
+use regex::Regex;
+use crate::common::{LlmReturn, call_llm_model};
+use crate::filters::{FilterAction, PromptFilter};
+
+/// Placeholders and the text they replaced
+pub type Placeholders = Vec<(String, String)>;
+
+/// Opt-in scrubbing of personal data from prompts. Matches are replaced
+/// with placeholders such as [EMAIL_1], which can be put back into the
+/// answer. Use as a `PromptFilter` to scrub only.
+#[derive(Debug, Clone)]
+pub struct Redactor {
+    rules: Vec<(String, Regex)>,
+}
+
+impl Default for Redactor {
+    /// Emails, card numbers and phone numbers
+    fn default() -> Self {
+        Redactor { rules: vec![
+            ("EMAIL".into(), Regex::new(r"[\w.+-]+@[\w-]+(\.[\w-]+)+").unwrap()),
+            ("CARD".into(), Regex::new(r"\b\d(?:[ -]?\d){12,18}\b").unwrap()),
+            ("PHONE".into(), Regex::new(r"\+?\(?\d[\d\s().-]{7,}\d").unwrap()),
+        ]}
+    }
+}
+
+impl Redactor {
+    /// No rules, add with `rule`
+    pub fn empty() -> Self {
+        Redactor { rules: Vec::new() }
+    }
+
+    /// Add rule, matches become [LABEL_n]
+    pub fn rule(mut self, label: &str, pattern: &str) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+        self.rules.push((label.to_uppercase(), regex));
+
+        Ok(self)
+    }
+
+    /// Redact text, adding to placeholders, so the same value gets the
+    /// same placeholder across many texts
+    pub fn redact(&self, text: &str, placeholders: &mut Placeholders) -> String {
+        let mut text = text.to_string();
+
+        for (label, regex) in &self.rules {
+            text = regex.replace_all(&text, |c: &regex::Captures| {
+                let found = &c[0];
+
+                // Only numbers passing the Luhn check are cards
+                if label == "CARD" && !luhn(found) {
+                    return found.to_string();
+                }
+
+                match placeholders.iter().find(|(_, original)| original == found) {
+                    Some((placeholder, _)) => placeholder.clone(),
+                    None => {
+                        let n = placeholders.iter().filter(|(p, _)| p.starts_with(&format!("[{label}_"))).count() + 1;
+                        let placeholder = format!("[{label}_{n}]");
+
+                        placeholders.push((placeholder.clone(), found.to_string()));
+
+                        placeholder
+                    }
+                }
+            }).to_string();
+        }
+
+        text
+    }
+
+    /// Put original values back in place of placeholders
+    pub fn restore(text: &str, placeholders: &Placeholders) -> String {
+        placeholders.iter()
+            .fold(text.to_string(), |text, (placeholder, original)| text.replace(placeholder, original))
+    }
+
+    /// Call named LLM and model with personal data redacted from the
+    /// prompts and, optionally, restored in the answer
+    #[allow(clippy::too_many_arguments)]
+    pub async fn call_llm_model(&self, llm: &str, model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool, restore: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+        let mut placeholders = Vec::new();
+        let system = self.redact(system, &mut placeholders);
+        let user: Vec<String> = user.iter().map(|u| self.redact(u, &mut placeholders)).collect();
+
+        let mut res = call_llm_model(llm, model, &system, &user, temperature, is_json, is_chat).await?;
+
+        if restore {
+            res.text = Self::restore(&res.text, &placeholders);
+        }
+
+        Ok(res)
+    }
+}
+
+impl PromptFilter for Redactor {
+    fn filter(&self, prompt: &str) -> FilterAction {
+        let redacted = self.redact(prompt, &mut Vec::new());
+
+        if redacted == prompt {
+            FilterAction::Allow
+        } else {
+            FilterAction::Rewrite(redacted)
+        }
+    }
+}
+
+fn luhn(number: &str) -> bool {
+    let digits: Vec<u32> = number.chars().filter_map(|c| c.to_digit(10)).collect();
+    let sum: u32 = digits.iter().rev()
+        .enumerate()
+        .map(|(i, d)| if i % 2 == 1 { if d * 2 > 9 { d * 2 - 9 } else { d * 2 } } else { *d })
+        .sum();
+
+    sum.is_multiple_of(10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_and_restore() {
+        let redactor = Redactor::default().rule("ref", r"ACC-\d{6}").unwrap();
+        let mut placeholders = Vec::new();
+        let text = redactor.redact("Mail jo@example.co.uk or call +44 20 7946 0958 about ACC-123456, card 4111 1111 1111 1111. Again: jo@example.co.uk", &mut placeholders);
+
+        assert_eq!(text, "Mail [EMAIL_1] or call [PHONE_1] about [REF_1], card [CARD_1]. Again: [EMAIL_1]");
+        assert_eq!(Redactor::restore("Emailed [EMAIL_1] re [REF_1]", &placeholders), "Emailed jo@example.co.uk re ACC-123456");
+    }
+
+    #[test]
+    fn test_redact_card_needs_luhn() {
+        let redactor = Redactor::empty().rule("card", r"\b\d(?:[ -]?\d){12,18}\b").unwrap();
+
+        assert!(luhn("4111 1111 1111 1111"));
+        assert!(!luhn("4111 1111 1111 1112"));
+        assert_eq!(redactor.filter("no card here"), FilterAction::Allow);
+    }
+}
@@ -0,0 +1,165 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Off unless asked for, repaired output may not be what the LLM meant
+static JSON_REPAIR: AtomicBool = AtomicBool::new(false);
+
+/// Attempt to repair almost valid JSON returned in JSON mode before
+/// parsing it, rather than asking the LLM again
+pub fn set_json_repair(on: bool) {
+    JSON_REPAIR.store(on, Ordering::Relaxed);
+}
+
+pub fn json_repair_enabled() -> bool {
+    JSON_REPAIR.load(Ordering::Relaxed)
+}
+
+/// Best effort repair of almost valid JSON as commonly returned by smaller
+/// models: surrounding prose and markdown fences, trailing commas,
+/// unquoted keys and truncated objects, arrays or strings.
+pub fn repair_json(text: &str) -> String {
+    let text = unfence(text);
+    let start = match text.find(['{', '[']) {
+        Some(start) => start,
+        None => return text.trim().to_string(),
+    };
+    let chars: Vec<char> = text[start..].chars().collect();
+    let mut out = String::new();
+    let mut stack: Vec<char> = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut expect_key = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if in_string {
+            out.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            i += 1;
+            continue;
+        }
+
+        match c {
+            '"' => {
+                in_string = true;
+                expect_key = false;
+                out.push(c);
+            },
+            '{' | '[' => {
+                stack.push(if c == '{' { '}' } else { ']' });
+                expect_key = c == '{';
+                out.push(c);
+            },
+            '}' | ']' => {
+                trim_trailing_comma(&mut out);
+                stack.pop();
+                expect_key = false;
+                out.push(c);
+                if stack.is_empty() {
+                    break;
+                }
+            },
+            ',' => {
+                expect_key = stack.last() == Some(&'}');
+                out.push(c);
+            },
+            c if expect_key && (c.is_alphabetic() || c == '_' || c == '$') => {
+                let key: String = chars[i..].iter()
+                    .take_while(|c| c.is_alphanumeric() || **c == '_' || **c == '$' || **c == '-')
+                    .collect();
+
+                i += key.chars().count();
+                out.push_str(&format!("\"{key}\""));
+                expect_key = false;
+                continue;
+            },
+            c => {
+                if !c.is_whitespace() {
+                    expect_key = false;
+                }
+                out.push(c);
+            },
+        }
+
+        i += 1;
+    }
+
+    // Close anything left open by truncation
+    if in_string {
+        if escaped {
+            out.pop();
+        }
+        out.push('"');
+    }
+    if !stack.is_empty() {
+        let trimmed = out.trim_end().len();
+        out.truncate(trimmed);
+        if out.ends_with(':') {
+            out.push_str(" null");
+        }
+        trim_trailing_comma(&mut out);
+        while let Some(close) = stack.pop() {
+            out.push(close);
+        }
+    }
+
+    out
+}
+
+// Content of first fenced block, if any
+fn unfence(text: &str) -> &str {
+    match text.find("```") {
+        None => text,
+        Some(start) => {
+            let body = &text[start + 3..];
+            let body = match body.find('\n') {
+                Some(nl) => &body[nl + 1..],
+                None => body,
+            };
+
+            match body.find("```") {
+                Some(end) => &body[..end],
+                None => body,
+            }
+        }
+    }
+}
+
+fn trim_trailing_comma(out: &mut String) {
+    let trimmed = out.trim_end();
+
+    if trimmed.ends_with(',') {
+        let len = trimmed.len() - 1;
+        out.truncate(len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn parse(text: &str) -> Value {
+        serde_json::from_str(&repair_json(text)).unwrap()
+    }
+
+    #[test]
+    fn test_repair_fences_commas_keys() {
+        assert_eq!(parse("Here you go:\n```json\n{\"a\": [1, 2,], b: \"x, }\",}\n```\nHope that helps"), json!({"a": [1, 2], "b": "x, }"}));
+        assert_eq!(parse("{\"ok\": true}"), json!({"ok": true}));
+    }
+
+    #[test]
+    fn test_repair_truncated() {
+        assert_eq!(parse("{\"a\": {\"b\": [1, 2"), json!({"a": {"b": [1, 2]}}));
+        assert_eq!(parse("{\"a\": \"unfinished"), json!({"a": "unfinished"}));
+        assert_eq!(parse("{\"a\": 1, \"b\":"), json!({"a": 1, "b": null}));
+    }
+}
@@ -0,0 +1,368 @@
+//! Replicate runs image and open models behind one API: a prediction is
+//! created with the model's input, then polled until it finishes. Language
+//! models are mapped to an `LlmReturn`, so "replicate" works with the common
+//! call functions, and image models to `GeneratedImage`s.
+//!
+//! Models are named "owner/name" for official models, or
+//! "owner/name:version" for a particular version of any model.
+use std::time::{Duration, Instant};
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::Client;
+use serde_derive::Deserialize;
+use crate::common::{LlmReturn, LlmType, FinishReason, get_client, send_idempotent};
+use crate::config::required_env;
+use crate::credentials::credential;
+use crate::images::GeneratedImage;
+use crate::pending::PendingResult;
+use crate::providers::providers;
+use crate::tokens::{estimate_tokens, estimate_tokens_all};
+
+/// Between polls of an unfinished prediction
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Longest a prediction is waited for, cold starts can take minutes
+const MAX_WAIT: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PredictionMetrics {
+    /// Seconds the model ran for
+    #[serde(default)]
+    pub predict_time: Option<f64>,
+    #[serde(default)]
+    pub input_token_count: Option<usize>,
+    #[serde(default)]
+    pub output_token_count: Option<usize>,
+}
+
+/// A model run: starting, processing, succeeded, failed or canceled
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Prediction {
+    pub id: String,
+    pub status: String,
+    #[serde(default)]
+    pub output: serde_json::Value,
+    #[serde(default)]
+    pub error: Option<serde_json::Value>,
+    #[serde(default)]
+    pub metrics: Option<PredictionMetrics>,
+}
+
+impl Prediction {
+    pub fn is_finished(&self) -> bool {
+        matches!(self.status.as_str(), "succeeded" | "failed" | "canceled")
+    }
+
+    /// Output of a language model, which streams tokens into an array
+    pub fn output_text(&self) -> String {
+        match self.output {
+            serde_json::Value::String(ref text) => text.clone(),
+            serde_json::Value::Array(ref parts) => parts.iter().filter_map(|p| p.as_str()).collect(),
+            serde_json::Value::Null => String::new(),
+            ref output => output.to_string(),
+        }
+    }
+
+    /// Output files of an image model
+    pub fn output_urls(&self) -> Vec<String> {
+        match self.output {
+            serde_json::Value::String(ref url) => vec![url.clone()],
+            serde_json::Value::Array(ref urls) => urls.iter().filter_map(|u| u.as_str().map(|u| u.to_string())).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn error_text(&self) -> String {
+        match self.error {
+            Some(serde_json::Value::String(ref error)) => error.clone(),
+            Some(ref error) => error.to_string(),
+            None => format!("Prediction {} {}", self.id, self.status),
+        }
+    }
+}
+
+/// Start a prediction, input being the model's parameters as JSON
+pub async fn create_prediction(model: &str, input: &serde_json::Value) -> Result<Prediction, Box<dyn std::error::Error + Send>> {
+    let (_, res) = create(model, input).await?;
+
+    prediction_json(&res)
+}
+
+// Status and body of the request creating a prediction
+async fn create(model: &str, input: &serde_json::Value) -> Result<(reqwest::StatusCode, String), Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("REPLICATE_URL")?;
+    let (url, body) = match model.split_once(':') {
+        Some((_, version)) => (format!("{url}/predictions"), serde_json::json!({ "version": version, "input": input })),
+        None => (format!("{url}/models/{model}/predictions"), serde_json::json!({ "input": input })),
+    };
+    let client = get_replicate_client().await?;
+
+    let res = send_idempotent(client
+        .post(url)
+        .json(&body))
+        .await?;
+    let status = res.status();
+    let res = res
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    Ok((status, res))
+}
+
+/// Stop a prediction that has not finished
+pub async fn cancel_prediction(id: &str) -> Result<Prediction, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("REPLICATE_URL")?;
+    let client = get_replicate_client().await?;
+
+    let res = client
+        .post(format!("{url}/predictions/{id}/cancel"))
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    prediction_json(&res)
+}
+
+/// Current state of a prediction
+pub async fn get_prediction(id: &str) -> Result<Prediction, Box<dyn std::error::Error + Send>> {
+    let url: String = required_env("REPLICATE_URL")?;
+    let client = get_replicate_client().await?;
+
+    let res = client
+        .get(format!("{url}/predictions/{id}"))
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    prediction_json(&res)
+}
+
+/// Poll a prediction until it finishes, however that is. One still running
+/// after 10 minutes is cancelled, so it stops running up charges.
+pub async fn wait_for_prediction(prediction: Prediction) -> Result<Prediction, Box<dyn std::error::Error + Send>> {
+    waited(prediction, MAX_WAIT).await
+}
+
+async fn waited(mut prediction: Prediction, max_wait: Duration) -> Result<Prediction, Box<dyn std::error::Error + Send>> {
+    let start = Instant::now();
+
+    while !prediction.is_finished() {
+        if start.elapsed() > max_wait {
+            cancel_prediction(&prediction.id).await?;
+
+            return Err(Box::new(std::io::Error::other(format!("Prediction {} did not finish in {} secs, so was cancelled", prediction.id, max_wait.as_secs()))));
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+        prediction = get_prediction(&prediction.id).await?;
+    }
+
+    Ok(prediction)
+}
+
+/// Create a prediction without waiting for it. The handle polls, waits or
+/// calls back with the finished prediction, whatever its status.
+pub async fn submit_prediction(model: &str, input: &serde_json::Value) -> Result<PendingResult<Prediction>, Box<dyn std::error::Error + Send>> {
+    let prediction = create_prediction(model, input).await?;
+
+    Ok(pending_prediction(&prediction.id))
+}
+
+/// Handle to a prediction created earlier, by its id
+pub fn pending_prediction(id: &str) -> PendingResult<Prediction> {
+    PendingResult::new(id, POLL_INTERVAL, |id| Box::pin(async move {
+        let prediction = get_prediction(&id).await?;
+
+        Ok(prediction.is_finished().then_some(prediction))
+    }))
+}
+
+/// Create a prediction and wait for it to succeed, or fail with its error
+pub async fn run_prediction(model: &str, input: &serde_json::Value) -> Result<Prediction, Box<dyn std::error::Error + Send>> {
+    let prediction = wait_for_prediction(create_prediction(model, input).await?).await?;
+
+    if prediction.status != "succeeded" {
+        return Err(Box::new(std::io::Error::other(prediction.error_text())));
+    }
+
+    Ok(prediction)
+}
+
+/// Call a language model on Replicate. Models take a single prompt, so a
+/// chat is laid out as a transcript. A rejected request or failed
+/// prediction is returned as a REPLICATE_ERROR, as other providers return
+/// their errors.
+pub async fn call_replicate_model(model: &str, system: &str, user: &[String], temperature: f32, is_json: bool, is_chat: bool) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+    let start = Instant::now();
+    let mut prompt = if is_chat {
+        user.iter()
+            .enumerate()
+            .map(|(i, u)| format!("{}: {u}", if i % 2 == 0 { "User" } else { "Assistant" }))
+            .collect::<Vec<_>>()
+            .join("\n\n") + "\n\nAssistant:"
+    } else {
+        user.join("\n")
+    };
+
+    if is_json {
+        prompt.push_str("\n\nReply only with JSON.");
+    }
+
+    let mut input = serde_json::json!({ "prompt": prompt, "temperature": temperature });
+
+    if !system.is_empty() {
+        input["system_prompt"] = system.into();
+    }
+
+    let (status, res) = create(model, &input).await?;
+
+    if !status.is_success() {
+        return Ok(LlmReturn::new(LlmType::REPLICATE_ERROR, error_detail(&res), "ERROR".into(), (0, 0, 0), start.elapsed().as_secs_f64(), None, None)
+            .with_status(status));
+    }
+
+    let prediction = wait_for_prediction(prediction_json(&res)?).await?;
+    let timing = start.elapsed().as_secs_f64();
+
+    if prediction.status != "succeeded" {
+        return Ok(LlmReturn::new(LlmType::REPLICATE_ERROR, prediction.error_text(), FinishReason::Other(prediction.status.clone()), (0, 0, 0), timing, None, None));
+    }
+
+    let text = prediction.output_text();
+    let metrics = prediction.metrics.as_ref();
+    // Not every model reports tokens
+    let input = metrics.and_then(|m| m.input_token_count).unwrap_or_else(|| estimate_tokens(system) + estimate_tokens_all(user));
+    let output = metrics.and_then(|m| m.output_token_count).unwrap_or_else(|| estimate_tokens(&text));
+
+    Ok(LlmReturn::new(LlmType::REPLICATE, text, FinishReason::Stop, (input, output, input + output), timing, None, None))
+}
+
+/// Images from an image model on Replicate, such as black-forest-labs/flux-schnell,
+/// given its input e.g. {"prompt": "..."}
+pub async fn replicate_image(model: &str, input: &serde_json::Value) -> Result<Vec<GeneratedImage>, Box<dyn std::error::Error + Send>> {
+    let prediction = run_prediction(model, input).await?;
+
+    Ok(prediction.output_urls().into_iter()
+        .map(|url| GeneratedImage { url: Some(url), b64_json: None, revised_prompt: None })
+        .collect())
+}
+
+// Errors come as {"detail": "...", "status": 4xx}
+fn error_detail(res: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(res).ok()
+        .and_then(|e| e["detail"].as_str().map(|d| d.trim().to_string()))
+        .unwrap_or_else(|| res.to_string())
+}
+
+fn prediction_json(res: &str) -> Result<Prediction, Box<dyn std::error::Error + Send>> {
+    serde_json::from_str(res)
+        // Errors come as {"detail": "...", "status": 4xx}
+        .map_err(|_| -> Box<dyn std::error::Error + Send> { Box::new(std::io::Error::other(res.to_string())) })
+}
+
+async fn get_replicate_client() -> Result<Client, Box<dyn std::error::Error + Send>> {
+    providers().client("replicate", new_replicate_client()).await
+}
+
+pub(crate) async fn new_replicate_client() -> Result<Client, Box<dyn std::error::Error + Send>> {
+    // Extract API Key information
+    let api_key: String = credential("REPLICATE_API_TOKEN")?;
+
+    // Create headers
+    let mut headers: HeaderMap = HeaderMap::new();
+
+    // Create api key header
+    headers.insert(
+        "Authorization",
+        HeaderValue::from_str(&format!("Bearer {}", api_key))
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?,
+    );
+
+    get_client(headers).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn mock_replicate(create: &str, model: &str) -> MockServer {
+        let server = crate::mock::mock_post(&format!("/v1/models/{model}/predictions"), 201, create).await;
+
+        Mock::given(method("GET"))
+            .and(path("/v1/predictions/gm3qorzdhgbfurvjtvhg6dckhu"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(crate::mock::fixture("replicate/prediction.json")))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        std::env::set_var("REPLICATE_URL", format!("{}/v1", server.uri()));
+        std::env::set_var("REPLICATE_API_TOKEN", "test-key");
+
+        server
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_call_replicate() {
+        let server = mock_replicate("replicate/created.json", "meta/meta-llama-3-8b-instruct").await;
+        let res = crate::common::call_llm_model("replicate", "meta/meta-llama-3-8b-instruct", "Be brief", &["Hi".to_string()], 0.2, false, false).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::REPLICATE);
+        assert_eq!(res.text, "Hello there.\n");
+        assert_eq!(res.usage, (20, 3, 23));
+
+        let body = String::from_utf8_lossy(&server.received_requests().await.unwrap()[0].body).to_string();
+
+        assert!(body.contains(r#""system_prompt":"Be brief""#));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_call_replicate_rejected() {
+        let server = crate::mock::mock_post("/v1/models/meta/meta-llama-3-8b-instruct/predictions", 422, "replicate/invalid.json").await;
+
+        std::env::set_var("REPLICATE_URL", format!("{}/v1", server.uri()));
+        std::env::set_var("REPLICATE_API_TOKEN", "test-key");
+
+        let res = call_replicate_model("meta/meta-llama-3-8b-instruct", "", &["Hi".to_string()], 9.0, false, false).await.unwrap();
+
+        assert_eq!(res.llm_type, LlmType::REPLICATE_ERROR);
+        assert_eq!(res.text, "- input.temperature: Must be less than or equal to 5");
+        assert_eq!(res.status, Some(422));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_wait_cancels() {
+        let server = crate::mock::mock_post("/v1/predictions/gm3qorzdhgbfurvjtvhg6dckhu/cancel", 200, "replicate/created.json").await;
+
+        std::env::set_var("REPLICATE_URL", format!("{}/v1", server.uri()));
+        std::env::set_var("REPLICATE_API_TOKEN", "test-key");
+
+        let prediction = prediction_json(&crate::mock::fixture("replicate/created.json")).unwrap();
+        let err = waited(prediction, Duration::ZERO).await.unwrap_err();
+
+        assert!(err.to_string().contains("was cancelled"));
+    }
+
+    #[test]
+    fn test_prediction_output() {
+        let prediction: Prediction = serde_json::from_str(r#"{"id":"p1","status":"succeeded","output":["https://replicate.delivery/a.webp","https://replicate.delivery/b.webp"]}"#).unwrap();
+
+        assert!(prediction.is_finished());
+        assert_eq!(prediction.output_urls().len(), 2);
+
+        let failed: Prediction = serde_json::from_str(r#"{"id":"p2","status":"failed","error":"CUDA out of memory"}"#).unwrap();
+
+        assert_eq!(failed.error_text(), "CUDA out of memory");
+        assert!(prediction_json(r#"{"detail":"Unauthenticated","status":401}"#).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::Client;
+use serde_derive::{Deserialize, Serialize};
+use crate::common::get_client;
+use crate::config::{env_var, required_env};
+use crate::credentials::credential;
+use crate::providers::providers;
+use crate::vector_store::Match;
+
+/// Document position in the input and its relevance to the query
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ranked {
+    pub index: usize,
+    pub score: f32,
+}
+
+/// Rescore documents against a query, most relevant first
+pub trait Reranker {
+    fn rerank(&self, query: &str, documents: &[String], top_n: usize) -> impl std::future::Future<Output = Result<Vec<Ranked>, Box<dyn std::error::Error + Send>>> + Send;
+}
+
+// Cohere and Jina share request and response shapes
+#[derive(Debug, Serialize)]
+pub struct RerankRequest<'a> {
+    pub model: &'a str,
+    pub query: &'a str,
+    pub documents: &'a [String],
+    pub top_n: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RerankResponse {
+    pub results: Vec<RerankResult>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RerankResult {
+    pub index: usize,
+    pub relevance_score: f32,
+}
+
+/// Cohere rerank, key from COHERE_API_KEY
+#[derive(Debug, Clone)]
+pub struct CohereReranker {
+    pub model: String,
+}
+
+impl CohereReranker {
+    pub fn new(model: &str) -> Self {
+        CohereReranker { model: model.into() }
+    }
+}
+
+impl Default for CohereReranker {
+    fn default() -> Self {
+        Self::new(&env_var("COHERE_RERANK_MODEL").unwrap_or("rerank-english-v3.0".into()))
+    }
+}
+
+impl Reranker for CohereReranker {
+    async fn rerank(&self, query: &str, documents: &[String], top_n: usize) -> Result<Vec<Ranked>, Box<dyn std::error::Error + Send>> {
+        let url: String = required_env("COHERE_RERANK_URL")?;
+        let client = get_rerank_client("COHERE_API_KEY").await?;
+
+        call_rerank(&client, &url, &RerankRequest { model: &self.model, query, documents, top_n }).await
+    }
+}
+
+/// Jina reranker, key from JINA_API_KEY
+#[derive(Debug, Clone)]
+pub struct JinaReranker {
+    pub model: String,
+}
+
+impl JinaReranker {
+    pub fn new(model: &str) -> Self {
+        JinaReranker { model: model.into() }
+    }
+}
+
+impl Default for JinaReranker {
+    fn default() -> Self {
+        Self::new(&env_var("JINA_RERANK_MODEL").unwrap_or("jina-reranker-v2-base-multilingual".into()))
+    }
+}
+
+impl Reranker for JinaReranker {
+    async fn rerank(&self, query: &str, documents: &[String], top_n: usize) -> Result<Vec<Ranked>, Box<dyn std::error::Error + Send>> {
+        let url: String = required_env("JINA_RERANK_URL")?;
+        let client = get_rerank_client("JINA_API_KEY").await?;
+
+        call_rerank(&client, &url, &RerankRequest { model: &self.model, query, documents, top_n }).await
+    }
+}
+
+async fn call_rerank(client: &Client, url: &str, request: &RerankRequest<'_>) -> Result<Vec<Ranked>, Box<dyn std::error::Error + Send>> {
+    let res = client
+        .post(url)
+        .json(request)
+        .send()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        .text()
+        .await
+        .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+    rerank_response(&res)
+}
+
+fn rerank_response(res: &str) -> Result<Vec<Ranked>, Box<dyn std::error::Error + Send>> {
+    let res: RerankResponse = serde_json::from_str(res)
+        .map_err(|_| -> Box<dyn std::error::Error + Send> { Box::new(std::io::Error::other(res.to_string())) })?;
+    let mut ranked: Vec<Ranked> = res.results.into_iter()
+        .map(|r| Ranked { index: r.index, score: r.relevance_score })
+        .collect();
+
+    ranked.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    Ok(ranked)
+}
+
+async fn get_rerank_client(key: &str) -> Result<Client, Box<dyn std::error::Error + Send>> {
+    providers().client(key, new_rerank_client(key)).await
+}
+
+async fn new_rerank_client(key: &str) -> Result<Client, Box<dyn std::error::Error + Send>> {
+    // Extract API Key information
+    let api_key: String = credential(key)?;
+
+    // Create headers
+    let mut headers: HeaderMap = HeaderMap::new();
+
+    // Create api key header
+    headers.insert(
+        "Authorization",
+        HeaderValue::from_str(&format!("Bearer {}", api_key))
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?,
+    );
+
+    get_client(headers).await
+}
+
+/// Rescore retrieved documents, keeping the top_n most relevant
+pub async fn rerank_matches(reranker: &impl Reranker, query: &str, matches: Vec<Match>, top_n: usize) -> Result<Vec<Match>, Box<dyn std::error::Error + Send>> {
+    let documents: Vec<String> = matches.iter().map(|m| m.document.text.clone()).collect();
+    let ranked = reranker.rerank(query, &documents, top_n).await?;
+
+    Ok(ranked.into_iter()
+        .filter_map(|r| matches.get(r.index).map(|m| Match { document: m.document.clone(), score: r.score }))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rerank_response() {
+        // Jina shape, Cohere omits document and has meta instead of usage
+        let res = r#"{"model":"jina-reranker-v2-base-multilingual","usage":{"total_tokens":38},"results":[{"index":2,"document":{"text":"c"},"relevance_score":0.1},{"index":0,"document":{"text":"a"},"relevance_score":0.9}]}"#;
+        let ranked = rerank_response(res).unwrap();
+
+        assert_eq!(ranked, vec![Ranked { index: 0, score: 0.9 }, Ranked { index: 2, score: 0.1 }]);
+        assert!(rerank_response(r#"{"message":"invalid api token"}"#).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use schemars::JsonSchema;
+use serde_derive::Deserialize;
+use crate::extract::extracted;
+
+/// Overall feeling of a text, mixed being both positive and negative
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Polarity {
+    Positive,
+    Negative,
+    Neutral,
+    Mixed,
+}
+
+/// Sentiment of a text with the model's reasons
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+pub struct Sentiment {
+    #[serde(rename = "sentiment")]
+    pub polarity: Polarity,
+    /// From -1 (very negative) to 1 (very positive)
+    pub score: f64,
+    /// One or two sentences
+    pub rationale: String,
+}
+
+/// Sentiment of the text, e.g. of a review or support message
+pub async fn sentiment(llm: &str, model: &str, text: &str) -> Result<Sentiment, Box<dyn std::error::Error + Send>> {
+    let mut sentiment: Sentiment = extracted(llm, model, text, "Judge the sentiment of the text, giving a score from -1 (very negative) to 1 (very positive) \
+        and your reasons in one or two sentences, as JSON matching this schema").await?;
+
+    sentiment.score = sentiment.score.clamp(-1.0, 1.0);
+
+    Ok(sentiment)
+}
+
+/// Score of a text against a criterion with the model's reasons
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+pub struct Score {
+    pub score: f64,
+    /// One or two sentences
+    pub rationale: String,
+}
+
+/// Score the text from 0 to max for a criterion, e.g. "clarity of
+/// explanation" or "politeness", as a fixed rubric for quality checks
+pub async fn score(llm: &str, model: &str, text: &str, criterion: &str, max: u32) -> Result<Score, Box<dyn std::error::Error + Send>> {
+    let task = format!("Score the text from 0 (worst) to {max} (best) for: {criterion}. \
+        Be strict and consistent, and give your reasons in one or two sentences, as JSON matching this schema");
+    let mut score: Score = extracted(llm, model, text, &task).await?;
+
+    score.score = score.score.clamp(0.0, max as f64);
+
+    Ok(score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[tokio::test]
+    #[serial]
+    async fn test_sentiment_and_score() {
+        let server = crate::mock::mock_post_sequence("/v1/chat/completions", &[(200, "gpt/sentiment.json"), (200, "gpt/score.json")]).await;
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        let text = "I was charged twice this month. Sort it out.";
+        let sentiment = sentiment("gpt", "gpt-4o", text).await.unwrap();
+
+        assert_eq!(sentiment.polarity, Polarity::Negative);
+        assert_eq!(sentiment.score, -0.7);
+
+        let score = score("gpt", "gpt-4o", text, "politeness", 10).await.unwrap();
+
+        assert_eq!(score.score, 10.0);
+        assert!(score.rationale.starts_with("Clear"));
+
+        let requests = server.received_requests().await.unwrap();
+
+        assert!(String::from_utf8_lossy(&requests[0].body).contains(r#"\"enum\":[\"positive\",\"negative\",\"neutral\",\"mixed\"]"#));
+        assert!(String::from_utf8_lossy(&requests[1].body).contains("from 0 (worst) to 10 (best) for: politeness"));
+    }
+}
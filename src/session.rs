@@ -0,0 +1,370 @@
+use std::sync::Arc;
+use futures::future::BoxFuture;
+use crate::common::{LlmReturn, Triple, call_llm_model};
+use crate::mapreduce::summarize_to_fit;
+use crate::error::LlmClientError;
+use crate::pricing::cost;
+use crate::tokens::{estimate_tokens, estimate_tokens_all};
+
+/// Strategy for keeping dialogue history within bounds. Prompts alternate
+/// user and assistant turns, starting and ending with a user turn, and
+/// must still do so once reduced.
+pub trait MemoryPolicy: Send + Sync {
+    /// Reduced history to send, which also replaces the session history
+    fn apply<'a>(&'a self, system: &'a str, prompts: Vec<String>) -> BoxFuture<'a, Result<Vec<String>, Box<dyn std::error::Error + Send>>>;
+}
+
+/// Limits on cumulative session usage. A call that would cross a limit,
+/// judged on its estimated input tokens, fails with BudgetExceeded unless
+/// a downgrade model is given, in which case the session switches to that
+/// (cheaper) model. The limits still hold for the downgrade model, so the
+/// call fails if it would cross them too.
+#[derive(Debug, Clone, Default)]
+pub struct Budget {
+    pub max_tokens: Option<usize>,
+    /// US dollars, for models with known prices
+    pub max_cost: Option<f64>,
+    pub downgrade_model: Option<String>,
+}
+
+impl Budget {
+    pub fn tokens(max_tokens: usize) -> Self {
+        Budget { max_tokens: Some(max_tokens), ..Default::default() }
+    }
+
+    pub fn cost(max_cost: f64) -> Self {
+        Budget { max_cost: Some(max_cost), ..Default::default() }
+    }
+
+    pub fn with_downgrade(mut self, model: &str) -> Self {
+        self.downgrade_model = Some(model.into());
+        self
+    }
+
+    // Would a call with this many input tokens cross the limits?
+    fn exceeded(&self, model: &str, used: Triple, spent: f64, input: usize) -> bool {
+        let tokens = self.max_tokens.map(|max| used.2 + input > max).unwrap_or(false);
+        let cost = self.max_cost
+            .map(|max| spent + cost(model, (input, 0, input)).unwrap_or(0.0) > max)
+            .unwrap_or(false);
+
+        tokens || cost
+    }
+}
+
+/// Multi-turn dialogue with an LLM, keeping history and usage
+#[derive(Clone)]
+pub struct ChatSession {
+    pub llm: String,
+    pub model: String,
+    pub system: String,
+    pub prompts: Vec<String>,
+    pub temperature: f32,
+    /// Tokens used by the session so far
+    pub usage: Triple,
+    pub timing: f64,
+    /// Estimated US dollars spent so far
+    pub cost: f64,
+    memory: Option<Arc<dyn MemoryPolicy>>,
+    budget: Option<Budget>,
+}
+
+impl ChatSession {
+    pub fn new(llm: &str, model: &str, system: &str) -> Self {
+        ChatSession {
+            llm: llm.into(),
+            model: model.into(),
+            system: system.into(),
+            prompts: Vec::new(),
+            temperature: 0.2,
+            usage: (0, 0, 0),
+            timing: 0.0,
+            cost: 0.0,
+            memory: None,
+            budget: None,
+        }
+    }
+
+    pub fn set_temperature(&mut self, temperature: f32) {
+        self.temperature = temperature;
+    }
+
+    /// Policy applied to history before each call
+    pub fn set_memory(&mut self, memory: impl MemoryPolicy + 'static) {
+        self.memory = Some(Arc::new(memory));
+    }
+
+    /// Limit cumulative tokens or cost
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.budget = Some(budget);
+    }
+
+    /// Independent copy of the session at this point, to explore another
+    /// continuation while keeping this one. Usage and cost so far are
+    /// copied too; the memory policy is shared.
+    pub fn fork(&self) -> Self {
+        self.clone()
+    }
+
+    /// Forget the dialogue so far
+    pub fn clear(&mut self) {
+        self.prompts.clear();
+    }
+
+    /// Send next user prompt and add the reply to the history
+    pub async fn send(&mut self, prompt: &str) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+        let mut prompts = self.prompts.clone();
+
+        prompts.push(prompt.into());
+
+        if let Some(ref memory) = self.memory {
+            prompts = memory.apply(&self.system, prompts).await?;
+        }
+
+        let input = estimate_tokens(&self.system) + estimate_tokens_all(&prompts);
+
+        self.check_budget(input)?;
+
+        let res = call_llm_model(&self.llm, &self.model, &self.system, &prompts, self.temperature, false, true).await?;
+
+        prompts.push(res.text.clone());
+
+        self.prompts = prompts;
+        self.usage = (self.usage.0 + res.usage.0, self.usage.1 + res.usage.1, self.usage.2 + res.usage.2);
+        self.timing += res.timing.total;
+        self.cost += cost(&self.model, res.usage).unwrap_or(0.0);
+
+        Ok(res)
+    }
+
+    // Switch to the downgrade model if a call with this many input tokens
+    // would cross the budget, failing if it would with that model too
+    fn check_budget(&mut self, input: usize) -> Result<(), Box<dyn std::error::Error + Send>> {
+        let Some(ref budget) = self.budget else {
+            return Ok(());
+        };
+
+        if !budget.exceeded(&self.model, self.usage, self.cost, input) {
+            return Ok(());
+        }
+
+        match budget.downgrade_model {
+            Some(ref model) if !budget.exceeded(model, self.usage, self.cost, input) => {
+                self.model = model.clone();
+
+                Ok(())
+            },
+            _ => Err(Box::new(LlmClientError::BudgetExceeded { tokens: self.usage.2, cost: self.cost })),
+        }
+    }
+}
+
+/// Keep only the last N turns, unlike `LlmCompletion::truncate_messages`
+/// which keeps the first. The system prompt is held separately, so is
+/// always kept.
+#[derive(Debug, Clone, Copy)]
+pub struct WindowMemory {
+    pub turns: usize,
+}
+
+impl WindowMemory {
+    pub fn new(turns: usize) -> Self {
+        WindowMemory { turns }
+    }
+}
+
+impl MemoryPolicy for WindowMemory {
+    fn apply<'a>(&'a self, _system: &'a str, prompts: Vec<String>) -> BoxFuture<'a, Result<Vec<String>, Box<dyn std::error::Error + Send>>> {
+        Box::pin(async move {
+            let keep = window_len(prompts.len(), self.turns);
+
+            Ok(prompts[prompts.len() - keep..].to_vec())
+        })
+    }
+}
+
+// Odd number of most recent turns, so the window starts with the user,
+// none if there is no history
+fn window_len(len: usize, turns: usize) -> usize {
+    if len == 0 {
+        return 0;
+    }
+
+    let keep = turns.max(1).min(len);
+
+    if keep.is_multiple_of(2) { keep - 1 } else { keep }
+}
+
+/// When history exceeds a token budget, older turns are summarised by
+/// a (preferably cheap) model, with `summarize_to_fit` so long histories
+/// are summarised in parts, and replaced with the summary. The most
+/// recent turns are kept as they are.
+#[derive(Debug, Clone)]
+pub struct SummaryMemory {
+    pub llm: String,
+    pub model: String,
+    pub max_tokens: usize,
+    pub keep_turns: usize,
+}
+
+impl SummaryMemory {
+    pub fn new(llm: &str, model: &str, max_tokens: usize) -> Self {
+        SummaryMemory { llm: llm.into(), model: model.into(), max_tokens, keep_turns: 4 }
+    }
+
+    /// Number of most recent turns never summarised
+    pub fn set_keep_turns(&mut self, keep_turns: usize) {
+        self.keep_turns = keep_turns;
+    }
+}
+
+const SUMMARY_PREFIX: &str = "Summary of our conversation so far:";
+
+impl MemoryPolicy for SummaryMemory {
+    fn apply<'a>(&'a self, system: &'a str, prompts: Vec<String>) -> BoxFuture<'a, Result<Vec<String>, Box<dyn std::error::Error + Send>>> {
+        Box::pin(async move {
+            if estimate_tokens(system) + estimate_tokens_all(&prompts) <= self.max_tokens {
+                return Ok(prompts);
+            }
+
+            let split = summary_split(prompts.len(), self.keep_turns);
+
+            if split == 0 {
+                return Ok(prompts);
+            }
+
+            let transcript: Vec<String> = prompts[..split].iter()
+                .enumerate()
+                .map(|(i, p)| format!("{}: {p}", if i % 2 == 0 { "User" } else { "Assistant" }))
+                .collect();
+            // Room left once the recent turns are kept
+            let room = self.max_tokens.saturating_sub(estimate_tokens(system) + estimate_tokens_all(&prompts[split..]));
+            let summary = summarize_to_fit(&self.llm, &self.model, &transcript.join("\n\n"), room).await?;
+
+            Ok(summarised(&summary, &prompts[split..]))
+        })
+    }
+}
+
+// Even number of older turns to summarise, so recent turns still start
+// with the user
+fn summary_split(len: usize, keep_turns: usize) -> usize {
+    let split = len.saturating_sub(keep_turns.max(1));
+
+    split - split % 2
+}
+
+// Summary as a user turn and acknowledgement, keeping turns alternating
+fn summarised(summary: &str, recent: &[String]) -> Vec<String> {
+    let mut prompts = vec![format!("{SUMMARY_PREFIX}\n{summary}"), "Understood.".to_string()];
+
+    prompts.extend_from_slice(recent);
+
+    prompts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_exceeded() {
+        let budget = Budget::tokens(1000);
+
+        assert!(!budget.exceeded("gpt-4o", (0, 0, 900), 0.0, 100));
+        assert!(budget.exceeded("gpt-4o", (0, 0, 901), 0.0, 100));
+
+        // gpt-4o input at $5 per million tokens
+        let budget = Budget::cost(0.01);
+
+        assert!(!budget.exceeded("gpt-4o", (0, 0, 0), 0.005, 1000));
+        assert!(budget.exceeded("gpt-4o", (0, 0, 0), 0.005, 1001));
+    }
+
+    #[tokio::test]
+    async fn test_session_budget_error() {
+        let mut session = ChatSession::new("groq", "llama3-8b-8192", "");
+
+        session.set_budget(Budget::tokens(2));
+
+        let err = session.send("This prompt is longer than the budget").await.unwrap_err();
+
+        assert!(matches!(err.downcast_ref::<LlmClientError>(), Some(LlmClientError::BudgetExceeded { .. })));
+        assert!(session.prompts.is_empty());
+    }
+
+    #[test]
+    fn test_budget_downgrade() {
+        let mut session = ChatSession::new("gpt", "gpt-4o", "");
+
+        // 1000 input tokens cost $0.005 on gpt-4o, $0.00015 on gpt-4o-mini
+        session.set_budget(Budget::cost(0.001).with_downgrade("gpt-4o-mini"));
+
+        session.check_budget(1000).unwrap();
+
+        assert_eq!(session.model, "gpt-4o-mini");
+
+        session.cost = 0.00095;
+
+        let err = session.check_budget(1000).unwrap_err();
+
+        assert!(matches!(err.downcast_ref::<LlmClientError>(), Some(LlmClientError::BudgetExceeded { .. })));
+
+        let mut session = ChatSession::new("gpt", "gpt-4o", "");
+
+        session.set_budget(Budget::tokens(100).with_downgrade("gpt-4o-mini"));
+
+        assert!(session.check_budget(1000).is_err());
+        assert_eq!(session.model, "gpt-4o");
+    }
+
+    #[test]
+    fn test_fork() {
+        let mut session = ChatSession::new("groq", "llama3-8b-8192", "Be brief");
+
+        session.prompts = vec!["Hi".into(), "Hello".into()];
+        session.set_memory(WindowMemory::new(3));
+
+        let mut branch = session.fork();
+
+        branch.prompts.push("Goodbye".into());
+        branch.system = "Be verbose".into();
+
+        assert_eq!(session.prompts.len(), 2);
+        assert_eq!(session.system, "Be brief");
+        assert_eq!(branch.prompts.len(), 3);
+        assert!(branch.memory.is_some());
+    }
+
+    #[test]
+    fn test_summary_split() {
+        assert_eq!(summary_split(9, 4), 4);
+        assert_eq!(summary_split(9, 3), 6);
+        assert_eq!(summary_split(3, 4), 0);
+        assert_eq!(summary_split(5, 0), 4);
+    }
+
+    #[tokio::test]
+    async fn test_window_memory() {
+        let prompts: Vec<String> = (1..=7).map(|i| i.to_string()).collect();
+
+        assert_eq!(WindowMemory::new(3).apply("", prompts.clone()).await.unwrap(), vec!["5", "6", "7"]);
+        assert_eq!(WindowMemory::new(4).apply("", prompts.clone()).await.unwrap(), vec!["5", "6", "7"]);
+        assert_eq!(WindowMemory::new(20).apply("", prompts.clone()).await.unwrap(), prompts);
+        assert_eq!(window_len(1, 0), 1);
+        assert!(WindowMemory::new(3).apply("", vec![]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_summary_memory_under_budget() {
+        let memory = SummaryMemory::new("groq", "llama3-8b-8192", 1000);
+        let prompts = vec!["Hi".to_string(), "Hello".to_string(), "How are you?".to_string()];
+
+        assert_eq!(memory.apply("", prompts.clone()).await.unwrap(), prompts);
+
+        let reduced = summarised("They said hi.", &prompts[2..]);
+
+        assert_eq!(reduced.len(), 3);
+        assert!(reduced[0].starts_with(SUMMARY_PREFIX));
+    }
+}
@@ -0,0 +1,211 @@
+//! Combinators over streamed replies, such as `claude::call_claude_stream`:
+//! collect into an `LlmReturn`, copy to a writer as text arrives (e.g. to
+//! print it while collecting), or regroup into whole sentences for text to
+//! speech.
+use std::time::Instant;
+use futures::{Stream, StreamExt};
+use crate::claude::{ClaudeStreamEvent, ContentDelta};
+use crate::common::{FinishReason, LlmReturn, LlmType};
+use crate::tokens::estimate_tokens;
+
+/// Join streamed text into an `LlmReturn`, timed from start (when the call
+/// was made) including the time to the first text. Text streams carry no
+/// usage, so output tokens are estimated and input tokens are 0.
+pub async fn collect<S>(stream: S, llm_type: LlmType, start: Instant) -> Result<LlmReturn, Box<dyn std::error::Error + Send>>
+where
+    S: Stream<Item = Result<String, Box<dyn std::error::Error + Send>>>,
+{
+    let mut stream = std::pin::pin!(stream);
+    let mut text = String::new();
+    let mut first_token = None;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+
+        if first_token.is_none() && !chunk.is_empty() {
+            first_token = Some(start.elapsed().as_secs_f64());
+        }
+        text.push_str(&chunk);
+    }
+
+    let output = estimate_tokens(&text);
+    let mut ret = LlmReturn::new(llm_type, text, FinishReason::Stop, (0, output, output), start.elapsed().as_secs_f64(), None, None);
+
+    if let Some(first_token) = first_token {
+        ret.timing = ret.timing.with_first_token(first_token);
+    }
+
+    Ok(ret)
+}
+
+/// Join Claude stream events into an `LlmReturn` with the exact usage and
+/// finish reason, and any thinking as reasoning
+pub async fn collect_claude_events<S>(events: S, start: Instant) -> Result<LlmReturn, Box<dyn std::error::Error + Send>>
+where
+    S: Stream<Item = Result<ClaudeStreamEvent, Box<dyn std::error::Error + Send>>>,
+{
+    let mut events = std::pin::pin!(events);
+    let (mut text, mut thinking) = (String::new(), String::new());
+    let (mut input, mut output) = (0, 0);
+    let mut finish_reason = FinishReason::Other(String::new());
+    let mut first_token = None;
+
+    while let Some(event) = events.next().await {
+        match event? {
+            ClaudeStreamEvent::MessageStart { message } => input = message.usage.input_tokens,
+            ClaudeStreamEvent::ContentBlockDelta { delta: ContentDelta::TextDelta { text: delta }, .. } => {
+                first_token.get_or_insert_with(|| start.elapsed().as_secs_f64());
+                text.push_str(&delta);
+            },
+            ClaudeStreamEvent::ContentBlockDelta { delta: ContentDelta::ThinkingDelta { thinking: delta }, .. } => {
+                first_token.get_or_insert_with(|| start.elapsed().as_secs_f64());
+                thinking.push_str(&delta);
+            },
+            ClaudeStreamEvent::MessageDelta { delta, usage } => {
+                output = usage.output_tokens;
+                if let Some(reason) = delta.stop_reason {
+                    finish_reason = reason.into();
+                }
+            },
+            ClaudeStreamEvent::Error { error } => return Err(Box::new(std::io::Error::other(error.to_string()))),
+            _ => (),
+        }
+    }
+
+    let mut ret = LlmReturn::new(LlmType::CLAUDE, text, finish_reason, (input, output, input + output), start.elapsed().as_secs_f64(), None, None)
+        .with_reasoning(if thinking.is_empty() { None } else { Some(thinking) });
+
+    if let Some(first_token) = first_token {
+        ret.timing = ret.timing.with_first_token(first_token);
+    }
+
+    Ok(ret)
+}
+
+/// Write each piece of text to writer as it passes, flushing so it shows
+/// at once. A write error ends the stream with that error.
+pub fn tee<S, W>(stream: S, mut writer: W) -> impl Stream<Item = Result<String, Box<dyn std::error::Error + Send>>>
+where
+    S: Stream<Item = Result<String, Box<dyn std::error::Error + Send>>>,
+    W: std::io::Write,
+{
+    stream.map(move |chunk| {
+        let chunk = chunk?;
+
+        writer.write_all(chunk.as_bytes())
+            .and_then(|_| writer.flush())
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+        Ok(chunk)
+    })
+}
+
+/// Regroup streamed text into sentences, each ending at '.', '!' or '?'
+/// (and any closing quotes or brackets) followed by white space, or at a
+/// line break. Whatever is left at the end is the last sentence.
+/// Sentences are trimmed and empty ones dropped.
+pub fn sentences<S>(stream: S) -> impl Stream<Item = Result<String, Box<dyn std::error::Error + Send>>>
+where
+    S: Stream<Item = Result<String, Box<dyn std::error::Error + Send>>>,
+{
+    let stream = Box::pin(stream.map(Some).chain(futures::stream::once(async { None })));
+
+    stream
+        .scan(String::new(), |buffer, chunk| {
+            let sentences: Vec<Result<String, Box<dyn std::error::Error + Send>>> = match chunk {
+                Some(Ok(text)) => {
+                    buffer.push_str(&text);
+
+                    let mut sentences = Vec::new();
+
+                    while let Some(end) = sentence_end(buffer) {
+                        sentences.push(Ok(buffer.drain(..end).collect()));
+                    }
+
+                    sentences
+                },
+                Some(Err(e)) => vec![Err(e)],
+                None => vec![Ok(std::mem::take(buffer))],
+            };
+
+            futures::future::ready(Some(futures::stream::iter(sentences)))
+        })
+        .flatten()
+        .filter_map(|sentence| futures::future::ready(match sentence {
+            Ok(sentence) if sentence.trim().is_empty() => None,
+            Ok(sentence) => Some(Ok(sentence.trim().to_string())),
+            Err(e) => Some(Err(e)),
+        }))
+}
+
+// Byte index just past the first complete sentence, if there is one
+fn sentence_end(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\n' => return Some(i + 1),
+            '.' | '!' | '?' => {
+                let mut end = i + c.len_utf8();
+
+                while let Some(&(j, close)) = chars.peek() {
+                    if matches!(close, '"' | '\'' | ')' | ']' | '\u{201d}' | '\u{2019}') {
+                        end = j + close.len_utf8();
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                if chars.peek().is_some_and(|&(_, next)| next.is_whitespace()) {
+                    return Some(end);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(texts: &[&str]) -> impl Stream<Item = Result<String, Box<dyn std::error::Error + Send>>> {
+        futures::stream::iter(texts.iter().map(|t| Ok(t.to_string())).collect::<Vec<_>>())
+    }
+
+    #[tokio::test]
+    async fn test_collect_and_tee() {
+        let mut written = Vec::new();
+        let ret = collect(tee(chunks(&["Hello", ", world"]), &mut written), LlmType::GPT, Instant::now()).await.unwrap();
+
+        assert_eq!(ret.text, "Hello, world");
+        assert_eq!(written, b"Hello, world");
+        assert_eq!(ret.usage, (0, 3, 3));
+        assert!(ret.timing.first_token.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_collect_claude_events() {
+        let events = crate::mock::fixture("claude/stream.txt")
+            .split("\n\n")
+            .filter_map(|e| e.lines().find_map(|l| l.strip_prefix("data:")).map(|d| serde_json::from_str(d.trim()).unwrap()))
+            .map(Ok)
+            .collect::<Vec<_>>();
+        let ret = collect_claude_events(futures::stream::iter(events), Instant::now()).await.unwrap();
+
+        assert_eq!(ret.text, "Hello there.");
+        assert_eq!(ret.usage, (25, 3, 28));
+        assert_eq!(ret.finish_reason, FinishReason::Stop);
+        assert!(ret.timing.first_token.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_sentences() {
+        let stream = chunks(&["Pi is 3.", "14. Is it", "? Yes!\" He", " said (quietly.) Then\nmore", " text"]);
+        let sentences: Vec<String> = sentences(stream).map(|s| s.unwrap()).collect().await;
+
+        assert_eq!(sentences, vec!["Pi is 3.14.", "Is it?", "Yes!\"", "He said (quietly.)", "Then", "more text"]);
+    }
+}
@@ -0,0 +1,89 @@
+//! Call metrics, published through the `metrics` facade when the `metrics`
+//! feature is enabled. Install any recorder, e.g. metrics-exporter-prometheus,
+//! to export them; without one, or without the feature, recording is a no-op.
+//!
+//! All are labelled with `provider` and `model`:
+//! - llmclient_calls_total, also labelled `outcome`: ok, tools or error
+//! - llmclient_errors_total, failed requests and provider error replies
+//! - llmclient_tokens_total, also labelled `direction`: input or output
+//! - llmclient_latency_seconds, histogram of request time
+use std::time::Duration;
+use crate::common::LlmReturn;
+
+pub const CALLS: &str = "llmclient_calls_total";
+pub const ERRORS: &str = "llmclient_errors_total";
+pub const TOKENS: &str = "llmclient_tokens_total";
+pub const LATENCY: &str = "llmclient_latency_seconds";
+
+/// Register descriptions with the installed recorder, call once at start up
+#[cfg(feature = "metrics")]
+pub fn describe() {
+    metrics::describe_counter!(CALLS, "LLM calls made, by outcome");
+    metrics::describe_counter!(ERRORS, "LLM calls that failed or returned an error");
+    metrics::describe_counter!(TOKENS, metrics::Unit::Count, "Tokens used, by direction");
+    metrics::describe_histogram!(LATENCY, metrics::Unit::Seconds, "LLM call latency");
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn describe() {}
+
+/// Record the outcome of one call to a provider
+#[cfg(feature = "metrics")]
+pub fn record_call(llm: &str, model: &str, res: &Result<LlmReturn, Box<dyn std::error::Error + Send>>, elapsed: Duration) {
+    let labels = [("provider", llm.to_string()), ("model", model.to_string())];
+    let outcome = match res {
+        Ok(ret) if ret.llm_type.is_error() => "error",
+        Ok(ret) if ret.llm_type.is_tools() => "tools",
+        Ok(_) => "ok",
+        Err(_) => "error",
+    };
+
+    metrics::counter!(CALLS, "provider" => llm.to_string(), "model" => model.to_string(), "outcome" => outcome).increment(1);
+    metrics::histogram!(LATENCY, &labels).record(elapsed.as_secs_f64());
+
+    if outcome == "error" {
+        metrics::counter!(ERRORS, &labels).increment(1);
+    }
+    if let Ok(ret) = res {
+        for (direction, tokens) in [("input", ret.usage.0), ("output", ret.usage.1)] {
+            metrics::counter!(TOKENS, "provider" => llm.to_string(), "model" => model.to_string(), "direction" => direction).increment(tokens as u64);
+        }
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn record_call(_llm: &str, _model: &str, _res: &Result<LlmReturn, Box<dyn std::error::Error + Send>>, _elapsed: Duration) {}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use crate::common::{FinishReason, LlmType};
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+    #[test]
+    fn test_record_call() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+
+        metrics::with_local_recorder(&recorder, || {
+            let ok = Ok(LlmReturn::new(LlmType::GPT, "Hi".into(), FinishReason::Stop, (10, 5, 15), 0.5, None, None));
+            let failed = Err(Box::new(std::io::Error::other("timeout")) as Box<dyn std::error::Error + Send>);
+
+            record_call("gpt", "gpt-4o", &ok, Duration::from_millis(500));
+            record_call("gpt", "gpt-4o", &failed, Duration::from_millis(100));
+        });
+
+        let values: Vec<(String, Vec<String>, DebugValue)> = snapshotter.snapshot().into_vec().into_iter()
+            .map(|(key, _, _, value)| (key.key().name().to_string(), key.key().labels().map(|l| l.value().to_string()).collect(), value))
+            .collect();
+        let value = |name: &str, label: &str| values.iter()
+            .find(|(n, labels, _)| n == name && labels.iter().any(|l| l == label))
+            .map(|(_, _, v)| v);
+
+        assert_eq!(value(CALLS, "ok"), Some(&DebugValue::Counter(1)));
+        assert_eq!(value(CALLS, "error"), Some(&DebugValue::Counter(1)));
+        assert_eq!(value(ERRORS, "gpt"), Some(&DebugValue::Counter(1)));
+        assert_eq!(value(TOKENS, "output"), Some(&DebugValue::Counter(5)));
+        assert!(matches!(value(LATENCY, "gpt"), Some(DebugValue::Histogram(h)) if h.len() == 2));
+    }
+}
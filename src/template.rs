@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use stemplate::Template;
+use crate::common::LlmCompletion;
+
+/// Reusable prompt with `${name}` variables, rendered with stemplate.
+/// Partials are named fragments, which may themselves use variables,
+/// substituted first. Few-shot examples fill the `${examples}` slot.
+///
+/// ```ignore
+/// let prompt = PromptTemplate::new("${persona}\nClassify: ${text}\n${examples}")
+///     .partial("persona", "You are a careful ${domain} analyst.")
+///     .example("great product", "positive")
+///     .example("broke in a day", "negative");
+/// let text = prompt.render(&HashMap::from([("domain", "retail"), ("text", "ok I guess")]))?;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PromptTemplate {
+    template: String,
+    partials: HashMap<String, String>,
+    examples: Vec<(String, String)>,
+    example_format: String,
+}
+
+impl PromptTemplate {
+    pub fn new(template: &str) -> Self {
+        PromptTemplate {
+            template: template.into(),
+            partials: HashMap::new(),
+            examples: Vec::new(),
+            example_format: "Input: ${input}\nOutput: ${output}".into(),
+        }
+    }
+
+    /// Named fragment substituted for `${name}`
+    pub fn partial(mut self, name: &str, text: &str) -> Self {
+        self.partials.insert(name.into(), text.into());
+        self
+    }
+
+    /// Few-shot example for the `${examples}` slot
+    pub fn example(mut self, input: &str, output: &str) -> Self {
+        self.examples.push((input.into(), output.into()));
+        self
+    }
+
+    /// Layout of each example, using `${input}` and `${output}`
+    pub fn example_format(mut self, format: &str) -> Self {
+        self.example_format = format.into();
+        self
+    }
+
+    /// Template with partials and examples filled in
+    fn expanded(&self) -> String {
+        let mut text = self.template.clone();
+
+        // Partials may refer to other partials
+        for _ in 0..=self.partials.len() {
+            let before = text.clone();
+            self.partials.iter()
+                .for_each(|(name, partial)| text = text.replace(&format!("${{{name}}}"), partial));
+            if text == before {
+                break;
+            }
+        }
+
+        let examples: Vec<String> = self.examples.iter()
+            .map(|(input, output)| {
+                self.example_format
+                    .replace("${input}", input)
+                    .replace("${output}", output)
+            })
+            .collect();
+
+        text.replace("${examples}", &examples.join("\n\n"))
+    }
+
+    /// Names of variables the template needs
+    pub fn variables(&self) -> Vec<String> {
+        let re = regex::Regex::new(r"\$\{(\w+)\}").unwrap();
+        let mut names: Vec<String> = Vec::new();
+
+        re.captures_iter(&self.expanded())
+            .for_each(|c| if !names.contains(&c[1].to_string()) { names.push(c[1].to_string()) });
+
+        names
+    }
+
+    /// Render with variables, all of which must be supplied
+    pub fn render<T: AsRef<str>>(&self, vars: &HashMap<&str, T>) -> Result<String, Box<dyn std::error::Error + Send>> {
+        let missing: Vec<String> = self.variables().into_iter()
+            .filter(|v| !vars.contains_key(v.as_str()))
+            .collect();
+
+        if !missing.is_empty() {
+            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("Prompt template variables not supplied: {}", missing.join(", ")))));
+        }
+
+        Ok(Template::new(&self.expanded()).render(vars))
+    }
+
+    /// Render and add as text for role, usually 'user'
+    pub fn render_into<T: AsRef<str>>(&self, completion: &mut impl LlmCompletion, role: &str, vars: &HashMap<&str, T>) -> Result<(), Box<dyn std::error::Error + Send>> {
+        completion.add_text(role, &self.render(vars)?);
+
+        Ok(())
+    }
+
+    /// Render and add as 'system' content
+    pub fn render_system_into<T: AsRef<str>>(&self, completion: &mut impl LlmCompletion, vars: &HashMap<&str, T>) -> Result<(), Box<dyn std::error::Error + Send>> {
+        completion.add_system(&self.render(vars)?);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template_render() {
+        let prompt = PromptTemplate::new("${persona}\nClassify: ${text}\n${examples}")
+            .partial("persona", "You are a careful ${domain} analyst.")
+            .example("great product", "positive")
+            .example("broke in a day", "negative");
+
+        assert_eq!(prompt.variables(), vec!["domain", "text"]);
+
+        let text = prompt.render(&HashMap::from([("domain", "retail"), ("text", "ok I guess")])).unwrap();
+
+        assert_eq!(text, "You are a careful retail analyst.\nClassify: ok I guess\nInput: great product\nOutput: positive\n\nInput: broke in a day\nOutput: negative");
+    }
+
+    #[test]
+    fn test_template_missing_variable() {
+        let prompt = PromptTemplate::new("Summarise ${text} in ${words} words");
+        let err = prompt.render(&HashMap::from([("text", "this")])).unwrap_err();
+
+        assert!(err.to_string().contains("words"));
+    }
+}
@@ -0,0 +1,141 @@
+//! Tenants, for services calling LLMs on behalf of many customers. A
+//! tenant has its own API keys and quotas, and its usage is tracked apart
+//! from other tenants'. Register each tenant once, then make its calls
+//! inside `with_tenant`:
+//!
+//! `with_tenant("acme", call_llm_model(...)).await?`
+//!
+//! Keys a tenant does not have come from the credentials provider as
+//! usual, so tenants may share the service's keys for some providers.
+//! Calls outside `with_tenant` are the service's own.
+use std::collections::HashMap;
+use std::sync::RwLock;
+use crate::common::llm_name;
+use crate::error::LlmClientError;
+use crate::quotas::Quota;
+
+/// A customer's keys and quotas
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Tenant {
+    pub id: String,
+    /// Secrets by name, e.g. OPENAI_API_KEY
+    pub keys: HashMap<String, String>,
+    /// Monthly quotas by provider
+    pub quotas: HashMap<String, Quota>,
+}
+
+impl Tenant {
+    pub fn new(id: &str) -> Self {
+        Tenant { id: id.into(), ..Default::default() }
+    }
+
+    pub fn with_key(mut self, name: &str, value: &str) -> Self {
+        self.keys.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn with_quota(mut self, llm: &str, quota: Quota) -> Self {
+        self.quotas.insert(llm_name(llm).unwrap_or(llm).into(), quota);
+        self
+    }
+}
+
+static TENANTS: RwLock<Option<HashMap<String, Tenant>>> = RwLock::new(None);
+
+tokio::task_local! {
+    static TENANT: String;
+}
+
+/// Add or replace a tenant. Clients built with its old keys are dropped.
+pub fn register_tenant(tenant: Tenant) {
+    crate::providers::providers().clear_tenant(&tenant.id);
+    TENANTS.write().unwrap().get_or_insert_with(HashMap::new).insert(tenant.id.clone(), tenant);
+}
+
+/// Remove a tenant, returning it if it was registered
+pub fn remove_tenant(id: &str) -> Option<Tenant> {
+    crate::providers::providers().clear_tenant(id);
+    TENANTS.write().unwrap().as_mut()?.remove(id)
+}
+
+/// Registered tenant, if any
+pub fn tenant(id: &str) -> Option<Tenant> {
+    TENANTS.read().unwrap().as_ref()?.get(id).cloned()
+}
+
+/// Run LLM calls for a tenant, with its keys and against its quotas.
+/// UnknownTenant, without running them, if it is not registered.
+pub async fn with_tenant<F: std::future::Future>(id: &str, call: F) -> Result<F::Output, Box<dyn std::error::Error + Send>> {
+    if tenant(id).is_none() {
+        return Err(Box::new(LlmClientError::UnknownTenant(id.into())));
+    }
+
+    Ok(TENANT.scope(id.to_string(), call).await)
+}
+
+/// Tenant of the current call, None if the service's own
+pub fn current_tenant() -> Option<String> {
+    TENANT.try_with(|t| t.clone()).ok()
+}
+
+/// Current tenant's own secret, if it has one
+pub(crate) fn tenant_credential(name: &str) -> Option<String> {
+    let id = current_tenant()?;
+
+    TENANTS.read().unwrap().as_ref()?.get(&id)?.keys.get(name).cloned()
+}
+
+/// Tenant's quota for a provider, if it has one
+pub(crate) fn tenant_quota(id: &str, llm: &str) -> Option<Quota> {
+    TENANTS.read().unwrap().as_ref()?.get(id)?.quotas.get(llm).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+    use crate::usage::{UsageTracker, set_usage_tracker, clear_usage_tracker};
+
+    #[tokio::test]
+    #[serial]
+    async fn test_tenants() {
+        let server = crate::mock::mock_post_sequence("/v1/chat/completions", &[(200, "gpt/success.json"), (200, "gpt/success.json")]).await;
+        let tracker = std::sync::Arc::new(UsageTracker::new());
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+        crate::quotas::clear_quotas();
+        set_usage_tracker(tracker.clone());
+        register_tenant(Tenant::new("acme").with_key("OPENAI_API_KEY", "acme-key").with_quota("openai", Quota::new().with_hard_tokens(10)));
+
+        let prompt = vec!["Say hello".to_string()];
+        let call = || crate::common::call_llm_model("gpt", "gpt-4o", "", &prompt, 0.2, false, false);
+
+        assert_eq!(with_tenant("acme", call()).await.unwrap().unwrap().text.trim(), "Hello there.");
+        call().await.unwrap();
+
+        // Over its quota, while the service is not limited
+        let err = with_tenant("acme", call()).await.unwrap().unwrap_err();
+
+        assert!(matches!(err.downcast_ref::<LlmClientError>(), Some(LlmClientError::QuotaExceeded { .. })));
+
+        // Never the service's keys for a tenant that is not registered
+        let err = with_tenant("initech", call()).await.unwrap_err();
+
+        assert!(matches!(err.downcast_ref::<LlmClientError>(), Some(LlmClientError::UnknownTenant(_))));
+
+        let requests = server.received_requests().await.unwrap();
+        let key = |i: usize| requests[i].headers.get("authorization").unwrap().to_str().unwrap().to_string();
+
+        assert_eq!(key(0), "Bearer acme-key");
+        assert_eq!(key(1), "Bearer test-key");
+
+        let tenants: Vec<String> = tracker.records().into_iter().map(|r| r.tenant).collect();
+
+        assert_eq!(tenants, vec!["".to_string(), "acme".to_string()]);
+
+        clear_usage_tracker();
+        crate::quotas::clear_quotas();
+        remove_tenant("acme");
+    }
+}
@@ -0,0 +1,59 @@
+/// Rough token count, for budgeting without a round trip to the LLM.
+/// Common tokenizers average about four characters per token for
+/// English text, so this will be out for code and other languages.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Rough token count of many texts e.g. a dialogue
+pub fn estimate_tokens_all(texts: &[String]) -> usize {
+    texts.iter().map(|t| estimate_tokens(t)).sum()
+}
+
+/// Estimated share of a known model's context window a dialogue takes,
+/// as a percentage
+pub fn context_used(model: &str, system: &str, prompts: &[String]) -> Option<f64> {
+    let window = crate::capabilities::capabilities(model)?.max_context;
+
+    Some((estimate_tokens(system) + estimate_tokens_all(prompts)) as f64 * 100.0 / window as f64)
+}
+
+/// Token count of audio as Gemini charges it, a flat 32 tokens per second
+pub fn estimate_audio_tokens(duration: std::time::Duration) -> usize {
+    (duration.as_secs_f64() * 32.0).ceil() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("Hello"), 2);
+        assert_eq!(estimate_tokens_all(&["abcd".into(), "efgh".into()]), 2);
+    }
+
+    #[test]
+    fn test_context_used() {
+        // llama3-8b has an 8,192 token window
+        let prompts = vec!["x".repeat(16_384), "y".repeat(8_192)];
+
+        assert_eq!(context_used("llama3-8b-8192", &"z".repeat(8_192), &prompts), Some(100.0));
+        assert_eq!(context_used("llama3-8b-8192", "", &prompts[1..]), Some(25.0));
+        assert_eq!(context_used("unknown-model", "", &prompts), None);
+    }
+
+    #[test]
+    fn test_estimate_audio_tokens() {
+        // One second of 16kHz mono 16 bit PCM
+        let mut wav = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0\x01\0\x01\0\x80\x3e\0\0\0\x7d\0\0\x02\0\x10\0data\0\x7d\0\0".to_vec();
+        wav.extend(vec![0; 32000]);
+
+        let audio = crate::common::Attachment::new("hello.wav", "audio/wav", wav);
+
+        assert_eq!(audio.duration(), Some(std::time::Duration::from_secs(1)));
+        assert_eq!(estimate_audio_tokens(audio.duration().unwrap()), 32);
+        assert_eq!(estimate_audio_tokens(std::time::Duration::from_millis(1500)), 48);
+    }
+}
@@ -0,0 +1,111 @@
+// Conversation export for sharing: prompts alternate between user and
+// assistant, each with its time if known, followed by token statistics.
+
+use llmclient::dates::civil_from_days;
+
+/// One side of the conversation
+pub struct Turn<'a> {
+    pub role: &'static str,
+    pub text: &'a str,
+    /// Seconds since the Unix epoch
+    pub time: Option<u64>,
+}
+
+/// Turns of a dialogue, user first, with times where known (non zero)
+pub fn turns<'a>(prompts: &'a [String], times: &[u64]) -> Vec<Turn<'a>> {
+    prompts.iter().enumerate()
+        .map(|(i, text)| Turn {
+            role: if i % 2 == 0 { "User" } else { "Assistant" },
+            text: text.trim_end(),
+            time: times.get(i).copied().filter(|&t| t > 0),
+        })
+        .collect()
+}
+
+/// Markdown transcript. Replies are usually markdown already so are left as is.
+pub fn to_markdown(title: &str, system: &str, turns: &[Turn], stats: &str) -> String {
+    let mut out = format!("# {title}\n\n");
+
+    if !system.is_empty() {
+        out.push_str(&format!("**System:**\n\n> {}\n\n", system.trim_end().replace('\n', "\n> ")));
+    }
+
+    for turn in turns {
+        match turn.time {
+            Some(time) => out.push_str(&format!("## {} _({})_\n\n", turn.role, utc(time))),
+            None => out.push_str(&format!("## {}\n\n", turn.role)),
+        }
+        out.push_str(turn.text);
+        out.push_str("\n\n");
+    }
+
+    out.push_str(&format!("---\n\n{stats}\n"));
+
+    out
+}
+
+/// Stand-alone HTML page of the transcript, text shown as written
+pub fn to_html(title: &str, system: &str, turns: &[Turn], stats: &str) -> String {
+    let mut out = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n<style>\n\
+        body {{ font-family: sans-serif; max-width: 50em; margin: auto; }}\n\
+        .text {{ white-space: pre-wrap; }}\n\
+        .system, .user {{ background: #f4f4f4; padding: 0.5em; }}\n\
+        .time, .stats {{ color: #777; font-size: smaller; }}\n\
+        </style>\n</head>\n<body>\n<h1>{0}</h1>\n", escape(title));
+
+    if !system.is_empty() {
+        out.push_str(&format!("<h2>System</h2>\n<div class=\"system text\">{}</div>\n", escape(system.trim_end())));
+    }
+
+    for turn in turns {
+        out.push_str(&format!("<h2>{}", turn.role));
+        if let Some(time) = turn.time {
+            out.push_str(&format!(" <span class=\"time\">{}</span>", utc(time)));
+        }
+        out.push_str(&format!("</h2>\n<div class=\"{} text\">{}</div>\n", turn.role.to_lowercase(), escape(turn.text)));
+    }
+
+    out.push_str(&format!("<hr>\n<p class=\"stats\">{}</p>\n</body>\n</html>\n", escape(stats)));
+
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Seconds since the Unix epoch as e.g. 2024-06-20 13:45:00 UTC
+pub fn utc(secs: u64) -> String {
+    let (y, m, d) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+
+    format!("{y:04}-{m:02}-{d:02} {:02}:{:02}:{:02} UTC", rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_utc() {
+        assert_eq!(utc(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(utc(1718891100), "2024-06-20 13:45:00 UTC");
+        assert_eq!(utc(951782400), "2000-02-29 00:00:00 UTC");
+    }
+
+    #[test]
+    fn test_transcript() {
+        let prompts = vec!["What is 2 < 3?".to_string(), "**Yes**\n".to_string()];
+        let turns = turns(&prompts, &[1718891100, 0]);
+        let markdown = to_markdown("Chat", "Be brief", &turns, "Tokens: 10");
+        let html = to_html("Chat", "", &turns, "Tokens: 10");
+
+        assert!(markdown.contains("> Be brief\n\n## User _(2024-06-20 13:45:00 UTC)_\n\nWhat is 2 < 3?\n\n## Assistant\n\n**Yes**\n\n"));
+        assert!(html.contains("<div class=\"user text\">What is 2 &lt; 3?</div>"));
+        assert!(html.contains("<h2>Assistant</h2>\n<div class=\"assistant text\">**Yes**</div>"));
+        assert!(!html.contains("System"));
+    }
+}
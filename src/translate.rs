@@ -0,0 +1,176 @@
+use futures::StreamExt;
+use crate::chunking::chunk_by_paragraphs;
+use crate::common::{LlmReturn, Triple, call_llm_model};
+
+/// Chunk size, small enough that a translation fits any model's output limit
+const DEFAULT_CHUNK_TOKENS: usize = 1_500;
+
+/// A translated text and the call for each chunk of it
+#[derive(Debug, Clone)]
+pub struct Translation {
+    pub text: String,
+    /// One per chunk, in order, with its own usage and timing
+    pub chunks: Vec<LlmReturn>,
+}
+
+impl Translation {
+    /// Usage of all chunks added up
+    pub fn usage(&self) -> Triple {
+        self.chunks.iter().fold((0, 0, 0), |(i, o, t), c| (i + c.usage.0, o + c.usage.1, t + c.usage.2))
+    }
+}
+
+/// Translates text, paragraphs grouped into chunks translated
+/// concurrently, so long documents work with any model. A glossary fixes
+/// how terms are translated, e.g. product names that must stay as they are.
+#[derive(Debug, Clone)]
+pub struct Translator {
+    pub llm: String,
+    pub model: String,
+    /// Language name, e.g. "French"
+    pub target: String,
+    /// Detected by the model if not given
+    pub source: Option<String>,
+    /// Terms and their required translations
+    pub glossary: Vec<(String, String)>,
+    pub chunk_tokens: usize,
+    /// Chunks in flight at once
+    pub concurrency: usize,
+}
+
+impl Translator {
+    pub fn new(llm: &str, model: &str, target: &str) -> Self {
+        Translator {
+            llm: llm.into(),
+            model: model.into(),
+            target: target.into(),
+            source: None,
+            glossary: Vec::new(),
+            chunk_tokens: DEFAULT_CHUNK_TOKENS,
+            concurrency: 4,
+        }
+    }
+
+    pub fn source(mut self, source: &str) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Always translate term as translation
+    pub fn term(mut self, term: &str, translation: &str) -> Self {
+        self.glossary.push((term.into(), translation.into()));
+        self
+    }
+
+    pub fn glossary(mut self, glossary: &[(&str, &str)]) -> Self {
+        self.glossary.extend(glossary.iter().map(|(t, tr)| (t.to_string(), tr.to_string())));
+        self
+    }
+
+    pub fn chunk_tokens(mut self, chunk_tokens: usize) -> Self {
+        self.chunk_tokens = chunk_tokens.max(1);
+        self
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    fn system(&self) -> String {
+        let from = match self.source {
+            Some(ref source) => format!(" from {source}"),
+            None => String::new(),
+        };
+        let mut system = format!("You are a professional translator. Translate the text{from} into {}. \
+            Keep the meaning, tone and formatting, including markdown, line breaks, numbers and code. \
+            Do not translate code, URLs or placeholders such as {{name}} or ${{name}}. \
+            Reply with the translation only, without notes or the original.", self.target);
+
+        if !self.glossary.is_empty() {
+            system.push_str("\n\nAlways translate these terms as given:\n");
+            self.glossary.iter().for_each(|(term, translation)| system.push_str(&format!("- {term} -> {translation}\n")));
+        }
+
+        system
+    }
+
+    async fn call(&self, system: &str, chunk: &str) -> Result<LlmReturn, Box<dyn std::error::Error + Send>> {
+        let ret = call_llm_model(&self.llm, &self.model, system, &[chunk.to_string()], 0.0, false, false).await?;
+
+        if ret.llm_type.is_error() {
+            return Err(Box::new(std::io::Error::other(format!("Translation failed: {}", ret.text))));
+        }
+
+        Ok(ret)
+    }
+
+    /// Translated text, chunks joined by blank lines as paragraphs were
+    pub async fn translate(&self, text: &str) -> Result<Translation, Box<dyn std::error::Error + Send>> {
+        let system = self.system();
+        let chunks: Vec<LlmReturn> = futures::stream::iter(chunk_by_paragraphs(text, self.chunk_tokens, 0))
+            .map(|chunk| {
+                let system = &system;
+
+                async move { self.call(system, &chunk).await }
+            })
+            .buffered(self.concurrency)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<_, _>>()?;
+        let text = chunks.iter().map(|c| c.text.trim()).collect::<Vec<_>>().join("\n\n");
+
+        Ok(Translation { text, chunks })
+    }
+}
+
+/// Translate text into the target language, from source if known
+pub async fn translate(llm: &str, model: &str, text: &str, source: Option<&str>, target: &str) -> Result<Translation, Box<dyn std::error::Error + Send>> {
+    let translator = Translator::new(llm, model, target);
+    let translator = match source {
+        Some(source) => translator.source(source),
+        None => translator,
+    };
+
+    translator.translate(text).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[tokio::test]
+    #[serial]
+    async fn test_translate() {
+        let server = wiremock::MockServer::start().await;
+
+        wiremock::Mock::given(wiremock::matchers::method("POST"))
+            .respond_with(wiremock::ResponseTemplate::new(200).set_body_string(crate::mock::fixture("gpt/french.json")))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        std::env::set_var("GPT_CHAT_URL", format!("{}/v1/chat/completions", server.uri()));
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+
+        let translation = Translator::new("gpt", "gpt-4o", "French")
+            .source("English")
+            .term("Acme Cloud", "Acme Cloud")
+            .chunk_tokens(10)
+            .translate("The capital of Australia is Canberra.\n\nIt is in the south east of the country.")
+            .await
+            .unwrap();
+
+        assert_eq!(translation.chunks.len(), 2);
+        assert_eq!(translation.usage(), (80, 36, 116));
+        assert!(translation.text.contains("Canberra, qui est dans le sud-est du pays.\n\nLa capitale"));
+
+        let requests = server.received_requests().await.unwrap();
+        let body = String::from_utf8_lossy(&requests[0].body);
+
+        assert!(body.contains("from English into French"));
+        assert!(body.contains("- Acme Cloud -> Acme Cloud"));
+    }
+}
@@ -0,0 +1,185 @@
+//! Calls, tokens and estimated cost per tenant, provider, model and UTC
+//! day, as recorded by this crate, for reconciling against provider invoices.
+//! Install a tracker with `set_usage_tracker` and every call to a provider
+//! is added to it; export the totals as CSV or JSON whenever needed.
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use serde_derive::{Deserialize, Serialize};
+use crate::common::{LlmReturn, Triple};
+use crate::dates::civil_from_days;
+use crate::pricing::cost;
+use crate::tenants::current_tenant;
+
+/// Totals for one tenant, provider and model on one day
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UsageRecord {
+    /// UTC, as YYYY-MM-DD
+    pub day: String,
+    /// Empty for calls outside `tenants::with_tenant`
+    #[serde(default)]
+    pub tenant: String,
+    pub llm: String,
+    pub model: String,
+    pub calls: usize,
+    /// Failed requests and provider error replies
+    pub errors: usize,
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+    /// US dollars at list price, None if the model's price is not known
+    pub cost: Option<f64>,
+}
+
+/// Running totals, safe to share between tasks
+#[derive(Debug, Default)]
+pub struct UsageTracker {
+    records: Mutex<BTreeMap<(String, String, String, String), UsageRecord>>,
+}
+
+impl UsageTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the outcome of a call made now
+    pub fn record(&self, llm: &str, model: &str, res: &Result<LlmReturn, Box<dyn std::error::Error + Send>>) {
+        let day = utc_day(std::time::SystemTime::now());
+        let tenant = current_tenant().unwrap_or_default();
+
+        match res {
+            Ok(ret) => self.add(&day, &tenant, llm, model, ret.usage, ret.llm_type.is_error()),
+            Err(_) => self.add(&day, &tenant, llm, model, (0, 0, 0), true),
+        }
+    }
+
+    /// Add one call's usage to a day's totals for a tenant, empty if none
+    pub fn add(&self, day: &str, tenant: &str, llm: &str, model: &str, usage: Triple, is_error: bool) {
+        let mut records = self.records.lock().unwrap();
+        let record = records.entry((day.into(), tenant.into(), llm.into(), model.into()))
+            .or_insert_with(|| UsageRecord {
+                day: day.into(),
+                tenant: tenant.into(),
+                llm: llm.into(),
+                model: model.into(),
+                calls: 0,
+                errors: 0,
+                input_tokens: 0,
+                output_tokens: 0,
+                cost: cost(model, (0, 0, 0)),
+            });
+
+        record.calls += 1;
+        record.errors += is_error as usize;
+        record.input_tokens += usage.0;
+        record.output_tokens += usage.1;
+        record.cost = cost(model, (record.input_tokens, record.output_tokens, 0));
+    }
+
+    /// Totals by day, then tenant, provider and model
+    pub fn records(&self) -> Vec<UsageRecord> {
+        self.records.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Forget everything recorded so far
+    pub fn reset(&self) {
+        self.records.lock().unwrap().clear();
+    }
+
+    /// Totals as CSV with a header line
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("day,tenant,llm,model,calls,errors,input_tokens,output_tokens,cost\n");
+
+        for r in self.records() {
+            let cost = r.cost.map(|c| format!("{c:.6}")).unwrap_or_default();
+
+            csv.push_str(&format!("{},{},{},{},{},{},{},{},{cost}\n", r.day, csv_field(&r.tenant), csv_field(&r.llm), csv_field(&r.model), r.calls, r.errors, r.input_tokens, r.output_tokens));
+        }
+
+        csv
+    }
+
+    /// Totals as a JSON array
+    pub fn to_json(&self) -> Result<String, Box<dyn std::error::Error + Send>> {
+        serde_json::to_string_pretty(&self.records())
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+    }
+
+    /// Write the totals to a file, as JSON if it ends .json and CSV otherwise
+    pub fn export(&self, path: &str) -> Result<(), Box<dyn std::error::Error + Send>> {
+        let text = if path.to_lowercase().ends_with(".json") { self.to_json()? } else { self.to_csv() };
+
+        std::fs::write(path, text)
+            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })
+    }
+}
+
+// Quoted if it would otherwise split or break the line
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// UTC date of a time as YYYY-MM-DD
+pub fn utc_day(time: std::time::SystemTime) -> String {
+    let days = time.duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs() / 86_400).unwrap_or(0) as i64;
+    let (year, month, day) = civil_from_days(days);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+static USAGE_TRACKER: RwLock<Option<Arc<UsageTracker>>> = RwLock::new(None);
+
+/// Add every call to the tracker from now on, keep a clone to export from
+pub fn set_usage_tracker(tracker: Arc<UsageTracker>) {
+    *USAGE_TRACKER.write().unwrap() = Some(tracker);
+}
+
+/// Stop tracking usage
+pub fn clear_usage_tracker() {
+    *USAGE_TRACKER.write().unwrap() = None;
+}
+
+/// Tracker in use, if any
+pub fn usage_tracker() -> Option<Arc<UsageTracker>> {
+    USAGE_TRACKER.read().unwrap().clone()
+}
+
+/// Add a call to the tracker in use, if any
+pub(crate) fn record_usage(llm: &str, model: &str, res: &Result<LlmReturn, Box<dyn std::error::Error + Send>>) {
+    if let Some(tracker) = usage_tracker() {
+        tracker.record(llm, model, res);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_export() {
+        let tracker = UsageTracker::new();
+
+        tracker.add("2024-06-01", "", "gpt", "gpt-4o", (1_000, 500, 1_500), false);
+        tracker.add("2024-06-01", "", "gpt", "gpt-4o", (0, 0, 0), true);
+        tracker.add("2024-05-31", "acme", "groq", "local,model", (10, 5, 15), false);
+
+        let records = tracker.records();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].day, "2024-05-31");
+        assert_eq!((records[1].calls, records[1].errors, records[1].input_tokens), (2, 1, 1_000));
+        assert_eq!(records[1].cost, Some(0.0125));
+
+        let csv = tracker.to_csv();
+
+        assert_eq!(csv.lines().nth(1), Some("2024-05-31,acme,groq,\"local,model\",1,0,10,5,"));
+        assert_eq!(csv.lines().nth(2), Some("2024-06-01,,gpt,gpt-4o,2,1,1000,500,0.012500"));
+
+        let json: Vec<UsageRecord> = serde_json::from_str(&tracker.to_json().unwrap()).unwrap();
+
+        assert_eq!(json, records);
+        assert_eq!(utc_day(std::time::UNIX_EPOCH + std::time::Duration::from_secs(951_782_400)), "2000-02-29");
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use serde_derive::{Deserialize, Serialize};
+use crate::embeddings::{call_embeddings, cosine_similarity};
+
+/// Text with its embedding and metadata to filter on
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Document {
+    pub id: String,
+    pub text: String,
+    pub embedding: Vec<f32>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
+/// Document found by a query, with its similarity score
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    pub document: Document,
+    pub score: f32,
+}
+
+/// Store of embedded documents for retrieval-augmented prompts
+pub trait VectorStore {
+    /// Insert or replace documents by id
+    fn upsert(&mut self, documents: Vec<Document>) -> impl std::future::Future<Output = Result<(), Box<dyn std::error::Error + Send>>> + Send;
+
+    /// Up to k most similar documents, with all filter metadata matching
+    fn query(&self, embedding: &[f32], k: usize, filter: Option<&HashMap<String, String>>) -> impl std::future::Future<Output = Result<Vec<Match>, Box<dyn std::error::Error + Send>>> + Send;
+
+    /// Remove document, true if it existed
+    fn delete(&mut self, id: &str) -> impl std::future::Future<Output = Result<bool, Box<dyn std::error::Error + Send>>> + Send;
+}
+
+/// Simple in-memory store, optionally saved to a JSON file on each change
+#[derive(Debug, Default, Clone)]
+pub struct MemoryVectorStore {
+    documents: Vec<Document>,
+    path: Option<String>,
+}
+
+impl MemoryVectorStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store backed by file, loaded if it already exists
+    pub fn open(path: &str) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        let documents = if std::path::Path::new(path).exists() {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+            serde_json::from_str(&text)
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?
+        } else {
+            Vec::new()
+        };
+
+        Ok(MemoryVectorStore { documents, path: Some(path.into()) })
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    fn save(&self) -> Result<(), Box<dyn std::error::Error + Send>> {
+        if let Some(ref path) = self.path {
+            let text = serde_json::to_string(&self.documents)
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+
+            std::fs::write(path, text)
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+        }
+
+        Ok(())
+    }
+}
+
+impl VectorStore for MemoryVectorStore {
+    async fn upsert(&mut self, documents: Vec<Document>) -> Result<(), Box<dyn std::error::Error + Send>> {
+        for document in documents {
+            match self.documents.iter_mut().find(|d| d.id == document.id) {
+                Some(existing) => *existing = document,
+                None => self.documents.push(document),
+            }
+        }
+
+        self.save()
+    }
+
+    async fn query(&self, embedding: &[f32], k: usize, filter: Option<&HashMap<String, String>>) -> Result<Vec<Match>, Box<dyn std::error::Error + Send>> {
+        let mut matches: Vec<Match> = self.documents.iter()
+            .filter(|d| filter.map(|f| f.iter().all(|(key, value)| d.metadata.get(key) == Some(value))).unwrap_or(true))
+            .map(|d| Match { document: d.clone(), score: cosine_similarity(embedding, &d.embedding) })
+            .collect();
+
+        matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+        matches.truncate(k);
+
+        Ok(matches)
+    }
+
+    async fn delete(&mut self, id: &str) -> Result<bool, Box<dyn std::error::Error + Send>> {
+        let len = self.documents.len();
+
+        self.documents.retain(|d| d.id != id);
+
+        if self.documents.len() == len {
+            Ok(false)
+        } else {
+            self.save()?;
+
+            Ok(true)
+        }
+    }
+}
+
+/// Embed texts, given as (id, text, metadata), and add them to the store
+pub async fn add_texts(store: &mut impl VectorStore, llm: &str, model: &str, texts: Vec<(String, String, HashMap<String, String>)>) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let input: Vec<String> = texts.iter().map(|(_, text, _)| text.clone()).collect();
+    let embeddings = call_embeddings(llm, model, &input).await?;
+
+    let documents = texts.into_iter()
+        .zip(embeddings.vectors)
+        .map(|((id, text, metadata), embedding)| Document { id, text, embedding, metadata })
+        .collect();
+
+    store.upsert(documents).await
+}
+
+/// Embed query and return up to k most similar documents
+pub async fn retrieve(store: &impl VectorStore, llm: &str, model: &str, query: &str, k: usize, filter: Option<&HashMap<String, String>>) -> Result<Vec<Match>, Box<dyn std::error::Error + Send>> {
+    let embeddings = call_embeddings(llm, model, &[query.to_string()]).await?;
+
+    match embeddings.vectors.first() {
+        Some(embedding) => store.query(embedding, k, filter).await,
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Prompt answering a question from retrieved documents
+pub fn context_prompt(question: &str, matches: &[Match]) -> String {
+    let context: Vec<String> = matches.iter()
+        .map(|m| format!("[{}]\n{}", m.document.id, m.document.text))
+        .collect();
+
+    format!("Answer the question using only the context below. If the context does not contain the answer, say so.\n\nContext:\n{}\n\nQuestion: {question}", context.join("\n\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(id: &str, embedding: Vec<f32>, lang: &str) -> Document {
+        Document { id: id.into(), text: id.into(), embedding, metadata: HashMap::from([("lang".to_string(), lang.to_string())]) }
+    }
+
+    #[tokio::test]
+    async fn test_memory_vector_store() {
+        let mut store = MemoryVectorStore::new();
+
+        store.upsert(vec![
+            document("a", vec![1.0, 0.0], "en"),
+            document("b", vec![0.7, 0.7], "fr"),
+            document("c", vec![0.0, 1.0], "en"),
+        ]).await.unwrap();
+        store.upsert(vec![document("c", vec![0.1, 1.0], "en")]).await.unwrap();
+
+        assert_eq!(store.len(), 3);
+
+        let matches = store.query(&[1.0, 0.1], 2, None).await.unwrap();
+
+        assert_eq!(matches[0].document.id, "a");
+        assert_eq!(matches[1].document.id, "b");
+
+        let filter = HashMap::from([("lang".to_string(), "en".to_string())]);
+        let matches = store.query(&[0.7, 0.7], 3, Some(&filter)).await.unwrap();
+
+        assert_eq!(matches.len(), 2);
+        assert!(store.delete("a").await.unwrap());
+        assert!(!store.delete("a").await.unwrap());
+    }
+}
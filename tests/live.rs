@@ -0,0 +1,101 @@
+//! Live checks against the real provider APIs, kept apart from the offline
+//! unit tests. They cost (a little) money, so only run when asked:
+//!
+//! ```text
+//! cargo test --features integration-tests --test live -- --nocapture
+//! ```
+//!
+//! Every provider whose settings are in the environment gets one cheap smoke
+//! prompt and one function call; LLM_LIVE_PROVIDERS=gpt,claude narrows the set.
+//! A compatibility report is printed and the run fails if any chat check fails.
+//! Function calling is reported but not enforced, as it is still provisional.
+#![cfg(feature = "integration-tests")]
+
+use llmclient::common::{call_llm_model, call_llm_model_function, get_model, LlmReturn};
+use llmclient::config::env_var;
+
+/// Environment each provider needs before it is tried
+const PROVIDERS: [(&str, &[&str]); 5] = [
+    ("gemini", &["GEMINI_URL", "GEMINI_MODEL"]),
+    ("gpt", &["GPT_CHAT_URL", "GPT_MODEL", "OPENAI_API_KEY"]),
+    ("claude", &["CLAUDE_URL", "CLAUDE_VERSION", "CLAUDE_MODEL", "ANTHROPIC_API_KEY"]),
+    ("mistral", &["MISTRAL_URL", "MISTRAL_MODEL", "MISTRAL_API_KEY"]),
+    ("groq", &["GROQ_CHAT_URL", "GROQ_MODEL", "GROQ_API_KEY"]),
+];
+
+const SMOKE_PROMPT: &str = "Reply with the single word: pong";
+
+const FUNC_DEF: &str =
+r#"
+// Derive the value of the arithmetic expression
+// expr: An arithmetic expression
+fn arithmetic(expr)
+"#;
+
+/// Providers configured in the environment, less any not in LLM_LIVE_PROVIDERS
+fn configured() -> Vec<&'static str> {
+    let wanted: Option<Vec<String>> = std::env::var("LLM_LIVE_PROVIDERS").ok()
+        .map(|s| s.split(',').map(|p| p.trim().to_lowercase()).collect());
+
+    PROVIDERS.iter()
+        .filter(|(llm, _)| wanted.as_ref().map(|w| w.iter().any(|p| p == llm)).unwrap_or(true))
+        .filter(|(_, vars)| vars.iter().all(|v| env_var(v).is_some()))
+        .map(|(llm, _)| *llm)
+        .collect()
+}
+
+/// One line of the compatibility report
+struct Check {
+    llm: &'static str,
+    model: String,
+    chat: Result<LlmReturn, String>,
+    function: Result<LlmReturn, String>,
+}
+
+impl Check {
+    async fn run(llm: &'static str) -> Self {
+        let model = get_model(llm).unwrap_or_default();
+        let chat = call_llm_model(llm, &model, "", &[SMOKE_PROMPT.to_string()], 0.0, false, false).await
+            .map_err(|e| e.to_string())
+            .and_then(|ret| if ret.llm_type.is_error() || ret.text.trim().is_empty() { Err(ret.text) } else { Ok(ret) });
+        let user = vec!["The answer is (60 * 24) * 365.25".to_string()];
+        let function = call_llm_model_function(llm, &model, "", &user, 0.0, false, false, &[FUNC_DEF]).await
+            .map_err(|e| e.to_string())
+            .and_then(|ret| if ret.llm_type.is_tools() { Ok(ret) } else { Err(format!("{}: {}", ret.llm_type, ret.text.trim())) });
+
+        Check { llm, model, chat, function }
+    }
+
+    fn status(res: &Result<LlmReturn, String>) -> String {
+        match res {
+            Ok(ret) => format!("ok {:.2}s {} tokens", ret.timing.total, ret.usage.2),
+            Err(e) => format!("FAIL {}", e.lines().next().unwrap_or_default()),
+        }
+    }
+}
+
+impl std::fmt::Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:<8} {:<32} chat: {:<28} functions: {}", self.llm, self.model, Check::status(&self.chat), Check::status(&self.function))
+    }
+}
+
+#[tokio::test]
+async fn live_compatibility_report() {
+    let providers = configured();
+
+    if providers.is_empty() {
+        println!("No providers configured, nothing to check");
+
+        return;
+    }
+
+    let checks = futures::future::join_all(providers.into_iter().map(Check::run)).await;
+
+    println!("Compatibility report");
+    checks.iter().for_each(|c| println!("{c}"));
+
+    let failed: Vec<&str> = checks.iter().filter(|c| c.chat.is_err()).map(|c| c.llm).collect();
+
+    assert!(failed.is_empty(), "Chat failed for: {}", failed.join(", "));
+}